@@ -33,6 +33,7 @@ impl Operator for Sub {
         }
         vec![Tensor::new(data)]
     }
+    luminal::op_equality!();
 }
 
 #[derive(Debug, Default)]
@@ -40,7 +41,7 @@ pub struct SubtractionCompiler;
 
 impl Compiler for SubtractionCompiler {
     type Output = ();
-    fn compile<To: ToIdsMut>(&self, graph: &mut Graph, _: To) {
+    fn compile<To: ToIdsMut>(&self, graph: &mut Graph, mut ids: To) {
         let (lhs, rhs) = (node(), node());
         let mul = binary::<Mul>(rhs.clone(), super::constant(-1.));
         let add = binary::<Add>(lhs.clone(), mul.clone());
@@ -82,6 +83,7 @@ impl Compiler for SubtractionCompiler {
                 .input(b, b_edge.1, b_edge.2)
                 .finish();
             move_outgoing_edge(add, sub, &mut graph.graph);
+            remap(add, sub, &mut ids, graph);
 
             graph.graph.remove_node(add);
             s.try_delete();
@@ -113,10 +115,11 @@ impl Operator for Equal {
             } else {
                 0.0
             };
-            data[i] = if a < b { 1. } else { 0. };
+            data[i] = if a == b { 1. } else { 0. };
         }
         vec![Tensor::new(data)]
     }
+    luminal::op_equality!();
 }
 
 #[derive(Debug, Default)]
@@ -124,7 +127,7 @@ pub struct EqualCompiler;
 
 impl Compiler for EqualCompiler {
     type Output = ();
-    fn compile<To: ToIdsMut>(&self, graph: &mut Graph, _: To) {
+    fn compile<To: ToIdsMut>(&self, graph: &mut Graph, mut ids: To) {
         let one = super::constant(1.);
         let (lhs, rhs) = (node(), node());
         let lt1 = binary::<LessThan>(lhs.clone(), rhs.clone());
@@ -160,6 +163,7 @@ impl Compiler for EqualCompiler {
                 .input(rhs, b_edge.1, b_edge.2)
                 .finish();
             move_outgoing_edge(eq, equals, &mut graph.graph);
+            remap(eq, equals, &mut ids, graph);
 
             graph.graph.remove_node(eq);
             s.try_delete();
@@ -188,6 +192,7 @@ impl Operator for Gather {
 
         vec![Tensor::new(out)]
     }
+    luminal::op_equality!(embed_dim);
 }
 
 #[derive(Debug, Default)]
@@ -195,7 +200,7 @@ pub struct GatherCompiler;
 
 impl Compiler for GatherCompiler {
     type Output = ();
-    fn compile<To: ToIdsMut>(&self, graph: &mut Graph, _: To) {
+    fn compile<To: ToIdsMut>(&self, graph: &mut Graph, mut ids: To) {
         let indexes = node();
         let eq = binary::<Equal>(indexes.clone(), op::<ARange>());
         let embedding = node();
@@ -241,6 +246,7 @@ impl Compiler for GatherCompiler {
                 .input(s.get(&embedding), 0, emb_shape)
                 .finish();
             move_outgoing_edge(s.get(&sum_reduce), gather, &mut graph.graph);
+            remap(s.get(&sum_reduce), gather, &mut ids, graph);
             graph.remove_node(s.get(&sum_reduce));
             s.try_delete();
         }
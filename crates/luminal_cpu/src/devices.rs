@@ -0,0 +1,226 @@
+use std::sync::{
+    mpsc::{channel, Receiver, Sender},
+    Arc, Mutex, OnceLock,
+};
+
+use itertools::Itertools;
+use petgraph::{visit::EdgeRef, Direction};
+
+use luminal::{
+    op::{Contiguous, InputTensor, Mul, Operator, SumReduce},
+    prelude::*,
+};
+
+/// Runs the shards of [`TensorParallel`] matmuls on separate threads, standing in for devices.
+///
+/// Shards run at the same time, and the collective joining them waits for all of them. Needs to
+/// run after [`TensorParallel`] and before any other CPU compilers.
+#[derive(Debug, Clone, Copy)]
+pub struct ThreadedDevices {
+    pub devices: usize,
+}
+
+impl Compiler for ThreadedDevices {
+    type Output = ();
+    fn compile<T: ToIdsMut>(&self, graph: &mut Graph, mut ids: T) {
+        let devices = (0..self.devices).map(|_| Device::spawn()).collect_vec();
+        for collective in graph.node_indices().collect_vec() {
+            if !graph.check_node_type::<AllGather>(collective)
+                && !graph.check_node_type::<AllReduce>(collective)
+            {
+                continue;
+            }
+            // Shards come in device order
+            for (device, (sum, _, _)) in graph.get_sources(collective).into_iter().enumerate() {
+                let Some(axis) = graph
+                    .node_weight(sum)
+                    .unwrap()
+                    .as_any()
+                    .downcast_ref::<SumReduce>()
+                    .map(|r| r.0)
+                else {
+                    continue;
+                };
+                let (mul, _, _) = graph.get_sources(sum)[0];
+                if !graph.check_node_type::<Mul>(mul)
+                    || graph.edges_directed(mul, Direction::Outgoing).count() != 1
+                {
+                    continue;
+                }
+                let sources = graph.get_sources(mul);
+                let mut shard = graph.add_op(DeviceMatmul {
+                    axis,
+                    device: devices[device % devices.len()].clone(),
+                });
+                for (n, o, sh) in sources {
+                    shard = shard.input(n, o, sh);
+                }
+                let shard = shard.finish();
+                for (target, weight) in graph
+                    .edges_directed(sum, Direction::Outgoing)
+                    .map(|e| (e.target(), *e.weight()))
+                    .collect_vec()
+                {
+                    graph.graph.add_edge(shard, target, weight);
+                }
+                remap(sum, shard, &mut ids, graph);
+                graph.graph.remove_node(sum);
+                graph.graph.remove_node(mul);
+            }
+            let op = graph.graph.node_weight_mut(collective).unwrap();
+            *op = Box::new(Join(std::mem::replace(op, Box::new(Contiguous))));
+        }
+    }
+}
+
+/// A thread running jobs in the order they're sent. It stops once every handle to it is dropped
+#[derive(Debug, Clone)]
+struct Device(Sender<Box<dyn FnOnce() + Send>>);
+
+impl Device {
+    fn spawn() -> Self {
+        let (sender, receiver) = channel::<Box<dyn FnOnce() + Send>>();
+        std::thread::spawn(move || {
+            for job in receiver {
+                job();
+            }
+        });
+        Self(sender)
+    }
+}
+
+/// A tensor still being computed on a device
+#[derive(Debug, Clone)]
+struct Pending {
+    receiver: Arc<Mutex<Receiver<Vec<f32>>>>,
+    result: Arc<OnceLock<Vec<f32>>>,
+}
+
+impl Pending {
+    fn wait(&self) -> &Vec<f32> {
+        self.result
+            .get_or_init(|| self.receiver.lock().unwrap().recv().unwrap())
+    }
+}
+
+impl Data for Pending {
+    fn as_any(&self) -> &dyn std::any::Any {
+        self
+    }
+    fn as_any_mut(&mut self) -> &mut dyn std::any::Any {
+        self
+    }
+}
+
+/// Multiplies two broadcasted tensors and sums along an axis on a device, without waiting for it
+/// to finish
+#[derive(Debug)]
+struct DeviceMatmul {
+    axis: usize,
+    device: Device,
+}
+
+impl Operator for DeviceMatmul {
+    fn process(&mut self, inp: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
+        // Shapes only live on this thread, so each input is copied without its broadcasted
+        // dimensions, along with how far to step through it along each dimension
+        let dims = inp[0].1.shape_usize();
+        let inputs = inp
+            .into_iter()
+            .map(|(tensor, mut shape)| {
+                let mut strides = vec![0; shape.len()];
+                let mut stride = 1;
+                for axis in (0..shape.len()).rev() {
+                    if !shape.fake[shape.indexes[axis]] {
+                        strides[axis] = stride;
+                        stride *= dims[axis];
+                    } else {
+                        shape.remove_dim(axis);
+                    }
+                }
+                let mut data = Contiguous.process(vec![(tensor, shape)]).pop().unwrap();
+                (
+                    std::mem::take(data.downcast_mut::<Vec<f32>>().unwrap()),
+                    strides,
+                )
+            })
+            .collect_vec();
+
+        let (sender, receiver) = channel();
+        let axis = self.axis;
+        let job = move || {
+            let ((a, a_strides), (b, b_strides)) = (&inputs[0], &inputs[1]);
+            let mut out = vec![0.; dims.iter().product::<usize>() / dims[axis]];
+            for (i, o) in out.iter_mut().enumerate() {
+                let (mut rem, mut a_start, mut b_start) = (i, 0, 0);
+                for d in (0..dims.len()).rev().filter(|d| *d != axis) {
+                    let p = rem % dims[d];
+                    rem /= dims[d];
+                    a_start += p * a_strides[d];
+                    b_start += p * b_strides[d];
+                }
+                *o = (0..dims[axis])
+                    .map(|k| a[a_start + k * a_strides[axis]] * b[b_start + k * b_strides[axis]])
+                    .sum();
+            }
+            sender.send(out).unwrap();
+        };
+        self.device.0.send(Box::new(job)).unwrap();
+        vec![Tensor::new(Pending {
+            receiver: Arc::new(Mutex::new(receiver)),
+            result: Default::default(),
+        })]
+    }
+}
+
+/// Waits for the shards a collective joins before running it
+#[derive(Debug)]
+struct Join(Box<dyn Operator>);
+
+impl Operator for Join {
+    fn process(&mut self, inp: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
+        let inp = inp
+            .into_iter()
+            .map(|(tensor, shape)| {
+                let data = tensor.borrowed().downcast_ref::<Pending>().unwrap().wait();
+                (InputTensor::Owned(Tensor::new(data.clone())), shape)
+            })
+            .collect();
+        self.0.process(inp)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{DeviceMatmul, ThreadedDevices};
+    luminal::test_imports!();
+
+    #[test]
+    fn test_threaded_devices() {
+        // An MLP block, split by output features and then by input features
+        let (x, w1, w2) = (random_vec(4 * 8), random_vec(8 * 12), random_vec(12 * 8));
+        let mut reference = Graph::new();
+        let mut cx = Graph::new();
+        let mut outputs = [&mut reference, &mut cx].map(|cx| {
+            let x = cx.tensor((2, 2, 8)).set(x.clone());
+            let w1 = cx.tensor((8, 12)).set(w1.clone()).shard(Sharding::Columns);
+            let w2 = cx.tensor((12, 8)).set(w2.clone()).shard(Sharding::Rows);
+            x.matmul(w1).relu().matmul(w2).retrieve()
+        });
+        cx.compile(
+            (
+                TensorParallel { devices: 4 },
+                ThreadedDevices { devices: 4 },
+            ),
+            &mut outputs[1],
+        );
+        let shards = cx
+            .node_indices()
+            .filter(|n| cx.check_node_type::<DeviceMatmul>(*n))
+            .count();
+        assert_eq!(shards, 8);
+        reference.execute();
+        cx.execute();
+        assert_close(&outputs[1].data(), &outputs[0].data());
+    }
+}
@@ -1,7 +1,10 @@
 mod binary;
+mod devices;
 mod matmul;
 mod other;
 
+pub use devices::ThreadedDevices;
+
 use std::any::Any;
 
 use itertools::Itertools;
@@ -131,6 +134,7 @@ impl Operator for FusedUnary {
 
         vec![t]
     }
+    luminal::op_equality!(0);
 }
 
 #[cfg(test)]
@@ -190,4 +194,64 @@ mod tests {
         cx.execute();
         assert_close(&c.data(), &unoptimized_c);
     }
+
+    #[test]
+    fn test_cpu_equals() {
+        let mut cx = Graph::new();
+        let a = cx.tensor(5).set(vec![1., 2., 3., 4., 5.]);
+        let b = cx.tensor(5).set(vec![1., 3., 3., 2., 5.]);
+        let mut c = (a.equals(b) * b).retrieve();
+
+        cx.compile(CPUCompiler::default(), &mut c);
+        cx.execute();
+        assert_exact(&c.data(), &[1., 0., 3., 0., 5.]);
+    }
+
+    #[test]
+    fn test_cpu_compilers_remap_outputs() {
+        // Fused ops replacing retrieved nodes take over their ids
+        let mut cx = Graph::new();
+        let a = cx.tensor(3).set(vec![1., 2., 3.]);
+        let b = cx.tensor(3).set(vec![3., 2., 1.]);
+        let embeddings = cx.tensor((4, 2)).set(vec![0., 1., 2., 3., 4., 5., 6., 7.]);
+        let indexes = cx.tensor(2).set(vec![3., 1.]);
+        let mut outputs = (
+            (a - b).retrieve(),
+            a.equals(b).retrieve(),
+            embeddings.gather(indexes).retrieve(),
+            cx.arange(4).retrieve(),
+        );
+
+        cx.compile(CPUCompiler::default(), &mut outputs);
+        cx.execute();
+        assert_exact(&outputs.0.data(), &[-2., 0., 2.]);
+        assert_exact(&outputs.1.data(), &[0., 1., 0.]);
+        assert_exact(&outputs.2.data(), &[6., 7., 2., 3.]);
+        assert_exact(&outputs.3.data(), &[0., 1., 2., 3.]);
+    }
+
+    #[test]
+    fn test_cpu_compiler_graphs() {
+        use luminal::tests::harness::{test_compilers_close, TestCompiler};
+        test_compilers_close(
+            test_graphs::ALL,
+            &[
+                TestCompiler::new(CPUCompiler::default()),
+                TestCompiler::new((GenericCompiler::default(), CPUCompiler::default())),
+            ],
+        );
+    }
+
+    #[test]
+    fn test_fuzz_cpu_compiler() {
+        use luminal::tests::{fuzz::fuzz_compilers, harness::TestCompiler};
+        fuzz_compilers(
+            &[TestCompiler::new((
+                GenericCompiler::default(),
+                CPUCompiler::default(),
+            ))],
+            40,
+            0,
+        );
+    }
 }
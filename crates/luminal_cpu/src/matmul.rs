@@ -40,6 +40,13 @@ impl Compiler for MatMul2DCompiler {
             srcs[0].2.remove_dim(1);
             srcs[1].2.remove_dim(0);
             srcs[1].2.permute(&[1, 0]);
+            // The kernel only understands strides, not slices or padding
+            if srcs
+                .iter()
+                .any(|(_, _, sh)| sh.is_sliced() || sh.is_padded())
+            {
+                continue;
+            }
             let new_op = graph
                 .add_op(MatMul2D)
                 .input(srcs[0].0, 0, srcs[0].2)
@@ -89,6 +96,7 @@ impl Operator for MatMul2D {
 
         vec![Tensor::new(c)]
     }
+    luminal::op_equality!();
 }
 
 #[derive(Debug, Default)]
@@ -127,6 +135,13 @@ impl Compiler for BatchMatMul2DCompiler {
             srcs[1].2.remove_dim(1);
             srcs[1].2.remove_dim(0);
             srcs[1].2.permute(&[1, 0]);
+            // The kernel only understands strides, not slices or padding
+            if srcs
+                .iter()
+                .any(|(_, _, sh)| sh.is_sliced() || sh.is_padded())
+            {
+                continue;
+            }
             let new_op = graph
                 .add_op(BatchedMatMul2D)
                 .input(srcs[0].0, 0, srcs[0].2)
@@ -186,4 +201,5 @@ impl Operator for BatchedMatMul2D {
 
         vec![Tensor::new(c)]
     }
+    luminal::op_equality!();
 }
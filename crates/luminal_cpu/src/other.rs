@@ -22,6 +22,7 @@ impl Operator for ARange {
             (0..n_elements).map(|i| i as f32).collect::<Vec<_>>(),
         )]
     }
+    luminal::op_equality!(size, dyn_map);
 }
 
 #[derive(Debug, Default)]
@@ -29,7 +30,7 @@ pub struct ARangeCompiler;
 
 impl Compiler for ARangeCompiler {
     type Output = ();
-    fn compile<To: ToIdsMut>(&self, graph: &mut Graph, _: To) {
+    fn compile<To: ToIdsMut>(&self, graph: &mut Graph, mut ids: To) {
         // TODO: Make sure this actually checks the shape transformations to ensure pooling happens
         let one1 = super::constant(1.);
         let one2 = super::constant(1.);
@@ -66,6 +67,7 @@ impl Compiler for ARangeCompiler {
                 })
                 .finish();
             move_outgoing_edge(s.get(&sub), arange_op, &mut graph.graph);
+            remap(s.get(&sub), arange_op, &mut ids, graph);
             graph.graph.remove_node(s.get(&sub));
             s.try_delete();
         }
@@ -87,6 +87,7 @@ impl<T: CudaFloat> Operator for CudaSub<T> {
         }
         None
     }
+    luminal::op_equality!();
 }
 
 #[derive(Debug, Default)]
@@ -219,6 +220,7 @@ impl<T: CudaFloat> Operator for CudaEqual<T> {
         }
         None
     }
+    luminal::op_equality!();
 }
 
 #[derive(Debug, Default)]
@@ -348,6 +350,7 @@ impl<T: CudaFloat> Operator for CudaGather<T> {
 
         vec![Tensor::new(CudaData(out))]
     }
+    luminal::op_equality!(embed_dim);
 }
 
 #[derive(Debug, Default)]
@@ -514,6 +514,8 @@ impl<T: CudaFloat> Operator for FusedElementwiseOp<T> {
         }
         None
     }
+    // The kernel is rendered from the subexpressions and input shapes
+    luminal::op_equality!(subexpressions, output_buffer_sizes, dyn_chars, dyn_map);
 }
 
 #[cfg(test)]
@@ -103,6 +103,7 @@ impl<T: CudaFloat> Operator for Matmul<T> {
 
         vec![Tensor::new(CudaData(out))]
     }
+    luminal::op_equality!();
 }
 
 #[derive(Default)]
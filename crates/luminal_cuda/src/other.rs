@@ -68,6 +68,7 @@ impl<T: CudaFloat> Operator for CudaARange<T> {
 
         vec![Tensor::new(CudaData(out))]
     }
+    luminal::op_equality!(size, dyn_map);
 }
 
 #[derive(Debug, Default)]
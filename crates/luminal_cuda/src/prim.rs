@@ -42,6 +42,7 @@ impl<T: CudaFloat> Operator for CudaCopyToDevice<T> {
             .collect::<Vec<_>>();
         vec![Tensor::new(CudaData(self.0.htod_sync_copy(&vec).unwrap()))]
     }
+    luminal::op_equality!();
 }
 
 /// Copy a tensor from the GPU
@@ -69,6 +70,7 @@ impl<T: CudaFloat> Operator for CudaCopyFromDevice<T> {
             buf.into_iter().map(T::to_f32).collect::<Vec<_>>(),
         )]
     }
+    luminal::op_equality!();
 }
 
 /// Constant value on device
@@ -121,6 +123,7 @@ impl<T: CudaFloat> Operator for CudaConstant<T> {
         }
         None
     }
+    luminal::op_equality!(value, dyn_map);
 }
 
 #[macro_export]
@@ -192,6 +195,7 @@ macro_rules! cuda_unary_op {
 
                 None
             }
+            luminal::op_equality!();
         }
 
         $crate::debug_type!($op_name);
@@ -277,6 +281,7 @@ impl<T: CudaFloat> Operator for CudaAdd<T> {
         }
         None
     }
+    luminal::op_equality!();
 }
 
 #[derive(Clone)]
@@ -348,6 +353,7 @@ impl<T: CudaFloat> Operator for CudaMul<T> {
         }
         None
     }
+    luminal::op_equality!();
 }
 
 #[derive(Clone)]
@@ -419,6 +425,7 @@ impl<T: CudaFloat> Operator for CudaMod<T> {
         }
         None
     }
+    luminal::op_equality!();
 }
 
 #[derive(Clone)]
@@ -496,6 +503,7 @@ impl<T: CudaFloat> Operator for CudaLessThan<T> {
         }
         None
     }
+    luminal::op_equality!();
 }
 
 #[derive(Clone)]
@@ -590,6 +598,7 @@ where
         }
         vec![Tensor::new(CudaData(out))]
     }
+    luminal::op_equality!(dim);
 }
 
 #[derive(Clone)]
@@ -680,6 +689,7 @@ impl<T: CudaFloat> Operator for CudaMaxReduce<T> {
         }
         vec![Tensor::new(CudaData(out))]
     }
+    luminal::op_equality!(dim);
 }
 
 /// Convert all primitive ops to cuda primitive ops, and insert copy to and from device ops
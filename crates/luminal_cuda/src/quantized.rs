@@ -168,6 +168,7 @@ impl<T: 'static + CudaFloat> Operator for QuantizedMatmul<T> {
 
         vec![Tensor::new(CudaData(out))]
     }
+    luminal::op_equality!();
 }
 
 #[derive(Clone)]
@@ -243,6 +244,7 @@ impl<T: CudaFloat> Operator for QuantizedGather<T> {
 
         vec![Tensor::new(CudaData(out))]
     }
+    luminal::op_equality!(embed_dim);
 }
 
 #[derive(Default, Debug)]
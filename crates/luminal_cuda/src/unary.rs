@@ -119,6 +119,7 @@ impl<T: CudaFloat> Operator for CudaMeanReduce<T> {
         }
         vec![Tensor::new(CudaData(out))]
     }
+    luminal::op_equality!(dim);
 }
 
 /// Replace the mean reduce pattern with a special kernel. This is meant to be ran **after** the FakeSumReduceCompiler.
@@ -305,6 +306,12 @@ impl<T: CudaFloat> Operator for CudaStdNorm<T> {
 
         vec![Tensor::new(CudaData(out))]
     }
+    fn equals(&self, other: &dyn Operator) -> bool {
+        luminal::op::downcast_op::<Self>(other).is_some_and(|other| other.epsilon == self.epsilon)
+    }
+    fn hash_op(&self, state: &mut dyn std::hash::Hasher) {
+        std::hash::Hash::hash(&self.epsilon.to_bits(), &mut &mut *state);
+    }
 }
 
 /// Replace the mean reduce pattern with a special kernel. This is meant to be ran **after** the FakeSumReduceCompiler.
@@ -600,6 +607,7 @@ impl<T: CudaFloat> Operator for CudaSoftmax<T> {
 
         vec![Tensor::new(CudaData(out))]
     }
+    luminal::op_equality!();
 }
 
 /// Replace the softmax pattern with a special kernel.
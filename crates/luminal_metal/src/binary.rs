@@ -142,6 +142,7 @@ impl<T: MetalFloat> Operator for MetalSub<T> {
         }
         None
     }
+    luminal::op_equality!();
 }
 
 #[derive(Debug, Default)]
@@ -324,6 +325,7 @@ impl<T: MetalFloat> Operator for MetalEqual<T> {
         }
         None
     }
+    luminal::op_equality!();
 }
 
 #[derive(Debug, Default)]
@@ -469,6 +471,7 @@ impl<T: MetalFloat> Operator for MetalGather<T> {
             vec![Tensor::new(MetalBuffer(out))]
         })
     }
+    luminal::op_equality!(embed_dim);
 }
 
 #[derive(Debug, Default)]
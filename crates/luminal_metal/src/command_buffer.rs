@@ -212,6 +212,7 @@ impl Debug for ExecuteMetalKernels {
     }
 }
 
+// Commits a command buffer shared with the kernels recorded into it, so it's never merged
 impl Operator for ExecuteMetalKernels {
     fn process(&mut self, _: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
         let buffer = unsafe { &mut *self.buffer.get() };
@@ -271,6 +272,7 @@ impl MetalKernel for CommandBufferWrapper {
     }
 }
 
+// Records into a command buffer executed by a specific ExecuteMetalKernels, so it's never merged
 impl Operator for CommandBufferWrapper {
     fn process(&mut self, inp: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
         self.without_storage_buffers(
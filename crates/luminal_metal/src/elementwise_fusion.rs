@@ -603,6 +603,14 @@ impl<T: MetalFloat> Operator for FusedElementwiseOp<T> {
         }
         None
     }
+    // The kernel is rendered from the subexpressions and input shapes
+    luminal::op_equality!(
+        subexpressions,
+        kernel_str,
+        output_buffer_sizes,
+        dyn_chars,
+        dyn_map
+    );
 }
 
 #[cfg(test)]
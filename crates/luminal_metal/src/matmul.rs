@@ -197,6 +197,7 @@ impl<T: MetalFloat> Operator for Matmul<T> {
         }
         None
     }
+    luminal::op_equality!();
 }
 
 #[derive(Default, Debug)]
@@ -171,6 +171,7 @@ impl<T: MetalFloat> Operator for MetalARange<T> {
         }
         None
     }
+    luminal::op_equality!(size, dyn_map);
 }
 
 /// Replace the arange pattern with a special kernel. This must be ran **after** the subtraction compiler
@@ -47,6 +47,7 @@ impl<T: MetalFloat> Operator for MetalCopyToDevice<T> {
         );
         vec![Tensor::new(MetalBuffer(buffer))]
     }
+    luminal::op_equality!();
 }
 
 /// Copy a tensor from the GPU
@@ -69,6 +70,7 @@ impl<T: MetalFloat> Operator for MetalCopyFromDevice<T> {
 
         vec![Tensor::new(data)]
     }
+    luminal::op_equality!();
 }
 
 #[derive(Clone)]
@@ -114,6 +116,7 @@ impl<T: MetalFloat> Operator for MetalConstant<T> {
         }
         None
     }
+    luminal::op_equality!(0, 2);
 }
 
 #[macro_export]
@@ -231,6 +234,7 @@ macro_rules! metal_unary_op {
                 }
                 None
             }
+            luminal::op_equality!();
         }
     }
 }
@@ -358,6 +362,7 @@ impl<T: MetalFloat> Operator for MetalAdd<T> {
         }
         None
     }
+    luminal::op_equality!();
 }
 
 #[derive(Clone)]
@@ -477,6 +482,7 @@ impl<T: MetalFloat> Operator for MetalMul<T> {
         }
         None
     }
+    luminal::op_equality!();
 }
 
 #[derive(Clone)]
@@ -604,6 +610,7 @@ impl<T: MetalFloat> Operator for MetalLessThan<T> {
         }
         None
     }
+    luminal::op_equality!();
 }
 
 #[derive(Clone)]
@@ -721,6 +728,7 @@ impl<T: MetalFloat> Operator for MetalMod<T> {
         }
         None
     }
+    luminal::op_equality!();
 }
 
 #[derive(Clone)]
@@ -875,6 +883,7 @@ impl<T: MetalFloat> Operator for MetalSumReduce<T> {
         }
         None
     }
+    luminal::op_equality!(dim, shape);
 }
 
 #[derive(Clone)]
@@ -1029,6 +1038,7 @@ impl<T: MetalFloat> Operator for MetalMaxReduce<T> {
         }
         None
     }
+    luminal::op_equality!(dim, shape);
 }
 
 #[derive(Default, Debug)]
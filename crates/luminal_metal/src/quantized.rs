@@ -414,6 +414,7 @@ impl<T: MetalFloat> Operator for QuantizedMatmul<T> {
         }
         None
     }
+    luminal::op_equality!();
 }
 
 #[derive(Clone)]
@@ -499,6 +500,7 @@ impl<T: MetalFloat> Operator for QuantizedGather<T> {
             vec![Tensor::new(MetalBuffer(out))]
         })
     }
+    luminal::op_equality!(embed_dim);
 }
 
 #[derive(Default)]
@@ -308,6 +308,7 @@ impl Debug for AllocateMetalBuffers {
     }
 }
 
+// Allocates the buffers shared by the graph's kernels, so it's never merged
 impl Operator for AllocateMetalBuffers {
     fn process(&mut self, _: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
         let buffers = unsafe { &mut *self.buffers.get() };
@@ -358,6 +359,7 @@ impl std::fmt::Debug for StorageBufferWrapper {
     }
 }
 
+// Writes into buffers assigned to this node, so it's never merged
 impl Operator for StorageBufferWrapper {
     fn process(&mut self, inp: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
         let buffers = unsafe { self.buffers.get().as_ref().unwrap() };
@@ -172,6 +172,7 @@ impl<T: MetalFloat> Operator for MetalMeanReduce<T> {
         }
         None
     }
+    luminal::op_equality!(3, 7);
 }
 
 /// Replace the mean reduce pattern with a special kernel. This is meant to be ran **after** the FakeSumReduceCompiler.
@@ -383,6 +384,12 @@ impl<T: 'static + Clone> Operator for MetalStdNorm<T> {
         }
         None
     }
+    fn equals(&self, other: &dyn Operator) -> bool {
+        luminal::op::downcast_op::<Self>(other).is_some_and(|other| other.epsilon == self.epsilon)
+    }
+    fn hash_op(&self, state: &mut dyn std::hash::Hasher) {
+        std::hash::Hash::hash(&self.epsilon.to_bits(), &mut &mut *state);
+    }
 }
 
 /// Replace the mean reduce pattern with a special kernel. This is meant to be ran **after** the FakeSumReduceCompiler.
@@ -576,6 +583,7 @@ impl<T: MetalFloat> Operator for MetalExp<T> {
         }
         None
     }
+    luminal::op_equality!();
 }
 
 #[derive(Default, Debug)]
@@ -711,6 +719,7 @@ impl<T: MetalFloat> Operator for MetalCos<T> {
         }
         None
     }
+    luminal::op_equality!();
 }
 
 #[derive(Default, Debug)]
@@ -1,13 +1,135 @@
 use luminal::prelude::*;
 
+/// How much to pad each spatial dimension of a convolution's input
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Padding<const N: usize> {
+    /// (left, right) padding for each spatial dimension
+    Explicit([(usize, usize); N]),
+    /// Pad so the output is the same size as the input, with any odd padding on the right.
+    /// Only supported with a stride of 1.
+    Same,
+}
+
+impl<const N: usize> Default for Padding<N> {
+    fn default() -> Self {
+        Self::Explicit([(0, 0); N])
+    }
+}
+
+impl<const N: usize> Padding<N> {
+    /// Get the (left, right) padding for each spatial dimension
+    fn resolve(
+        &self,
+        kernel: [usize; N],
+        stride: [usize; N],
+        dilation: [usize; N],
+    ) -> [(usize, usize); N] {
+        match self {
+            Padding::Explicit(padding) => *padding,
+            Padding::Same => {
+                assert!(
+                    stride.iter().all(|s| *s == 1),
+                    "Same padding requires a stride of 1"
+                );
+                std::array::from_fn(|i| {
+                    let total = dilation[i] * (kernel[i] - 1);
+                    (total / 2, total - total / 2)
+                })
+            }
+        }
+    }
+}
+
+/// What padded positions of a convolution's input are filled with
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum PaddingMode {
+    #[default]
+    Zeros,
+    /// Mirror the input around its edges, not repeating the edge itself
+    Reflect,
+    /// Repeat the edge values
+    Replicate,
+}
+
+/// Take `len` entries along an axis starting at `start`
+fn take_along(input: GraphTensor, start: Expression, len: usize, axis: usize) -> GraphTensor {
+    let mut dims = input.dims();
+    dims[axis] = len.into();
+    // Reshape to get a simple expression for the new size
+    input.slice_along(start..start + len, axis).reshape(dims)
+}
+
+/// Reverse the order of the entries along an axis
+fn reverse_along(input: GraphTensor, axis: usize) -> GraphTensor {
+    let size = input.dims()[axis].to_usize().unwrap();
+    let last = input.shape.len() - 1;
+    let mut axes = (0..=last).filter(|a| *a != axis).collect::<Vec<_>>();
+    axes.push(axis);
+    let mut inverse = (0..last).collect::<Vec<_>>();
+    inverse.insert(axis, last);
+    input
+        .permute(axes)
+        .matmul(reversal(size, input.graph()))
+        .permute(inverse)
+}
+
+/// Repeat a size 1 axis `times` times
+fn repeat_along(input: GraphTensor, times: usize, axis: usize) -> GraphTensor {
+    let mut dims = input.dims();
+    dims[axis] = times.into();
+    input.expand(axis + 1, times).reshape(dims)
+}
+
+/// Pad the spatial dimensions of a convolution's input, starting at `first_axis`
+fn pad_spatial(
+    mut input: GraphTensor,
+    padding: &[(usize, usize)],
+    first_axis: usize,
+    mode: PaddingMode,
+) -> GraphTensor {
+    for (axis, &(left, right)) in (first_axis..).zip(padding) {
+        if left == 0 && right == 0 {
+            continue;
+        }
+        let size = input.dims()[axis];
+        let (left, right) = match mode {
+            PaddingMode::Zeros => {
+                input = input.pad_along(left, 0, axis).contiguous();
+                input = input.pad_along(0, right, axis);
+                continue;
+            }
+            PaddingMode::Reflect => (
+                (left > 0).then(|| reverse_along(take_along(input, 1.into(), left, axis), axis)),
+                (right > 0)
+                    .then(|| reverse_along(take_along(input, size - right - 1, right, axis), axis)),
+            ),
+            PaddingMode::Replicate => (
+                (left > 0).then(|| repeat_along(take_along(input, 0.into(), 1, axis), left, axis)),
+                (right > 0)
+                    .then(|| repeat_along(take_along(input, size - 1, 1, axis), right, axis)),
+            ),
+        };
+        if let Some(left) = left {
+            input = left.concat_along(input, axis);
+        }
+        if let Some(right) = right {
+            input = input.concat_along(right, axis);
+        }
+    }
+    input
+}
+
 pub struct Conv1D {
-    pub weight: GraphTensor, // ch_out, ch_in * kernel
+    pub weight: GraphTensor, // ch_out, ch_in / groups * kernel
     pub bias: Option<GraphTensor>,
-    padding: usize,
+    padding: Padding<1>,
+    padding_mode: PaddingMode,
     dilation: usize,
     stride: usize,
     kernel: usize,
+    groups: usize,
     ch_in: usize,
+    ch_out: usize,
 }
 
 impl Conv1D {
@@ -23,20 +145,58 @@ impl Conv1D {
         bias: bool,
         cx: &mut Graph,
     ) -> Self {
+        Self::new_grouped(
+            ch_in, ch_out, kernel, stride, dilation, padding, 1, bias, cx,
+        )
+    }
+
+    /// Create a new 1D convolution layer with the channels split into `groups` independent
+    /// convolutions. Use `groups == ch_in` for a depthwise convolution.
+    #[allow(clippy::too_many_arguments)]
+    pub fn new_grouped(
+        ch_in: usize,
+        ch_out: usize,
+        kernel: usize,
+        stride: usize,
+        dilation: usize,
+        padding: usize,
+        groups: usize,
+        bias: bool,
+        cx: &mut Graph,
+    ) -> Self {
+        assert!(
+            ch_in % groups == 0 && ch_out % groups == 0,
+            "Channels must be divisible by groups"
+        );
         Self {
-            weight: cx.named_tensor("Weight", (ch_out, ch_in * kernel)),
+            weight: cx.named_tensor("Weight", (ch_out, ch_in / groups * kernel)),
             bias: if bias {
                 Some(cx.named_tensor("Bias", ch_out))
             } else {
                 None
             },
-            padding,
+            padding: Padding::Explicit([(padding, padding)]),
+            padding_mode: PaddingMode::Zeros,
             dilation,
             stride,
             kernel,
+            groups,
             ch_in,
+            ch_out,
         }
     }
+
+    /// Set the padding on each side of the input
+    pub fn with_padding(mut self, padding: Padding<1>) -> Self {
+        self.padding = padding;
+        self
+    }
+
+    /// Set what the padded positions are filled with
+    pub fn with_padding_mode(mut self, padding_mode: PaddingMode) -> Self {
+        self.padding_mode = padding_mode;
+        self
+    }
 }
 
 impl SerializeModule for Conv1D {
@@ -59,29 +219,43 @@ impl Module<GraphTensor> for Conv1D {
         for _ in 0..n_expands {
             inp = inp.expand(0, 1);
         }
+        let padding = self
+            .padding
+            .resolve([self.kernel], [self.stride], [self.dilation]);
+        let inp = pad_spatial(inp, &padding, 3, self.padding_mode);
 
         let batch1 = inp.dims()[0];
         let batch2 = inp.dims()[1];
-        let dim_in = *input.dims().last().unwrap();
-        let dim_out = (((dim_in + 2 * self.padding - self.dilation * (self.kernel - 1) - 1)
-            / self.stride)
-            + 1)
-        .simplify();
-        let w = self.weight.permute((1, 0));
-        let mut out = inp
-            // Add padding
-            .pad(((0, 0), (0, 0), (0, 0), (self.padding, 0)))
-            .contiguous()
-            .pad(((0, 0), (0, 0), (0, 0), (0, self.padding)))
+        let dim_in = inp.dims()[3];
+        let dim_out =
+            (((dim_in - self.dilation * (self.kernel - 1) - 1) / self.stride) + 1).simplify();
+        let windows = inp
             // Pool
             .pool_last_dim(self.kernel, self.stride, self.dilation)
             // Combine channel_in and kernel
             .permute((0, 1, 3, 2, 4))
-            .reshape((batch1, batch2, dim_out, self.ch_in * self.kernel))
-            .matmul(w)
-            .permute((0, 1, 3, 2));
+            .reshape((batch1, batch2, dim_out, self.ch_in * self.kernel));
+        let mut out = if self.groups == 1 {
+            windows
+                .matmul(self.weight.permute((1, 0)))
+                .permute((0, 1, 3, 2))
+        } else {
+            let group_in = self.ch_in / self.groups * self.kernel;
+            let w = self
+                .weight
+                .reshape((self.groups, self.ch_out / self.groups, group_in))
+                .permute((0, 2, 1))
+                .expand(0, batch1)
+                .expand(1, batch2);
+            windows
+                .reshape((batch1, batch2, dim_out, self.groups, group_in))
+                .permute((0, 1, 3, 2, 4))
+                .matmul(w)
+                .permute((0, 1, 2, 4, 3))
+                .reshape((batch1, batch2, self.ch_out, dim_out))
+        };
         if let Some(b) = self.bias {
-            out += b.expand_to(out.shape);
+            out += b.expand(0, batch1).expand(1, batch2).expand(3, dim_out);
         }
 
         // Reshape back to original shape
@@ -94,11 +268,14 @@ impl Module<GraphTensor> for Conv1D {
 }
 
 pub struct Conv2D {
-    pub weight: GraphTensor,       // ch_out, ch_in * kernel_x * kernel_y
+    pub weight: GraphTensor,       // ch_out, ch_in / groups * kernel_x * kernel_y
     pub bias: Option<GraphTensor>, // ch_out
     kernel: (usize, usize),
     stride: (usize, usize),
     dilation: (usize, usize),
+    padding: Padding<2>,
+    padding_mode: PaddingMode,
+    groups: usize,
     ch_out: usize,
     ch_in: usize,
 }
@@ -113,8 +290,28 @@ impl Conv2D {
         bias: bool,
         cx: &mut Graph,
     ) -> Self {
+        Self::new_grouped(ch_in, ch_out, kernel, stride, dilation, 1, bias, cx)
+    }
+
+    /// Create a new 2D convolution layer with the channels split into `groups` independent
+    /// convolutions. Use `groups == ch_in` for a depthwise convolution.
+    #[allow(clippy::too_many_arguments)]
+    pub fn new_grouped(
+        ch_in: usize,
+        ch_out: usize,
+        kernel: (usize, usize),
+        stride: (usize, usize),
+        dilation: (usize, usize),
+        groups: usize,
+        bias: bool,
+        cx: &mut Graph,
+    ) -> Self {
+        assert!(
+            ch_in % groups == 0 && ch_out % groups == 0,
+            "Channels must be divisible by groups"
+        );
         Self {
-            weight: cx.named_tensor("Weight", (ch_out, ch_in * kernel.0 * kernel.1)),
+            weight: cx.named_tensor("Weight", (ch_out, ch_in / groups * kernel.0 * kernel.1)),
             bias: if bias {
                 Some(cx.named_tensor("Bias", ch_out))
             } else {
@@ -123,10 +320,25 @@ impl Conv2D {
             kernel,
             stride,
             dilation,
+            padding: Padding::default(),
+            padding_mode: PaddingMode::Zeros,
+            groups,
             ch_out,
             ch_in,
         }
     }
+
+    /// Set the padding on each side of the input
+    pub fn with_padding(mut self, padding: Padding<2>) -> Self {
+        self.padding = padding;
+        self
+    }
+
+    /// Set what the padded positions are filled with
+    pub fn with_padding_mode(mut self, padding_mode: PaddingMode) -> Self {
+        self.padding_mode = padding_mode;
+        self
+    }
 }
 
 impl SerializeModule for Conv2D {
@@ -147,6 +359,12 @@ impl Conv2D {
             input = input.expand(0, 1);
             expanded = true;
         }
+        let padding = self.padding.resolve(
+            [self.kernel.0, self.kernel.1],
+            [self.stride.0, self.stride.1],
+            [self.dilation.0, self.dilation.1],
+        );
+        let input = pad_spatial(input, &padding, 2, self.padding_mode);
         let (batch, _, dimx_in, dimy_in) = input.dims4();
         let dimx_out = (((dimx_in - self.dilation.0 * (self.kernel.0 - 1) - 1) / self.stride.0)
             + 1)
@@ -165,14 +383,18 @@ impl Conv2D {
                 dimx_out * dimy_out,
             ));
 
-        let mut o = self.weight.expand(0, batch).matmul(input_pooled).reshape((
-            batch,
-            self.ch_out,
-            dimx_out,
-            dimy_out,
-        ));
+        let mut o = if self.groups == 1 {
+            self.weight.expand(0, batch).matmul(input_pooled)
+        } else {
+            let group_in = self.ch_in / self.groups * self.kernel.0 * self.kernel.1;
+            self.weight
+                .reshape((self.groups, self.ch_out / self.groups, group_in))
+                .expand(0, batch)
+                .matmul(input_pooled.reshape((batch, self.groups, group_in, dimx_out * dimy_out)))
+        }
+        .reshape((batch, self.ch_out, dimx_out, dimy_out));
         if let Some(b) = self.bias {
-            o += b.expand_to(o.shape);
+            o += b.expand(0, batch).expand(2, dimx_out).expand(3, dimy_out);
         }
         if expanded {
             o.reshape((self.ch_out, dimx_out, dimy_out))
@@ -182,11 +404,14 @@ impl Conv2D {
     }
 }
 pub struct Conv3D {
-    pub weight: GraphTensor, // ch_out, ch_in * kernel_x * kernel_y * kernel_z
+    pub weight: GraphTensor, // ch_out, ch_in / groups * kernel_x * kernel_y * kernel_z
     pub bias: Option<GraphTensor>, // ch_out
     kernel: (usize, usize, usize),
     stride: (usize, usize, usize),
     dilation: (usize, usize, usize),
+    padding: Padding<3>,
+    padding_mode: PaddingMode,
+    groups: usize,
     ch_in: usize,
     ch_out: usize,
 }
@@ -201,8 +426,31 @@ impl Conv3D {
         bias: bool,
         cx: &mut Graph,
     ) -> Self {
+        Self::new_grouped(ch_in, ch_out, kernel, stride, dilation, 1, bias, cx)
+    }
+
+    /// Create a new 3D convolution layer with the channels split into `groups` independent
+    /// convolutions. Use `groups == ch_in` for a depthwise convolution.
+    #[allow(clippy::too_many_arguments)]
+    pub fn new_grouped(
+        ch_in: usize,
+        ch_out: usize,
+        kernel: (usize, usize, usize),
+        stride: (usize, usize, usize),
+        dilation: (usize, usize, usize),
+        groups: usize,
+        bias: bool,
+        cx: &mut Graph,
+    ) -> Self {
+        assert!(
+            ch_in % groups == 0 && ch_out % groups == 0,
+            "Channels must be divisible by groups"
+        );
         Self {
-            weight: cx.named_tensor("Weight", (ch_out, ch_in * kernel.0 * kernel.1 * kernel.2)),
+            weight: cx.named_tensor(
+                "Weight",
+                (ch_out, ch_in / groups * kernel.0 * kernel.1 * kernel.2),
+            ),
             bias: if bias {
                 Some(cx.named_tensor("Bias", ch_out))
             } else {
@@ -211,10 +459,25 @@ impl Conv3D {
             kernel,
             stride,
             dilation,
+            padding: Padding::default(),
+            padding_mode: PaddingMode::Zeros,
+            groups,
             ch_in,
             ch_out,
         }
     }
+
+    /// Set the padding on each side of the input
+    pub fn with_padding(mut self, padding: Padding<3>) -> Self {
+        self.padding = padding;
+        self
+    }
+
+    /// Set what the padded positions are filled with
+    pub fn with_padding_mode(mut self, padding_mode: PaddingMode) -> Self {
+        self.padding_mode = padding_mode;
+        self
+    }
 }
 
 impl SerializeModule for Conv3D {
@@ -229,6 +492,12 @@ impl SerializeModule for Conv3D {
 impl Conv3D {
     pub fn forward(&self, input: GraphTensor) -> GraphTensor {
         // Input: ch_in, dimx_in, dimy_in, dimz_in
+        let padding = self.padding.resolve(
+            [self.kernel.0, self.kernel.1, self.kernel.2],
+            [self.stride.0, self.stride.1, self.stride.2],
+            [self.dilation.0, self.dilation.1, self.dilation.2],
+        );
+        let input = pad_spatial(input, &padding, 1, self.padding_mode);
         let dimx_in = input.dims()[1];
         let dimy_in = input.dims()[2];
         let dimz_in = input.dims()[3];
@@ -263,15 +532,272 @@ impl Conv3D {
             dimx_out * dimy_out * dimz_out,
         ));
 
-        self.weight
-            .matmul(reshaped)
-            .reshape((self.ch_out, dimx_out, dimy_out, dimz_out))
+        if self.groups == 1 {
+            self.weight.matmul(reshaped)
+        } else {
+            let group_in = self.ch_in / self.groups * self.kernel.0 * self.kernel.1 * self.kernel.2;
+            self.weight
+                .reshape((self.groups, self.ch_out / self.groups, group_in))
+                .matmul(reshaped.reshape((self.groups, group_in, dimx_out * dimy_out * dimz_out)))
+        }
+        .reshape((self.ch_out, dimx_out, dimy_out, dimz_out))
+    }
+}
+
+/// Spread out the entries along an axis with `stride - 1` zeros between each
+fn dilate_along(input: GraphTensor, stride: usize, axis: usize) -> GraphTensor {
+    if stride == 1 {
+        return input;
+    }
+    let mut dims = input.dims();
+    let size = dims[axis];
+    dims.insert(axis + 1, 1.into());
+    let spread = input
+        .reshape(dims.clone())
+        .pad_along(0, stride - 1, axis + 1);
+    dims.remove(axis + 1);
+    dims[axis] = size * stride;
+    let spread = spread
+        .reshape(dims.clone())
+        .slice_along(..(size - 1) * stride + 1, axis);
+    // Reshape to get a simple expression for the new size
+    dims[axis] = ((size - 1) * stride + 1).simplify();
+    spread.reshape(dims)
+}
+
+/// Pad (positive) or crop (negative) each side of an axis
+fn pad_or_crop(input: GraphTensor, left: isize, right: isize, axis: usize) -> GraphTensor {
+    let mut input = input;
+    if left < 0 || right < 0 {
+        let mut dims = input.dims();
+        let (crop_left, crop_right) = (left.min(0).unsigned_abs(), right.min(0).unsigned_abs());
+        let size = dims[axis];
+        dims[axis] = (size - crop_left - crop_right).simplify();
+        input = input
+            .slice_along(Expression::from(crop_left)..size - crop_right, axis)
+            .reshape(dims);
+    }
+    input.pad_along(left.max(0) as usize, right.max(0) as usize, axis)
+}
+
+/// Anti-diagonal matrix reversing the last dimension of whatever it's multiplied with
+fn reversal(size: usize, cx: &mut Graph) -> GraphTensor {
+    let mut data = vec![0.; size * size];
+    for i in 0..size {
+        data[i * size + size - 1 - i] = 1.;
+    }
+    cx.tensor((size, size)).set(data)
+}
+
+pub struct ConvTranspose1D {
+    pub weight: GraphTensor, // ch_in, ch_out * kernel
+    pub bias: Option<GraphTensor>,
+    kernel: usize,
+    stride: usize,
+    padding: usize,
+    output_padding: usize,
+    dilation: usize,
+    ch_in: usize,
+    ch_out: usize,
+}
+
+impl ConvTranspose1D {
+    /// Create a new 1D transposed convolution layer
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        ch_in: usize,
+        ch_out: usize,
+        kernel: usize,
+        stride: usize,
+        padding: usize,
+        output_padding: usize,
+        dilation: usize,
+        bias: bool,
+        cx: &mut Graph,
+    ) -> Self {
+        Self {
+            weight: cx.named_tensor("Weight", (ch_in, ch_out * kernel)),
+            bias: if bias {
+                Some(cx.named_tensor("Bias", ch_out))
+            } else {
+                None
+            },
+            kernel,
+            stride,
+            padding,
+            output_padding,
+            dilation,
+            ch_in,
+            ch_out,
+        }
+    }
+}
+
+impl SerializeModule for ConvTranspose1D {
+    fn serialize(&self, s: &mut luminal::module::Serializer) {
+        s.tensor("weight", self.weight);
+        if let Some(bias) = self.bias {
+            s.tensor("bias", bias);
+        }
+    }
+}
+
+impl Module<GraphTensor> for ConvTranspose1D {
+    type Output = GraphTensor;
+    fn forward(&self, input: GraphTensor) -> Self::Output {
+        // Input: batch_dims, ch_in, dim_in
+        // A transposed convolution is a convolution over the input spread out by the stride,
+        // using the kernel flipped with input and output channels swapped
+        let last = input.shape.len() - 1;
+        let edge = (self.dilation * (self.kernel - 1)) as isize - self.padding as isize;
+        let input = pad_or_crop(
+            dilate_along(input, self.stride, last),
+            edge,
+            edge + self.output_padding as isize,
+            last,
+        );
+        let weight = self
+            .weight
+            .reshape((self.ch_in, self.ch_out, self.kernel))
+            .matmul(reversal(self.kernel, input.graph()))
+            .permute((1, 0, 2))
+            .reshape((self.ch_out, self.ch_in * self.kernel));
+        Conv1D {
+            weight,
+            bias: self.bias,
+            padding: Padding::default(),
+            padding_mode: PaddingMode::Zeros,
+            dilation: self.dilation,
+            stride: 1,
+            kernel: self.kernel,
+            groups: 1,
+            ch_in: self.ch_in,
+            ch_out: self.ch_out,
+        }
+        .forward(input) // Output: batch_dims, ch_out, dim_out
+    }
+}
+
+pub struct ConvTranspose2D {
+    pub weight: GraphTensor,       // ch_in, ch_out * kernel_x * kernel_y
+    pub bias: Option<GraphTensor>, // ch_out
+    kernel: (usize, usize),
+    stride: (usize, usize),
+    padding: (usize, usize),
+    output_padding: (usize, usize),
+    dilation: (usize, usize),
+    ch_in: usize,
+    ch_out: usize,
+}
+
+impl ConvTranspose2D {
+    /// Create a new 2D transposed convolution layer
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        ch_in: usize,
+        ch_out: usize,
+        kernel: (usize, usize),
+        stride: (usize, usize),
+        padding: (usize, usize),
+        output_padding: (usize, usize),
+        dilation: (usize, usize),
+        bias: bool,
+        cx: &mut Graph,
+    ) -> Self {
+        Self {
+            weight: cx.named_tensor("Weight", (ch_in, ch_out * kernel.0 * kernel.1)),
+            bias: if bias {
+                Some(cx.named_tensor("Bias", ch_out))
+            } else {
+                None
+            },
+            kernel,
+            stride,
+            padding,
+            output_padding,
+            dilation,
+            ch_in,
+            ch_out,
+        }
+    }
+}
+
+impl SerializeModule for ConvTranspose2D {
+    fn serialize(&self, s: &mut luminal::module::Serializer) {
+        s.tensor("weight", self.weight);
+        if let Some(bias) = self.bias {
+            s.tensor("bias", bias);
+        }
+    }
+}
+
+impl Module<GraphTensor> for ConvTranspose2D {
+    type Output = GraphTensor;
+    fn forward(&self, mut input: GraphTensor) -> Self::Output {
+        // Input: (batch (optional), ch_in, dimx_in, dimy_in)
+        // A transposed convolution is a convolution over the input spread out by the stride,
+        // using the kernel flipped with input and output channels swapped
+        let x_axis = input.shape.len() - 2;
+        for (axis, kernel, stride, padding, output_padding, dilation) in [
+            (
+                x_axis,
+                self.kernel.0,
+                self.stride.0,
+                self.padding.0,
+                self.output_padding.0,
+                self.dilation.0,
+            ),
+            (
+                x_axis + 1,
+                self.kernel.1,
+                self.stride.1,
+                self.padding.1,
+                self.output_padding.1,
+                self.dilation.1,
+            ),
+        ] {
+            let edge = (dilation * (kernel - 1)) as isize - padding as isize;
+            input = pad_or_crop(
+                dilate_along(input, stride, axis),
+                edge,
+                edge + output_padding as isize,
+                axis,
+            );
+        }
+        let weight = self
+            .weight
+            .reshape((self.ch_in, self.ch_out, self.kernel.0, self.kernel.1))
+            .matmul(reversal(self.kernel.1, input.graph()))
+            .permute((0, 1, 3, 2))
+            .matmul(reversal(self.kernel.0, input.graph()))
+            .permute((1, 0, 3, 2));
+        // Spread out the kernel itself rather than dilating the windows, which would need more
+        // dimensions than shapes support
+        let kernel = (
+            self.dilation.0 * (self.kernel.0 - 1) + 1,
+            self.dilation.1 * (self.kernel.1 - 1) + 1,
+        );
+        let weight = dilate_along(dilate_along(weight, self.dilation.0, 2), self.dilation.1, 3)
+            .reshape((self.ch_out, self.ch_in * kernel.0 * kernel.1));
+        Conv2D {
+            weight,
+            bias: self.bias,
+            kernel,
+            stride: (1, 1),
+            dilation: (1, 1),
+            padding: Padding::default(),
+            padding_mode: PaddingMode::Zeros,
+            groups: 1,
+            ch_out: self.ch_out,
+            ch_in: self.ch_in,
+        }
+        .forward(input)
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use super::{Conv1D, Conv2D, Conv3D};
+    use super::{Conv1D, Conv2D, Conv3D, ConvTranspose1D, ConvTranspose2D, Padding, PaddingMode};
     use candle_core::{Device, Tensor};
     use luminal::{
         prelude::*,
@@ -383,6 +909,157 @@ mod tests {
         );
     }
 
+    /// Pad a dimension of a reference tensor by gathering the positions each mode reads from
+    fn reference_pad(
+        tensor: &Tensor,
+        (left, right): (usize, usize),
+        mode: PaddingMode,
+        dim: usize,
+    ) -> Tensor {
+        if mode == PaddingMode::Zeros {
+            return tensor.pad_with_zeros(dim, left, right).unwrap();
+        }
+        let size = tensor.dims()[dim] as isize;
+        let index = (-(left as isize)..size + right as isize)
+            .map(|i| match mode {
+                PaddingMode::Reflect => i.abs().min(2 * (size - 1) - i) as u32,
+                _ => i.clamp(0, size - 1) as u32,
+            })
+            .collect::<Vec<_>>();
+        let len = index.len();
+        let index = Tensor::from_vec(index, len, &Device::Cpu).unwrap();
+        tensor.index_select(&index, dim).unwrap()
+    }
+
+    #[test]
+    fn test_conv1d_padding_groups() {
+        let mut cx = Graph::new();
+        let mut rng = StdRng::seed_from_u64(0);
+
+        const CH_IN: usize = 6;
+        const CH_OUT: usize = 6;
+        const KERNEL: usize = 3;
+        const DIM_IN: usize = 9;
+        let input_data = random_vec_rng(2 * CH_IN * DIM_IN, &mut rng);
+        let input = cx
+            .tensor((2, CH_IN, 's'))
+            .set_dyn(input_data.clone(), (2, CH_IN, DIM_IN));
+        // (groups, stride, dilation, (left, right) padding or same, mode)
+        let configs = [
+            (1, 1, 1, None, PaddingMode::Reflect),
+            (3, 2, 1, Some((2, 1)), PaddingMode::Zeros),
+            (6, 1, 2, None, PaddingMode::Replicate),
+            (2, 2, 1, Some((0, 3)), PaddingMode::Reflect),
+        ];
+        let kernels = configs
+            .iter()
+            .map(|(groups, ..)| random_vec_rng(CH_OUT * CH_IN / groups * KERNEL, &mut rng))
+            .collect::<Vec<_>>();
+        let mut outputs = configs
+            .iter()
+            .zip(&kernels)
+            .map(|(&(groups, stride, dilation, padding, mode), kernel)| {
+                let model = Conv1D::new_grouped(
+                    CH_IN, CH_OUT, KERNEL, stride, dilation, 0, groups, false, &mut cx,
+                )
+                .with_padding(padding.map_or(Padding::Same, |p| Padding::Explicit([p])))
+                .with_padding_mode(mode);
+                model.weight.set(kernel.clone());
+                model.forward(input).retrieve()
+            })
+            .collect::<Vec<_>>();
+        cx.compile(GenericCompiler::default(), &mut outputs);
+        cx.execute();
+
+        let input = Tensor::from_vec(input_data, (2, CH_IN, DIM_IN), &Device::Cpu).unwrap();
+        for ((output, kernel), (groups, stride, dilation, padding, mode)) in
+            outputs.iter().zip(kernels).zip(configs)
+        {
+            let padding = padding.unwrap_or((dilation, dilation));
+            let kernel =
+                Tensor::from_vec(kernel, (CH_OUT, CH_IN / groups, KERNEL), &Device::Cpu).unwrap();
+            let expected = reference_pad(&input, padding, mode, 2)
+                .conv1d(&kernel, 0, stride, dilation, groups)
+                .unwrap();
+            assert_close(
+                &output.data(),
+                &expected.flatten_all().unwrap().to_vec1::<f32>().unwrap(),
+            );
+        }
+    }
+
+    #[test]
+    fn test_conv2d_padding_groups() {
+        let mut cx = Graph::new();
+        let mut rng = StdRng::seed_from_u64(0);
+
+        const CH_IN: usize = 4;
+        const CH_OUT: usize = 8;
+        const KERNEL: (usize, usize) = (3, 2);
+        const DIM_IN: (usize, usize) = (6, 7);
+        let input_data = random_vec_rng(CH_IN * DIM_IN.0 * DIM_IN.1, &mut rng);
+        let input = cx
+            .tensor((1, CH_IN, 'h', 'w'))
+            .set_dyn(input_data.clone(), (1, CH_IN, DIM_IN.0, DIM_IN.1));
+        // (groups, stride, padding or same, mode)
+        let configs = [
+            (4, 1, None, PaddingMode::Reflect),
+            (2, 2, Some([(1, 1), (0, 2)]), PaddingMode::Replicate),
+            (1, 1, Some([(2, 0), (1, 1)]), PaddingMode::Zeros),
+        ];
+        let kernels = configs
+            .iter()
+            .map(|(groups, ..)| {
+                random_vec_rng(CH_OUT * CH_IN / groups * KERNEL.0 * KERNEL.1, &mut rng)
+            })
+            .collect::<Vec<_>>();
+        let mut outputs = configs
+            .iter()
+            .zip(&kernels)
+            .map(|(&(groups, stride, padding, mode), kernel)| {
+                let model = Conv2D::new_grouped(
+                    CH_IN,
+                    CH_OUT,
+                    KERNEL,
+                    (stride, stride),
+                    (1, 1),
+                    groups,
+                    false,
+                    &mut cx,
+                )
+                .with_padding(padding.map_or(Padding::Same, Padding::Explicit))
+                .with_padding_mode(mode);
+                model.weight.set(kernel.clone());
+                model.forward(input).retrieve()
+            })
+            .collect::<Vec<_>>();
+        cx.compile(GenericCompiler::default(), &mut outputs);
+        cx.execute();
+
+        let input =
+            Tensor::from_vec(input_data, (1, CH_IN, DIM_IN.0, DIM_IN.1), &Device::Cpu).unwrap();
+        for ((output, kernel), (groups, stride, padding, mode)) in
+            outputs.iter().zip(kernels).zip(configs)
+        {
+            // Same padding puts the odd padding on the right
+            let padding = padding.unwrap_or([(1, 1), (0, 1)]);
+            let kernel = Tensor::from_vec(
+                kernel,
+                (CH_OUT, CH_IN / groups, KERNEL.0, KERNEL.1),
+                &Device::Cpu,
+            )
+            .unwrap();
+            let padded = reference_pad(&input, padding[0], mode, 2);
+            let expected = reference_pad(&padded, padding[1], mode, 3)
+                .conv2d(&kernel, 0, stride, 1, groups)
+                .unwrap();
+            assert_close(
+                &output.data(),
+                &expected.flatten_all().unwrap().to_vec1::<f32>().unwrap(),
+            );
+        }
+    }
+
     #[test]
     fn test_conv2d() {
         let mut cx = Graph::new();
@@ -473,6 +1150,84 @@ mod tests {
         assert_close(&out1.data(), &exp_out1.data())
     }
 
+    #[test]
+    fn test_conv_bias() {
+        // Bias is added per output channel, even when the batch size matches the channel count
+        let mut cx = Graph::new();
+        let mut rng = StdRng::seed_from_u64(0);
+
+        const BATCH: usize = 3;
+        const CH_IN: usize = 2;
+        const CH_OUT: usize = BATCH;
+        const KERNEL: usize = 2;
+        const DIM_IN: usize = 5;
+        let weight_1d = random_vec_rng(CH_OUT * CH_IN * KERNEL, &mut rng);
+        let weight_2d = random_vec_rng(CH_OUT * CH_IN * KERNEL * KERNEL, &mut rng);
+        let bias_data = random_vec_rng(CH_OUT, &mut rng);
+        let input_1d = random_vec_rng(BATCH * CH_IN * DIM_IN, &mut rng);
+        let input_2d = random_vec_rng(BATCH * CH_IN * DIM_IN * DIM_IN, &mut rng);
+
+        let conv1d = Conv1D::new(CH_IN, CH_OUT, KERNEL, 1, 1, 0, true, &mut cx);
+        conv1d.weight.set(weight_1d.clone());
+        conv1d.bias.unwrap().set(bias_data.clone());
+        let conv2d = Conv2D::new(
+            CH_IN,
+            CH_OUT,
+            (KERNEL, KERNEL),
+            (1, 1),
+            (1, 1),
+            true,
+            &mut cx,
+        );
+        conv2d.weight.set(weight_2d.clone());
+        conv2d.bias.unwrap().set(bias_data.clone());
+        let out1 = conv1d
+            .forward(cx.tensor((BATCH, CH_IN, DIM_IN)).set(input_1d.clone()))
+            .retrieve();
+        let out2 = conv2d
+            .forward(
+                cx.tensor((BATCH, CH_IN, DIM_IN, DIM_IN))
+                    .set(input_2d.clone()),
+            )
+            .retrieve();
+        cx.execute();
+
+        let bias = Tensor::from_vec(bias_data, CH_OUT, &Device::Cpu).unwrap();
+        let expected1 = Tensor::from_vec(input_1d, (BATCH, CH_IN, DIM_IN), &Device::Cpu)
+            .unwrap()
+            .conv1d(
+                &Tensor::from_vec(weight_1d, (CH_OUT, CH_IN, KERNEL), &Device::Cpu).unwrap(),
+                0,
+                1,
+                1,
+                1,
+            )
+            .unwrap()
+            .broadcast_add(&bias.reshape((1, CH_OUT, 1)).unwrap())
+            .unwrap();
+        let expected2 = Tensor::from_vec(input_2d, (BATCH, CH_IN, DIM_IN, DIM_IN), &Device::Cpu)
+            .unwrap()
+            .conv2d(
+                &Tensor::from_vec(weight_2d, (CH_OUT, CH_IN, KERNEL, KERNEL), &Device::Cpu)
+                    .unwrap(),
+                0,
+                1,
+                1,
+                1,
+            )
+            .unwrap()
+            .broadcast_add(&bias.reshape((1, CH_OUT, 1, 1)).unwrap())
+            .unwrap();
+        assert_close(
+            &out1.data(),
+            &expected1.flatten_all().unwrap().to_vec1::<f32>().unwrap(),
+        );
+        assert_close(
+            &out2.data(),
+            &expected2.flatten_all().unwrap().to_vec1::<f32>().unwrap(),
+        );
+    }
+
     #[test]
     fn test_conv3d() {
         let mut cx = Graph::new();
@@ -544,4 +1299,118 @@ mod tests {
 
         assert_close(&out1.data(), &exp_out1.data());
     }
+
+    #[test]
+    fn test_conv_transpose1d() {
+        let mut cx = Graph::new();
+        let mut rng = StdRng::seed_from_u64(0);
+
+        const CH_IN: usize = 3;
+        const CH_OUT: usize = 2;
+        const KERNEL: usize = 3;
+        const DIM_IN: usize = 5;
+        let kernel_data = random_vec_rng(CH_IN * CH_OUT * KERNEL, &mut rng);
+        let input_data = random_vec_rng(2 * CH_IN * DIM_IN, &mut rng);
+        let bias_data = random_vec_rng(CH_OUT, &mut rng);
+
+        let input = cx
+            .tensor((2, CH_IN, 's'))
+            .set_dyn(input_data.clone(), (2, CH_IN, DIM_IN));
+        // (stride, padding, output_padding, dilation)
+        let configs = [(1, 0, 0, 1), (2, 1, 1, 1), (3, 2, 0, 2), (2, 4, 1, 1)];
+        let mut outputs = configs
+            .iter()
+            .map(|&(stride, padding, output_padding, dilation)| {
+                let model = ConvTranspose1D::new(
+                    CH_IN,
+                    CH_OUT,
+                    KERNEL,
+                    stride,
+                    padding,
+                    output_padding,
+                    dilation,
+                    true,
+                    &mut cx,
+                );
+                model.weight.set(kernel_data.clone());
+                model.bias.unwrap().set(bias_data.clone());
+                model.forward(input).retrieve()
+            })
+            .collect::<Vec<_>>();
+        cx.compile(GenericCompiler::default(), &mut outputs);
+        cx.execute();
+
+        let input = Tensor::from_vec(input_data, (2, CH_IN, DIM_IN), &Device::Cpu).unwrap();
+        let kernel = Tensor::from_vec(kernel_data, (CH_IN, CH_OUT, KERNEL), &Device::Cpu).unwrap();
+        let bias = Tensor::from_vec(bias_data, (1, CH_OUT, 1), &Device::Cpu).unwrap();
+        for (output, (stride, padding, output_padding, dilation)) in outputs.iter().zip(configs) {
+            let expected = input
+                .conv_transpose1d(&kernel, padding, output_padding, stride, dilation, 1)
+                .unwrap()
+                .broadcast_add(&bias)
+                .unwrap();
+            assert_eq!(output.dims()[2].to_usize(), None);
+            assert_close(
+                &output.data(),
+                &expected.flatten_all().unwrap().to_vec1::<f32>().unwrap(),
+            );
+        }
+    }
+
+    #[test]
+    fn test_conv_transpose2d() {
+        let mut cx = Graph::new();
+        let mut rng = StdRng::seed_from_u64(0);
+
+        const CH_IN: usize = 3;
+        const CH_OUT: usize = 4;
+        const KERNEL: (usize, usize) = (3, 2);
+        const DIM_IN: (usize, usize) = (4, 5);
+        let kernel_data = random_vec_rng(CH_IN * CH_OUT * KERNEL.0 * KERNEL.1, &mut rng);
+        let input_data = random_vec_rng(CH_IN * DIM_IN.0 * DIM_IN.1, &mut rng);
+
+        let input = cx
+            .tensor((1, CH_IN, 'h', 'w'))
+            .set_dyn(input_data.clone(), (1, CH_IN, DIM_IN.0, DIM_IN.1));
+        // (stride, padding, output_padding, dilation)
+        let configs = [(1, 0, 0, 1), (2, 1, 1, 1), (3, 1, 0, 2)];
+        let mut outputs = configs
+            .iter()
+            .map(|&(stride, padding, output_padding, dilation)| {
+                let model = ConvTranspose2D::new(
+                    CH_IN,
+                    CH_OUT,
+                    KERNEL,
+                    (stride, stride),
+                    (padding, padding),
+                    (output_padding, output_padding),
+                    (dilation, dilation),
+                    false,
+                    &mut cx,
+                );
+                model.weight.set(kernel_data.clone());
+                model.forward(input).retrieve()
+            })
+            .collect::<Vec<_>>();
+        cx.compile(GenericCompiler::default(), &mut outputs);
+        cx.execute();
+
+        let input =
+            Tensor::from_vec(input_data, (1, CH_IN, DIM_IN.0, DIM_IN.1), &Device::Cpu).unwrap();
+        let kernel = Tensor::from_vec(
+            kernel_data,
+            (CH_IN, CH_OUT, KERNEL.0, KERNEL.1),
+            &Device::Cpu,
+        )
+        .unwrap();
+        for (output, (stride, padding, output_padding, dilation)) in outputs.iter().zip(configs) {
+            let expected = input
+                .conv_transpose2d(&kernel, padding, output_padding, stride, dilation)
+                .unwrap();
+            assert_close(
+                &output.data(),
+                &expected.flatten_all().unwrap().to_vec1::<f32>().unwrap(),
+            );
+        }
+    }
 }
@@ -0,0 +1,141 @@
+use std::cell::Cell;
+
+use luminal::prelude::*;
+
+/// A module that behaves differently while training, like [`Dropout`] or [`crate::BatchNorm`].
+pub trait Train {
+    /// Switch between training and eval mode. Only affects forward passes made afterwards.
+    fn train(&mut self, training: bool);
+}
+
+thread_local! {
+    static NEXT_SEED: Cell<usize> = const { Cell::new(0) };
+}
+
+/// Zeroes each element with probability `p` in training mode, scaling the rest by `1 / (1 - p)`.
+/// Does nothing in eval mode (the default).
+///
+/// Each dropout gets its own seed when created, and drops different elements each time the graph is
+/// ran. To control which elements get dropped, set a seed, which drops the same elements every run
+/// unless it contains a dynamic dimension like a step counter:
+/// ```rust
+/// use luminal::prelude::*;
+/// use luminal_nn::{Dropout, Train};
+/// let mut cx = Graph::new();
+/// let mut dropout = Dropout::new(0.5).with_seed(Expression::from('t') * 16 + 1);
+/// dropout.train(true);
+/// let output = dropout.forward(cx.tensor(8).set(vec![1.; 8])).retrieve();
+/// cx.set_dyn_dim('t', 0);
+/// cx.execute();
+/// ```
+pub struct Dropout {
+    pub p: f32,
+    pub seed: Expression,
+    /// Whether to drop different elements each run, rather than following the seed
+    pub per_run: bool,
+    training: bool,
+}
+
+impl Dropout {
+    pub fn new(p: f32) -> Self {
+        assert!(
+            (0. ..1.).contains(&p),
+            "Dropout probability must be in [0, 1)"
+        );
+        Self {
+            p,
+            seed: NEXT_SEED.with(|s| s.replace(s.get() + 1)).into(),
+            per_run: true,
+            training: false,
+        }
+    }
+
+    pub fn with_seed(mut self, seed: impl Into<Expression>) -> Self {
+        self.seed = seed.into();
+        self.per_run = false;
+        self
+    }
+
+    pub fn is_training(&self) -> bool {
+        self.training
+    }
+}
+
+impl Train for Dropout {
+    fn train(&mut self, training: bool) {
+        self.training = training;
+    }
+}
+
+impl SerializeModule for Dropout {
+    fn serialize(&self, _: &mut Serializer) {}
+}
+
+impl Module<GraphTensor> for Dropout {
+    type Output = GraphTensor;
+
+    fn forward(&self, input: GraphTensor) -> Self::Output {
+        if !self.training || self.p == 0. {
+            return input;
+        }
+        let keep_prob = input.graph().constant(1. - self.p).expand_to(input.shape);
+        let keep = if self.per_run {
+            keep_prob.bernoulli_per_run(self.seed)
+        } else {
+            keep_prob.bernoulli(self.seed)
+        };
+        input * keep / (1. - self.p)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use luminal::prelude::*;
+
+    use super::{Dropout, Train};
+
+    #[test]
+    fn test_dropout() {
+        let mut cx = Graph::new();
+        let input = cx.tensor((4, 100)).set(vec![2.; 400]);
+        let mut dropout = Dropout::new(0.25);
+        let eval = dropout.forward(input).retrieve();
+        dropout.train(true);
+        let train = dropout.forward(input).retrieve();
+        let again = dropout.forward(input).retrieve();
+        let mut other = Dropout::new(0.25).with_seed(10);
+        other.train(true);
+        let other = other.forward(input).retrieve();
+        cx.execute();
+
+        assert_eq!(eval.data(), vec![2.; 400]);
+        let data = train.data();
+        // Kept elements are scaled up so the expected value is unchanged
+        assert!(data.iter().all(|x| *x == 0. || *x == 2. / 0.75));
+        let dropped = data.iter().filter(|x| **x == 0.).count() as f32 / 400.;
+        assert!((dropped - 0.25).abs() < 0.06);
+        assert_eq!(again.data(), data);
+        assert_ne!(other.data(), data);
+    }
+
+    #[test]
+    fn test_dropout_per_run() {
+        let mut cx = Graph::new();
+        let input = cx.tensor(400).set(vec![1.; 400]);
+        let mut dropout = Dropout::new(0.5);
+        dropout.train(true);
+        let output = dropout.forward(input).retrieve();
+        let mut seeded = Dropout::new(0.5).with_seed(3);
+        seeded.train(true);
+        let seeded = seeded.forward(input).retrieve();
+        cx.execute();
+        let (first, first_seeded) = (output.data(), seeded.data());
+
+        output.drop();
+        seeded.drop();
+        cx.execute();
+        // A new mask each step by default, and the same one with a fixed seed
+        assert_ne!(output.data(), first);
+        assert_eq!(seeded.data(), first_seeded);
+    }
+}
@@ -1,4 +1,7 @@
-use luminal::{prelude::*, tests::random_vec};
+use luminal::prelude::*;
+use rand::thread_rng;
+
+use crate::init::Init;
 
 pub struct Embedding {
     permute: bool,
@@ -24,9 +27,8 @@ impl Embedding {
     }
 
     pub fn initialize(self) -> Self {
-        self.weight.set(random_vec(
-            self.weight.shape.n_elements().to_usize().unwrap(),
-        ));
+        // Init weight as normal(0, 1)
+        Init::Normal { mean: 0., std: 1. }.init(self.weight, &mut thread_rng());
         self
     }
 }
@@ -0,0 +1,370 @@
+//! Weight initialization schemes.
+//!
+//! Fan in and fan out are computed the same way as PyTorch, treating dimensions as
+//! (out, in, ..kernel). Modules storing parameters another way, like [`crate::Linear::new`] with
+//! its (in, out) weight, report their fans when serialized, which [`init_params`] uses instead.
+
+use std::f32::consts::PI;
+
+use itertools::Itertools;
+use luminal::prelude::*;
+use rand::Rng;
+
+/// Which fan Kaiming initialization preserves the variance of
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum FanMode {
+    /// Preserve the variance of activations in the forward pass
+    #[default]
+    FanIn,
+    /// Preserve the variance of gradients in the backward pass
+    FanOut,
+}
+
+/// How to initialize a tensor.
+///
+/// The gains scale the standard deviation for the nonlinearity that follows, like PyTorch's
+/// `calculate_gain`: 1 for linear / sigmoid, 5/3 for tanh and sqrt(2) for ReLU.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Init {
+    Zeros,
+    Ones,
+    Constant(f32),
+    Uniform {
+        low: f32,
+        high: f32,
+    },
+    Normal {
+        mean: f32,
+        std: f32,
+    },
+    /// Normal, redrawing values outside of [low, high]
+    TruncatedNormal {
+        mean: f32,
+        std: f32,
+        low: f32,
+        high: f32,
+    },
+    /// Glorot uniform, bound of gain * sqrt(6 / (fan_in + fan_out))
+    XavierUniform {
+        gain: f32,
+    },
+    /// Glorot normal, std of gain * sqrt(2 / (fan_in + fan_out))
+    XavierNormal {
+        gain: f32,
+    },
+    /// He uniform, bound of gain * sqrt(3 / fan)
+    KaimingUniform {
+        gain: f32,
+        mode: FanMode,
+    },
+    /// He normal, std of gain / sqrt(fan)
+    KaimingNormal {
+        gain: f32,
+        mode: FanMode,
+    },
+    /// A (semi) orthogonal matrix of (dims[0], rest) scaled by gain
+    Orthogonal {
+        gain: f32,
+    },
+}
+
+/// Fan in and fan out of a tensor laid out as (out, in, ..kernel)
+pub fn fans(dims: &[usize]) -> (usize, usize) {
+    match dims {
+        [] => (1, 1),
+        [n] => (*n, *n),
+        [out, inp, kernel @ ..] => {
+            let receptive = kernel.iter().product::<usize>();
+            (inp * receptive, out * receptive)
+        }
+    }
+}
+
+/// A standard normal sample, using Box-Muller
+fn standard_normal(rng: &mut impl Rng) -> f32 {
+    let u = 1. - rng.gen::<f32>();
+    let v = rng.gen::<f32>();
+    (-2. * u.ln()).sqrt() * (2. * PI * v).cos()
+}
+
+/// A random (rows, cols) matrix with orthonormal rows or columns, whichever there are fewer of
+fn orthogonal(rows: usize, cols: usize, rng: &mut impl Rng) -> Vec<f32> {
+    let (n, len) = (rows.min(cols), rows.max(cols));
+    // Gram-Schmidt over the shorter side
+    let mut vectors: Vec<Vec<f32>> = vec![];
+    while vectors.len() < n {
+        let mut v = (0..len).map(|_| standard_normal(rng)).collect_vec();
+        for u in &vectors {
+            let dot = v.iter().zip(u).map(|(a, b)| a * b).sum::<f32>();
+            v.iter_mut().zip(u).for_each(|(a, b)| *a -= dot * b);
+        }
+        let norm = v.iter().map(|a| a * a).sum::<f32>().sqrt();
+        // Redraw the rare vector that's nearly dependent on the others
+        if norm > 1e-3 {
+            vectors.push(v.into_iter().map(|a| a / norm).collect());
+        }
+    }
+    if rows <= cols {
+        vectors.concat()
+    } else {
+        (0..rows)
+            .flat_map(|r| vectors.iter().map(move |v| v[r]))
+            .collect()
+    }
+}
+
+impl Init {
+    /// Sample data for a tensor of these dimensions, laid out as (out, in, ..kernel)
+    pub fn sample(&self, dims: &[usize], rng: &mut impl Rng) -> Vec<f32> {
+        self.sample_with_fans(dims, fans(dims), rng)
+    }
+
+    /// Sample data for a tensor of these dimensions with the given fan in and fan out
+    pub fn sample_with_fans(
+        &self,
+        dims: &[usize],
+        (fan_in, fan_out): (usize, usize),
+        rng: &mut impl Rng,
+    ) -> Vec<f32> {
+        let n = dims.iter().product::<usize>();
+        let uniform = |bound: f32, rng: &mut _| {
+            Init::Uniform {
+                low: -bound,
+                high: bound,
+            }
+            .sample(dims, rng)
+        };
+        let normal = |std: f32, rng: &mut _| Init::Normal { mean: 0., std }.sample(dims, rng);
+        let fan = |mode| match mode {
+            FanMode::FanIn => fan_in,
+            FanMode::FanOut => fan_out,
+        } as f32;
+        match *self {
+            Init::Zeros => vec![0.; n],
+            Init::Ones => vec![1.; n],
+            Init::Constant(c) => vec![c; n],
+            Init::Uniform { low, high } => (0..n).map(|_| rng.gen_range(low..high)).collect(),
+            Init::Normal { mean, std } => {
+                (0..n).map(|_| mean + std * standard_normal(rng)).collect()
+            }
+            Init::TruncatedNormal {
+                mean,
+                std,
+                low,
+                high,
+            } => {
+                assert!(low < high, "Truncated normal needs low < high");
+                (0..n)
+                    .map(|_| loop {
+                        let x = mean + std * standard_normal(rng);
+                        if (low..=high).contains(&x) {
+                            break x;
+                        }
+                    })
+                    .collect()
+            }
+            Init::XavierUniform { gain } => {
+                uniform(gain * (6. / (fan_in + fan_out) as f32).sqrt(), rng)
+            }
+            Init::XavierNormal { gain } => {
+                normal(gain * (2. / (fan_in + fan_out) as f32).sqrt(), rng)
+            }
+            Init::KaimingUniform { gain, mode } => uniform(gain * (3. / fan(mode)).sqrt(), rng),
+            Init::KaimingNormal { gain, mode } => normal(gain / fan(mode).sqrt(), rng),
+            Init::Orthogonal { gain } => {
+                let rows = dims.first().copied().unwrap_or(1);
+                orthogonal(rows, n / rows.max(1), rng)
+                    .into_iter()
+                    .map(|x| x * gain)
+                    .collect()
+            }
+        }
+    }
+
+    /// Set a tensor's data with this scheme
+    pub fn init(&self, tensor: GraphTensor, rng: &mut impl Rng) -> GraphTensor {
+        tensor.set(self.sample(&shape_dims(tensor.shape), rng))
+    }
+
+    /// Set a tensor's data with this scheme and the given fan in and fan out
+    pub fn init_with_fans(
+        &self,
+        tensor: GraphTensor,
+        fans: (usize, usize),
+        rng: &mut impl Rng,
+    ) -> GraphTensor {
+        tensor.set(self.sample_with_fans(&shape_dims(tensor.shape), fans, rng))
+    }
+}
+
+/// Initialize the parameters of a module. `scheme` picks the scheme for each parameter from its
+/// path (as in [`param_dict`]) and dimensions, and parameters it maps to `None` are left as is.
+/// Parameters use the fans their module reports, or are treated as (out, in, ..kernel).
+/// ```rust
+/// use luminal::prelude::*;
+/// use luminal_nn::{init::*, Linear};
+/// use rand::{rngs::StdRng, SeedableRng};
+///
+/// let mut cx = Graph::new();
+/// let model = (Linear::new(4, 8, true, &mut cx), Linear::new(8, 2, true, &mut cx));
+/// init_params(&model, &mut cx, &mut StdRng::seed_from_u64(0), |path, _| {
+///     Some(if path.ends_with("bias") {
+///         Init::Zeros
+///     } else {
+///         Init::XavierUniform { gain: 1. }
+///     })
+/// });
+/// ```
+pub fn init_params(
+    model: impl SerializeModule,
+    cx: &mut Graph,
+    rng: &mut impl Rng,
+    scheme: impl Fn(&str, &[usize]) -> Option<Init>,
+) {
+    let mut s = Serializer::default();
+    model.serialize(&mut s);
+    // Go in a fixed order so a seed always gives the same weights
+    for (path, id) in s.state.into_iter().sorted_by(|a, b| a.0.cmp(&b.0)) {
+        let shape = s.shapes[&path];
+        let dims = shape_dims(shape);
+        if let Some(init) = scheme(&path, &dims) {
+            let fans = s.fans.get(&path).copied().unwrap_or_else(|| fans(&dims));
+            init.init_with_fans(GraphTensor::from_id(id, shape, cx), fans, rng);
+        }
+    }
+}
+
+fn shape_dims(shape: ShapeTracker) -> Vec<usize> {
+    shape
+        .dims()
+        .into_iter()
+        .map(|d| {
+            d.to_usize()
+                .expect("Can't initialize a tensor of dynamic size")
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use luminal::prelude::*;
+    use rand::{rngs::StdRng, SeedableRng};
+
+    use super::{fans, init_params, FanMode, Init};
+    use crate::Linear;
+
+    fn mean_std(data: &[f32]) -> (f32, f32) {
+        let mean = data.iter().sum::<f32>() / data.len() as f32;
+        let var = data.iter().map(|x| (x - mean).powi(2)).sum::<f32>() / data.len() as f32;
+        (mean, var.sqrt())
+    }
+
+    #[test]
+    fn test_init_schemes() {
+        let mut rng = StdRng::seed_from_u64(0);
+        let dims = [64, 32, 3];
+        assert_eq!(fans(&dims), (96, 192));
+
+        let data = Init::Normal { mean: 1., std: 2. }.sample(&dims, &mut rng);
+        let (mean, std) = mean_std(&data);
+        assert!((mean - 1.).abs() < 0.1 && (std - 2.).abs() < 0.1);
+
+        let data = Init::TruncatedNormal {
+            mean: 0.,
+            std: 1.,
+            low: -0.5,
+            high: 1.,
+        }
+        .sample(&dims, &mut rng);
+        assert!(data.iter().all(|x| (-0.5..=1.).contains(x)));
+
+        let bound = (6. / (96. + 192.) as f32).sqrt();
+        let data = Init::XavierUniform { gain: 1. }.sample(&dims, &mut rng);
+        assert!(data.iter().all(|x| x.abs() <= bound));
+        assert!((mean_std(&data).1 - bound / 3_f32.sqrt()).abs() < 0.01);
+
+        let gain = 2_f32.sqrt();
+        for (mode, fan) in [(FanMode::FanIn, 96.), (FanMode::FanOut, 192.)] {
+            let data = Init::KaimingNormal { gain, mode }.sample(&dims, &mut rng);
+            assert!((mean_std(&data).1 - gain / f32::sqrt(fan)).abs() < 0.01);
+            let data = Init::KaimingUniform { gain, mode }.sample(&dims, &mut rng);
+            assert!((mean_std(&data).1 - gain / f32::sqrt(fan)).abs() < 0.01);
+        }
+
+        // Rows are orthonormal when there are fewer rows, columns otherwise
+        for (rows, cols) in [(4, 6), (6, 4)] {
+            let data = Init::Orthogonal { gain: 2. }.sample(&[rows, cols], &mut rng);
+            let (n, stride, step) = if rows <= cols {
+                (rows, cols, 1)
+            } else {
+                (cols, 1, cols)
+            };
+            for (i, j) in (0..n).flat_map(|i| (0..n).map(move |j| (i, j))) {
+                let dot = (0..rows.max(cols))
+                    .map(|k| data[i * stride + k * step] * data[j * stride + k * step])
+                    .sum::<f32>();
+                let expected = if i == j { 4. } else { 0. };
+                assert!((dot - expected).abs() < 1e-4);
+            }
+        }
+    }
+
+    #[test]
+    fn test_init_params() {
+        let mut cx = Graph::new();
+        let model = (
+            Linear::new(4, 8, true, &mut cx),
+            Linear::new(8, 2, false, &mut cx),
+        );
+        model.1.weight.set(vec![5.; 16]);
+        let scheme = |path: &str, dims: &[usize]| {
+            if path.ends_with("bias") {
+                Some(Init::Ones)
+            } else if dims == [4, 8] {
+                Some(Init::Constant(3.))
+            } else {
+                None
+            }
+        };
+        init_params(&model, &mut cx, &mut StdRng::seed_from_u64(0), scheme);
+        let weights = [model.0.weight, model.0.bias.unwrap(), model.1.weight];
+        let weights = weights.map(|w| w.retrieve());
+        cx.execute();
+        assert_eq!(weights[0].data(), vec![3.; 32]);
+        assert_eq!(weights[1].data(), vec![1.; 8]);
+        assert_eq!(weights[2].data(), vec![5.; 16]);
+
+        // The same seed gives the same weights
+        let mut run = |seed| {
+            let xavier = |_: &str, _: &[usize]| Some(Init::XavierNormal { gain: 1. });
+            init_params(&model, &mut cx, &mut StdRng::seed_from_u64(seed), xavier);
+            weights.iter().for_each(|w| w.drop());
+            cx.execute();
+            weights.map(|w| w.data())
+        };
+        let first = run(1);
+        assert_eq!(run(1), first);
+        assert_ne!(run(2), first);
+    }
+
+    #[test]
+    fn test_linear_fans() {
+        let mut cx = Graph::new();
+        let model = Linear::new(4, 8, false, &mut cx);
+        let gain = 2_f32.sqrt();
+        let kaiming = |_: &str, _: &[usize]| {
+            Some(Init::KaimingUniform {
+                gain,
+                mode: FanMode::FanIn,
+            })
+        };
+        init_params(&model, &mut cx, &mut StdRng::seed_from_u64(0), kaiming);
+        let weight = model.weight.retrieve();
+        cx.execute();
+        // Bounded by a fan in of 4 rather than the 8 an (out, in) layout would give
+        let data = weight.data();
+        let bound = |fan: f32| gain * (3. / fan).sqrt();
+        assert!(data.iter().all(|x| x.abs() <= bound(4.)));
+        assert!(data.iter().any(|x| x.abs() > bound(8.)));
+    }
+}
@@ -0,0 +1,373 @@
+use std::{cell::Cell, ops::Index};
+
+use luminal::prelude::*;
+
+/// The key and value cache of a single attention layer.
+///
+/// The cache is stored sequence-major (seq, batch, heads, head_dim) so that shrinking the
+/// sequence dimension never requires moving any data. Callers see the usual
+/// (batch, heads, seq, head_dim) layout.
+pub struct KVCacheLayer {
+    /// Cached keys from previous steps: (prev_seq, batch, heads, head_dim)
+    pub keys: GraphTensor,
+    /// Cached values from previous steps: (prev_seq, batch, heads, head_dim)
+    pub values: GraphTensor,
+    seq_dim: char,
+    capacity: Option<usize>,
+    updated: Cell<Option<(GraphTensor, GraphTensor)>>,
+    total_seq: Cell<Option<Expression>>,
+}
+
+impl KVCacheLayer {
+    /// The number of tokens already in the cache when the graph runs
+    pub fn prev_seq(&self) -> Expression {
+        self.seq_dim.into()
+    }
+}
+
+impl Module<(GraphTensor, GraphTensor)> for KVCacheLayer {
+    type Output = (GraphTensor, GraphTensor);
+
+    /// Append new keys and values (batch, heads, seq, head_dim) to the cache, returning the full
+    /// keys and values (batch, heads, prev_seq + seq, head_dim)
+    fn forward(&self, (keys, values): (GraphTensor, GraphTensor)) -> Self::Output {
+        let (_, _, seq, _) = keys.dims4();
+        let prev_seq = self.prev_seq();
+        let (keys, values) = if let Some(capacity) = self.capacity {
+            // Write the new entries into the fixed-size buffer at the current offset
+            let mask = keys
+                .graph()
+                .arange(capacity)
+                .less_than(keys.graph().constant_expr(prev_seq).expand(0, capacity))
+                .expand_to(self.keys.shape);
+            let right = Expression::from(capacity) - prev_seq - seq;
+            (
+                self.keys * mask + keys.permute((2, 0, 1, 3)).pad_along(prev_seq, right, 0),
+                self.values * mask + values.permute((2, 0, 1, 3)).pad_along(prev_seq, right, 0),
+            )
+        } else {
+            (
+                self.keys.concat_along(keys.permute((2, 0, 1, 3)), 0),
+                self.values.concat_along(values.permute((2, 0, 1, 3)), 0),
+            )
+        };
+        self.updated.set(Some((keys.keep(), values.keep())));
+        self.total_seq.set(Some(prev_seq + seq));
+        let (keys, values) = if self.capacity.is_some() {
+            (
+                keys.slice_along(..prev_seq + seq, 0),
+                values.slice_along(..prev_seq + seq, 0),
+            )
+        } else {
+            (keys, values)
+        };
+        (keys.permute((1, 2, 0, 3)), values.permute((1, 2, 0, 3)))
+    }
+}
+
+/// A key-value cache for every layer of a model.
+///
+/// The number of cached tokens is tracked by a single dynamic dimension shared by all layers.
+/// Run each layer's attention through its [`KVCacheLayer`], then call [`KVCache::swap`] after every
+/// execution to move the newly computed entries into the cache.
+/// ```rust
+/// use luminal::prelude::*;
+/// use luminal_nn::KVCache;
+/// let mut cx = Graph::new();
+/// let cache = KVCache::new(2, 1, 4, 8, 'p', &mut cx);
+/// let keys = cx.tensor((1, 4, 's', 8));
+/// let values = cx.tensor((1, 4, 's', 8));
+/// let (all_keys, all_values) = cache[0].forward((keys, values));
+/// ```
+pub struct KVCache {
+    layers: Vec<KVCacheLayer>,
+    seq_dim: char,
+    capacity: Option<usize>,
+    /// Nodes actually holding the cache data once the graph has been compiled
+    sources: Option<Vec<NodeIndex>>,
+    graph_ref: *mut Graph,
+}
+
+impl KVCache {
+    /// Create a growable cache. Each step concatenates the new entries onto the cache.
+    pub fn new(
+        n_layers: usize,
+        batch: usize,
+        heads: usize,
+        head_dim: usize,
+        seq_dim: char,
+        cx: &mut Graph,
+    ) -> Self {
+        let layers = (0..n_layers)
+            .map(|_| {
+                let keys = cx
+                    .named_tensor("Key Cache", (seq_dim, batch, heads, head_dim))
+                    .set_dyn(vec![], (0, batch, heads, head_dim))
+                    .keep();
+                let values = cx
+                    .named_tensor("Value Cache", (seq_dim, batch, heads, head_dim))
+                    .set_dyn(vec![], (0, batch, heads, head_dim))
+                    .keep();
+                KVCacheLayer {
+                    keys,
+                    values,
+                    seq_dim,
+                    capacity: None,
+                    updated: Cell::default(),
+                    total_seq: Cell::default(),
+                }
+            })
+            .collect();
+        Self {
+            layers,
+            seq_dim,
+            capacity: None,
+            sources: None,
+            graph_ref: cx,
+        }
+    }
+
+    /// Create a cache preallocated to a fixed capacity. Each step writes the new entries into the
+    /// buffer at the current offset, so shapes stay static regardless of the cache length.
+    pub fn new_preallocated(
+        n_layers: usize,
+        batch: usize,
+        heads: usize,
+        head_dim: usize,
+        capacity: usize,
+        seq_dim: char,
+        cx: &mut Graph,
+    ) -> Self {
+        let n_elements = capacity * batch * heads * head_dim;
+        let layers = (0..n_layers)
+            .map(|_| {
+                let keys = cx
+                    .named_tensor("Key Cache", (capacity, batch, heads, head_dim))
+                    .set_deferred(move || vec![0.; n_elements])
+                    .keep();
+                let values = cx
+                    .named_tensor("Value Cache", (capacity, batch, heads, head_dim))
+                    .set_deferred(move || vec![0.; n_elements])
+                    .keep();
+                KVCacheLayer {
+                    keys,
+                    values,
+                    seq_dim,
+                    capacity: Some(capacity),
+                    updated: Cell::default(),
+                    total_seq: Cell::default(),
+                }
+            })
+            .collect();
+        cx.set_dyn_dim(seq_dim, 0);
+        Self {
+            layers,
+            seq_dim,
+            capacity: Some(capacity),
+            sources: None,
+            graph_ref: cx,
+        }
+    }
+
+    #[allow(clippy::mut_from_ref)]
+    fn graph(&self) -> &mut Graph {
+        unsafe { self.graph_ref.as_mut().unwrap() }
+    }
+
+    pub fn layers(&self) -> &[KVCacheLayer] {
+        &self.layers
+    }
+
+    /// Maximum number of tokens the cache can hold, if preallocated
+    pub fn capacity(&self) -> Option<usize> {
+        self.capacity
+    }
+
+    /// Number of tokens currently in the cache
+    pub fn len(&self) -> usize {
+        self.graph()
+            .dyn_map
+            .get(&self.seq_dim)
+            .copied()
+            .unwrap_or_default()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Move the entries computed by the last execution into the cache
+    pub fn swap(&mut self) {
+        let dests = self
+            .layers
+            .iter()
+            .flat_map(|l| {
+                let (k, v) = l
+                    .updated
+                    .get()
+                    .expect("KV cache swapped before any layer was run through it");
+                [k.id, v.id]
+            })
+            .collect::<Vec<_>>();
+        let total_seq = self.layers[0].total_seq.get().unwrap();
+        let graph = unsafe { self.graph_ref.as_mut().unwrap() };
+        if self.sources.is_none() {
+            // The cache inputs may have been moved behind other ops (like device copies) during
+            // compilation, so find where the data should really go and remove the initial loaders
+            let srcs = self
+                .layers
+                .iter()
+                .flat_map(|l| [l.keys.id, l.values.id])
+                .collect::<Vec<_>>();
+            let sources = downstream(&srcs, graph);
+            graph.keep_tensors(&sources);
+            delete_inputs(&sources, graph);
+            self.sources = Some(sources);
+        }
+        let sources = self.sources.as_ref().unwrap();
+        transfer_data_same_graph(&dests, sources, graph);
+        let len = total_seq
+            .exec(&graph.dyn_map)
+            .expect("Failed to resolve KV cache length");
+        if let Some(capacity) = self.capacity {
+            assert!(
+                len <= capacity,
+                "KV cache overflowed: {len} tokens with a capacity of {capacity}"
+            );
+        }
+        graph.set_dyn_dim(self.seq_dim, len);
+    }
+
+    /// Remove all entries from the cache
+    pub fn reset(&mut self) {
+        self.graph().set_dyn_dim(self.seq_dim, 0);
+    }
+
+    /// Keep only the first `n` entries of the cache
+    pub fn truncate(&mut self, n: usize) {
+        if n < self.len() {
+            self.graph().set_dyn_dim(self.seq_dim, n);
+        }
+    }
+}
+
+impl Index<usize> for KVCache {
+    type Output = KVCacheLayer;
+    fn index(&self, index: usize) -> &Self::Output {
+        &self.layers[index]
+    }
+}
+
+impl ToIdsMut for KVCache {
+    fn to_ids_mut(&mut self) -> Vec<&mut NodeIndex> {
+        let mut ids = vec![];
+        for layer in &mut self.layers {
+            ids.push(&mut layer.keys.id);
+            ids.push(&mut layer.values.id);
+            if let Some((k, v)) = layer.updated.get_mut() {
+                ids.push(&mut k.id);
+                ids.push(&mut v.id);
+            }
+        }
+        if let Some(sources) = &mut self.sources {
+            ids.extend(sources.iter_mut());
+        }
+        ids
+    }
+}
+
+impl ToIds for KVCache {
+    fn to_ids(&self) -> Vec<NodeIndex> {
+        let mut ids = vec![];
+        for layer in &self.layers {
+            ids.push(layer.keys.id);
+            ids.push(layer.values.id);
+            if let Some((k, v)) = layer.updated.get() {
+                ids.push(k.id);
+                ids.push(v.id);
+            }
+        }
+        if let Some(sources) = &self.sources {
+            ids.extend(sources.iter().copied());
+        }
+        ids
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use luminal::prelude::Module;
+
+    use super::KVCache;
+    luminal::test_imports!();
+
+    // Convert between (heads, seq, head_dim) data and per-token rows of (heads * head_dim)
+    fn to_rows(data: &[f32], seq: usize) -> Vec<Vec<f32>> {
+        (0..seq)
+            .map(|s| {
+                (0..2)
+                    .flat_map(|h| data[(h * seq + s) * 3..(h * seq + s) * 3 + 3].to_vec())
+                    .collect()
+            })
+            .collect()
+    }
+
+    fn from_rows(rows: &[Vec<f32>]) -> Vec<f32> {
+        (0..2)
+            .flat_map(|h| rows.iter().flat_map(move |r| r[h * 3..h * 3 + 3].to_vec()))
+            .collect()
+    }
+
+    fn check_cache(cx: &mut Graph, mut cache: KVCache) {
+        let keys = cx.named_tensor("Keys", (1, 2, 's', 3));
+        let values = cx.named_tensor("Values", (1, 2, 's', 3));
+        let (out_keys, out_values) = cache[0].forward((keys, values));
+        let (out_keys, out_values) = (out_keys.retrieve(), out_values.retrieve());
+
+        let mut ref_keys = vec![];
+        let mut ref_values = vec![];
+        for (seq, truncate) in [(3, None), (1, None), (2, Some(2)), (1, Some(0)), (2, None)] {
+            match truncate {
+                Some(0) => {
+                    cache.reset();
+                    assert!(cache.is_empty());
+                    ref_keys.clear();
+                    ref_values.clear();
+                }
+                Some(n) => {
+                    cache.truncate(n);
+                    ref_keys.truncate(n);
+                    ref_values.truncate(n);
+                }
+                None => {}
+            }
+            let new_keys = random_vec(2 * seq * 3);
+            let new_values = random_vec(2 * seq * 3);
+            keys.set_dyn(new_keys.clone(), (1, 2, seq, 3));
+            values.set_dyn(new_values.clone(), (1, 2, seq, 3));
+            cx.execute();
+
+            ref_keys.extend(to_rows(&new_keys, seq));
+            ref_values.extend(to_rows(&new_values, seq));
+            assert_exact(&out_keys.data(), &from_rows(&ref_keys));
+            assert_exact(&out_values.data(), &from_rows(&ref_values));
+            cache.swap();
+            assert_eq!(cache.len(), ref_keys.len());
+        }
+    }
+
+    #[test]
+    fn test_kv_cache() {
+        let mut cx = Graph::new();
+        let cache = KVCache::new(1, 1, 2, 3, 'p', &mut cx);
+        check_cache(&mut cx, cache);
+    }
+
+    #[test]
+    fn test_kv_cache_preallocated() {
+        let mut cx = Graph::new();
+        let cache = KVCache::new_preallocated(1, 1, 2, 3, 8, 'p', &mut cx);
+        assert_eq!(cache.capacity(), Some(8));
+        check_cache(&mut cx, cache);
+    }
+}
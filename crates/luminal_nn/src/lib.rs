@@ -2,11 +2,23 @@ mod activation;
 pub use activation::*;
 mod convolution;
 pub use convolution::*;
+mod dropout;
+pub use dropout::*;
 mod embedding;
 pub use embedding::*;
 mod linear;
 pub use linear::*;
 mod norm;
 pub use norm::*;
+mod pooling;
+pub use pooling::*;
+mod recurrent;
+pub use recurrent::*;
 mod transformer;
 pub use transformer::*;
+mod kv_cache;
+pub use kv_cache::*;
+mod rotary;
+pub use rotary::*;
+pub mod init;
+pub mod llm;
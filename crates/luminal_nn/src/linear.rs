@@ -1,7 +1,9 @@
-use rand::{thread_rng, Rng};
+use rand::thread_rng;
 
 use luminal::prelude::*;
 
+use crate::init::Init;
+
 /// A simple unbiased linear layer
 pub struct Linear {
     pub weight: GraphTensor,
@@ -35,20 +37,31 @@ impl Linear {
     }
 
     pub fn initialize(self) -> Self {
-        // Init weight as uniform(-1, 1)
+        // Init weight and bias as uniform(-1/sqrt(in), 1/sqrt(in))
         let mut rng = thread_rng();
-        self.weight.set(
-            (0..self.weight.shape.n_elements().to_usize().unwrap())
-                .map(|_| rng.gen_range(-1_f32..1_f32))
-                .collect::<Vec<_>>(),
-        );
+        let dims = self.weight.dims();
+        let inp = dims[self.permute as usize].to_usize().unwrap() as f32;
+        let init = Init::Uniform {
+            low: -inp.sqrt().recip(),
+            high: inp.sqrt().recip(),
+        };
+        init.init(self.weight, &mut rng);
+        if let Some(bias) = self.bias {
+            init.init(bias, &mut rng);
+        }
         self
     }
 }
 
 impl SerializeModule for Linear {
     fn serialize(&self, s: &mut luminal::module::Serializer) {
-        s.tensor("weight", self.weight);
+        if self.permute {
+            s.tensor("weight", self.weight);
+        } else {
+            let dims = self.weight.dims();
+            let fans = (dims[0].to_usize().unwrap(), dims[1].to_usize().unwrap());
+            s.tensor_with_fans("weight", self.weight, fans);
+        }
         if let Some(bias) = self.bias {
             s.tensor("bias", bias);
         }
@@ -0,0 +1,609 @@
+//! A configurable decoder-only language model covering the Llama family of architectures
+//! (Llama, Mistral, Phi-3, Qwen2 and friends), which only differ in their hyperparameters.
+
+use luminal::prelude::*;
+
+use crate::{
+    Embedding, KVCache, KVCacheLayer, Linear, RMSNorm, RopeLayout, RopeScaling, RotaryEmbedding,
+};
+
+/// Hyperparameters of a decoder-only transformer
+#[derive(Debug, Clone, PartialEq)]
+pub struct LlmConfig {
+    pub vocab_size: usize,
+    pub hidden_dim: usize,
+    pub n_layers: usize,
+    pub n_heads: usize,
+    /// Number of key / value heads. Less than `n_heads` for grouped-query attention.
+    pub n_kv_heads: usize,
+    pub head_dim: usize,
+    /// Intermediate dimension of the gated feed-forward network
+    pub mlp_dim: usize,
+    pub rope_theta: f32,
+    pub rope_scaling: RopeScaling,
+    pub rope_layout: RopeLayout,
+    /// Number of dimensions of each head that are rotated
+    pub rotary_dim: usize,
+    /// Maximum sequence length the rotary tables cover
+    pub max_seq_len: usize,
+    /// Epsilon of the RMS norms
+    pub norm_eps: f32,
+    /// Whether the query, key and value projections have biases
+    pub qkv_bias: bool,
+    /// Whether the output projection reuses the token embedding weights
+    pub tied_embeddings: bool,
+}
+
+impl LlmConfig {
+    pub fn llama3_8b() -> Self {
+        Self {
+            vocab_size: 128256,
+            hidden_dim: 4096,
+            n_layers: 32,
+            n_heads: 32,
+            n_kv_heads: 8,
+            head_dim: 128,
+            mlp_dim: 14336,
+            rope_theta: 500_000.,
+            rope_scaling: RopeScaling::None,
+            rope_layout: RopeLayout::Interleaved,
+            rotary_dim: 128,
+            max_seq_len: 8192,
+            norm_eps: 1e-5,
+            qkv_bias: false,
+            tied_embeddings: false,
+        }
+    }
+
+    pub fn mistral_7b() -> Self {
+        Self {
+            vocab_size: 32000,
+            hidden_dim: 4096,
+            n_layers: 32,
+            n_heads: 32,
+            n_kv_heads: 8,
+            head_dim: 128,
+            mlp_dim: 14336,
+            rope_theta: 10_000.,
+            rope_scaling: RopeScaling::None,
+            rope_layout: RopeLayout::Interleaved,
+            rotary_dim: 128,
+            max_seq_len: 32768,
+            norm_eps: 1e-5,
+            qkv_bias: false,
+            tied_embeddings: false,
+        }
+    }
+
+    pub fn phi3_mini() -> Self {
+        Self {
+            vocab_size: 32064,
+            hidden_dim: 3072,
+            n_layers: 32,
+            n_heads: 32,
+            n_kv_heads: 32,
+            head_dim: 96,
+            mlp_dim: 8192,
+            rope_theta: 10_000.,
+            rope_scaling: RopeScaling::None,
+            rope_layout: RopeLayout::HalfSplit,
+            rotary_dim: 96,
+            max_seq_len: 4096,
+            norm_eps: 1e-5,
+            qkv_bias: false,
+            tied_embeddings: false,
+        }
+    }
+
+    pub fn qwen2_0_5b() -> Self {
+        Self {
+            vocab_size: 151936,
+            hidden_dim: 896,
+            n_layers: 24,
+            n_heads: 14,
+            n_kv_heads: 2,
+            head_dim: 64,
+            mlp_dim: 4864,
+            rope_theta: 1_000_000.,
+            rope_scaling: RopeScaling::None,
+            rope_layout: RopeLayout::HalfSplit,
+            rotary_dim: 64,
+            max_seq_len: 32768,
+            norm_eps: 1e-6,
+            qkv_bias: true,
+            tied_embeddings: true,
+        }
+    }
+
+    /// Read the config from the metadata of a GGUF file, panicking if a required key is missing
+    pub fn from_gguf(gguf: &impl GgufMetadata) -> Self {
+        let arch = gguf
+            .get_str("general.architecture")
+            .expect("Missing GGUF key general.architecture")
+            .to_string();
+        let key = |name: &str| format!("{arch}.{name}");
+        let int = |name: &str| {
+            gguf.get_int(&key(name))
+                .unwrap_or_else(|| panic!("Missing GGUF key {}", key(name))) as usize
+        };
+        let hidden_dim = int("embedding_length");
+        let n_heads = int("attention.head_count");
+        let rope_scaling = match gguf.get_str(&key("rope.scaling.type")) {
+            None | Some("none") => RopeScaling::None,
+            Some("linear") => RopeScaling::Linear {
+                factor: gguf
+                    .get_float(&key("rope.scaling.factor"))
+                    .unwrap_or_else(|| panic!("Missing GGUF key {}", key("rope.scaling.factor"))),
+            },
+            Some("yarn") => RopeScaling::Yarn {
+                factor: gguf
+                    .get_float(&key("rope.scaling.factor"))
+                    .unwrap_or_else(|| panic!("Missing GGUF key {}", key("rope.scaling.factor"))),
+                original_max_positions: int("rope.scaling.original_context_length"),
+                beta_fast: 32.,
+                beta_slow: 1.,
+            },
+            Some(t) => panic!("Unsupported rope scaling type {t}"),
+        };
+        let head_dim = gguf
+            .get_int(&key("attention.key_length"))
+            .map(|v| v as usize)
+            .unwrap_or(hidden_dim / n_heads);
+        // Architectures converted from NeoX-style checkpoints keep the half-split layout
+        let rope_layout = match arch.as_str() {
+            "qwen2" | "phi2" | "phi3" | "gptneox" | "stablelm" => RopeLayout::HalfSplit,
+            _ => RopeLayout::Interleaved,
+        };
+        Self {
+            vocab_size: gguf
+                .get_int(&key("vocab_size"))
+                .map(|v| v as usize)
+                .or_else(|| gguf.get_array_len("tokenizer.ggml.tokens"))
+                .expect("GGUF file has no vocab size"),
+            hidden_dim,
+            n_layers: int("block_count"),
+            n_heads,
+            n_kv_heads: gguf
+                .get_int(&key("attention.head_count_kv"))
+                .map(|v| v as usize)
+                .unwrap_or(n_heads),
+            head_dim,
+            mlp_dim: int("feed_forward_length"),
+            rope_theta: gguf.get_float(&key("rope.freq_base")).unwrap_or(10_000.),
+            rope_scaling,
+            rope_layout,
+            rotary_dim: gguf
+                .get_int(&key("rope.dimension_count"))
+                .map(|v| v as usize)
+                .unwrap_or(head_dim),
+            max_seq_len: int("context_length"),
+            norm_eps: gguf
+                .get_float(&key("attention.layer_norm_rms_epsilon"))
+                .unwrap_or(1e-5),
+            qkv_bias: gguf.has_tensor("blk.0.attn_q.bias"),
+            tied_embeddings: !gguf.has_tensor("output.weight"),
+        }
+    }
+}
+
+/// Access to the metadata and tensor names of a GGUF file
+pub trait GgufMetadata {
+    /// An integer value of any width
+    fn get_int(&self, key: &str) -> Option<u64>;
+    /// A floating point value of any width
+    fn get_float(&self, key: &str) -> Option<f32>;
+    fn get_str(&self, key: &str) -> Option<&str>;
+    /// The number of elements of an array value
+    fn get_array_len(&self, key: &str) -> Option<usize>;
+    fn has_tensor(&self, name: &str) -> bool;
+}
+
+/// A gated (SwiGLU) feed-forward network
+pub struct Mlp {
+    pub gate_proj: Linear, // hidden -> intermediate
+    pub down_proj: Linear, // intermediate -> hidden
+    pub up_proj: Linear,   // hidden -> intermediate
+}
+
+impl Module<GraphTensor> for Mlp {
+    type Output = GraphTensor;
+
+    fn forward(&self, input: GraphTensor) -> Self::Output {
+        let gate = self.gate_proj.forward(input).swish();
+        let up = self.up_proj.forward(input) * gate;
+        self.down_proj.forward(up)
+    }
+}
+
+impl Mlp {
+    pub fn new(hidden: usize, intermediate: usize, cx: &mut Graph) -> Self {
+        Self {
+            gate_proj: Linear::new_permuted(hidden, intermediate, false, cx),
+            down_proj: Linear::new_permuted(intermediate, hidden, false, cx),
+            up_proj: Linear::new_permuted(hidden, intermediate, false, cx),
+        }
+    }
+}
+
+impl SerializeModule for Mlp {
+    fn serialize(&self, s: &mut Serializer) {
+        s.module("ffn_gate", &self.gate_proj);
+        s.module("ffn_up", &self.up_proj);
+        s.module("ffn_down", &self.down_proj);
+    }
+}
+
+/// Causal self attention with rotary embeddings and grouped-query attention
+pub struct SelfAttention {
+    pub q_proj: Linear, // hidden -> heads * head_dim
+    pub k_proj: Linear, // hidden -> kv_heads * head_dim
+    pub v_proj: Linear, // hidden -> kv_heads * head_dim
+    pub o_proj: Linear, // heads * head_dim -> hidden
+    n_heads: usize,
+    n_kv_heads: usize,
+    head_dim: usize,
+    pub rope: RotaryEmbedding,
+}
+
+impl Module<(GraphTensor, &KVCacheLayer)> for SelfAttention {
+    type Output = GraphTensor;
+    fn forward(&self, (x, cache): (GraphTensor, &KVCacheLayer)) -> Self::Output {
+        // x: batch, seq, hidden
+        let (batch, seq, _) = x.dims3();
+        let prev_seq = cache.prev_seq();
+        let n_groups = self.n_heads / self.n_kv_heads;
+        // Apply the projections
+        let queries = self
+            .q_proj
+            .forward(x)
+            .reshape((batch, seq, self.n_heads, self.head_dim))
+            .permute((0, 2, 1, 3));
+        let keys = self
+            .k_proj
+            .forward(x)
+            .reshape((batch, seq, self.n_kv_heads, self.head_dim))
+            .permute((0, 2, 1, 3));
+        let values = self
+            .v_proj
+            .forward(x)
+            .reshape((batch, seq, self.n_kv_heads, self.head_dim))
+            .permute((0, 2, 1, 3));
+
+        // Rotary embed queries and keys
+        let queries = self.rope.forward((queries, prev_seq));
+        let keys = self.rope.forward((keys, prev_seq));
+
+        // Add KV cache
+        let (keys, values) = cache.forward((keys, values));
+
+        // Repeat the KV states for grouped-query attention
+        let repeated_keys = keys.expand(2, n_groups);
+        let repeated_values = values.expand(2, n_groups);
+
+        // Calculate attention weights
+        let mut attention_weights = queries
+            .reshape((batch, self.n_kv_heads, n_groups, seq, self.head_dim)) // Split query heads into groups
+            .matmul(repeated_keys.permute((0, 1, 2, 4, 3)))
+            / (self.head_dim as f32).sqrt();
+
+        let attention_mask = x.graph().triu(seq, 1) * f16::MIN.to_f32();
+        attention_weights += attention_mask
+            .pad(((0, 0), (prev_seq, 0)))
+            .expand(0, batch)
+            .expand(1, self.n_kv_heads)
+            .expand(2, n_groups);
+
+        // Calculate final outputs
+        let output = attention_weights
+            .softmax(4)
+            // Apply distribution to values
+            .matmul(repeated_values)
+            // Merge heads
+            .permute((0, 3, 1, 2, 4))
+            .reshape((batch, seq, self.n_heads * self.head_dim));
+        // Apply output projection
+        self.o_proj.forward(output)
+    }
+}
+
+impl SelfAttention {
+    pub fn new(config: &LlmConfig, rope: RotaryEmbedding, cx: &mut Graph) -> Self {
+        assert_eq!(
+            config.n_heads % config.n_kv_heads,
+            0,
+            "Query heads must be a multiple of key / value heads"
+        );
+        let (hidden, q_dim, kv_dim) = (
+            config.hidden_dim,
+            config.n_heads * config.head_dim,
+            config.n_kv_heads * config.head_dim,
+        );
+        Self {
+            q_proj: Linear::new_permuted(hidden, q_dim, config.qkv_bias, cx),
+            k_proj: Linear::new_permuted(hidden, kv_dim, config.qkv_bias, cx),
+            v_proj: Linear::new_permuted(hidden, kv_dim, config.qkv_bias, cx),
+            o_proj: Linear::new_permuted(q_dim, hidden, false, cx),
+            n_heads: config.n_heads,
+            n_kv_heads: config.n_kv_heads,
+            head_dim: config.head_dim,
+            rope,
+        }
+    }
+}
+
+impl SerializeModule for SelfAttention {
+    fn serialize(&self, s: &mut Serializer) {
+        s.module("attn_q", &self.q_proj);
+        s.module("attn_k", &self.k_proj);
+        s.module("attn_v", &self.v_proj);
+        s.module("attn_output", &self.o_proj);
+    }
+}
+
+/// A pre-norm transformer block
+pub struct TransformerBlock {
+    pub attention: SelfAttention,
+    pub attention_norm: RMSNorm,
+    pub feed_forward: Mlp,
+    pub feed_forward_norm: RMSNorm,
+}
+
+impl Module<(GraphTensor, &KVCacheLayer)> for TransformerBlock {
+    type Output = GraphTensor;
+    fn forward(&self, (mut x, cache): (GraphTensor, &KVCacheLayer)) -> Self::Output {
+        // Attention
+        let y = self
+            .attention
+            .forward((self.attention_norm.forward(x), cache));
+
+        // Residual
+        x += y;
+
+        // Feed Forward
+        let y = self.feed_forward.forward(self.feed_forward_norm.forward(x));
+
+        // Residual
+        x + y
+    }
+}
+
+impl TransformerBlock {
+    pub fn new(config: &LlmConfig, rope: RotaryEmbedding, cx: &mut Graph) -> Self {
+        Self {
+            attention: SelfAttention::new(config, rope, cx),
+            attention_norm: RMSNorm::new(config.hidden_dim, config.norm_eps, cx),
+            feed_forward: Mlp::new(config.hidden_dim, config.mlp_dim, cx),
+            feed_forward_norm: RMSNorm::new(config.hidden_dim, config.norm_eps, cx),
+        }
+    }
+}
+
+impl SerializeModule for TransformerBlock {
+    fn serialize(&self, s: &mut Serializer) {
+        s.module("", &self.attention);
+        s.module("attn_norm", &self.attention_norm);
+        s.module("ffn_norm", &self.feed_forward_norm);
+        s.module("", &self.feed_forward);
+    }
+}
+
+/// A decoder-only language model. Weights are named following GGUF conventions.
+/// ```rust
+/// use luminal::prelude::*;
+/// use luminal_nn::{llm::{Llm, LlmConfig}, KVCache};
+/// let mut cx = Graph::new();
+/// let config = LlmConfig {
+///     n_layers: 2,
+///     ..LlmConfig::qwen2_0_5b()
+/// };
+/// let model = Llm::new(&config, &mut cx);
+/// let cache = KVCache::new(config.n_layers, 1, config.n_kv_heads, config.head_dim, 'p', &mut cx);
+/// let input = cx.tensor((1, 's'));
+/// let logits = model.forward((input, &cache)); // (1, s, vocab)
+/// ```
+pub struct Llm {
+    // Token embeddings
+    pub embedding: Embedding,
+    // Transformer layers
+    pub layers: Vec<TransformerBlock>,
+    pub norm: RMSNorm,
+    /// Output projection, or None if it's tied to the token embeddings
+    pub lm_head: Option<Linear>,
+}
+
+impl Module<(GraphTensor, &KVCache)> for Llm {
+    type Output = GraphTensor;
+    /// Returns the next token logits (batch, seq, vocab) for token ids (batch, seq)
+    fn forward(&self, (input, cache): (GraphTensor, &KVCache)) -> Self::Output {
+        // Embed tokens
+        let mut x = self.embedding.forward(input);
+
+        // Run through layers, appending to each layer's cache
+        for (layer, layer_cache) in self.layers.iter().zip(cache.layers()) {
+            x = layer.forward((x, layer_cache));
+        }
+        // Run through last norm and output projection
+        let x = self.norm.forward(x);
+        match &self.lm_head {
+            Some(lm_head) => lm_head.forward(x),
+            None => x.matmul(self.embedding.weight.permute((1, 0))),
+        }
+    }
+}
+
+impl Llm {
+    pub fn new(config: &LlmConfig, cx: &mut Graph) -> Self {
+        // All layers share the same rotary tables
+        let rope = RotaryEmbedding::new(
+            config.head_dim,
+            config.rotary_dim,
+            config.max_seq_len,
+            config.rope_theta,
+            config.rope_scaling,
+            config.rope_layout,
+            cx,
+        );
+        Self {
+            embedding: Embedding::new(config.vocab_size, config.hidden_dim, cx),
+            layers: (0..config.n_layers)
+                .map(|_| TransformerBlock::new(config, rope, cx))
+                .collect(),
+            norm: RMSNorm::new(config.hidden_dim, config.norm_eps, cx),
+            lm_head: (!config.tied_embeddings)
+                .then(|| Linear::new_permuted(config.hidden_dim, config.vocab_size, false, cx)),
+        }
+    }
+}
+
+impl SerializeModule for Llm {
+    fn serialize(&self, s: &mut Serializer) {
+        s.module("token_embd", &self.embedding);
+        s.module("output_norm", &self.norm);
+        if let Some(lm_head) = &self.lm_head {
+            s.module("output", lm_head);
+        }
+        for (i, layer) in self.layers.iter().enumerate() {
+            s.module(&format!("blk/{i}"), layer);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use luminal::prelude::Module;
+
+    use super::{GgufMetadata, Llm, LlmConfig};
+    use crate::{KVCache, RopeLayout, RopeScaling};
+    luminal::test_imports!();
+
+    fn tiny_config() -> LlmConfig {
+        LlmConfig {
+            vocab_size: 10,
+            hidden_dim: 8,
+            n_layers: 2,
+            n_heads: 4,
+            n_kv_heads: 2,
+            head_dim: 2,
+            mlp_dim: 12,
+            rope_theta: 10_000.,
+            rope_scaling: RopeScaling::None,
+            rope_layout: RopeLayout::HalfSplit,
+            rotary_dim: 2,
+            max_seq_len: 16,
+            norm_eps: 1e-5,
+            qkv_bias: true,
+            tied_embeddings: true,
+        }
+    }
+
+    fn randomize(model: &Llm) {
+        let mut weights = vec![model.embedding.weight, model.norm.weight];
+        for layer in &model.layers {
+            let attn = &layer.attention;
+            let mlp = &layer.feed_forward;
+            for linear in [
+                &attn.q_proj,
+                &attn.k_proj,
+                &attn.v_proj,
+                &attn.o_proj,
+                &mlp.gate_proj,
+                &mlp.up_proj,
+                &mlp.down_proj,
+            ] {
+                weights.push(linear.weight);
+                weights.extend(linear.bias);
+            }
+            weights.push(layer.attention_norm.weight);
+            weights.push(layer.feed_forward_norm.weight);
+        }
+        for weight in weights {
+            weight.set(random_vec(weight.shape.n_elements().to_usize().unwrap()));
+        }
+    }
+
+    #[test]
+    fn test_llm_cached_decoding() {
+        // Running a prompt through the cache in pieces should match running it all at once
+        let config = tiny_config();
+        let mut cx = Graph::new();
+        let model = Llm::new(&config, &mut cx);
+        randomize(&model);
+        cx.keep_tensors(params(&model));
+        let mut cache = KVCache::new(
+            config.n_layers,
+            1,
+            config.n_kv_heads,
+            config.head_dim,
+            'p',
+            &mut cx,
+        );
+        let mut input = cx.named_tensor("Input", (1, 's'));
+        let mut logits = model.forward((input, &cache)).retrieve();
+        cx.compile(
+            GenericCompiler::default(),
+            (&mut input, &mut logits, &mut cache),
+        );
+
+        let tokens = [3., 1., 4., 1., 5.];
+        input.set_dyn(tokens.to_vec(), (1, 5));
+        cx.execute();
+        let full = logits.data();
+        logits.drop();
+        assert_eq!(full.len(), 5 * config.vocab_size);
+        cache.swap();
+        cache.reset();
+
+        let mut pieces = vec![];
+        for chunk in [&tokens[..3], &tokens[3..4], &tokens[4..]] {
+            input.set_dyn(chunk.to_vec(), (1, chunk.len()));
+            cx.execute();
+            pieces.extend(logits.data());
+            logits.drop();
+            cache.swap();
+        }
+        assert_eq!(cache.len(), 5);
+        assert_close(&pieces, &full);
+    }
+
+    struct Metadata(HashMap<&'static str, f64>, &'static str);
+
+    impl GgufMetadata for Metadata {
+        fn get_int(&self, key: &str) -> Option<u64> {
+            self.0.get(key).map(|v| *v as u64)
+        }
+        fn get_float(&self, key: &str) -> Option<f32> {
+            self.0.get(key).map(|v| *v as f32)
+        }
+        fn get_str(&self, key: &str) -> Option<&str> {
+            (key == "general.architecture").then_some(self.1)
+        }
+        fn get_array_len(&self, key: &str) -> Option<usize> {
+            (key == "tokenizer.ggml.tokens").then_some(128256)
+        }
+        fn has_tensor(&self, name: &str) -> bool {
+            name == "output.weight"
+        }
+    }
+
+    #[test]
+    fn test_config_from_gguf() {
+        let metadata = Metadata(
+            [
+                ("llama.embedding_length", 4096.),
+                ("llama.context_length", 8192.),
+                ("llama.block_count", 32.),
+                ("llama.attention.head_count", 32.),
+                ("llama.attention.head_count_kv", 8.),
+                ("llama.feed_forward_length", 14336.),
+                ("llama.rope.freq_base", 500_000.),
+                ("llama.attention.layer_norm_rms_epsilon", 1e-5),
+            ]
+            .into_iter()
+            .collect(),
+            "llama",
+        );
+        assert_eq!(LlmConfig::from_gguf(&metadata), LlmConfig::llama3_8b());
+    }
+}
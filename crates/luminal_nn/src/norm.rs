@@ -1,5 +1,8 @@
-use luminal::{prelude::*, tests::random_vec_rng};
-use rand::thread_rng;
+use std::cell::Cell;
+
+use luminal::prelude::*;
+
+use crate::Train;
 
 /// A simple layer norm with an optional weight and bias
 #[derive(Default)]
@@ -35,19 +38,12 @@ impl LayerNorm {
         }
     }
     pub fn initialize(self) -> Self {
-        // Init weight as uniform(-1, 1)
-        let mut rng = thread_rng();
+        // Init weight as 1 and bias as 0
         if let Some(w) = self.weight {
-            w.set(random_vec_rng(
-                w.shape.n_elements().to_usize().unwrap(),
-                &mut rng,
-            ));
+            w.set(vec![1.; w.shape.n_elements().to_usize().unwrap()]);
         }
         if let Some(b) = self.bias {
-            b.set(random_vec_rng(
-                b.shape.n_elements().to_usize().unwrap(),
-                &mut rng,
-            ));
+            b.set(vec![0.; b.shape.n_elements().to_usize().unwrap()]);
         }
         self
     }
@@ -80,3 +76,456 @@ impl SerializeModule for LayerNorm {
         }
     }
 }
+
+/// Root mean square norm, scaling by a learned weight
+pub struct RMSNorm {
+    pub weight: GraphTensor,
+    epsilon: f32,
+}
+
+impl RMSNorm {
+    pub fn new(dim: usize, epsilon: f32, cx: &mut Graph) -> Self {
+        Self {
+            weight: cx.named_tensor("RMSNorm Weight", dim),
+            epsilon,
+        }
+    }
+
+    pub fn initialize(self) -> Self {
+        // Init weight as 1
+        self.weight
+            .set(vec![1.; self.weight.shape.n_elements().to_usize().unwrap()]);
+        self
+    }
+}
+
+impl Module<GraphTensor> for RMSNorm {
+    type Output = GraphTensor;
+    fn forward(&self, input: GraphTensor) -> Self::Output {
+        input.std_norm(input.shape.last_axis(), self.epsilon) * self.weight.expand_to(input.shape)
+    }
+}
+
+impl SerializeModule for RMSNorm {
+    fn serialize(&self, s: &mut Serializer) {
+        s.tensor("weight", self.weight);
+    }
+}
+
+/// Expand per-channel values (channels,) to the shape of `input`
+fn expand_channels(values: GraphTensor, input: GraphTensor, channel_axis: usize) -> GraphTensor {
+    let mut values = values;
+    for (i, dim) in input.dims().into_iter().enumerate() {
+        if i != channel_axis {
+            values = values.expand(i, dim);
+        }
+    }
+    values
+}
+
+/// Batch normalization over `DIMS` spatial dimensions, with running statistics.
+///
+/// In training mode the input is normalized with the statistics of the batch, and the running
+/// statistics are updated with `momentum` each time [`BatchNorm::update_running_stats`] is called
+/// after an execution. In eval mode (the default) the running statistics are used. Pass the module
+/// to [`Graph::compile`] to keep the running statistics tracked through compilation.
+/// ```rust
+/// use luminal::prelude::*;
+/// use luminal_nn::{BatchNorm2D, Train};
+/// let mut cx = Graph::new();
+/// let mut norm = BatchNorm2D::new(3, 1e-5, 0.1, &mut cx).initialize();
+/// norm.train(true);
+/// let input = cx.tensor((4, 3, 8, 8)).set(vec![0.5; 4 * 3 * 8 * 8]);
+/// let output = norm.forward(input).retrieve();
+/// cx.execute();
+/// norm.update_running_stats(&mut cx);
+/// ```
+pub struct BatchNorm<const DIMS: usize> {
+    pub weight: GraphTensor,
+    pub bias: GraphTensor,
+    pub running_mean: GraphTensor,
+    pub running_var: GraphTensor,
+    epsilon: f32,
+    momentum: f32,
+    training: bool,
+    updated: Cell<Option<(GraphTensor, GraphTensor)>>,
+}
+
+/// Batch normalization of (batch, channels) or (batch, channels, length)
+pub type BatchNorm1D = BatchNorm<1>;
+/// Batch normalization of (channels, height, width) or (batch, channels, height, width)
+pub type BatchNorm2D = BatchNorm<2>;
+
+impl<const DIMS: usize> BatchNorm<DIMS> {
+    pub fn new(channels: usize, epsilon: f32, momentum: f32, cx: &mut Graph) -> Self {
+        Self {
+            weight: cx.named_tensor("BatchNorm Weight", channels),
+            bias: cx.named_tensor("BatchNorm Bias", channels),
+            running_mean: cx
+                .named_tensor("BatchNorm Running Mean", channels)
+                .set(vec![0.; channels])
+                .keep(),
+            running_var: cx
+                .named_tensor("BatchNorm Running Var", channels)
+                .set(vec![1.; channels])
+                .keep(),
+            epsilon,
+            momentum,
+            training: false,
+            updated: Cell::default(),
+        }
+    }
+
+    pub fn initialize(self) -> Self {
+        // Init weight as 1 and bias as 0
+        let channels = self.weight.shape.n_elements().to_usize().unwrap();
+        self.weight.set(vec![1.; channels]);
+        self.bias.set(vec![0.; channels]);
+        self
+    }
+
+    pub fn is_training(&self) -> bool {
+        self.training
+    }
+
+    /// Move the running statistics computed by the last execution into the module. Requires a
+    /// forward pass to have been made in training mode.
+    pub fn update_running_stats(&self, cx: &mut Graph) {
+        let (mean, var) = self
+            .updated
+            .get()
+            .expect("Running stats updated without a forward pass in training mode");
+        transfer_data_same_graph((mean, var), (self.running_mean, self.running_var), cx);
+    }
+
+    fn channel_axis(&self, input: GraphTensor) -> usize {
+        let n_dims = input.shape.len();
+        if DIMS == 1 {
+            assert!(
+                n_dims == 2 || n_dims == 3,
+                "BatchNorm1D expects (batch, channels) or (batch, channels, length)"
+            );
+            1
+        } else {
+            assert!(
+                n_dims == DIMS + 1 || n_dims == DIMS + 2,
+                "BatchNorm expects (channels, ..spatial) or (batch, channels, ..spatial)"
+            );
+            n_dims - DIMS - 1
+        }
+    }
+}
+
+impl<const DIMS: usize> Train for BatchNorm<DIMS> {
+    /// Switch between training (batch statistics) and eval (running statistics) mode
+    fn train(&mut self, training: bool) {
+        self.training = training;
+    }
+}
+
+impl<const DIMS: usize> Module<GraphTensor> for BatchNorm<DIMS> {
+    type Output = GraphTensor;
+    fn forward(&self, input: GraphTensor) -> Self::Output {
+        let channel_axis = self.channel_axis(input);
+        let (mean, var) = if self.training {
+            let axes = (0..input.shape.len())
+                .filter(|i| *i != channel_axis)
+                .collect::<Vec<_>>();
+            let n = axes
+                .iter()
+                .map(|i| input.dims()[*i])
+                .product::<Expression>();
+            let mean = input.mean_reduce(axes.clone());
+            let centered = input - expand_channels(mean, input, channel_axis);
+            let var = (centered * centered).mean_reduce(axes);
+            // The running variance is an unbiased estimate
+            self.updated.set(Some((
+                (self.running_mean * (1. - self.momentum) + mean * self.momentum).keep(),
+                (self.running_var * (1. - self.momentum) + var * n / (n - 1) * self.momentum)
+                    .keep(),
+            )));
+            (mean, var)
+        } else {
+            (self.running_mean, self.running_var)
+        };
+        let scale = self.weight * (var + self.epsilon).sqrt().recip();
+        (input - expand_channels(mean, input, channel_axis))
+            * expand_channels(scale, input, channel_axis)
+            + expand_channels(self.bias, input, channel_axis)
+    }
+}
+
+impl<const DIMS: usize> ToIdsMut for BatchNorm<DIMS> {
+    fn to_ids_mut(&mut self) -> Vec<&mut NodeIndex> {
+        let mut ids = vec![&mut self.running_mean.id, &mut self.running_var.id];
+        if let Some((mean, var)) = self.updated.get_mut() {
+            ids.push(&mut mean.id);
+            ids.push(&mut var.id);
+        }
+        ids
+    }
+}
+
+impl<const DIMS: usize> ToIds for BatchNorm<DIMS> {
+    fn to_ids(&self) -> Vec<NodeIndex> {
+        let mut ids = vec![self.running_mean.id, self.running_var.id];
+        if let Some((mean, var)) = self.updated.get() {
+            ids.extend([mean.id, var.id]);
+        }
+        ids
+    }
+}
+
+impl<const DIMS: usize> SerializeModule for BatchNorm<DIMS> {
+    fn serialize(&self, s: &mut Serializer) {
+        s.tensor("weight", self.weight);
+        s.tensor("bias", self.bias);
+        s.tensor("running_mean", self.running_mean);
+        s.tensor("running_var", self.running_var);
+    }
+}
+
+/// Normalize groups of channels of (batch, channels, ..spatial) together, then apply a
+/// per-channel weight and bias
+pub struct GroupNorm {
+    pub weight: GraphTensor,
+    pub bias: GraphTensor,
+    groups: usize,
+    epsilon: f32,
+}
+
+impl GroupNorm {
+    pub fn new(groups: usize, channels: usize, epsilon: f32, cx: &mut Graph) -> Self {
+        assert_eq!(
+            channels % groups,
+            0,
+            "Channels must be divisible by the number of groups"
+        );
+        Self {
+            weight: cx.named_tensor("GroupNorm Weight", channels),
+            bias: cx.named_tensor("GroupNorm Bias", channels),
+            groups,
+            epsilon,
+        }
+    }
+
+    pub fn initialize(self) -> Self {
+        // Init weight as 1 and bias as 0
+        let channels = self.weight.shape.n_elements().to_usize().unwrap();
+        self.weight.set(vec![1.; channels]);
+        self.bias.set(vec![0.; channels]);
+        self
+    }
+}
+
+impl Module<GraphTensor> for GroupNorm {
+    type Output = GraphTensor;
+    fn forward(&self, input: GraphTensor) -> Self::Output {
+        let dims = input.dims();
+        let group_size = dims[1..].iter().copied().product::<Expression>() / self.groups;
+        let normed = input
+            .reshape((dims[0], self.groups, group_size))
+            .layer_norm(2, self.epsilon)
+            .reshape(input.shape);
+        normed * expand_channels(self.weight, input, 1) + expand_channels(self.bias, input, 1)
+    }
+}
+
+impl SerializeModule for GroupNorm {
+    fn serialize(&self, s: &mut Serializer) {
+        s.tensor("weight", self.weight);
+        s.tensor("bias", self.bias);
+    }
+}
+
+/// Normalize each channel of each item of (batch, channels, ..spatial) over its spatial
+/// dimensions, with an optional per-channel weight and bias
+pub struct InstanceNorm {
+    pub weight: Option<GraphTensor>,
+    pub bias: Option<GraphTensor>,
+    epsilon: f32,
+}
+
+impl InstanceNorm {
+    pub fn new(channels: usize, affine: bool, epsilon: f32, cx: &mut Graph) -> Self {
+        Self {
+            weight: affine.then(|| cx.named_tensor("InstanceNorm Weight", channels)),
+            bias: affine.then(|| cx.named_tensor("InstanceNorm Bias", channels)),
+            epsilon,
+        }
+    }
+
+    pub fn initialize(self) -> Self {
+        // Init weight as 1 and bias as 0
+        if let Some(w) = self.weight {
+            w.set(vec![1.; w.shape.n_elements().to_usize().unwrap()]);
+        }
+        if let Some(b) = self.bias {
+            b.set(vec![0.; b.shape.n_elements().to_usize().unwrap()]);
+        }
+        self
+    }
+}
+
+impl Module<GraphTensor> for InstanceNorm {
+    type Output = GraphTensor;
+    fn forward(&self, input: GraphTensor) -> Self::Output {
+        let mut output = input.layer_norm((2..input.shape.len()).collect::<Vec<_>>(), self.epsilon);
+        if let Some(w) = self.weight {
+            output *= expand_channels(w, input, 1);
+        }
+        if let Some(b) = self.bias {
+            output += expand_channels(b, input, 1);
+        }
+        output
+    }
+}
+
+impl SerializeModule for InstanceNorm {
+    fn serialize(&self, s: &mut Serializer) {
+        if let Some(w) = self.weight {
+            s.tensor("weight", w);
+        }
+        if let Some(b) = self.bias {
+            s.tensor("bias", b);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use dfdx::prelude::{DeviceBuildExt, Module as DfdxModule, ModuleMut as DfdxModuleMut};
+    use luminal::prelude::Module;
+
+    use super::{BatchNorm1D, BatchNorm2D, GroupNorm, InstanceNorm, RMSNorm};
+    use crate::Train;
+    luminal::test_imports!();
+
+    #[test]
+    fn test_rms_norm() {
+        let mut cx = Graph::new();
+        let input_data = random_vec(2 * 3 * 4);
+        let weight_data = random_vec(4);
+        let input = cx.tensor((2, 3, 4)).set(input_data.clone());
+        let model = RMSNorm::new(4, 1e-5, &mut cx);
+        model.weight.set(weight_data.clone());
+        let mut output = model.forward(input).retrieve();
+        cx.compile(GenericCompiler::default(), &mut output);
+        cx.execute();
+
+        let d_dev = Cpu::default();
+        let d_input = d_dev.tensor_from_vec(input_data, (DConst::<2>, DConst::<3>, DConst::<4>));
+        let d_weight = d_dev.tensor_from_vec(weight_data, (DConst::<4>,));
+        let d_rms = (d_input.clone().square().mean::<_, DAxis<2>>() + 1e-5)
+            .sqrt()
+            .broadcast::<_, DAxis<2>>();
+        let d_output = d_input / d_rms * d_weight.broadcast::<_, DAxes2<0, 1>>();
+        assert_close(&output.data(), &d_output.as_vec());
+    }
+
+    #[test]
+    fn test_batch_norm_1d() {
+        let mut cx = Graph::new();
+        let input_data = random_vec(3 * 2 * 4);
+        let input = cx.tensor((3, 2, 4)).set(input_data.clone());
+        let mut model = BatchNorm1D::new(2, 1e-5, 0.1, &mut cx).initialize();
+        model.train(true);
+        let train_output = model.forward(input).retrieve();
+        model.train(false);
+        let eval_output = model.forward(input).retrieve();
+        cx.execute();
+
+        let d_dev = Cpu::default();
+        let mut d_model = d_dev.build_module::<dfdx::nn::builders::BatchNorm1D<2>, f32>();
+        let d_input = d_dev.tensor_from_vec(input_data, (DConst::<3>, DConst::<2>, DConst::<4>));
+        let d_train_output = d_model.forward_mut(d_input.leaky_trace());
+        assert_close(&train_output.data(), &d_train_output.as_vec());
+
+        // Running stats move into the module, and are used in eval mode
+        model.update_running_stats(&mut cx);
+        assert_close(&model.running_mean.data(), &d_model.running_mean.as_vec());
+        assert_close(&model.running_var.data(), &d_model.running_var.as_vec());
+        eval_output.drop();
+        cx.execute();
+        assert_close(&eval_output.data(), &d_model.forward(d_input).as_vec());
+    }
+
+    #[test]
+    fn test_batch_norm_2d() {
+        let mut cx = Graph::new();
+        let input_data = random_vec(2 * 3 * 2 * 2);
+        let weight_data = random_vec(3);
+        let bias_data = random_vec(3);
+        let mut input = cx.tensor((2, 3, 2, 2));
+        let mut model = BatchNorm2D::new(3, 1e-5, 0.1, &mut cx);
+        model.weight.set(weight_data.clone());
+        model.bias.set(bias_data.clone());
+        model.train(true);
+        let mut output = model.forward(input).retrieve();
+        cx.compile(
+            GenericCompiler::default(),
+            (&mut input, &mut output, &mut model),
+        );
+
+        let d_dev = Cpu::default();
+        let mut d_model = d_dev.build_module::<dfdx::nn::builders::BatchNorm2D<3>, f32>();
+        d_model.scale = d_dev.tensor_from_vec(weight_data, (DConst::<3>,));
+        d_model.bias = d_dev.tensor_from_vec(bias_data, (DConst::<3>,));
+        let d_input = d_dev.tensor_from_vec(
+            input_data.clone(),
+            (DConst::<2>, DConst::<3>, DConst::<2>, DConst::<2>),
+        );
+        // Running stats accumulate over steps
+        for _ in 0..2 {
+            input.set(input_data.clone());
+            cx.execute();
+            let d_output = d_model.forward_mut(d_input.leaky_trace());
+            assert_close(&output.data(), &d_output.as_vec());
+            output.drop();
+            model.update_running_stats(&mut cx);
+        }
+        assert_close(&model.running_mean.data(), &d_model.running_mean.as_vec());
+        assert_close(&model.running_var.data(), &d_model.running_var.as_vec());
+    }
+
+    #[test]
+    fn test_group_and_instance_norm() {
+        let mut cx = Graph::new();
+        let input_data = random_vec(2 * 4 * 3);
+        let weight_data = random_vec(4);
+        let bias_data = random_vec(4);
+        let input = cx.tensor((2, 4, 3)).set(input_data.clone());
+        let group_norm = GroupNorm::new(2, 4, 1e-5, &mut cx);
+        group_norm.weight.set(weight_data.clone());
+        group_norm.bias.set(bias_data.clone());
+        let instance_norm = InstanceNorm::new(4, false, 1e-5, &mut cx).initialize();
+        let mut group_output = group_norm.forward(input).retrieve();
+        let mut instance_output = instance_norm.forward(input).retrieve();
+        cx.compile(
+            GenericCompiler::default(),
+            (&mut group_output, &mut instance_output),
+        );
+        cx.execute();
+
+        let d_dev = Cpu::default();
+        let d_input = d_dev.tensor_from_vec(input_data, (DConst::<2>, DConst::<4>, DConst::<3>));
+        let d_weight = d_dev
+            .tensor_from_vec(weight_data, (DConst::<4>,))
+            .broadcast::<(DConst<2>, DConst<4>, DConst<3>), DAxes2<0, 2>>();
+        let d_bias = d_dev
+            .tensor_from_vec(bias_data, (DConst::<4>,))
+            .broadcast::<(DConst<2>, DConst<4>, DConst<3>), DAxes2<0, 2>>();
+        let d_group_output = d_input
+            .clone()
+            .reshape::<(DConst<2>, DConst<2>, DConst<6>)>()
+            .normalize::<DAxis<2>>(1e-5)
+            .reshape::<(DConst<2>, DConst<4>, DConst<3>)>()
+            * d_weight
+            + d_bias;
+        assert_close(&group_output.data(), &d_group_output.as_vec());
+
+        // Instance norm is a group norm with a group per channel
+        let d_instance_output = d_input.normalize::<DAxis<2>>(1e-5);
+        assert_close(&instance_output.data(), &d_instance_output.as_vec());
+    }
+}
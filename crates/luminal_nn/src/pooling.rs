@@ -0,0 +1,364 @@
+use luminal::prelude::*;
+
+/// Expose the (kernel.0, kernel.1) windows of the last two dimensions of (batch, channels, h, w)
+/// as (batch, channels, h_out, w_out, kernel.0 * kernel.1)
+fn windows_2d(
+    input: GraphTensor,
+    kernel: (usize, usize),
+    stride: (usize, usize),
+    dilation: (usize, usize),
+) -> GraphTensor {
+    let windows = input
+        .pool_last_dim(kernel.1, stride.1, dilation.1)
+        .permute((0, 1, 3, 4, 2))
+        .pool_last_dim(kernel.0, stride.0, dilation.0)
+        .permute((0, 1, 4, 2, 5, 3));
+    let dims = windows.dims();
+    windows.reshape((dims[0], dims[1], dims[2], dims[3], kernel.0 * kernel.1))
+}
+
+/// Run a 2D op on (batch, channels, h, w), also accepting unbatched (channels, h, w) input
+fn batched_2d(input: GraphTensor, f: impl FnOnce(GraphTensor) -> GraphTensor) -> GraphTensor {
+    assert!(
+        input.shape.len() == 3 || input.shape.len() == 4,
+        "Expected (channels, h, w) or (batch, channels, h, w) input"
+    );
+    if input.shape.len() == 3 {
+        let output = f(input.expand(0, 1));
+        let (_, channels, h, w) = output.dims4();
+        output.reshape((channels, h, w))
+    } else {
+        f(input)
+    }
+}
+
+/// Apply (out_h, h) and (out_w, w) matrices to the spatial dimensions of (batch, channels, h, w)
+fn resample_2d(input: GraphTensor, rows: GraphTensor, cols: GraphTensor) -> GraphTensor {
+    input
+        .matmul(cols.permute((1, 0)))
+        .permute((0, 1, 3, 2))
+        .matmul(rows.permute((1, 0)))
+        .permute((0, 1, 3, 2))
+}
+
+/// 2D max pooling. Padded positions never win the max.
+pub struct MaxPool2D {
+    kernel: (usize, usize),
+    stride: (usize, usize),
+    padding: (usize, usize),
+    dilation: (usize, usize),
+}
+
+impl MaxPool2D {
+    pub fn new(
+        kernel: (usize, usize),
+        stride: (usize, usize),
+        padding: (usize, usize),
+        dilation: (usize, usize),
+    ) -> Self {
+        Self {
+            kernel,
+            stride,
+            padding,
+            dilation,
+        }
+    }
+}
+
+impl SerializeModule for MaxPool2D {
+    fn serialize(&self, _: &mut Serializer) {}
+}
+
+impl Module<GraphTensor> for MaxPool2D {
+    type Output = GraphTensor;
+
+    fn forward(&self, input: GraphTensor) -> Self::Output {
+        batched_2d(input, |mut input| {
+            if self.padding != (0, 0) {
+                let padding = (
+                    (0, 0),
+                    (0, 0),
+                    (self.padding.0, self.padding.0),
+                    (self.padding.1, self.padding.1),
+                );
+                // Push padded positions far below any real value
+                let valid = (input * 0. + 1.).pad(padding);
+                input = input.pad(padding) + (valid - 1.) * f16::MAX.to_f32();
+            }
+            windows_2d(input, self.kernel, self.stride, self.dilation).max_reduce(4)
+        })
+    }
+}
+
+/// 2D average pooling. Padded zeros count towards the average.
+pub struct AvgPool2D {
+    kernel: (usize, usize),
+    stride: (usize, usize),
+    padding: (usize, usize),
+    dilation: (usize, usize),
+}
+
+impl AvgPool2D {
+    pub fn new(
+        kernel: (usize, usize),
+        stride: (usize, usize),
+        padding: (usize, usize),
+        dilation: (usize, usize),
+    ) -> Self {
+        Self {
+            kernel,
+            stride,
+            padding,
+            dilation,
+        }
+    }
+}
+
+impl SerializeModule for AvgPool2D {
+    fn serialize(&self, _: &mut Serializer) {}
+}
+
+impl Module<GraphTensor> for AvgPool2D {
+    type Output = GraphTensor;
+
+    fn forward(&self, input: GraphTensor) -> Self::Output {
+        batched_2d(input, |input| {
+            let input = input.pad((
+                (0, 0),
+                (0, 0),
+                (self.padding.0, self.padding.0),
+                (self.padding.1, self.padding.1),
+            ));
+            windows_2d(input, self.kernel, self.stride, self.dilation).mean_reduce(4)
+        })
+    }
+}
+
+/// 2D average pooling to a fixed output size. Each output covers the input positions
+/// `floor(i * in / out)..ceil((i + 1) * in / out)`, so windows may overlap when the sizes don't
+/// divide evenly.
+pub struct AdaptiveAvgPool2D {
+    output: (usize, usize),
+}
+
+impl AdaptiveAvgPool2D {
+    pub fn new(output: (usize, usize)) -> Self {
+        Self { output }
+    }
+}
+
+/// Averaging weights (out, size) for adaptive pooling of a dimension
+fn adaptive_weights(cx: &mut Graph, size: Expression, out: usize) -> GraphTensor {
+    // Floor division on whole numbers held as floats
+    let floor_div = |x: GraphTensor| (x - x % out as f32) / out as f32;
+    let i = cx.arange(out);
+    let start = floor_div(i * size).expand(1, size);
+    let end = floor_div((i + 1.) * size + (out - 1) as f32).expand(1, size);
+    let j = cx.arange(size).expand(0, out);
+    let in_window = start.less_than(j + 1.) * j.less_than(end);
+    in_window / in_window.sum_reduce(1).expand(1, size)
+}
+
+impl SerializeModule for AdaptiveAvgPool2D {
+    fn serialize(&self, _: &mut Serializer) {}
+}
+
+impl Module<GraphTensor> for AdaptiveAvgPool2D {
+    type Output = GraphTensor;
+
+    fn forward(&self, input: GraphTensor) -> Self::Output {
+        batched_2d(input, |input| {
+            let (_, _, h, w) = input.dims4();
+            let rows = adaptive_weights(input.graph(), h, self.output.0);
+            let cols = adaptive_weights(input.graph(), w, self.output.1);
+            resample_2d(input, rows, cols)
+        })
+    }
+}
+
+/// How upsampled values are interpolated
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum UpsampleMode {
+    /// Repeat each value
+    #[default]
+    Nearest,
+    /// Linearly interpolate between the nearest values along each dimension, treating values as
+    /// pixel centers (`align_corners = false`)
+    Bilinear,
+}
+
+/// Upsample the spatial dimensions of (batch, channels, h, w) by an integer factor
+pub struct Upsample {
+    scale_factor: usize,
+    mode: UpsampleMode,
+}
+
+impl Upsample {
+    pub fn new(scale_factor: usize, mode: UpsampleMode) -> Self {
+        Self { scale_factor, mode }
+    }
+}
+
+/// Interpolation weights (size * scale, size) for bilinear upsampling of a dimension
+fn bilinear_weights(cx: &mut Graph, size: Expression, scale: usize) -> GraphTensor {
+    let out = size * scale;
+    // Position of each output in the input, clamped to the edges
+    let src = ((cx.arange(out) + 0.5) / scale as f32 - 0.5).relu();
+    let src = src - (src - (size - 1)).relu();
+    let j = cx.arange(size).expand(0, out);
+    (1. - (j - src.expand(1, size)).abs()).relu()
+}
+
+impl SerializeModule for Upsample {
+    fn serialize(&self, _: &mut Serializer) {}
+}
+
+impl Module<GraphTensor> for Upsample {
+    type Output = GraphTensor;
+
+    fn forward(&self, input: GraphTensor) -> Self::Output {
+        batched_2d(input, |input| {
+            let (batch, channels, h, w) = input.dims4();
+            match self.mode {
+                UpsampleMode::Nearest => input
+                    .expand(3, self.scale_factor)
+                    .expand(5, self.scale_factor)
+                    .reshape((
+                        batch,
+                        channels,
+                        h * self.scale_factor,
+                        w * self.scale_factor,
+                    )),
+                UpsampleMode::Bilinear => {
+                    let rows = bilinear_weights(input.graph(), h, self.scale_factor);
+                    let cols = bilinear_weights(input.graph(), w, self.scale_factor);
+                    resample_2d(input, rows, cols)
+                }
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use candle_core::{Device, Tensor};
+    use luminal::{
+        prelude::*,
+        tests::{assert_close, random_vec_rng},
+    };
+    use rand::{rngs::StdRng, SeedableRng};
+
+    use super::{AdaptiveAvgPool2D, AvgPool2D, MaxPool2D, Upsample, UpsampleMode};
+
+    fn to_vec(tensor: Tensor) -> Vec<f32> {
+        tensor.flatten_all().unwrap().to_vec1::<f32>().unwrap()
+    }
+
+    #[test]
+    fn test_pooling() {
+        let mut cx = Graph::new();
+        let mut rng = StdRng::seed_from_u64(0);
+        let data = random_vec_rng(2 * 3 * 7 * 6, &mut rng);
+        let input = cx
+            .tensor((2, 3, 'h', 'w'))
+            .set_dyn(data.clone(), (2, 3, 7, 6));
+        let mut max = MaxPool2D::new((3, 3), (2, 2), (0, 0), (1, 1))
+            .forward(input)
+            .retrieve();
+        let mut max_padded = MaxPool2D::new((3, 2), (2, 1), (1, 1), (1, 1))
+            .forward(input)
+            .retrieve();
+        let mut avg = AvgPool2D::new((2, 3), (2, 3), (0, 0), (1, 1))
+            .forward(input)
+            .retrieve();
+        cx.compile(
+            GenericCompiler::default(),
+            (&mut max, &mut max_padded, &mut avg),
+        );
+        cx.execute();
+
+        let reference = Tensor::from_vec(data, (2, 3, 7, 6), &Device::Cpu).unwrap();
+        assert_close(
+            &max.data(),
+            &to_vec(reference.max_pool2d_with_stride(3, 2).unwrap()),
+        );
+        // Pad with values that never win the max
+        let padded = reference
+            .pad_with_zeros(2, 1, 1)
+            .unwrap()
+            .pad_with_zeros(3, 1, 1)
+            .unwrap();
+        let valid = reference
+            .ones_like()
+            .unwrap()
+            .pad_with_zeros(2, 1, 1)
+            .unwrap()
+            .pad_with_zeros(3, 1, 1)
+            .unwrap();
+        let padded = (padded + ((valid - 1.).unwrap() * 1e9).unwrap()).unwrap();
+        assert_close(
+            &max_padded.data(),
+            &to_vec(padded.max_pool2d_with_stride((3, 2), (2, 1)).unwrap()),
+        );
+        assert_close(
+            &avg.data(),
+            &to_vec(reference.avg_pool2d_with_stride((2, 3), (2, 3)).unwrap()),
+        );
+    }
+
+    #[test]
+    fn test_adaptive_avg_pool() {
+        let mut cx = Graph::new();
+        let data = (0..5 * 4).map(|i| i as f32).collect::<Vec<_>>();
+        let input = cx.tensor((1, 'h', 'w')).set_dyn(data.clone(), (1, 5, 4));
+        let mut output = AdaptiveAvgPool2D::new((3, 2)).forward(input).retrieve();
+        cx.compile(GenericCompiler::default(), &mut output);
+        cx.execute();
+
+        // Rows 0..2, 1..4 and 3..5, columns 0..2 and 2..4
+        let mut expected = vec![];
+        for (r0, r1) in [(0, 2), (1, 4), (3, 5)] {
+            for (c0, c1) in [(0, 2), (2, 4)] {
+                let mut sum = 0.;
+                for r in r0..r1 {
+                    for c in c0..c1 {
+                        sum += data[r * 4 + c];
+                    }
+                }
+                expected.push(sum / ((r1 - r0) * (c1 - c0)) as f32);
+            }
+        }
+        assert_close(&output.data(), &expected);
+    }
+
+    #[test]
+    fn test_upsample() {
+        let mut cx = Graph::new();
+        let mut rng = StdRng::seed_from_u64(0);
+        let data = random_vec_rng(2 * 3 * 4, &mut rng);
+        let input = cx.tensor((2, 'h', 'w')).set_dyn(data.clone(), (2, 3, 4));
+        let small = cx.tensor((1, 1, 2, 2)).set(vec![1., 2., 3., 4.]);
+        let mut nearest = Upsample::new(2, UpsampleMode::Nearest)
+            .forward(input)
+            .retrieve();
+        let mut bilinear = Upsample::new(2, UpsampleMode::Bilinear)
+            .forward(small)
+            .retrieve();
+        cx.compile(GenericCompiler::default(), (&mut nearest, &mut bilinear));
+        cx.execute();
+
+        let reference = Tensor::from_vec(data, (1, 2, 3, 4), &Device::Cpu).unwrap();
+        assert_close(
+            &nearest.data(),
+            &to_vec(reference.upsample_nearest2d(6, 8).unwrap()),
+        );
+        // Reference values from PyTorch
+        assert_close(
+            &bilinear.data(),
+            &[
+                1.0, 1.25, 1.75, 2.0, 1.5, 1.75, 2.25, 2.5, 2.5, 2.75, 3.25, 3.5, 3.0, 3.25, 3.75,
+                4.0,
+            ],
+        );
+    }
+}
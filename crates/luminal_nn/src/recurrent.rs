@@ -0,0 +1,454 @@
+use luminal::prelude::*;
+
+/// The weights of one direction of a recurrent layer, laid out like PyTorch with the gates
+/// stacked along the first dimension
+pub struct RecurrentWeights {
+    pub weight_ih: GraphTensor, // gates * hidden, input
+    pub weight_hh: GraphTensor, // gates * hidden, hidden
+    pub bias_ih: Option<GraphTensor>,
+    pub bias_hh: Option<GraphTensor>,
+}
+
+impl RecurrentWeights {
+    fn new(gates: usize, input: usize, hidden: usize, bias: bool, cx: &mut Graph) -> Self {
+        Self {
+            weight_ih: cx.named_tensor("Weight IH", (gates * hidden, input)),
+            weight_hh: cx.named_tensor("Weight HH", (gates * hidden, hidden)),
+            bias_ih: bias.then(|| cx.named_tensor("Bias IH", gates * hidden)),
+            bias_hh: bias.then(|| cx.named_tensor("Bias HH", gates * hidden)),
+        }
+    }
+
+    /// Project the whole input sequence at once: (batch, seq, input) -> (seq, batch, gates * hidden)
+    fn project_input(&self, input: GraphTensor) -> GraphTensor {
+        let (batch, seq, _) = input.dims3();
+        let mut projected = input.matmul(self.weight_ih.permute((1, 0)));
+        if let Some(b) = self.bias_ih {
+            projected += b.expand(0, batch).expand(1, seq);
+        }
+        projected.permute((1, 0, 2))
+    }
+}
+
+/// Project the hidden state inside a scan body: (batch, hidden) -> (batch, gates * hidden)
+fn project_hidden(hidden: GraphTensor, weights: &[GraphTensor]) -> GraphTensor {
+    let projected = hidden.matmul(weights[0].permute((1, 0)));
+    if let Some(b) = weights.get(1) {
+        projected + b.expand(0, hidden.dims()[0])
+    } else {
+        projected
+    }
+}
+
+/// Take the nth gate out of stacked gates (batch, gates * hidden)
+fn gate(gates: GraphTensor, n: usize, hidden: usize) -> GraphTensor {
+    gates.slice_along(n * hidden..(n + 1) * hidden, 1)
+}
+
+fn lstm_cell(states: &[GraphTensor], x: GraphTensor, weights: &[GraphTensor]) -> Vec<GraphTensor> {
+    let hidden = weights[0].dims()[1].to_usize().unwrap();
+    let gates = x + project_hidden(states[0], weights);
+    let c = gate(gates, 1, hidden).sigmoid() * states[1]
+        + gate(gates, 0, hidden).sigmoid() * gate(gates, 2, hidden).tanh();
+    let h = gate(gates, 3, hidden).sigmoid() * c.tanh();
+    vec![h, c]
+}
+
+fn gru_cell(states: &[GraphTensor], x: GraphTensor, weights: &[GraphTensor]) -> Vec<GraphTensor> {
+    let hidden = weights[0].dims()[1].to_usize().unwrap();
+    let h = project_hidden(states[0], weights);
+    let r = (gate(x, 0, hidden) + gate(h, 0, hidden)).sigmoid();
+    let z = (gate(x, 1, hidden) + gate(h, 1, hidden)).sigmoid();
+    let n = (gate(x, 2, hidden) + r * gate(h, 2, hidden)).tanh();
+    vec![(1. - z) * n + z * states[0]]
+}
+
+type Cell = fn(&[GraphTensor], GraphTensor, &[GraphTensor]) -> Vec<GraphTensor>;
+
+/// Run stacked recurrent layers over (batch, seq, input), each layer scanning over the sequence
+/// once per direction.
+///
+/// `init` holds each initial state as (layers * directions, batch, hidden), and the final states
+/// come back the same way. The first state is the hidden state that gets output at each step.
+fn run_layers(
+    input: GraphTensor,
+    layers: &[Vec<RecurrentWeights>],
+    init: &[GraphTensor],
+    cell: Cell,
+) -> (GraphTensor, Vec<GraphTensor>) {
+    assert_eq!(input.shape.len(), 3, "Expected (batch, seq, input) input");
+    let mut output = input;
+    let mut finals = vec![vec![]; init.len()];
+    for (directions, index) in layers.iter().zip((0..).step_by(layers[0].len())) {
+        let mut layer_output: Option<GraphTensor> = None;
+        for (reverse, weights) in directions.iter().enumerate() {
+            let states = init
+                .iter()
+                .map(|s| {
+                    let (_, batch, hidden) = s.dims3();
+                    s.slice_along(index + reverse..index + reverse + 1, 0)
+                        .reshape((batch, hidden))
+                })
+                .collect::<Vec<_>>();
+            let constants = [Some(weights.weight_hh), weights.bias_hh]
+                .into_iter()
+                .flatten()
+                .collect::<Vec<_>>();
+            let projected = weights.project_input(output);
+            let (states, outputs) = input.graph().scan(
+                &states,
+                &[projected],
+                &constants,
+                reverse == 1,
+                |_, states, steps, weights| {
+                    let states = cell(states, steps[0], weights);
+                    let hidden = states[0];
+                    (states, vec![hidden])
+                },
+            );
+            for (finals, state) in finals.iter_mut().zip(states) {
+                // Concatenating needs a real dimension rather than an expanded one
+                let (batch, hidden) = state.dims2();
+                finals.push(state.reshape((1, batch, hidden)));
+            }
+            let outputs = outputs[0].permute((1, 0, 2));
+            layer_output = Some(match layer_output {
+                Some(forward) => forward.concat_along(outputs, 2),
+                None => outputs,
+            });
+        }
+        output = layer_output.unwrap();
+    }
+    let finals = finals
+        .into_iter()
+        .map(|states| {
+            states
+                .into_iter()
+                .reduce(|acc, state| acc.concat_along(state, 0))
+                .unwrap()
+        })
+        .collect();
+    (output, finals)
+}
+
+/// Zero initial states of (layers * directions, batch, hidden)
+fn zero_state(input: GraphTensor, layers: &[Vec<RecurrentWeights>], hidden: usize) -> GraphTensor {
+    input
+        .graph()
+        .constant(0.)
+        .expand(0, layers.len() * layers[0].len())
+        .expand(1, input.dims()[0])
+        .expand(2, hidden)
+}
+
+fn serialize_layers(layers: &[Vec<RecurrentWeights>], s: &mut Serializer) {
+    for (l, directions) in layers.iter().enumerate() {
+        for (weights, suffix) in directions.iter().zip(["", "_reverse"]) {
+            s.tensor(&format!("weight_ih_l{l}{suffix}"), weights.weight_ih);
+            s.tensor(&format!("weight_hh_l{l}{suffix}"), weights.weight_hh);
+            if let Some(bias) = weights.bias_ih {
+                s.tensor(&format!("bias_ih_l{l}{suffix}"), bias);
+            }
+            if let Some(bias) = weights.bias_hh {
+                s.tensor(&format!("bias_hh_l{l}{suffix}"), bias);
+            }
+        }
+    }
+}
+
+fn new_layers(
+    gates: usize,
+    input: usize,
+    hidden: usize,
+    layers: usize,
+    bidirectional: bool,
+    bias: bool,
+    cx: &mut Graph,
+) -> Vec<Vec<RecurrentWeights>> {
+    let directions = if bidirectional { 2 } else { 1 };
+    (0..layers)
+        .map(|l| {
+            let input = if l == 0 { input } else { hidden * directions };
+            (0..directions)
+                .map(|_| RecurrentWeights::new(gates, input, hidden, bias, cx))
+                .collect()
+        })
+        .collect()
+}
+
+/// A multi-layer LSTM over (batch, seq, input), optionally bidirectional.
+///
+/// Outputs (batch, seq, directions * hidden) along with the final hidden and cell states, each
+/// (layers * directions, batch, hidden). Weights serialize with PyTorch's names.
+pub struct LSTM {
+    /// The weights of each layer, with the reverse direction second
+    pub layers: Vec<Vec<RecurrentWeights>>,
+    hidden: usize,
+}
+
+impl LSTM {
+    pub fn new(
+        input: usize,
+        hidden: usize,
+        layers: usize,
+        bidirectional: bool,
+        bias: bool,
+        cx: &mut Graph,
+    ) -> Self {
+        Self {
+            layers: new_layers(4, input, hidden, layers, bidirectional, bias, cx),
+            hidden,
+        }
+    }
+}
+
+impl SerializeModule for LSTM {
+    fn serialize(&self, s: &mut Serializer) {
+        serialize_layers(&self.layers, s);
+    }
+}
+
+impl Module<GraphTensor> for LSTM {
+    type Output = (GraphTensor, GraphTensor, GraphTensor);
+
+    fn forward(&self, input: GraphTensor) -> Self::Output {
+        let zeros = zero_state(input, &self.layers, self.hidden);
+        self.forward((input, zeros, zeros))
+    }
+}
+
+impl Module<(GraphTensor, GraphTensor, GraphTensor)> for LSTM {
+    type Output = (GraphTensor, GraphTensor, GraphTensor);
+
+    /// Run from the initial (hidden, cell) states
+    fn forward(&self, (input, h, c): (GraphTensor, GraphTensor, GraphTensor)) -> Self::Output {
+        let (output, states) = run_layers(input, &self.layers, &[h, c], lstm_cell);
+        (output, states[0], states[1])
+    }
+}
+
+/// A multi-layer GRU over (batch, seq, input), optionally bidirectional.
+///
+/// Outputs (batch, seq, directions * hidden) along with the final hidden state,
+/// (layers * directions, batch, hidden). Weights serialize with PyTorch's names.
+pub struct GRU {
+    /// The weights of each layer, with the reverse direction second
+    pub layers: Vec<Vec<RecurrentWeights>>,
+    hidden: usize,
+}
+
+impl GRU {
+    pub fn new(
+        input: usize,
+        hidden: usize,
+        layers: usize,
+        bidirectional: bool,
+        bias: bool,
+        cx: &mut Graph,
+    ) -> Self {
+        Self {
+            layers: new_layers(3, input, hidden, layers, bidirectional, bias, cx),
+            hidden,
+        }
+    }
+}
+
+impl SerializeModule for GRU {
+    fn serialize(&self, s: &mut Serializer) {
+        serialize_layers(&self.layers, s);
+    }
+}
+
+impl Module<GraphTensor> for GRU {
+    type Output = (GraphTensor, GraphTensor);
+
+    fn forward(&self, input: GraphTensor) -> Self::Output {
+        let zeros = zero_state(input, &self.layers, self.hidden);
+        self.forward((input, zeros))
+    }
+}
+
+impl Module<(GraphTensor, GraphTensor)> for GRU {
+    type Output = (GraphTensor, GraphTensor);
+
+    /// Run from the initial hidden state
+    fn forward(&self, (input, h): (GraphTensor, GraphTensor)) -> Self::Output {
+        let (output, states) = run_layers(input, &self.layers, &[h], gru_cell);
+        (output, states[0])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use luminal::{
+        prelude::*,
+        tests::{assert_close, random_vec_rng},
+    };
+    use rand::{rngs::StdRng, SeedableRng};
+
+    use super::{RecurrentWeights, GRU, LSTM};
+
+    const BATCH: usize = 2;
+    const SEQ: usize = 4;
+    const INPUT: usize = 3;
+    const HIDDEN: usize = 5;
+
+    /// (weight_ih, weight_hh, bias_ih, bias_hh) data for each layer and direction
+    type WeightData = Vec<Vec<[Vec<f32>; 4]>>;
+
+    fn set_weights(layers: &[Vec<RecurrentWeights>], rng: &mut StdRng) -> WeightData {
+        layers
+            .iter()
+            .map(|directions| {
+                directions
+                    .iter()
+                    .map(|w| {
+                        [
+                            w.weight_ih,
+                            w.weight_hh,
+                            w.bias_ih.unwrap(),
+                            w.bias_hh.unwrap(),
+                        ]
+                        .map(|t| {
+                            let n = t.shape.n_elements().to_usize().unwrap();
+                            let data = random_vec_rng(n, rng);
+                            t.set(data.clone());
+                            data
+                        })
+                    })
+                    .collect()
+            })
+            .collect()
+    }
+
+    /// W x + b for a row-major (out, in) matrix
+    fn affine(w: &[f32], b: &[f32], x: &[f32]) -> Vec<f32> {
+        b.iter()
+            .enumerate()
+            .map(|(o, b)| b + (0..x.len()).map(|i| w[o * x.len() + i] * x[i]).sum::<f32>())
+            .collect()
+    }
+
+    fn sigmoid(x: f32) -> f32 {
+        1. / (1. + (-x).exp())
+    }
+
+    fn reference_lstm(x: &[f32], h: &[f32], states: &[Vec<f32>]) -> Vec<Vec<f32>> {
+        let g = |n: usize, i: usize| x[n * HIDDEN + i] + h[n * HIDDEN + i];
+        let c = (0..HIDDEN)
+            .map(|i| sigmoid(g(1, i)) * states[1][i] + sigmoid(g(0, i)) * g(2, i).tanh())
+            .collect::<Vec<_>>();
+        let h = (0..HIDDEN)
+            .map(|i| sigmoid(g(3, i)) * c[i].tanh())
+            .collect();
+        vec![h, c]
+    }
+
+    fn reference_gru(x: &[f32], h: &[f32], states: &[Vec<f32>]) -> Vec<Vec<f32>> {
+        let h = (0..HIDDEN)
+            .map(|i| {
+                let r = sigmoid(x[i] + h[i]);
+                let z = sigmoid(x[HIDDEN + i] + h[HIDDEN + i]);
+                let n = (x[2 * HIDDEN + i] + r * h[2 * HIDDEN + i]).tanh();
+                (1. - z) * n + z * states[0][i]
+            })
+            .collect();
+        vec![h]
+    }
+
+    type ReferenceCell = fn(&[f32], &[f32], &[Vec<f32>]) -> Vec<Vec<f32>>;
+
+    /// Run the layers one batch entry at a time, returning (output, final states)
+    fn reference(
+        weights: &WeightData,
+        input: &[f32],
+        init: &[Vec<f32>],
+        cell: ReferenceCell,
+    ) -> (Vec<f32>, Vec<Vec<f32>>) {
+        let directions = weights[0].len();
+        let mut outputs = vec![];
+        let mut finals = vec![vec![0.; weights.len() * directions * BATCH * HIDDEN]; init.len()];
+        for b in 0..BATCH {
+            let mut seq = (0..SEQ)
+                .map(|t| input[(b * SEQ + t) * INPUT..(b * SEQ + t + 1) * INPUT].to_vec())
+                .collect::<Vec<_>>();
+            for (l, layer) in weights.iter().enumerate() {
+                let mut next = vec![vec![]; SEQ];
+                for (d, [w_ih, w_hh, b_ih, b_hh]) in layer.iter().enumerate() {
+                    let offset = ((l * directions + d) * BATCH + b) * HIDDEN;
+                    let mut states = init
+                        .iter()
+                        .map(|s| s[offset..offset + HIDDEN].to_vec())
+                        .collect::<Vec<_>>();
+                    let order = (0..SEQ).collect::<Vec<_>>();
+                    let order = if d == 1 {
+                        order.into_iter().rev().collect()
+                    } else {
+                        order
+                    };
+                    for t in order {
+                        let x = affine(w_ih, b_ih, &seq[t]);
+                        let h = affine(w_hh, b_hh, &states[0]);
+                        states = cell(&x, &h, &states);
+                        next[t].extend(states[0].clone());
+                    }
+                    for (finals, state) in finals.iter_mut().zip(states) {
+                        finals[offset..offset + HIDDEN].copy_from_slice(&state);
+                    }
+                }
+                seq = next;
+            }
+            outputs.extend(seq.concat());
+        }
+        (outputs, finals)
+    }
+
+    #[test]
+    fn test_lstm() {
+        let mut cx = Graph::new();
+        let mut rng = StdRng::seed_from_u64(0);
+        let model = LSTM::new(INPUT, HIDDEN, 2, true, true, &mut cx);
+        let weights = set_weights(&model.layers, &mut rng);
+        let input_data = random_vec_rng(BATCH * SEQ * INPUT, &mut rng);
+        let input = cx
+            .tensor((BATCH, 's', INPUT))
+            .set_dyn(input_data.clone(), (BATCH, SEQ, INPUT));
+        let (output, h, c) = model.forward(input);
+        let mut outputs = (output.retrieve(), h.retrieve(), c.retrieve());
+        cx.compile(GenericCompiler::default(), &mut outputs);
+        cx.execute();
+
+        let zeros = vec![0.; 4 * BATCH * HIDDEN];
+        let (output, finals) = reference(
+            &weights,
+            &input_data,
+            &[zeros.clone(), zeros],
+            reference_lstm,
+        );
+        assert_close(&outputs.0.data(), &output);
+        assert_close(&outputs.1.data(), &finals[0]);
+        assert_close(&outputs.2.data(), &finals[1]);
+    }
+
+    #[test]
+    fn test_gru() {
+        let mut cx = Graph::new();
+        let mut rng = StdRng::seed_from_u64(0);
+        let model = GRU::new(INPUT, HIDDEN, 2, false, true, &mut cx);
+        let weights = set_weights(&model.layers, &mut rng);
+        let input_data = random_vec_rng(BATCH * SEQ * INPUT, &mut rng);
+        let init_data = random_vec_rng(2 * BATCH * HIDDEN, &mut rng);
+        let input = cx
+            .tensor((BATCH, 's', INPUT))
+            .set_dyn(input_data.clone(), (BATCH, SEQ, INPUT));
+        let init = cx.tensor((2, BATCH, HIDDEN)).set(init_data.clone());
+        let (output, h) = model.forward((input, init));
+        let mut outputs = (output.retrieve(), h.retrieve());
+        cx.compile(GenericCompiler::default(), &mut outputs);
+        cx.execute();
+
+        let (output, finals) = reference(&weights, &input_data, &[init_data], reference_gru);
+        assert_close(&outputs.0.data(), &output);
+        assert_close(&outputs.1.data(), &finals[0]);
+    }
+}
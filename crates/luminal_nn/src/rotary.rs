@@ -0,0 +1,366 @@
+use std::f64::consts::PI;
+
+use luminal::prelude::*;
+
+/// How the rotated dimensions are paired up
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum RopeLayout {
+    /// Adjacent pairs (x0, x1), (x2, x3), ... as in GGML and the original Llama weights
+    #[default]
+    Interleaved,
+    /// The first half of the dimensions is paired with the second half, as in GPT-NeoX and most
+    /// HuggingFace checkpoints
+    HalfSplit,
+}
+
+/// How rotary frequencies are scaled to extend the context beyond what the model was trained on
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum RopeScaling {
+    #[default]
+    None,
+    /// Divide positions by a constant factor
+    Linear { factor: f32 },
+    /// Scale the rotary base so low frequencies are interpolated and high frequencies preserved
+    Ntk { factor: f32 },
+    /// Interpolate low frequencies and extrapolate high frequencies with a linear ramp between,
+    /// and scale attention by `0.1 * ln(factor) + 1`
+    Yarn {
+        factor: f32,
+        original_max_positions: usize,
+        beta_fast: f32,
+        beta_slow: f32,
+    },
+    /// Llama 3.1 style: divide low frequencies by the factor, keeping high frequencies and smoothly
+    /// interpolating between
+    Llama3 {
+        factor: f32,
+        low_freq_factor: f32,
+        high_freq_factor: f32,
+        original_max_positions: usize,
+    },
+}
+
+/// Rotary position embeddings with precomputed cos / sin tables.
+///
+/// The tables cover positions up to `max_positions` and are kept in the graph, so they're only
+/// computed once. Only the first `rotary_dim` dimensions of each head are rotated, the rest pass
+/// through unchanged. The embedding is `Copy`, so layers can share the same tables.
+/// ```rust
+/// use luminal::prelude::*;
+/// use luminal_nn::{RopeLayout, RopeScaling, RotaryEmbedding};
+/// let mut cx = Graph::new();
+/// let rope = RotaryEmbedding::new(64, 64, 2048, 10_000., RopeScaling::None, RopeLayout::HalfSplit, &mut cx);
+/// let queries = cx.tensor((1, 8, 's', 64));
+/// // Rotate a chunk of tokens starting at position 'p'
+/// let rotated = rope.forward((queries, Expression::from('p')));
+/// ```
+#[derive(Clone, Copy)]
+pub struct RotaryEmbedding {
+    /// Cosine of each position and frequency: (max_positions, rotary_dim / 2)
+    pub cos: GraphTensor,
+    /// Sine of each position and frequency: (max_positions, rotary_dim / 2)
+    pub sin: GraphTensor,
+    rotary_dim: usize,
+    layout: RopeLayout,
+}
+
+impl RotaryEmbedding {
+    pub fn new(
+        head_dim: usize,
+        rotary_dim: usize,
+        max_positions: usize,
+        theta: f32,
+        scaling: RopeScaling,
+        layout: RopeLayout,
+        cx: &mut Graph,
+    ) -> Self {
+        assert!(
+            rotary_dim <= head_dim && rotary_dim % 2 == 0,
+            "Rotary dim must be even and at most the head dim"
+        );
+        let inv_freqs = inv_freqs(rotary_dim, theta, scaling);
+        let attention_scale = match scaling {
+            RopeScaling::Yarn { factor, .. } if factor > 1. => 0.1 * (factor as f64).ln() + 1.,
+            _ => 1.,
+        };
+        let angles = (0..max_positions)
+            .flat_map(|p| inv_freqs.iter().map(move |f| p as f64 * f))
+            .collect::<Vec<_>>();
+        let table = |f: fn(f64) -> f64| {
+            angles
+                .iter()
+                .map(|a| (f(*a) * attention_scale) as f32)
+                .collect::<Vec<_>>()
+        };
+        let shape = (max_positions, rotary_dim / 2);
+        Self {
+            cos: cx
+                .named_tensor("RoPE Cos", shape)
+                .set(table(f64::cos))
+                .keep(),
+            sin: cx
+                .named_tensor("RoPE Sin", shape)
+                .set(table(f64::sin))
+                .keep(),
+            rotary_dim,
+            layout,
+        }
+    }
+
+    /// Rotate (batch, heads, seq, head_dim) by the cos / sin tables of each position (seq, rotary_dim / 2)
+    fn rotate(&self, input: GraphTensor, cos: GraphTensor, sin: GraphTensor) -> GraphTensor {
+        let (batch, n_heads, seq, head_dim) = input.dims4();
+        let half = self.rotary_dim / 2;
+        let partial = head_dim.to_usize() != Some(self.rotary_dim);
+        let x = if partial {
+            input.slice_along(..self.rotary_dim, 3)
+        } else {
+            input
+        };
+        let (cos, sin) = (
+            cos.expand(0, batch).expand(1, n_heads),
+            sin.expand(0, batch).expand(1, n_heads),
+        );
+        let rotated = match self.layout {
+            RopeLayout::Interleaved => {
+                // Split into evens and odds
+                let split = x.reshape((batch, n_heads, seq, half, 2));
+                let x0 = split.slice_along(..1, 4);
+                let x1 = split.slice_along(1.., 4);
+                let (cos, sin) = (cos.expand(4, 1), sin.expand(4, 1));
+                let out0 = x0 * cos - x1 * sin;
+                let out1 = x0 * sin + x1 * cos;
+                out0.concat_along(out1, 4)
+                    .reshape((batch, n_heads, seq, self.rotary_dim))
+            }
+            RopeLayout::HalfSplit => {
+                let x0 = x.slice_along(..half, 3);
+                let x1 = x.slice_along(half.., 3);
+                let out0 = x0 * cos - x1 * sin;
+                let out1 = x0 * sin + x1 * cos;
+                out0.concat_along(out1, 3)
+            }
+        };
+        if partial {
+            rotated.concat_along(input.slice_along(self.rotary_dim.., 3), 3)
+        } else {
+            rotated
+        }
+    }
+}
+
+/// Rotary frequencies of each pair of dimensions, after scaling
+pub fn inv_freqs(rotary_dim: usize, theta: f32, scaling: RopeScaling) -> Vec<f64> {
+    let dim = rotary_dim as f64;
+    let theta = match scaling {
+        RopeScaling::Ntk { factor } => theta as f64 * (factor as f64).powf(dim / (dim - 2.)),
+        _ => theta as f64,
+    };
+    let freqs = (0..rotary_dim / 2).map(move |i| theta.powf(-2. * i as f64 / dim));
+    match scaling {
+        RopeScaling::None | RopeScaling::Ntk { .. } => freqs.collect(),
+        RopeScaling::Linear { factor } => freqs.map(|f| f / factor as f64).collect(),
+        RopeScaling::Yarn {
+            factor,
+            original_max_positions,
+            beta_fast,
+            beta_slow,
+        } => {
+            // Dimension at which a frequency completes `rotations` turns over the original context
+            let correction_dim = |rotations: f32| {
+                dim * (original_max_positions as f64 / (rotations as f64 * 2. * PI)).ln()
+                    / (2. * theta.ln())
+            };
+            let low = correction_dim(beta_fast).floor().max(0.);
+            let mut high = correction_dim(beta_slow).ceil().min(dim - 1.);
+            if low == high {
+                high += 0.001;
+            }
+            freqs
+                .enumerate()
+                .map(|(i, f)| {
+                    let ramp = ((i as f64 - low) / (high - low)).clamp(0., 1.);
+                    // Fully extrapolate high frequencies, fully interpolate low ones
+                    f / factor as f64 * ramp + f * (1. - ramp)
+                })
+                .collect()
+        }
+        RopeScaling::Llama3 {
+            factor,
+            low_freq_factor,
+            high_freq_factor,
+            original_max_positions,
+        } => {
+            let original = original_max_positions as f64;
+            let low_freq_wavelen = original / low_freq_factor as f64;
+            let high_freq_wavelen = original / high_freq_factor as f64;
+            freqs
+                .map(|f| {
+                    let wavelen = 2. * PI / f;
+                    if wavelen < high_freq_wavelen {
+                        f
+                    } else if wavelen > low_freq_wavelen {
+                        f / factor as f64
+                    } else {
+                        let smooth = (original / wavelen - low_freq_factor as f64)
+                            / (high_freq_factor - low_freq_factor) as f64;
+                        (1. - smooth) * f / factor as f64 + smooth * f
+                    }
+                })
+                .collect()
+        }
+    }
+}
+
+impl Module<(GraphTensor, Expression)> for RotaryEmbedding {
+    type Output = GraphTensor;
+
+    /// Rotate (batch, heads, seq, head_dim) whose tokens start at position `offset`
+    fn forward(&self, (input, offset): (GraphTensor, Expression)) -> Self::Output {
+        let (_, _, seq, _) = input.dims4();
+        let positions = offset..offset + seq;
+        self.rotate(
+            input,
+            self.cos.slice_along(positions.clone(), 0),
+            self.sin.slice_along(positions, 0),
+        )
+    }
+}
+
+impl Module<(GraphTensor, GraphTensor)> for RotaryEmbedding {
+    type Output = GraphTensor;
+
+    /// Rotate (batch, heads, seq, head_dim) by the position of each token (seq)
+    fn forward(&self, (input, positions): (GraphTensor, GraphTensor)) -> Self::Output {
+        self.rotate(
+            input,
+            self.cos.gather(positions),
+            self.sin.gather(positions),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use luminal::prelude::Module;
+
+    use super::{inv_freqs, RopeLayout, RopeScaling, RotaryEmbedding};
+    luminal::test_imports!();
+
+    /// Rotate (heads, seq, head_dim) data directly
+    fn reference(
+        data: &[f32],
+        heads: usize,
+        seq: usize,
+        head_dim: usize,
+        rotary_dim: usize,
+        offset: usize,
+        layout: RopeLayout,
+    ) -> Vec<f32> {
+        let freqs = inv_freqs(rotary_dim, 10_000., RopeScaling::None);
+        let mut out = data.to_vec();
+        for h in 0..heads {
+            for s in 0..seq {
+                let row = &mut out[(h * seq + s) * head_dim..][..head_dim];
+                for (i, f) in freqs.iter().enumerate() {
+                    let (a, b) = match layout {
+                        RopeLayout::Interleaved => (2 * i, 2 * i + 1),
+                        RopeLayout::HalfSplit => (i, i + rotary_dim / 2),
+                    };
+                    let angle = (offset + s) as f64 * f;
+                    let (x0, x1) = (row[a] as f64, row[b] as f64);
+                    row[a] = (x0 * angle.cos() - x1 * angle.sin()) as f32;
+                    row[b] = (x0 * angle.sin() + x1 * angle.cos()) as f32;
+                }
+            }
+        }
+        out
+    }
+
+    #[test]
+    fn test_rotary_layouts() {
+        let mut cx = Graph::new();
+        let positions = cx.tensor(3).set(vec![5., 6., 7.]);
+        let mut outputs = vec![];
+        let mut expected = vec![];
+        for (layout, rotary_dim) in [
+            (RopeLayout::Interleaved, 8),
+            (RopeLayout::HalfSplit, 8),
+            (RopeLayout::Interleaved, 4),
+            (RopeLayout::HalfSplit, 4),
+        ] {
+            let rope = RotaryEmbedding::new(
+                8,
+                rotary_dim,
+                16,
+                10_000.,
+                RopeScaling::None,
+                layout,
+                &mut cx,
+            );
+            let data = random_vec(2 * 3 * 8);
+            let input = cx.tensor((1, 2, 3, 8)).set(data.clone());
+            outputs.push(rope.forward((input, Expression::from(5))).retrieve());
+            outputs.push(rope.forward((input, positions)).retrieve());
+            let reference = reference(&data, 2, 3, 8, rotary_dim, 5, layout);
+            expected.extend([reference.clone(), reference]);
+        }
+        cx.execute();
+        for (output, expected) in outputs.iter().zip(&expected) {
+            assert_close(&output.data(), expected);
+        }
+
+        // Compiled graphs give the same results
+        for output in &outputs {
+            output.drop();
+        }
+        cx.compile(GenericCompiler::default(), &mut outputs);
+        cx.execute();
+        for (output, expected) in outputs.iter().zip(&expected) {
+            assert_close(&output.data(), expected);
+        }
+    }
+
+    #[test]
+    fn test_rotary_scaling() {
+        let base = inv_freqs(64, 10_000., RopeScaling::None);
+        let linear = inv_freqs(64, 10_000., RopeScaling::Linear { factor: 4. });
+        for (b, l) in base.iter().zip(&linear) {
+            assert!((b / 4. - l).abs() < 1e-12);
+        }
+
+        // NTK keeps the highest frequency and scales the lowest by the factor
+        let ntk = inv_freqs(64, 10_000., RopeScaling::Ntk { factor: 4. });
+        assert_eq!(ntk[0], base[0]);
+        assert!((ntk[31] / base[31] - 4_f64.powf(-62. / 62.)).abs() < 1e-9);
+
+        // YaRN and Llama 3 keep high frequencies and interpolate low ones
+        for scaling in [
+            RopeScaling::Yarn {
+                factor: 4.,
+                original_max_positions: 4096,
+                beta_fast: 32.,
+                beta_slow: 1.,
+            },
+            RopeScaling::Llama3 {
+                factor: 8.,
+                low_freq_factor: 1.,
+                high_freq_factor: 4.,
+                original_max_positions: 8192,
+            },
+        ] {
+            let factor = match scaling {
+                RopeScaling::Yarn { factor, .. } | RopeScaling::Llama3 { factor, .. } => factor,
+                _ => unreachable!(),
+            } as f64;
+            let scaled = inv_freqs(64, 500_000., scaling);
+            let base = inv_freqs(64, 500_000., RopeScaling::None);
+            assert_eq!(scaled[0], base[0]);
+            assert!((scaled[31] - base[31] / factor).abs() < 1e-12);
+            for ((s, b), next) in scaled.iter().zip(&base).zip(scaled.iter().skip(1)) {
+                assert!(*s <= *b && *s >= b / factor);
+                assert!(next < s, "Frequencies should stay decreasing");
+            }
+        }
+    }
+}
@@ -1,31 +1,200 @@
 use std::ops::Mul;
 
-use crate::Linear;
+use crate::{KVCacheLayer, Linear};
 use luminal::prelude::*;
 
 /// Multi-head self attention as layed out in [*Attention Is All You Need*](https://arxiv.org/abs/1706.03762).
+///
+/// Also supports grouped-query attention (see [`MultiHeadSelfAttention::new_grouped`]), causal
+/// masking, ALiBi position biases, padding masks, KV caches and cross attention over precomputed
+/// encoder keys and values.
 pub struct MultiHeadSelfAttention {
     pub w_q: Linear, // dim x k_dim
-    pub w_k: Linear, // dim x k_dim
-    pub w_v: Linear, // dim x v_dim
+    pub w_k: Linear, // dim x k_dim * kv_heads / heads
+    pub w_v: Linear, // dim x v_dim * kv_heads / heads
     pub w_o: Linear, // v_dim x dim
     k_dim: usize,
     v_dim: usize,
     heads: usize,
+    kv_heads: usize,
+    causal: bool,
+    alibi: bool,
 }
 
 impl MultiHeadSelfAttention {
     pub fn new(dim: usize, k_dim: usize, v_dim: usize, heads: usize, cx: &mut Graph) -> Self {
+        Self::new_grouped(dim, k_dim, v_dim, heads, heads, cx)
+    }
+
+    /// Grouped-query attention, where each of the `kv_heads` key and value heads is shared by a
+    /// group of query heads. One key and value head is multi-query attention.
+    pub fn new_grouped(
+        dim: usize,
+        k_dim: usize,
+        v_dim: usize,
+        heads: usize,
+        kv_heads: usize,
+        cx: &mut Graph,
+    ) -> Self {
+        assert!(
+            heads % kv_heads == 0,
+            "Query heads must be a multiple of key and value heads"
+        );
         Self {
             w_q: Linear::new(dim, k_dim, false, cx),
-            w_k: Linear::new(dim, k_dim, false, cx),
-            w_v: Linear::new(dim, v_dim, false, cx),
+            w_k: Linear::new(dim, k_dim / heads * kv_heads, false, cx),
+            w_v: Linear::new(dim, v_dim / heads * kv_heads, false, cx),
             w_o: Linear::new(v_dim, dim, false, cx),
             k_dim,
             v_dim,
             heads,
+            kv_heads,
+            causal: false,
+            alibi: false,
         }
     }
+
+    /// Only let each query attend to keys at or before its position. Queries are aligned to the
+    /// end of the keys, so cached keys are always visible.
+    pub fn with_causal(mut self, causal: bool) -> Self {
+        self.causal = causal;
+        self
+    }
+
+    /// Add [ALiBi](https://arxiv.org/abs/2108.12409) biases, penalizing attention linearly in the
+    /// distance between query and key with a different slope for each head
+    pub fn with_alibi(mut self, alibi: bool) -> Self {
+        self.alibi = alibi;
+        self
+    }
+
+    /// Project keys and values (batch, seq, dim) into heads (batch, kv_heads, seq, head_dim), like
+    /// the encoder outputs cross attention attends to. Pass them back in with the queries to reuse
+    /// them across decoding steps.
+    pub fn project_kv(&self, keys: GraphTensor, values: GraphTensor) -> (GraphTensor, GraphTensor) {
+        let (batch, seq, _) = keys.dims3();
+        (
+            self.w_k
+                .forward(keys)
+                .reshape((batch, seq, self.kv_heads, self.k_dim / self.heads))
+                .permute((0, 2, 1, 3)),
+            self.w_v
+                .forward(values)
+                .reshape((batch, seq, self.kv_heads, self.v_dim / self.heads))
+                .permute((0, 2, 1, 3)),
+        )
+    }
+
+    /// Attend queries (batch, s2, dim) to key and value heads (batch, kv_heads, s1, head_dim), with
+    /// an optional mask (batch, s2, s1) of 1 where attention is allowed
+    fn attend(
+        &self,
+        queries: GraphTensor,
+        keys: GraphTensor,
+        values: GraphTensor,
+        mask: Option<GraphTensor>,
+    ) -> GraphTensor {
+        let (batch, s2, _) = queries.dims3();
+        let s1 = keys.dims4().2;
+        let head_dim = self.k_dim / self.heads;
+        let queries = self
+            .w_q
+            .forward(queries)
+            .reshape((batch, s2, self.heads, head_dim))
+            .permute((0, 2, 1, 3));
+        let (keys, values) = (self.repeat_kv(keys), self.repeat_kv(values));
+        let mut weights = queries
+            .matmul(keys.permute((0, 1, 3, 2)))
+            .mul((1.0 / (head_dim as f64).sqrt()) as f32);
+
+        // Key position minus query position, with queries at the end of the keys
+        let cx = queries.graph();
+        let distance = cx.arange(s1).expand(0, s2) - (cx.arange(s2) + (s1 - s2)).expand(1, s1);
+        let mut allowed = mask.map(|m| m.expand(1, self.heads));
+        if self.causal {
+            let causal = distance
+                .less_than_equal(cx.constant(0.).expand_to(distance.shape))
+                .expand(0, batch)
+                .expand(1, self.heads);
+            allowed = Some(allowed.map(|a| a * causal).unwrap_or(causal));
+        }
+        if let Some(allowed) = allowed {
+            weights += (allowed - 1.) * f16::MAX.to_f32();
+        }
+        if self.alibi {
+            let slopes = cx
+                .named_tensor("ALiBi Slopes", self.heads)
+                .set(alibi_slopes(self.heads));
+            let bias = slopes.expand(1, s2).expand(2, s1) * distance.abs().expand(0, self.heads);
+            weights -= bias.expand(0, batch);
+        }
+
+        weights
+            .softmax(3)
+            .matmul(values)
+            .permute((0, 2, 1, 3))
+            .reshape((batch, s2, self.v_dim))
+    }
+
+    /// Repeat each key or value head for its group of query heads
+    fn repeat_kv(&self, x: GraphTensor) -> GraphTensor {
+        if self.kv_heads == self.heads {
+            return x;
+        }
+        let (batch, kv_heads, seq, head_dim) = x.dims4();
+        x.expand(2, self.heads / self.kv_heads).reshape((
+            batch,
+            kv_heads * (self.heads / self.kv_heads),
+            seq,
+            head_dim,
+        ))
+    }
+
+    /// Attention with separate keys, queries and values, and an optional mask of 1 where attention
+    /// is allowed. The mask is either a padding mask over keys (batch_dims, s1) or a full mask
+    /// (batch_dims, s2, s1).
+    fn forward_masked(
+        &self,
+        keys: GraphTensor,
+        queries: GraphTensor,
+        values: GraphTensor,
+        mask: Option<GraphTensor>,
+    ) -> GraphTensor {
+        let orig_query_shape = queries.dims();
+        let batch_dims = queries.shape.len() - 2;
+        let s1 = keys.dims()[keys.shape.len() - 2];
+        let s2 = queries.dims()[batch_dims];
+        let n_batches = queries
+            .dims()
+            .into_iter()
+            .take(batch_dims)
+            .product::<Expression>()
+            .max(1);
+        let dim = *queries.dims().last().unwrap();
+        let (keys, values) = self.project_kv(
+            keys.reshape((n_batches, s1, dim)),
+            values.reshape((n_batches, s1, dim)),
+        );
+        let mask = mask.map(|m| {
+            if m.shape.len() == batch_dims + 1 {
+                m.reshape((n_batches, s1)).expand(1, s2)
+            } else {
+                m.reshape((n_batches, s2, s1))
+            }
+        });
+        let tokens = self.attend(queries.reshape((n_batches, s2, dim)), keys, values, mask);
+        self.w_o.forward(tokens).reshape(orig_query_shape) // batch_dims, s2, dim
+    }
+}
+
+/// ALiBi slopes for each head, a geometric sequence from 2^(-8 / heads) when heads is a power of
+/// 2, otherwise topped up with every other slope of the next power of 2
+fn alibi_slopes(heads: usize) -> Vec<f32> {
+    let slopes = |n: usize| (1..=n).map(move |i| 2_f32.powf(-8. * i as f32 / n as f32));
+    let closest = 1 << heads.ilog2();
+    slopes(closest)
+        .chain(slopes(2 * closest).step_by(2).take(heads - closest))
+        .collect()
 }
 
 impl SerializeModule for MultiHeadSelfAttention {
@@ -62,45 +231,50 @@ impl Module<(GraphTensor, GraphTensor, GraphTensor)> for MultiHeadSelfAttention
             GraphTensor, // batch, s1, dim
         ),
     ) -> Self::Output {
-        let orig_query_shape = queries.dims();
-        let s1 = keys.dims()[keys.shape.len() - 2];
-        let s2 = queries.dims()[queries.shape.len() - 2];
-        let n_batches = queries
-            .dims()
-            .into_iter()
-            .take(queries.shape.len() - 2)
-            .product::<Expression>()
-            .max(1);
-        let dim = *queries.dims().last().unwrap();
-        let keys = keys.reshape((n_batches, s1, dim));
-        let values = values.reshape((n_batches, s1, dim));
-        let queries = queries.reshape((n_batches, s2, dim));
-        let values = self
-            .w_v
-            .forward(values)
-            .reshape((n_batches, s1, self.heads, self.k_dim / self.heads))
-            .permute((0, 2, 1, 3));
-        let keys = self
-            .w_k
-            .forward(keys)
-            .reshape((n_batches, s1, self.heads, self.k_dim / self.heads))
-            .permute((0, 2, 3, 1));
-        let queries = self
-            .w_q
-            .forward(queries)
-            .reshape((n_batches, s2, self.heads, self.k_dim / self.heads))
-            .permute((0, 2, 1, 3));
+        self.forward_masked(keys, queries, values, None)
+    }
+}
 
-        let weights = queries
-            .matmul(keys)
-            .mul((1.0 / ((self.k_dim / self.heads) as f64).sqrt()) as f32)
-            .softmax(3);
+// Batched different key-query-value with a mask
+impl Module<(GraphTensor, GraphTensor, GraphTensor, GraphTensor)> for MultiHeadSelfAttention {
+    type Output = GraphTensor;
 
-        let tokens = weights
-            .matmul(values)
-            .permute((0, 2, 1, 3))
-            .reshape((n_batches, s2, self.v_dim));
-        self.w_o.forward(tokens).reshape(orig_query_shape) // batch_dims, s2, dim
+    fn forward(
+        &self,
+        (keys, queries, values, mask): (
+            GraphTensor, // batch, s1, dim
+            GraphTensor, // batch, s2, dim
+            GraphTensor, // batch, s1, dim
+            GraphTensor, // batch, s1 or batch, s2, s1
+        ),
+    ) -> Self::Output {
+        self.forward_masked(keys, queries, values, Some(mask))
+    }
+}
+
+// Self attention over new tokens and the tokens in a KV cache
+impl Module<(GraphTensor, &KVCacheLayer)> for MultiHeadSelfAttention {
+    type Output = GraphTensor;
+
+    fn forward(&self, (input, cache): (GraphTensor, &KVCacheLayer)) -> Self::Output {
+        // Input: batch, seq, dim
+        let (keys, values) = cache.forward(self.project_kv(input, input));
+        self.w_o.forward(self.attend(input, keys, values, None))
+    }
+}
+
+// Cross attention to precomputed key and value heads from project_kv
+impl Module<(GraphTensor, (GraphTensor, GraphTensor))> for MultiHeadSelfAttention {
+    type Output = GraphTensor;
+
+    fn forward(
+        &self,
+        (queries, (keys, values)): (
+            GraphTensor,                // batch, s2, dim
+            (GraphTensor, GraphTensor), // batch, kv_heads, s1, head_dim
+        ),
+    ) -> Self::Output {
+        self.w_o.forward(self.attend(queries, keys, values, None))
     }
 }
 
@@ -109,10 +283,12 @@ mod tests {
     use dfdx::prelude::{Module as DfdxModule, *};
     use luminal::{
         prelude::{Module, *},
-        tests::assert_close,
+        tests::{assert_close, random_vec_rng},
     };
+    use rand::{rngs::StdRng, SeedableRng};
 
-    use super::MultiHeadSelfAttention;
+    use super::{alibi_slopes, MultiHeadSelfAttention};
+    use crate::KVCache;
     #[test]
     fn test_self_attention() {
         let mut cx = Graph::new();
@@ -194,4 +370,185 @@ mod tests {
 
         assert_close(&b.data(), &d_b.as_vec());
     }
+
+    const DIM: usize = 8;
+    const HEADS: usize = 4;
+    const HEAD_DIM: usize = DIM / HEADS;
+
+    /// x @ w for row-major x (rows, inp) and w (inp, out)
+    fn matmul(x: &[f32], w: &[f32], inp: usize) -> Vec<f32> {
+        let out = w.len() / inp;
+        x.chunks(inp)
+            .flat_map(|row| (0..out).map(move |o| (0..inp).map(|i| row[i] * w[i * out + o]).sum()))
+            .collect()
+    }
+
+    /// Attention of queries (batch, s2, DIM) to keys and values (batch, s1, DIM), where
+    /// `allowed(batch, query, key)` says whether a query can see a key
+    fn reference_attention(
+        weights: &[Vec<f32>],
+        queries: &[f32],
+        kv: &[f32],
+        (batch, kv_heads): (usize, usize),
+        allowed: impl Fn(usize, usize, usize) -> bool,
+        alibi: bool,
+    ) -> Vec<f32> {
+        let (s2, s1) = (queries.len() / batch / DIM, kv.len() / batch / DIM);
+        let q = matmul(queries, &weights[0], DIM);
+        let k = matmul(kv, &weights[1], DIM);
+        let v = matmul(kv, &weights[2], DIM);
+        let kv_dim = kv_heads * HEAD_DIM;
+        let mut out = vec![0.; batch * s2 * DIM];
+        for (b, h, i) in
+            (0..batch).flat_map(|b| (0..HEADS).flat_map(move |h| (0..s2).map(move |i| (b, h, i))))
+        {
+            let kh = h / (HEADS / kv_heads);
+            let logits = (0..s1)
+                .map(|j| {
+                    let mut logit = (0..HEAD_DIM)
+                        .map(|d| {
+                            q[(b * s2 + i) * DIM + h * HEAD_DIM + d]
+                                * k[(b * s1 + j) * kv_dim + kh * HEAD_DIM + d]
+                        })
+                        .sum::<f32>()
+                        / (HEAD_DIM as f32).sqrt();
+                    if alibi {
+                        let slope = 2_f32.powf(-8. * (h + 1) as f32 / HEADS as f32);
+                        logit -= slope * (j as f32 - (i + s1 - s2) as f32).abs();
+                    }
+                    if !allowed(b, i, j) {
+                        logit -= f16::MAX.to_f32();
+                    }
+                    logit
+                })
+                .collect::<Vec<_>>();
+            let max = logits.iter().copied().fold(f32::MIN, f32::max);
+            let exps = logits.iter().map(|l| (l - max).exp()).collect::<Vec<_>>();
+            let total = exps.iter().sum::<f32>();
+            for (j, e) in exps.iter().enumerate() {
+                for d in 0..HEAD_DIM {
+                    out[(b * s2 + i) * DIM + h * HEAD_DIM + d] +=
+                        e / total * v[(b * s1 + j) * kv_dim + kh * HEAD_DIM + d];
+                }
+            }
+        }
+        matmul(&out, &weights[3], DIM)
+    }
+
+    fn set_weights(model: &MultiHeadSelfAttention, rng: &mut StdRng) -> Vec<Vec<f32>> {
+        [&model.w_q, &model.w_k, &model.w_v, &model.w_o]
+            .map(|l| {
+                let data = random_vec_rng(l.weight.shape.n_elements().to_usize().unwrap(), rng);
+                l.weight.set(data.clone());
+                data
+            })
+            .to_vec()
+    }
+
+    #[test]
+    fn test_alibi_slopes() {
+        let pow2 = |e: i32| 2_f32.powi(e);
+        assert_eq!(
+            alibi_slopes(4),
+            vec![pow2(-2), pow2(-4), pow2(-6), pow2(-8)]
+        );
+        assert_eq!(
+            alibi_slopes(6),
+            vec![pow2(-2), pow2(-4), pow2(-6), pow2(-8), pow2(-1), pow2(-3)]
+        );
+    }
+
+    #[test]
+    fn test_attention_masks() {
+        let mut cx = Graph::new();
+        let mut rng = StdRng::seed_from_u64(0);
+        // Grouped-query causal self attention with ALiBi and a padding mask
+        let gqa = MultiHeadSelfAttention::new_grouped(DIM, DIM, DIM, HEADS, 2, &mut cx)
+            .with_causal(true)
+            .with_alibi(true);
+        let gqa_weights = set_weights(&gqa, &mut rng);
+        let input_data = random_vec_rng(2 * 5 * DIM, &mut rng);
+        let padding_data = vec![1., 1., 1., 1., 1., 1., 1., 1., 0., 0.];
+        let input = cx.tensor((2, 5, DIM)).set(input_data.clone());
+        let padding = cx.tensor((2, 5)).set(padding_data.clone());
+        let gqa_out = gqa.forward((input, input, input, padding)).retrieve();
+
+        // Multi-query cross attention with a full mask
+        let mqa = MultiHeadSelfAttention::new_grouped(DIM, DIM, DIM, HEADS, 1, &mut cx);
+        let mqa_weights = set_weights(&mqa, &mut rng);
+        let query_data = random_vec_rng(2 * 3 * DIM, &mut rng);
+        let mask_data = (0..2 * 3 * 5)
+            .map(|i| ((i % 7) % 3 != 0) as i32 as f32)
+            .collect::<Vec<_>>();
+        let queries = cx.tensor((2, 3, DIM)).set(query_data.clone());
+        let mask = cx.tensor((2, 3, 5)).set(mask_data.clone());
+        let mqa_out = mqa.forward((input, queries, input, mask)).retrieve();
+        cx.execute();
+
+        let allowed = |b, i, j| j <= i && padding_data[b * 5 + j] == 1.;
+        let expected = reference_attention(
+            &gqa_weights,
+            &input_data,
+            &input_data,
+            (2, 2),
+            allowed,
+            true,
+        );
+        assert_close(&gqa_out.data(), &expected);
+        let allowed = |b, i, j| mask_data[(b * 3 + i) * 5 + j] == 1.;
+        let expected = reference_attention(
+            &mqa_weights,
+            &query_data,
+            &input_data,
+            (2, 1),
+            allowed,
+            false,
+        );
+        assert_close(&mqa_out.data(), &expected);
+    }
+
+    #[test]
+    fn test_attention_kv_cache() {
+        let mut cx = Graph::new();
+        let mut rng = StdRng::seed_from_u64(1);
+        let model = MultiHeadSelfAttention::new_grouped(DIM, DIM, DIM, HEADS, 2, &mut cx)
+            .with_causal(true)
+            .with_alibi(true);
+        let weights = set_weights(&model, &mut rng);
+        let mut cache = KVCache::new(1, 1, 2, HEAD_DIM, 'p', &mut cx);
+        let input = cx.tensor((1, 's', DIM));
+        let output = model.forward((input, &cache[0])).retrieve();
+
+        // Cross attention to cached encoder keys and values matches attending to the encoder outputs
+        let encoded_data = random_vec_rng(4 * DIM, &mut rng);
+        let encoded = cx.tensor((1, 4, DIM)).set(encoded_data.clone());
+        let (keys, values) = model.project_kv(encoded, encoded);
+        let cross = model.with_causal(false);
+        let cached_cross = cross.forward((input, (keys, values))).retrieve();
+        let direct_cross = cross.forward((encoded, input, encoded)).retrieve();
+
+        // Feeding tokens in chunks through the cache matches attending to all of them at once
+        let input_data = random_vec_rng(5 * DIM, &mut rng);
+        let expected = reference_attention(
+            &weights,
+            &input_data,
+            &input_data,
+            (1, 2),
+            |_, i, j| j <= i,
+            true,
+        );
+        for (start, end) in [(0, 3), (3, 5)] {
+            input.set_dyn(
+                input_data[start * DIM..end * DIM].to_vec(),
+                (1, end - start, DIM),
+            );
+            cx.execute();
+            assert_close(&output.data(), &expected[start * DIM..end * DIM]);
+            assert_close(&cached_cross.data(), &direct_cross.data());
+            cache.swap();
+            for t in [output, cached_cross, direct_cross] {
+                t.drop();
+            }
+        }
+    }
 }
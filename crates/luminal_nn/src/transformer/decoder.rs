@@ -1,4 +1,4 @@
-use crate::{Linear, ReLU};
+use crate::{Dropout, Linear, ReLU, Train};
 use luminal::prelude::*;
 
 use super::attention::MultiHeadSelfAttention;
@@ -18,6 +18,14 @@ impl TransformerDecoder {
     }
 }
 
+impl Train for TransformerDecoder {
+    fn train(&mut self, training: bool) {
+        for layer in &mut self.layers {
+            layer.train(training);
+        }
+    }
+}
+
 impl SerializeModule for TransformerDecoder {
     fn serialize(&self, s: &mut Serializer) {
         for (i, l) in self.layers.iter().enumerate() {
@@ -42,6 +50,8 @@ pub struct TransformerDecoderBlock {
     pub self_attention: MultiHeadSelfAttention,
     pub cross_attention: MultiHeadSelfAttention,
     pub ff: (Linear, ReLU, Linear),
+    /// Dropout on the self attention, cross attention and feed forward outputs, with p = 0.1
+    pub dropout: (Dropout, Dropout, Dropout),
 }
 
 impl TransformerDecoderBlock {
@@ -54,10 +64,19 @@ impl TransformerDecoderBlock {
                 ReLU,
                 Linear::new(ff, dim, false, cx),
             ),
+            dropout: (Dropout::new(0.1), Dropout::new(0.1), Dropout::new(0.1)),
         }
     }
 }
 
+impl Train for TransformerDecoderBlock {
+    fn train(&mut self, training: bool) {
+        self.dropout.0.train(training);
+        self.dropout.1.train(training);
+        self.dropout.2.train(training);
+    }
+}
+
 impl SerializeModule for TransformerDecoderBlock {
     fn serialize(&self, s: &mut Serializer) {
         s.module("self_attn", &self.self_attention);
@@ -85,11 +104,14 @@ impl Module<(GraphTensor, GraphTensor)> for TransformerDecoderBlock {
         let inp = input.reshape((n_batches, seq1, dim));
         let fe = from_enc.reshape((n_batches, seq2, dim));
         // Batched forward pass
-        let y = self.self_attention.forward(inp);
+        let y = self.dropout.0.forward(self.self_attention.forward(inp));
         let x = (y + inp).layer_norm(2, 1e-5);
-        let y = self.cross_attention.forward((fe, x, fe));
+        let y = self
+            .dropout
+            .1
+            .forward(self.cross_attention.forward((fe, x, fe)));
         let x = (y + x).layer_norm(2, 1e-5);
-        let y = self.ff.forward(x);
+        let y = self.dropout.2.forward(self.ff.forward(x));
         (y + x).layer_norm(2, 1e-5).reshape(input.shape)
     }
 }
@@ -1,4 +1,4 @@
-use crate::{Linear, ReLU};
+use crate::{Dropout, Linear, ReLU, Train};
 use luminal::prelude::*;
 
 use super::attention::MultiHeadSelfAttention;
@@ -18,6 +18,14 @@ impl TransformerEncoder {
     }
 }
 
+impl Train for TransformerEncoder {
+    fn train(&mut self, training: bool) {
+        for layer in &mut self.layers {
+            layer.train(training);
+        }
+    }
+}
+
 impl SerializeModule for TransformerEncoder {
     fn serialize(&self, s: &mut Serializer) {
         for (i, l) in self.layers.iter().enumerate() {
@@ -41,6 +49,8 @@ impl Module<GraphTensor> for TransformerEncoder {
 pub struct TransformerEncoderBlock {
     pub attention: MultiHeadSelfAttention,
     pub ff: (Linear, ReLU, Linear),
+    /// Dropout on the attention and feed forward outputs, with p = 0.1
+    pub dropout: (Dropout, Dropout),
 }
 
 impl TransformerEncoderBlock {
@@ -52,10 +62,18 @@ impl TransformerEncoderBlock {
                 ReLU,
                 Linear::new(ff, dim, false, cx),
             ),
+            dropout: (Dropout::new(0.1), Dropout::new(0.1)),
         }
     }
 }
 
+impl Train for TransformerEncoderBlock {
+    fn train(&mut self, training: bool) {
+        self.dropout.0.train(training);
+        self.dropout.1.train(training);
+    }
+}
+
 impl SerializeModule for TransformerEncoderBlock {
     fn serialize(&self, s: &mut Serializer) {
         s.module("self_attn", &self.attention);
@@ -79,9 +97,9 @@ impl Module<GraphTensor> for TransformerEncoderBlock {
         let sequence = input.dims()[input.shape.len() - 2];
         let dim = input.dims()[input.shape.len() - 1];
         let x = input.reshape((n_batches, sequence, dim));
-        let x = x + self.attention.forward(x);
+        let x = x + self.dropout.0.forward(self.attention.forward(x));
         let x = x.layer_norm(2, 1e-5);
-        let x = x + self.ff.forward(x);
+        let x = x + self.dropout.1.forward(self.ff.forward(x));
         x.layer_norm(2, 1e-5).reshape(input.dims())
     }
 }
@@ -96,10 +114,12 @@ mod tests {
 
     use luminal::{
         prelude::{Module, *},
-        tests::assert_close,
+        tests::{assert_close, random_vec_rng},
     };
+    use rand::{rngs::StdRng, SeedableRng};
 
     use super::TransformerEncoderBlock;
+    use crate::Train;
     #[test]
     fn test_transformer_encoder_block() {
         let mut cx = Graph::new();
@@ -204,4 +224,33 @@ mod tests {
 
         assert_close(&b.data(), &d_b.as_vec());
     }
+
+    #[test]
+    fn test_transformer_encoder_block_training() {
+        let mut cx = Graph::new();
+        let mut model = TransformerEncoderBlock::new(4, 8, 2, &mut cx);
+        let mut rng = StdRng::seed_from_u64(0);
+        let attention = &model.attention;
+        for linear in [
+            &attention.w_q,
+            &attention.w_k,
+            &attention.w_v,
+            &attention.w_o,
+        ] {
+            linear.weight.set(random_vec_rng(16, &mut rng));
+        }
+        model.ff.0.weight.set(random_vec_rng(32, &mut rng));
+        model.ff.2.weight.set(random_vec_rng(32, &mut rng));
+        let input = cx.tensor((3, 4)).set(random_vec_rng(12, &mut rng));
+
+        let eval = model.forward(input).retrieve();
+        model.train(true);
+        let train = model.forward(input).retrieve();
+        model.train(false);
+        let eval_again = model.forward(input).retrieve();
+        cx.execute();
+
+        assert_ne!(train.data(), eval.data());
+        assert_eq!(eval_again.data(), eval.data());
+    }
 }
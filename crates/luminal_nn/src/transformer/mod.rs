@@ -1,5 +1,7 @@
 use luminal::prelude::*;
 
+use crate::Train;
+
 mod attention;
 pub use attention::*;
 mod decoder;
@@ -29,6 +31,13 @@ impl Transformer {
     }
 }
 
+impl Train for Transformer {
+    fn train(&mut self, training: bool) {
+        self.encoder.train(training);
+        self.decoder.train(training);
+    }
+}
+
 impl SerializeModule for Transformer {
     fn serialize(&self, s: &mut Serializer) {
         s.module("encoder", &self.encoder);
@@ -4,6 +4,7 @@
 #![allow(unused)]
 
 use byteorder::{LittleEndian, ReadBytesExt};
+use luminal_nn::llm::GgufMetadata;
 use std::collections::HashMap;
 
 pub const DEFAULT_ALIGNMENT: u64 = 32;
@@ -252,7 +253,7 @@ impl Content {
             Some(Value::I32(v)) if *v >= 0 => *v as u64,
             _ => DEFAULT_ALIGNMENT,
         };
-        let tensor_data_offset = (position + alignment - 1) / alignment * alignment;
+        let tensor_data_offset = position.div_ceil(alignment) * alignment;
         Ok(Self {
             magic,
             metadata,
@@ -262,6 +263,48 @@ impl Content {
     }
 }
 
+impl GgufMetadata for Content {
+    fn get_int(&self, key: &str) -> Option<u64> {
+        match self.metadata.get(key)? {
+            Value::U8(v) => Some(*v as u64),
+            Value::U16(v) => Some(*v as u64),
+            Value::U32(v) => Some(*v as u64),
+            Value::U64(v) => Some(*v),
+            Value::I8(v) => u64::try_from(*v).ok(),
+            Value::I16(v) => u64::try_from(*v).ok(),
+            Value::I32(v) => u64::try_from(*v).ok(),
+            Value::I64(v) => u64::try_from(*v).ok(),
+            _ => None,
+        }
+    }
+
+    fn get_float(&self, key: &str) -> Option<f32> {
+        match self.metadata.get(key)? {
+            Value::F32(v) => Some(*v),
+            Value::F64(v) => Some(*v as f32),
+            _ => None,
+        }
+    }
+
+    fn get_str(&self, key: &str) -> Option<&str> {
+        match self.metadata.get(key)? {
+            Value::String(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    fn get_array_len(&self, key: &str) -> Option<usize> {
+        match self.metadata.get(key)? {
+            Value::Array(a) => Some(a.len()),
+            _ => None,
+        }
+    }
+
+    fn has_tensor(&self, name: &str) -> bool {
+        self.tensor_infos.contains_key(name)
+    }
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub enum GgmlDType {
     F32,
@@ -1,13 +1,37 @@
+use std::{convert::Infallible, sync::Arc};
+
+use async_stream::stream;
+use axum::response::sse::Event;
 use chrono::Utc;
+use futures_core::Stream;
 use serde::{Deserialize, Serialize};
 use uuid::Uuid;
 
-// src/chat.rs
-use crate::llama::setup::Model; // Import the Model struct
+use crate::{
+    error::ApiError,
+    generation::{check_model, tokenize, Generation, TextEvent, Usage},
+    AppState,
+};
 
 #[derive(Deserialize)]
 pub struct ChatRequest {
+    #[serde(default)]
+    pub model: Option<String>,
     pub messages: Vec<Message>,
+    /// Maximum number of tokens to generate
+    #[serde(default)]
+    pub max_tokens: Option<usize>,
+    #[serde(default)]
+    pub stream: bool,
+    #[serde(default)]
+    pub stream_options: StreamOptions,
+}
+
+#[derive(Deserialize, Default)]
+pub struct StreamOptions {
+    /// Send a final chunk with the token usage of the request
+    #[serde(default)]
+    pub include_usage: bool,
 }
 
 #[derive(Deserialize, Serialize)]
@@ -16,7 +40,7 @@ pub struct Message {
     pub content: String,
 }
 
-#[derive(Deserialize, Serialize, PartialEq, Eq, Debug)]
+#[derive(Deserialize, Serialize, PartialEq, Eq, Debug, Clone, Copy)]
 pub enum Role {
     #[serde(rename = "system")]
     System,
@@ -44,10 +68,29 @@ pub struct Choice {
 }
 
 #[derive(Serialize)]
-pub struct Usage {
-    pub prompt_tokens: usize,
-    pub completion_tokens: usize,
-    pub total_tokens: usize,
+pub struct ChatChunk {
+    pub id: String,
+    pub object: String,
+    pub created: i64,
+    pub model: String,
+    pub choices: Vec<ChunkChoice>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub usage: Option<Usage>,
+}
+
+#[derive(Serialize)]
+pub struct ChunkChoice {
+    pub index: usize,
+    pub delta: Delta,
+    pub finish_reason: Option<String>,
+}
+
+#[derive(Serialize, Default)]
+pub struct Delta {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub role: Option<Role>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub content: Option<String>,
 }
 
 pub fn apply_chat_template(messages: Vec<Message>) -> String {
@@ -69,54 +112,121 @@ pub fn apply_chat_template(messages: Vec<Message>) -> String {
     output
 }
 
+/// Validate the chat and queue it for generation
+fn start_generation(state: &AppState, request: ChatRequest) -> Result<Generation, ApiError> {
+    check_model(state, request.model.as_deref())?;
+    if request.messages.is_empty() {
+        return Err(ApiError::invalid(
+            "messages",
+            "At least one message is required",
+        ));
+    }
+    let mut prompt = apply_chat_template(request.messages);
+    prompt += "<|start_header_id|>assistant<|end_header_id|>\n";
+    println!("Prompt: {:?}", prompt);
+    let prompt_tokens = tokenize(&state.scheduler, &prompt)?;
+    Generation::start(
+        &state.scheduler,
+        prompt_tokens,
+        request.max_tokens,
+        "messages",
+    )
+}
+
 /// Respond to chat request
-pub async fn respond_chat_request(model: &mut Model, request: ChatRequest) -> ChatResponse {
+pub async fn respond_chat_request(
+    state: &AppState,
+    request: ChatRequest,
+) -> Result<ChatResponse, ApiError> {
     let created = Utc::now().timestamp();
     let raw_uuid = Uuid::new_v4();
     let id = format!("chatcmpl-{}", raw_uuid);
 
-    let mut prompt = apply_chat_template(request.messages);
-    prompt += "<|start_header_id|>assistant<|end_header_id|>\n";
-    // let prompt = "<|begin_of_text|>Here is an implementation of merge sort:
-    //
-    // ```python"
-    // .to_string();
-    let prompt_tokens = model.tokenizer.encode(prompt.clone(), false).unwrap();
-    let prompt_tokens = prompt_tokens.get_ids();
-    let prompt_tokens = prompt_tokens.len();
-    println!("Prompt: {:?}", prompt);
+    let completion = start_generation(state, request)?
+        .complete(&state.scheduler)
+        .await;
 
-    // Generate
-    let mut completion = vec![];
-    model.generate(&prompt, |token| {
-        const EOS_TOKEN: u32 = 128009;
-        if token != EOS_TOKEN {
-            completion.push(token);
-        }
-        true
-    });
-    // For now, just clear the cache each time
-    model.clear_cache();
-    let completion_str = model.tokenizer.decode(&completion, false).unwrap();
-    let completion_tokens = completion.len();
-
-    ChatResponse {
+    Ok(ChatResponse {
         id,
         created,
         object: "chat.completion".to_string(),
-        model: "meta-llama/Meta-Llama-3-70B-Instruct".to_string(),
+        model: state.model_name.clone(),
         choices: vec![Choice {
             index: 0,
             message: Message {
                 role: Role::Assistant,
-                content: completion_str,
+                content: completion.text,
             },
-            finish_reason: "stop".to_string(),
+            finish_reason: completion.finish_reason,
         }],
-        usage: Usage {
-            total_tokens: prompt_tokens + completion_tokens,
-            prompt_tokens,
-            completion_tokens,
-        },
-    }
+        usage: completion.usage,
+    })
+}
+
+/// Respond to chat request with a stream of server-sent completion chunks, one per decoded piece
+/// of text. Dropping the stream (when the client disconnects) cancels generation.
+pub fn stream_chat_request(
+    state: Arc<AppState>,
+    request: ChatRequest,
+) -> Result<impl Stream<Item = Result<Event, Infallible>>, ApiError> {
+    let created = Utc::now().timestamp();
+    let id = format!("chatcmpl-{}", Uuid::new_v4());
+    let include_usage = request.stream_options.include_usage;
+    let events = start_generation(&state, request)?.stream(state.scheduler.clone());
+
+    let model = state.model_name.clone();
+    let chunk = move |delta: Delta, finish_reason: Option<String>, usage: Option<Usage>| {
+        let chunk = ChatChunk {
+            id: id.clone(),
+            object: "chat.completion.chunk".to_string(),
+            created,
+            model: model.clone(),
+            choices: if usage.is_some() {
+                vec![]
+            } else {
+                vec![ChunkChoice {
+                    index: 0,
+                    delta,
+                    finish_reason,
+                }]
+            },
+            usage,
+        };
+        Ok(Event::default().data(serde_json::to_string(&chunk).unwrap()))
+    };
+
+    Ok(stream! {
+        yield chunk(
+            Delta {
+                role: Some(Role::Assistant),
+                content: Some(String::new()),
+            },
+            None,
+            None,
+        );
+        for await event in events {
+            match event {
+                TextEvent::Text(text) => {
+                    yield chunk(
+                        Delta {
+                            content: Some(text),
+                            ..Default::default()
+                        },
+                        None,
+                        None,
+                    );
+                }
+                TextEvent::Finished {
+                    finish_reason,
+                    usage,
+                } => {
+                    yield chunk(Delta::default(), Some(finish_reason), None);
+                    if include_usage {
+                        yield chunk(Delta::default(), None, Some(usage));
+                    }
+                }
+            }
+        }
+        yield Ok(Event::default().data("[DONE]"));
+    })
 }
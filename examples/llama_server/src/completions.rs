@@ -0,0 +1,136 @@
+use std::{convert::Infallible, sync::Arc};
+
+use async_stream::stream;
+use axum::response::sse::Event;
+use chrono::Utc;
+use futures_core::Stream;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::{
+    chat::StreamOptions,
+    error::ApiError,
+    generation::{check_model, tokenize, Generation, TextEvent, Usage},
+    AppState,
+};
+
+#[derive(Deserialize)]
+pub struct CompletionRequest {
+    #[serde(default)]
+    pub model: Option<String>,
+    pub prompt: String,
+    /// Maximum number of tokens to generate
+    #[serde(default)]
+    pub max_tokens: Option<usize>,
+    #[serde(default)]
+    pub stream: bool,
+    #[serde(default)]
+    pub stream_options: StreamOptions,
+}
+
+#[derive(Serialize)]
+pub struct CompletionResponse {
+    pub id: String,
+    pub object: String,
+    pub created: i64,
+    pub model: String,
+    pub choices: Vec<CompletionChoice>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub usage: Option<Usage>,
+}
+
+#[derive(Serialize)]
+pub struct CompletionChoice {
+    pub index: usize,
+    pub text: String,
+    pub finish_reason: Option<String>,
+}
+
+/// Validate the prompt and queue it for generation. The prompt is used as-is, without a chat
+/// template.
+fn start_generation(state: &AppState, request: &CompletionRequest) -> Result<Generation, ApiError> {
+    check_model(state, request.model.as_deref())?;
+    let prompt_tokens = tokenize(&state.scheduler, &request.prompt)?;
+    Generation::start(
+        &state.scheduler,
+        prompt_tokens,
+        request.max_tokens,
+        "prompt",
+    )
+}
+
+pub async fn respond_completion_request(
+    state: &AppState,
+    request: CompletionRequest,
+) -> Result<CompletionResponse, ApiError> {
+    let created = Utc::now().timestamp();
+    let id = format!("cmpl-{}", Uuid::new_v4());
+
+    let completion = start_generation(state, &request)?
+        .complete(&state.scheduler)
+        .await;
+
+    Ok(CompletionResponse {
+        id,
+        object: "text_completion".to_string(),
+        created,
+        model: state.model_name.clone(),
+        choices: vec![CompletionChoice {
+            index: 0,
+            text: completion.text,
+            finish_reason: Some(completion.finish_reason),
+        }],
+        usage: Some(completion.usage),
+    })
+}
+
+/// Respond to a completion request with a stream of server-sent chunks, one per decoded piece of
+/// text. Dropping the stream (when the client disconnects) cancels generation.
+pub fn stream_completion_request(
+    state: Arc<AppState>,
+    request: CompletionRequest,
+) -> Result<impl Stream<Item = Result<Event, Infallible>>, ApiError> {
+    let created = Utc::now().timestamp();
+    let id = format!("cmpl-{}", Uuid::new_v4());
+    let include_usage = request.stream_options.include_usage;
+    let events = start_generation(&state, &request)?.stream(state.scheduler.clone());
+
+    let model = state.model_name.clone();
+    let chunk = move |text: String, finish_reason: Option<String>, usage: Option<Usage>| {
+        let chunk = CompletionResponse {
+            id: id.clone(),
+            object: "text_completion".to_string(),
+            created,
+            model: model.clone(),
+            choices: if usage.is_some() {
+                vec![]
+            } else {
+                vec![CompletionChoice {
+                    index: 0,
+                    text,
+                    finish_reason,
+                }]
+            },
+            usage,
+        };
+        Ok(Event::default().data(serde_json::to_string(&chunk).unwrap()))
+    };
+
+    Ok(stream! {
+        for await event in events {
+            match event {
+                TextEvent::Text(text) => yield chunk(text, None, None),
+                TextEvent::Finished {
+                    finish_reason,
+                    usage,
+                } => {
+                    yield chunk(String::new(), Some(finish_reason), None);
+                    if include_usage {
+                        yield chunk(String::new(), None, Some(usage));
+                    }
+                }
+            }
+        }
+        yield Ok(Event::default().data("[DONE]"));
+    })
+}
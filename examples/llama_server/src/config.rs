@@ -0,0 +1,142 @@
+use std::{fs, path::PathBuf};
+
+use clap::Parser;
+use serde::Deserialize;
+
+use crate::llama::paged::BLOCK_SIZE;
+
+/// Serve a llama model over an OpenAI-compatible API
+#[derive(Debug, Parser)]
+#[command(author, version, about, long_about = None)]
+pub struct CLIArgs {
+    /// JSON config file. Flags passed on the command line override its values.
+    #[clap(short, long)]
+    config: Option<PathBuf>,
+
+    /// GGUF model weights
+    #[clap(short, long)]
+    model: Option<PathBuf>,
+
+    /// Tokenizer definition
+    #[clap(short, long)]
+    tokenizer: Option<PathBuf>,
+
+    /// Model name reported by the API. Defaults to the model file name.
+    #[clap(long)]
+    model_name: Option<String>,
+
+    /// Address to listen on
+    #[clap(short, long)]
+    bind: Option<String>,
+
+    /// Maximum number of tokens in a single sequence, prompt included
+    #[clap(long)]
+    context_length: Option<usize>,
+
+    /// Total number of tokens the KV cache holds across all running sequences. Defaults to the
+    /// context length. Every step rewrites the whole cache, so larger caches slow down decoding.
+    #[clap(long)]
+    cache_tokens: Option<usize>,
+
+    /// Number of tokens of finished requests kept in the KV cache so later requests sharing a
+    /// prefix can reuse them. Defaults to the whole cache.
+    #[clap(long)]
+    prefix_cache_tokens: Option<usize>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub model: PathBuf,
+    pub tokenizer: PathBuf,
+    pub model_name: Option<String>,
+    pub bind: String,
+    pub context_length: usize,
+    pub cache_tokens: Option<usize>,
+    pub prefix_cache_tokens: Option<usize>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            model: "./setup/llama3-8b.gguf".into(),
+            tokenizer: "./setup/tokenizer.json".into(),
+            model_name: None,
+            bind: "127.0.0.1:3000".to_string(),
+            context_length: 8192,
+            cache_tokens: None,
+            prefix_cache_tokens: None,
+        }
+    }
+}
+
+impl Config {
+    /// Load the config from the command line and the config file it points to
+    pub fn load() -> Self {
+        let args = CLIArgs::parse();
+        let mut config = match &args.config {
+            Some(path) => {
+                let file = fs::read_to_string(path)
+                    .unwrap_or_else(|e| panic!("Failed to read config {}: {e}", path.display()));
+                serde_json::from_str(&file)
+                    .unwrap_or_else(|e| panic!("Invalid config {}: {e}", path.display()))
+            }
+            None => Config::default(),
+        };
+        if let Some(model) = args.model {
+            config.model = model;
+        }
+        if let Some(tokenizer) = args.tokenizer {
+            config.tokenizer = tokenizer;
+        }
+        if let Some(model_name) = args.model_name {
+            config.model_name = Some(model_name);
+        }
+        if let Some(bind) = args.bind {
+            config.bind = bind;
+        }
+        if let Some(context_length) = args.context_length {
+            config.context_length = context_length;
+        }
+        if let Some(cache_tokens) = args.cache_tokens {
+            config.cache_tokens = Some(cache_tokens);
+        }
+        if let Some(prefix_cache_tokens) = args.prefix_cache_tokens {
+            config.prefix_cache_tokens = Some(prefix_cache_tokens);
+        }
+        assert!(config.context_length > 0, "Context length must be positive");
+        assert!(
+            config.cache_tokens() >= config.context_length,
+            "The KV cache must hold at least one full context"
+        );
+        config
+    }
+
+    /// Name the model is served under
+    pub fn model_name(&self) -> String {
+        self.model_name.clone().unwrap_or_else(|| {
+            self.model
+                .file_stem()
+                .map(|s| s.to_string_lossy().to_string())
+                .unwrap_or_else(|| "llama".to_string())
+        })
+    }
+
+    /// Number of tokens the KV cache holds. Defaults to a single context, the smallest cache any
+    /// sequence fits in, since each step costs time proportional to the cache size.
+    pub fn cache_tokens(&self) -> usize {
+        self.cache_tokens.unwrap_or(self.context_length)
+    }
+
+    /// Number of KV cache blocks needed to hold the configured number of tokens
+    pub fn cache_blocks(&self) -> usize {
+        self.cache_tokens().div_ceil(BLOCK_SIZE)
+    }
+
+    /// Number of unused KV cache blocks kept for prefix reuse
+    pub fn prefix_cache_blocks(&self) -> usize {
+        self.prefix_cache_tokens
+            .map(|t| t / BLOCK_SIZE)
+            .unwrap_or_else(|| self.cache_blocks())
+    }
+}
@@ -0,0 +1,113 @@
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    error::ApiError,
+    generation::{check_model, tokenize},
+    AppState,
+};
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+pub enum EmbeddingInput {
+    Single(String),
+    Batch(Vec<String>),
+}
+
+#[derive(Deserialize)]
+pub struct EmbeddingRequest {
+    #[serde(default)]
+    pub model: Option<String>,
+    pub input: EmbeddingInput,
+    #[serde(default)]
+    pub encoding_format: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct EmbeddingResponse {
+    pub object: String,
+    pub data: Vec<Embedding>,
+    pub model: String,
+    pub usage: EmbeddingUsage,
+}
+
+#[derive(Serialize)]
+pub struct Embedding {
+    pub object: String,
+    pub index: usize,
+    pub embedding: Vec<f32>,
+}
+
+#[derive(Serialize)]
+pub struct EmbeddingUsage {
+    pub prompt_tokens: usize,
+    pub total_tokens: usize,
+}
+
+/// Embed each input as the mean of the model's final hidden states over its tokens
+pub async fn respond_embedding_request(
+    state: &AppState,
+    request: EmbeddingRequest,
+) -> Result<EmbeddingResponse, ApiError> {
+    check_model(state, request.model.as_deref())?;
+    if let Some(format) = request.encoding_format.as_deref().filter(|f| *f != "float") {
+        return Err(ApiError::invalid(
+            "encoding_format",
+            format!("Unsupported encoding format `{format}`, only `float` is supported"),
+        ));
+    }
+    let inputs = match request.input {
+        EmbeddingInput::Single(input) => vec![input],
+        EmbeddingInput::Batch(inputs) => inputs,
+    };
+    if inputs.is_empty() {
+        return Err(ApiError::invalid("input", "At least one input is required"));
+    }
+
+    // Validate every input before queueing any of them
+    let context_length = state.scheduler.context_length;
+    let prompts = inputs
+        .iter()
+        .map(|input| {
+            let tokens = tokenize(&state.scheduler, input)?;
+            if tokens.is_empty() {
+                Err(ApiError::invalid("input", "Inputs must not be empty"))
+            } else if tokens.len() > context_length {
+                Err(ApiError::context_length_exceeded(
+                    "input",
+                    context_length,
+                    tokens.len(),
+                ))
+            } else {
+                Ok(tokens)
+            }
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    let prompt_tokens = prompts.iter().map(|p| p.len()).sum();
+
+    // Queue all inputs at once so they're batched together
+    let receivers = prompts
+        .into_iter()
+        .map(|p| state.scheduler.embed(p))
+        .collect::<Vec<_>>();
+    let mut data = vec![];
+    for (index, receiver) in receivers.into_iter().enumerate() {
+        let embedding = receiver
+            .await
+            .map_err(|_| ApiError::internal("Embedding was dropped by the scheduler"))?;
+        data.push(Embedding {
+            object: "embedding".to_string(),
+            index,
+            embedding,
+        });
+    }
+
+    Ok(EmbeddingResponse {
+        object: "list".to_string(),
+        data,
+        model: state.model_name.clone(),
+        usage: EmbeddingUsage {
+            prompt_tokens,
+            total_tokens: prompt_tokens,
+        },
+    })
+}
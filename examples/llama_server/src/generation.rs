@@ -0,0 +1,190 @@
+use async_stream::stream;
+use futures_core::Stream;
+use serde::Serialize;
+use tokenizers::Tokenizer;
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver};
+
+use crate::{
+    error::ApiError,
+    scheduler::{Scheduler, EOS_TOKEN},
+    AppState,
+};
+
+#[derive(Serialize, Clone, Copy)]
+pub struct Usage {
+    pub prompt_tokens: usize,
+    pub completion_tokens: usize,
+    pub total_tokens: usize,
+}
+
+impl Usage {
+    pub fn new(prompt_tokens: usize, completion_tokens: usize) -> Self {
+        Self {
+            prompt_tokens,
+            completion_tokens,
+            total_tokens: prompt_tokens + completion_tokens,
+        }
+    }
+}
+
+/// Check that a request is for the served model
+pub fn check_model(state: &AppState, model: Option<&str>) -> Result<(), ApiError> {
+    match model {
+        Some(model) if model != state.model_name => Err(ApiError::model_not_found(model)),
+        _ => Ok(()),
+    }
+}
+
+pub fn tokenize(scheduler: &Scheduler, text: &str) -> Result<Vec<u32>, ApiError> {
+    scheduler
+        .tokenizer
+        .encode(text, false)
+        .map(|e| e.get_ids().to_vec())
+        .map_err(|e| ApiError::internal(format!("Failed to tokenize prompt: {e}")))
+}
+
+/// A completion being generated by the scheduler
+pub struct Generation {
+    pub prompt_tokens: usize,
+    receiver: UnboundedReceiver<u32>,
+}
+
+/// The generated text of a finished completion
+pub struct Completion {
+    pub text: String,
+    pub finish_reason: String,
+    pub usage: Usage,
+}
+
+/// A piece of a streamed completion
+pub enum TextEvent {
+    Text(String),
+    Finished { finish_reason: String, usage: Usage },
+}
+
+fn finish_reason(hit_eos: bool) -> String {
+    if hit_eos { "stop" } else { "length" }.to_string()
+}
+
+impl Generation {
+    /// Validate the prompt and completion length against the context window and queue the prompt
+    /// for generation. Without `max_tokens` generation runs until EOS or the end of the context.
+    pub fn start(
+        scheduler: &Scheduler,
+        prompt: Vec<u32>,
+        max_tokens: Option<usize>,
+        prompt_param: &'static str,
+    ) -> Result<Self, ApiError> {
+        let context_length = scheduler.context_length;
+        let prompt_tokens = prompt.len();
+        if prompt_tokens == 0 {
+            return Err(ApiError::invalid(prompt_param, "The prompt is empty"));
+        }
+        let max_tokens = match max_tokens {
+            Some(0) => {
+                return Err(ApiError::invalid(
+                    "max_tokens",
+                    "max_tokens must be at least 1",
+                ))
+            }
+            Some(max_tokens) => max_tokens,
+            None => context_length.saturating_sub(prompt_tokens).max(1),
+        };
+        if prompt_tokens + max_tokens > context_length {
+            return Err(ApiError::context_length_exceeded(
+                prompt_param,
+                context_length,
+                prompt_tokens + max_tokens,
+            ));
+        }
+
+        // Generation stops once the receiver is dropped
+        let (sender, receiver) = unbounded_channel();
+        let mut generated = 0;
+        scheduler.generate(prompt, move |token| {
+            generated += 1;
+            sender.send(token).is_ok() && generated < max_tokens
+        });
+        Ok(Self {
+            prompt_tokens,
+            receiver,
+        })
+    }
+
+    /// Wait for the whole completion
+    pub async fn complete(mut self, scheduler: &Scheduler) -> Completion {
+        let mut completion = vec![];
+        let mut hit_eos = false;
+        while let Some(token) = self.receiver.recv().await {
+            if token == EOS_TOKEN {
+                hit_eos = true;
+            } else {
+                completion.push(token);
+            }
+        }
+        Completion {
+            text: scheduler.tokenizer.decode(&completion, false).unwrap(),
+            finish_reason: finish_reason(hit_eos),
+            usage: Usage::new(self.prompt_tokens, completion.len()),
+        }
+    }
+
+    /// Stream the completion as text as it's generated, ending with a [`TextEvent::Finished`].
+    /// Dropping the stream cancels generation.
+    pub fn stream(mut self, scheduler: Scheduler) -> impl Stream<Item = TextEvent> {
+        stream! {
+            let mut detokenizer = Detokenizer::default();
+            let mut hit_eos = false;
+            while let Some(token) = self.receiver.recv().await {
+                if token == EOS_TOKEN {
+                    hit_eos = true;
+                    continue;
+                }
+                if let Some(text) = detokenizer.push(&scheduler.tokenizer, token) {
+                    yield TextEvent::Text(text);
+                }
+            }
+            yield TextEvent::Finished {
+                finish_reason: finish_reason(hit_eos),
+                usage: Usage::new(self.prompt_tokens, detokenizer.tokens.len()),
+            };
+        }
+    }
+}
+
+/// Decodes generated tokens into text as they come in.
+///
+/// Tokens don't always decode to text on their own, since a character can span several tokens
+/// and some tokenizers merge spaces with the tokens before them. So new tokens are decoded along
+/// with the few before them that were already sent, and only the text past those is returned.
+#[derive(Default)]
+struct Detokenizer {
+    tokens: Vec<u32>,
+    /// Start of the already sent tokens decoded along with new ones
+    prefix: usize,
+    /// Start of the tokens not sent yet
+    read: usize,
+}
+
+impl Detokenizer {
+    /// Add a token, returning any new text once it decodes to complete characters
+    fn push(&mut self, tokenizer: &Tokenizer, token: u32) -> Option<String> {
+        self.tokens.push(token);
+        let sent = tokenizer
+            .decode(&self.tokens[self.prefix..self.read], false)
+            .unwrap();
+        let text = tokenizer
+            .decode(&self.tokens[self.prefix..], false)
+            .unwrap();
+        if text.ends_with('\u{FFFD}') {
+            return None;
+        }
+        let new = text
+            .get(sent.len()..)
+            .filter(|t| !t.is_empty())?
+            .to_string();
+        self.prefix = self.read;
+        self.read = self.tokens.len();
+        Some(new)
+    }
+}
@@ -1,4 +1,6 @@
 pub mod gguf;
 pub mod loader;
 pub mod model;
+pub mod paged;
+pub mod prefix_cache;
 pub mod setup;
@@ -1,5 +1,7 @@
-use luminal::prelude::{binary::F32Pow, *};
-use luminal_nn::{Embedding, LayerNorm, Linear};
+use luminal::prelude::*;
+use luminal_nn::{Embedding, Linear, RMSNorm, RopeLayout, RopeScaling, RotaryEmbedding};
+
+use super::paged::{PagedKVCache, PagedLayerCache};
 
 // Llama3 8B Config
 pub const VOCAB_SIZE: usize = 128256;
@@ -13,8 +15,6 @@ pub const N_ATTENTION_GROUPS: usize = N_HEADS / N_KV_HEADS;
 pub const HEAD_DIM: usize = HIDDEN_DIM / N_HEADS;
 pub const ATTN_PROJ_DIM: usize = HEAD_DIM * N_KV_HEADS;
 
-pub type KVCache = (GraphTensor, GraphTensor);
-
 pub struct Mlp {
     pub gate_proj: Linear, // hidden -> intermediate
     pub down_proj: Linear, // intermediate -> hidden
@@ -49,42 +49,19 @@ impl SerializeModule for Mlp {
     }
 }
 
-fn apply_rotary_embeddings_ggml(input: GraphTensor, prev_seq: Expression) -> GraphTensor {
-    assert_eq!(input.shape.len(), 4); // batch, n_heads, seq, head_dim
-    let (batch, n_heads, seq, head_dim) = input.dims4();
-    // Get freqs
-    let freqs = (input.graph().arange(head_dim / 2) * 2.0) / (head_dim.to_usize().unwrap() as f32);
-    let freqs = 500_000_f32.pow(freqs);
-    let pos = input.graph().arange(seq) + prev_seq;
-    let emb = pos.expand(1, 1).matmul(freqs.expand(0, 1));
-
-    // Split input into evens and odds
-    let split = input.reshape((batch, n_heads, seq, head_dim / 2, 2));
-    let x0 = split.slice((.., .., .., .., ..1));
-    let x1 = split.slice((.., .., .., .., 1..));
-
-    // Apply sin and cos embeddings
-    let x0_out = x0 * emb.cos().expand_to(x0.shape) - x1 * emb.sin().expand_to(x1.shape);
-    let x1_out = x0 * emb.sin().expand_to(x0.shape) + x1 * emb.cos().expand_to(x1.shape);
-
-    // Combine back into output
-    x0_out.concat_along(x1_out, 4).reshape(input.shape)
-}
-
 pub struct SelfAttention {
     pub q_proj: GraphTensor, // Hidden -> hidden
     pub k_proj: GraphTensor, // Proj dim -> hidden
     pub v_proj: GraphTensor, // Proj dim -> hidden
     pub o_proj: GraphTensor, // Hidden -> hidden
+    pub rope: RotaryEmbedding,
 }
 
-impl Module<(GraphTensor, KVCache)> for SelfAttention {
-    type Output = (GraphTensor, KVCache);
-    fn forward(&self, (x, (k_cache, v_cache)): (GraphTensor, KVCache)) -> Self::Output {
-        // x: batch, seq, hidden
-        // cache: batch, kv_heads, prev_seq, head_dim
+impl Module<(GraphTensor, &PagedLayerCache)> for SelfAttention {
+    type Output = GraphTensor;
+    fn forward(&self, (x, cache): (GraphTensor, &PagedLayerCache)) -> Self::Output {
+        // x: batch, seq, hidden (every sequence in the batch packed along seq)
         let (batch, seq, _) = x.dims3();
-        let (_, _, prev_seq, _) = k_cache.dims4();
         // Apply the Projections
         let queries = x
             .matmul(self.q_proj.permute((1, 0)))
@@ -102,12 +79,11 @@ impl Module<(GraphTensor, KVCache)> for SelfAttention {
             .permute((0, 2, 1, 3));
 
         // Rotary embed queries and keys
-        let queries = apply_rotary_embeddings_ggml(queries, prev_seq);
-        let keys = apply_rotary_embeddings_ggml(keys, prev_seq);
+        let queries = self.rope.forward((queries, cache.inputs.positions));
+        let keys = self.rope.forward((keys, cache.inputs.positions));
 
-        // Add KV cache
-        let keys = k_cache.concat_along(keys, 2);
-        let values = v_cache.concat_along(values, 2);
+        // Write to the KV cache and read back each sequence's context
+        let (keys, values) = cache.forward((keys, values));
 
         // Repeat the KV States for Grouped-Query Attention
         let repeated_keys = keys.expand(2, N_ATTENTION_GROUPS);
@@ -119,9 +95,9 @@ impl Module<(GraphTensor, KVCache)> for SelfAttention {
             .matmul(repeated_keys.permute((0, 1, 2, 4, 3)))
             / (HEAD_DIM as f32).sqrt();
 
-        let attention_mask = self.k_proj.graph().triu(seq, 1) * f16::MIN.to_f32();
-        attention_weights += attention_mask
-            .pad(((0, 0), (prev_seq, 0)))
+        attention_weights += cache
+            .inputs
+            .mask
             .expand(0, batch)
             .expand(1, N_KV_HEADS)
             .expand(2, N_ATTENTION_GROUPS);
@@ -134,20 +110,19 @@ impl Module<(GraphTensor, KVCache)> for SelfAttention {
             // Merge heads
             .permute((0, 3, 1, 2, 4))
             .reshape((batch, seq, HIDDEN_DIM));
-        let output = output
-            // Apply output projection
-            .matmul(self.o_proj.permute((1, 0)));
-        (output, (keys.contiguous(), values.contiguous())) // Cache needs to be contiguous for transferring to another graph
+        // Apply output projection
+        output.matmul(self.o_proj.permute((1, 0)))
     }
 }
 
 impl SelfAttention {
-    pub fn new(cx: &mut Graph) -> Self {
+    pub fn new(rope: RotaryEmbedding, cx: &mut Graph) -> Self {
         Self {
             q_proj: cx.named_tensor("Q Proj", (HIDDEN_DIM, HIDDEN_DIM)),
             k_proj: cx.named_tensor("K Proj", (ATTN_PROJ_DIM, HIDDEN_DIM)),
             v_proj: cx.named_tensor("V Proj", (ATTN_PROJ_DIM, HIDDEN_DIM)),
             o_proj: cx.named_tensor("O Proj", (HIDDEN_DIM, HIDDEN_DIM)),
+            rope,
         }
     }
 }
@@ -163,16 +138,16 @@ impl SerializeModule for SelfAttention {
 
 pub struct TransformerBlock {
     pub attention: SelfAttention,
-    pub attention_norm: LayerNorm,
+    pub attention_norm: RMSNorm,
     pub feed_forward: Mlp,
-    pub feed_forward_norm: LayerNorm,
+    pub feed_forward_norm: RMSNorm,
 }
 
-impl Module<(GraphTensor, KVCache)> for TransformerBlock {
-    type Output = (GraphTensor, KVCache);
-    fn forward(&self, (mut x, cache): (GraphTensor, KVCache)) -> Self::Output {
+impl Module<(GraphTensor, &PagedLayerCache)> for TransformerBlock {
+    type Output = GraphTensor;
+    fn forward(&self, (mut x, cache): (GraphTensor, &PagedLayerCache)) -> Self::Output {
         // Attention
-        let (y, cache) = self
+        let y = self
             .attention
             .forward((self.attention_norm.forward(x), cache));
 
@@ -183,17 +158,17 @@ impl Module<(GraphTensor, KVCache)> for TransformerBlock {
         let y = self.feed_forward.forward(self.feed_forward_norm.forward(x));
 
         // Residual
-        (x + y, cache)
+        x + y
     }
 }
 
 impl TransformerBlock {
-    pub fn new(cx: &mut Graph) -> Self {
+    pub fn new(rope: RotaryEmbedding, cx: &mut Graph) -> Self {
         Self {
-            attention: SelfAttention::new(cx),
-            attention_norm: LayerNorm::new(HIDDEN_DIM, true, false, false, 1e-5, cx),
+            attention: SelfAttention::new(rope, cx),
+            attention_norm: RMSNorm::new(HIDDEN_DIM, 1e-5, cx),
             feed_forward: Mlp::new(HIDDEN_DIM, MLP_DIM, cx),
-            feed_forward_norm: LayerNorm::new(HIDDEN_DIM, true, false, false, 1e-5, cx),
+            feed_forward_norm: RMSNorm::new(HIDDEN_DIM, 1e-5, cx),
         }
     }
 }
@@ -213,36 +188,51 @@ pub struct Llama {
     // Transformer layers
     pub layers: Vec<TransformerBlock>,
     // Norm + LM head
-    pub head: (LayerNorm, Linear),
+    pub head: (RMSNorm, Linear),
 }
 
-impl Module<(GraphTensor, &[KVCache])> for Llama {
-    type Output = (GraphTensor, Vec<KVCache>);
-    fn forward(&self, (input, cache): (GraphTensor, &[KVCache])) -> Self::Output {
+impl Module<(GraphTensor, &PagedKVCache)> for Llama {
+    type Output = (GraphTensor, GraphTensor);
+    /// Returns the next token logits for the last token of each sequence in the batch (batch, vocab),
+    /// and the final hidden states mean-pooled over the new tokens of each sequence (batch, hidden)
+    fn forward(&self, (input, cache): (GraphTensor, &PagedKVCache)) -> Self::Output {
         // Embed tokens
         let mut x = self.embedding.forward(input);
 
-        // Run through layers and collect new caches
-        let mut new_caches = vec![];
-        let mut new_cache;
-        for (i, layer) in self.layers.iter().enumerate() {
-            (x, new_cache) = layer.forward((x, cache[i]));
-            new_caches.push(new_cache);
+        // Run through layers, writing to each layer's cache
+        for (layer, layer_cache) in self.layers.iter().zip(cache.layers()) {
+            x = layer.forward((x, layer_cache));
         }
-        // Run through last norm and output projection
-        (self.head.forward(x), new_caches)
+        let (_, seq, _) = x.dims3();
+        let x = self.head.0.forward(x).reshape((seq, HIDDEN_DIM));
+        let embeddings = cache.inputs.pool_weights.matmul(x);
+        // Only the last token of each sequence is needed for sampling
+        let logits = self.head.1.forward(x.gather(cache.inputs.last_tokens));
+        (logits, embeddings)
     }
 }
 
 impl Llama {
-    pub fn new(cx: &mut Graph) -> Self {
+    /// Build the model for sequences of up to `context_length` tokens
+    pub fn new(context_length: usize, cx: &mut Graph) -> Self {
+        let rope = RotaryEmbedding::new(
+            HEAD_DIM,
+            HEAD_DIM,
+            context_length,
+            500_000.,
+            RopeScaling::None,
+            RopeLayout::Interleaved,
+            cx,
+        );
         Self {
             embedding: Embedding::new(VOCAB_SIZE, HIDDEN_DIM, cx),
             head: (
-                LayerNorm::new(HIDDEN_DIM, true, false, false, 1e-5, cx),
+                RMSNorm::new(HIDDEN_DIM, 1e-5, cx),
                 Linear::new_permuted(HIDDEN_DIM, VOCAB_SIZE, false, cx),
             ),
-            layers: (0..NUM_LAYERS).map(|_| TransformerBlock::new(cx)).collect(),
+            layers: (0..NUM_LAYERS)
+                .map(|_| TransformerBlock::new(rope, cx))
+                .collect(),
         }
     }
 }
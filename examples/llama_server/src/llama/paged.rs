@@ -0,0 +1,418 @@
+use std::{cell::Cell, collections::HashMap};
+
+use luminal::prelude::{f16, *};
+
+use super::prefix_cache::PrefixCache;
+
+/// Number of token slots in each cache block
+pub const BLOCK_SIZE: usize = 16;
+
+pub type SeqId = u64;
+
+#[derive(Default)]
+struct SeqBlocks {
+    blocks: Vec<usize>,
+    /// Number of cached tokens
+    len: usize,
+    /// Prefix cache nodes of the leading full blocks
+    path: Vec<usize>,
+}
+
+/// Hands out fixed-size blocks of cache slots to sequences.
+///
+/// Full blocks are shared through a [`PrefixCache`], so sequences starting with the same tokens
+/// (like a common system prompt) reuse the cached keys and values instead of recomputing them.
+/// Shared blocks are never written to, since new tokens always go after the last full block.
+pub struct BlockManager {
+    block_size: usize,
+    n_blocks: usize,
+    free_blocks: Vec<usize>,
+    tables: HashMap<SeqId, SeqBlocks>,
+    prefixes: PrefixCache,
+}
+
+impl BlockManager {
+    /// `prefix_budget` is the number of blocks no running sequence uses that are kept for reuse
+    pub fn new(n_blocks: usize, block_size: usize, prefix_budget: usize) -> Self {
+        Self {
+            block_size,
+            n_blocks,
+            free_blocks: (0..n_blocks).rev().collect(),
+            tables: HashMap::default(),
+            prefixes: PrefixCache::new(prefix_budget),
+        }
+    }
+
+    /// Total number of token slots in the cache
+    pub fn n_slots(&self) -> usize {
+        self.n_blocks * self.block_size
+    }
+
+    /// Number of tokens cached for a sequence
+    pub fn len(&self, seq: SeqId) -> usize {
+        self.tables.get(&seq).map(|t| t.len).unwrap_or_default()
+    }
+
+    /// Number of new blocks needed to append tokens to a sequence
+    pub fn blocks_needed(&self, seq: SeqId, n_tokens: usize) -> usize {
+        let (n_blocks, len) = self
+            .tables
+            .get(&seq)
+            .map(|t| (t.blocks.len(), t.len))
+            .unwrap_or_default();
+        (len + n_tokens).div_ceil(self.block_size) - n_blocks
+    }
+
+    /// Number of blocks that are free or can be evicted from the prefix cache
+    pub fn available(&self) -> usize {
+        self.free_blocks.len() + self.prefixes.n_evictable()
+    }
+
+    /// Check if a sequence could ever fit in the cache
+    pub fn fits(&self, n_tokens: usize) -> bool {
+        n_tokens.div_ceil(self.block_size) <= self.n_blocks
+    }
+
+    /// Reserve slots for new tokens of a sequence, returning the slot of each token
+    pub fn append(&mut self, seq: SeqId, n_tokens: usize) -> Option<Vec<usize>> {
+        let needed = self.blocks_needed(seq, n_tokens);
+        if needed > self.available() {
+            return None;
+        }
+        let new_blocks = (0..needed)
+            .map(|_| {
+                self.free_blocks
+                    .pop()
+                    .or_else(|| self.prefixes.evict())
+                    .unwrap()
+            })
+            .collect::<Vec<_>>();
+        let table = self.tables.entry(seq).or_default();
+        table.blocks.extend(new_blocks);
+        let slots = (table.len..table.len + n_tokens)
+            .map(|p| table.blocks[p / self.block_size] * self.block_size + p % self.block_size)
+            .collect();
+        table.len += n_tokens;
+        Some(slots)
+    }
+
+    /// Start a new sequence from the longest cached prefix of its tokens, returning the number of
+    /// tokens already cached. At least the last token is always left to be run.
+    pub fn share_prefix(&mut self, seq: SeqId, tokens: &[u32]) -> usize {
+        assert!(
+            !self.tables.contains_key(&seq),
+            "Sequence already has cache blocks"
+        );
+        let max_shared = tokens.len().saturating_sub(1) / self.block_size * self.block_size;
+        let (path, blocks): (Vec<_>, Vec<_>) = self
+            .prefixes
+            .acquire(&tokens[..max_shared], self.block_size)
+            .into_iter()
+            .unzip();
+        let len = blocks.len() * self.block_size;
+        if len > 0 {
+            self.tables.insert(seq, SeqBlocks { blocks, len, path });
+        }
+        len
+    }
+
+    /// Make the full blocks of a sequence available for sharing. `tokens` are the sequence's
+    /// cached tokens.
+    pub fn commit(&mut self, seq: SeqId, tokens: &[u32]) {
+        let Some(table) = self.tables.get_mut(&seq) else {
+            return;
+        };
+        assert_eq!(
+            tokens.len(),
+            table.len,
+            "Committed tokens aren't all cached"
+        );
+        for i in table.path.len()..table.len / self.block_size {
+            let chunk = &tokens[i * self.block_size..(i + 1) * self.block_size];
+            let (node, block) =
+                self.prefixes
+                    .insert(table.path.last().copied(), chunk, table.blocks[i]);
+            if block != table.blocks[i] {
+                // Another sequence already cached this prefix, so use its block instead
+                self.free_blocks.push(table.blocks[i]);
+                table.blocks[i] = block;
+            }
+            table.path.push(node);
+        }
+    }
+
+    /// Slots of every cached token of a sequence, in order
+    pub fn slots(&self, seq: SeqId) -> Vec<usize> {
+        let table = &self.tables[&seq];
+        (0..table.len)
+            .map(|p| table.blocks[p / self.block_size] * self.block_size + p % self.block_size)
+            .collect()
+    }
+
+    /// Release all blocks held by a sequence. Committed blocks stay in the prefix cache while
+    /// within budget.
+    pub fn free(&mut self, seq: SeqId) {
+        if let Some(table) = self.tables.remove(&seq) {
+            for &node in &table.path {
+                self.prefixes.release(node);
+            }
+            self.free_blocks
+                .extend(table.blocks.into_iter().skip(table.path.len()));
+            self.free_blocks.extend(self.prefixes.trim());
+        }
+    }
+}
+
+/// Graph inputs describing how the tokens of a batch map onto the paged cache
+#[derive(Clone, Copy)]
+pub struct PagedInputs {
+    /// Position of each new token in its sequence: (tokens)
+    pub positions: GraphTensor,
+    /// Index of the new token written to each cache slot: (slots)
+    pub slot_sources: GraphTensor,
+    /// 1 for cache slots written this step, 0 otherwise: (slots)
+    pub slot_written: GraphTensor,
+    /// Cache slots each attention context entry is read from: (context)
+    pub context_slots: GraphTensor,
+    /// Additive attention mask between new tokens and context entries: (tokens, context)
+    pub mask: GraphTensor,
+    /// Index of the last new token of each sequence: (sequences)
+    pub last_tokens: GraphTensor,
+    /// Weights averaging the new tokens of each sequence: (sequences, tokens)
+    pub pool_weights: GraphTensor,
+}
+
+/// The key and value cache pools of a single attention layer
+pub struct PagedLayerCache {
+    keys: GraphTensor,
+    values: GraphTensor,
+    pub inputs: PagedInputs,
+    updated: Cell<Option<(GraphTensor, GraphTensor)>>,
+}
+
+impl PagedLayerCache {
+    /// Write new rows into their cache slots. There's no scatter op, so this is a masked blend of
+    /// the whole pool, and each step costs time proportional to the pool size rather than the
+    /// number of new tokens.
+    fn write(&self, pool: GraphTensor, new: GraphTensor) -> GraphTensor {
+        let (_, heads, seq, head_dim) = new.dims4();
+        let dim = heads * head_dim;
+        let new = new.permute((0, 2, 1, 3)).reshape((seq, dim));
+        let written = self.inputs.slot_written.expand(1, dim);
+        pool * (-written + 1.0) + new.gather(self.inputs.slot_sources) * written
+    }
+
+    fn read(&self, pool: GraphTensor, heads: usize, head_dim: usize) -> GraphTensor {
+        let context = self.inputs.context_slots.dims1();
+        pool.gather(self.inputs.context_slots)
+            .reshape((context, heads, head_dim))
+            .permute((1, 0, 2))
+            .expand(0, 1)
+    }
+}
+
+impl Module<(GraphTensor, GraphTensor)> for PagedLayerCache {
+    type Output = (GraphTensor, GraphTensor);
+
+    /// Write new keys and values (1, heads, tokens, head_dim) into the pools, returning the
+    /// attention context keys and values (1, heads, context, head_dim)
+    fn forward(&self, (keys, values): (GraphTensor, GraphTensor)) -> Self::Output {
+        let (_, heads, _, head_dim) = keys.dims4();
+        let (heads, head_dim) = (heads.to_usize().unwrap(), head_dim.to_usize().unwrap());
+        let keys = self.write(self.keys, keys).keep();
+        let values = self.write(self.values, values).keep();
+        self.updated.set(Some((keys, values)));
+        (
+            self.read(keys, heads, head_dim),
+            self.read(values, heads, head_dim),
+        )
+    }
+}
+
+/// A batch entry: a sequence and the tokens to run for it this step
+pub struct BatchEntry<'a> {
+    pub seq: SeqId,
+    pub tokens: &'a [u32],
+}
+
+/// A key-value cache split into fixed-size blocks shared between many sequences.
+///
+/// All sequences in a batch are packed along the token dimension of a single graph execution.
+/// Each layer keeps a pool of cache slots which new keys and values are scattered into, and the
+/// attention context of every sequence is gathered back out through its block table.
+///
+/// Writing rewrites every slot of every layer's pool each step, so the pool shouldn't be much
+/// bigger than the tokens the running sequences actually need.
+pub struct PagedKVCache {
+    pub blocks: BlockManager,
+    pub inputs: PagedInputs,
+    layers: Vec<PagedLayerCache>,
+    sources: Option<Vec<NodeIndex>>,
+}
+
+impl PagedKVCache {
+    pub fn new(
+        n_layers: usize,
+        n_blocks: usize,
+        prefix_budget: usize,
+        kv_dim: usize,
+        cx: &mut Graph,
+    ) -> Self {
+        let blocks = BlockManager::new(n_blocks, BLOCK_SIZE, prefix_budget);
+        let n_slots = blocks.n_slots();
+        let inputs = PagedInputs {
+            positions: cx.named_tensor("Positions", 's'),
+            slot_sources: cx.named_tensor("Slot Sources", n_slots),
+            slot_written: cx.named_tensor("Slot Written", n_slots),
+            context_slots: cx.named_tensor("Context Slots", 'c'),
+            mask: cx.named_tensor("Attention Mask", ('s', 'c')),
+            last_tokens: cx.named_tensor("Last Tokens", 'b'),
+            pool_weights: cx.named_tensor("Pool Weights", ('b', 's')),
+        };
+        let layers = (0..n_layers)
+            .map(|_| PagedLayerCache {
+                keys: cx
+                    .named_tensor("Key Cache", (n_slots, kv_dim))
+                    .set_deferred(move || vec![0.; n_slots * kv_dim]),
+                values: cx
+                    .named_tensor("Value Cache", (n_slots, kv_dim))
+                    .set_deferred(move || vec![0.; n_slots * kv_dim]),
+                inputs,
+                updated: Cell::default(),
+            })
+            .collect();
+        Self {
+            blocks,
+            inputs,
+            layers,
+            sources: None,
+        }
+    }
+
+    pub fn layers(&self) -> &[PagedLayerCache] {
+        &self.layers
+    }
+
+    /// Reserve cache slots for a batch and set the graph inputs describing it. Returns the packed
+    /// token ids of the batch.
+    pub fn prepare(&mut self, batch: &[BatchEntry]) -> Vec<f32> {
+        let n_slots = self.blocks.n_slots();
+        let mut tokens = vec![];
+        let mut positions = vec![];
+        let mut slot_sources = vec![0.; n_slots];
+        let mut slot_written = vec![0.; n_slots];
+        let mut last_tokens = vec![];
+        // (sequence index, position) of each new token and context entry
+        let mut token_owners = vec![];
+        let mut context_owners = vec![];
+        let mut context_slots = vec![];
+        let mut seq_ranges = vec![];
+        for (i, entry) in batch.iter().enumerate() {
+            let start = self.blocks.len(entry.seq);
+            let slots = self
+                .blocks
+                .append(entry.seq, entry.tokens.len())
+                .expect("Ran out of KV cache blocks");
+            for (p, slot) in slots.into_iter().enumerate() {
+                slot_sources[slot] = tokens.len() as f32;
+                slot_written[slot] = 1.;
+                tokens.push(entry.tokens[p] as f32);
+                positions.push((start + p) as f32);
+                token_owners.push((i, start + p));
+            }
+            last_tokens.push(tokens.len() as f32 - 1.);
+            seq_ranges.push(tokens.len() - entry.tokens.len()..tokens.len());
+            for (p, slot) in self.blocks.slots(entry.seq).into_iter().enumerate() {
+                context_slots.push(slot as f32);
+                context_owners.push((i, p));
+            }
+        }
+        // Tokens can only see earlier tokens of their own sequence
+        let mask = token_owners
+            .iter()
+            .flat_map(|(seq, pos)| {
+                context_owners.iter().map(move |(c_seq, c_pos)| {
+                    if seq == c_seq && c_pos <= pos {
+                        0.
+                    } else {
+                        f16::MIN.to_f32()
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+
+        let (n_tokens, n_context) = (tokens.len(), context_slots.len());
+        let pool_weights = seq_ranges
+            .iter()
+            .flat_map(|range| {
+                (0..n_tokens).map(move |t| {
+                    if range.contains(&t) {
+                        1. / range.len() as f32
+                    } else {
+                        0.
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+        self.inputs.positions.set_dyn(positions, n_tokens);
+        self.inputs.slot_sources.set(slot_sources);
+        self.inputs.slot_written.set(slot_written);
+        self.inputs.context_slots.set_dyn(context_slots, n_context);
+        self.inputs.mask.set_dyn(mask, (n_tokens, n_context));
+        self.inputs.last_tokens.set_dyn(last_tokens, batch.len());
+        self.inputs
+            .pool_weights
+            .set_dyn(pool_weights, (batch.len(), n_tokens));
+        tokens
+    }
+
+    /// Move the cache pools computed by the last execution back into the cache
+    pub fn swap(&mut self, graph: &mut Graph) {
+        let dests = self
+            .layers
+            .iter()
+            .flat_map(|l| {
+                let (k, v) = l.updated.get().unwrap();
+                [k.id, v.id]
+            })
+            .collect::<Vec<_>>();
+        if self.sources.is_none() {
+            // Find where the pools live after compilation and remove the initial loaders
+            let srcs = self
+                .layers
+                .iter()
+                .flat_map(|l| [l.keys.id, l.values.id])
+                .collect::<Vec<_>>();
+            let sources = downstream(&srcs, graph);
+            delete_inputs(&sources, graph);
+            self.sources = Some(sources);
+        }
+        transfer_data_same_graph(&dests, self.sources.as_ref().unwrap(), graph);
+    }
+}
+
+impl ToIdsMut for PagedKVCache {
+    fn to_ids_mut(&mut self) -> Vec<&mut NodeIndex> {
+        let mut ids = vec![
+            &mut self.inputs.positions.id,
+            &mut self.inputs.slot_sources.id,
+            &mut self.inputs.slot_written.id,
+            &mut self.inputs.context_slots.id,
+            &mut self.inputs.mask.id,
+            &mut self.inputs.last_tokens.id,
+            &mut self.inputs.pool_weights.id,
+        ];
+        for layer in &mut self.layers {
+            ids.push(&mut layer.keys.id);
+            ids.push(&mut layer.values.id);
+            if let Some((k, v)) = layer.updated.get_mut() {
+                ids.push(&mut k.id);
+                ids.push(&mut v.id);
+            }
+        }
+        if let Some(sources) = &mut self.sources {
+            ids.extend(sources.iter_mut());
+        }
+        ids
+    }
+}
@@ -0,0 +1,149 @@
+use std::collections::HashMap;
+
+type NodeId = usize;
+
+struct Node {
+    /// Cache block holding the keys and values of this node's tokens
+    block: usize,
+    parent: Option<NodeId>,
+    children: HashMap<Vec<u32>, NodeId>,
+    /// Number of sequences currently using this block
+    refs: usize,
+    last_used: u64,
+}
+
+/// A trie of token prefixes whose keys and values are held in full cache blocks.
+///
+/// Each edge is the tokens of one block, so a path from the root is the prefix covered by a chain
+/// of blocks. A sequence using a block also uses every block before it, so unreferenced nodes
+/// always form whole subtrees and can be evicted leaf-first, least recently used first.
+pub struct PrefixCache {
+    nodes: HashMap<NodeId, Node>,
+    roots: HashMap<Vec<u32>, NodeId>,
+    next_id: NodeId,
+    clock: u64,
+    /// Maximum number of unreferenced blocks kept around for future requests
+    budget: usize,
+}
+
+impl PrefixCache {
+    pub fn new(budget: usize) -> Self {
+        Self {
+            nodes: HashMap::default(),
+            roots: HashMap::default(),
+            next_id: 0,
+            clock: 0,
+            budget,
+        }
+    }
+
+    fn tick(&mut self) -> u64 {
+        self.clock += 1;
+        self.clock
+    }
+
+    fn child(&self, parent: Option<NodeId>, tokens: &[u32]) -> Option<NodeId> {
+        match parent {
+            Some(parent) => self.nodes[&parent].children.get(tokens).copied(),
+            None => self.roots.get(tokens).copied(),
+        }
+    }
+
+    /// Find the longest chain of cached blocks matching the start of `tokens`, taking a reference
+    /// to each. Returns the node and block of each matched chunk of `block_size` tokens.
+    pub fn acquire(&mut self, tokens: &[u32], block_size: usize) -> Vec<(NodeId, usize)> {
+        let now = self.tick();
+        let mut path = vec![];
+        let mut parent = None;
+        for chunk in tokens.chunks_exact(block_size) {
+            let Some(id) = self.child(parent, chunk) else {
+                break;
+            };
+            let node = self.nodes.get_mut(&id).unwrap();
+            node.refs += 1;
+            node.last_used = now;
+            path.push((id, node.block));
+            parent = Some(id);
+        }
+        path
+    }
+
+    /// Add a full block of `tokens` following `parent`, taking a reference to it. If the same
+    /// prefix is already cached the existing node is returned along with its block, and the caller
+    /// should switch to that block.
+    pub fn insert(
+        &mut self,
+        parent: Option<NodeId>,
+        tokens: &[u32],
+        block: usize,
+    ) -> (NodeId, usize) {
+        let now = self.tick();
+        if let Some(id) = self.child(parent, tokens) {
+            let node = self.nodes.get_mut(&id).unwrap();
+            node.refs += 1;
+            node.last_used = now;
+            return (id, node.block);
+        }
+        let id = self.next_id;
+        self.next_id += 1;
+        self.nodes.insert(
+            id,
+            Node {
+                block,
+                parent,
+                children: HashMap::default(),
+                refs: 1,
+                last_used: now,
+            },
+        );
+        match parent {
+            Some(parent) => self
+                .nodes
+                .get_mut(&parent)
+                .unwrap()
+                .children
+                .insert(tokens.to_vec(), id),
+            None => self.roots.insert(tokens.to_vec(), id),
+        };
+        (id, block)
+    }
+
+    /// Drop a reference to a node
+    pub fn release(&mut self, id: NodeId) {
+        self.nodes.get_mut(&id).unwrap().refs -= 1;
+    }
+
+    /// Number of blocks no sequence is using, which can be evicted to make room
+    pub fn n_evictable(&self) -> usize {
+        self.nodes.values().filter(|n| n.refs == 0).count()
+    }
+
+    /// Remove the least recently used unreferenced leaf, returning its block
+    pub fn evict(&mut self) -> Option<usize> {
+        let (&id, _) = self
+            .nodes
+            .iter()
+            .filter(|(_, n)| n.refs == 0 && n.children.is_empty())
+            .min_by_key(|(_, n)| n.last_used)?;
+        let node = self.nodes.remove(&id).unwrap();
+        match node.parent {
+            Some(parent) => self
+                .nodes
+                .get_mut(&parent)
+                .unwrap()
+                .children
+                .retain(|_, c| *c != id),
+            None => self.roots.retain(|_, c| *c != id),
+        }
+        Some(node.block)
+    }
+
+    /// Evict blocks until the unreferenced ones fit in the budget, returning the freed blocks
+    pub fn trim(&mut self) -> Vec<usize> {
+        let mut freed = vec![];
+        while self.n_evictable() > self.budget {
+            freed.extend(self.evict());
+        }
+        freed
+    }
+}
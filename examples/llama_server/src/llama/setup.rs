@@ -1,6 +1,5 @@
 use std::{
     io::{self, Write},
-    path::Path,
     time::Instant,
 };
 
@@ -8,38 +7,37 @@ use itertools::Itertools;
 use luminal::prelude::*;
 use tokenizers::Tokenizer;
 
-use crate::llama::{
-    loader,
-    model::{KVCache, Llama, HEAD_DIM, NUM_LAYERS, N_KV_HEADS},
+use crate::{
+    config::Config,
+    llama::{
+        loader,
+        model::{Llama, ATTN_PROJ_DIM, HIDDEN_DIM, NUM_LAYERS, VOCAB_SIZE},
+        paged::{BatchEntry, PagedKVCache},
+    },
 };
 
 /// Define the model
 pub struct Model {
     pub graph: Box<Graph>,
     pub input: GraphTensor,
-    kv_cache_src_set: Vec<NodeIndex>,
-    kv_cache_dest_set: Vec<NodeIndex>,
+    pub cache: PagedKVCache,
     logits: GraphTensor,
+    embeddings: GraphTensor,
     pub tokenizer: Tokenizer,
-    pub last_generated_token: Option<u32>,
 }
 
 unsafe impl Send for Model {}
-unsafe impl Sync for Model {}
-
-const TOKENIZER_PATH: &str = "./setup/tokenizer.json";
-const MODEL_PATH: &str = "./setup/llama3-8b.gguf";
 
 // Load the model
 impl Model {
-    pub fn setup() -> Self {
-        if Path::new(TOKENIZER_PATH).exists() && Path::new(MODEL_PATH).exists() {
+    pub fn setup(config: &Config) -> Self {
+        if config.tokenizer.exists() && config.model.exists() {
             println!("Tokenizer and Model Exists");
         } else {
             panic!("Model does not exist");
         }
 
-        let tokenizer = Tokenizer::from_file(TOKENIZER_PATH).unwrap();
+        let tokenizer = Tokenizer::from_file(&config.tokenizer).unwrap();
 
         print!("Defining graph");
         let now = Instant::now();
@@ -48,29 +46,24 @@ impl Model {
         let mut cx = Box::new(Graph::new());
 
         let mut input = cx.named_tensor("Input", (1, 's'));
-        let mut cache_src: Vec<KVCache> = (0..NUM_LAYERS)
-            .map(|_| {
-                (
-                    cx.named_tensor("Key Cache", (1, N_KV_HEADS, 'p', HEAD_DIM)),
-                    cx.named_tensor("Value Cache", (1, N_KV_HEADS, 'p', HEAD_DIM)),
-                )
-            })
-            .collect();
-        cache_src.set_dyn(vec![], (1, N_KV_HEADS, 0, HEAD_DIM));
-        let model = Llama::new(&mut cx);
+        let mut cache = PagedKVCache::new(
+            NUM_LAYERS,
+            config.cache_blocks(),
+            config.prefix_cache_blocks(),
+            ATTN_PROJ_DIM,
+            &mut cx,
+        );
+        let model = Llama::new(config.context_length, &mut cx);
         let mut model_weights = params(&model);
         cx.keep_tensors(&model_weights);
-        let (logits, mut cache_dest) = model.forward((input, &cache_src));
-        let mut logits = logits
-            .slice((.., (Expression::from('s') - 1).., ..))
-            .retrieve();
-        cache_dest.keep();
+        let (logits, embeddings) = model.forward((input, &cache));
+        let (mut logits, mut embeddings) = (logits.retrieve(), embeddings.retrieve());
 
         // Set up model loading
         #[cfg(any(feature = "metal", feature = "cuda"))]
-        let q_weights = loader::q8_load(MODEL_PATH, &model, &mut cx);
+        let q_weights = loader::q8_load(&config.model, &model, &mut cx);
         #[cfg(all(not(feature = "metal"), not(feature = "cuda")))]
-        loader::q8_load(MODEL_PATH, &model, &mut cx);
+        loader::q8_load(&config.model, &model, &mut cx);
         println!("\t\t - {}ms", now.elapsed().as_millis());
 
         print!("Compiling graph");
@@ -96,124 +89,67 @@ impl Model {
             (
                 &mut input,
                 &mut logits,
-                &mut cache_src,
-                &mut cache_dest,
+                &mut embeddings,
+                &mut cache,
                 &mut model_weights,
             ),
         );
-        let cache_src = downstream(&cache_src, &cx);
-        let cache_dest = cache_dest.to_ids();
         println!("\t\t - {}ms", now.elapsed().as_millis());
 
+        let mut model = Model {
+            input,
+            tokenizer,
+            cache,
+            graph: cx,
+            logits,
+            embeddings,
+        };
+
         // Initial forward pass to load weights
         print!("Loading model");
         io::stdout().flush().unwrap();
         let now = Instant::now();
-        input.set_dyn(vec![1.], (1, 1));
-        cx.set_dyn_dim('t', 1);
-        cx.execute();
-        logits.drop();
-        cx.drop_tensors(&cache_dest);
+        model.step(&[BatchEntry {
+            seq: 0,
+            tokens: &[1],
+        }]);
+        model.cache.blocks.free(0);
         println!("\t\t - {}ms", now.elapsed().as_millis());
 
         // Now that weights are loaded, delete the loading nodes so they don't run again
-        delete_inputs(downstream(model_weights, &cx), &mut cx);
-
-        Model {
-            input,
-            tokenizer,
-            kv_cache_src_set: downstream(cache_src, &cx),
-            kv_cache_dest_set: cache_dest.to_ids(),
-            graph: cx,
-            logits,
-            last_generated_token: None,
-        }
-    }
+        delete_inputs(downstream(model_weights, &model.graph), &mut model.graph);
 
-    /// Generate new tokens given some input
-    pub fn generate(&mut self, prompt: &str, mut continue_callback: impl FnMut(u32) -> bool) {
-        let input_tokens = self.tokenizer.encode(prompt, false).unwrap();
-        let input_tokens = input_tokens.get_ids();
-
-        self.generate_internal(input_tokens, |dist| {
-            let output_id = argmax(dist);
-            (output_id, continue_callback(output_id))
-        })
+        model
     }
 
-    fn generate_internal(
-        &mut self,
-        prompt: &[u32],
-        mut callback: impl FnMut(&[f32]) -> (u32, bool),
-    ) {
-        const EOS_TOKEN: u32 = 128009; // From the llama3 vocab
-
-        let mut input_ids = prompt.to_vec();
-
-        // Set the dyn dims
-        let mut seq_len = input_ids.len();
-        let mut p = 0;
-        if self.graph.dyn_map[&'p'] != 0 {
-            input_ids.insert(0, self.last_generated_token.unwrap());
-            p = self.graph.dyn_map[&'t'];
-            seq_len += p + 1;
-        }
-
-        self.graph.set_dyn_dim('p', p);
-        self.graph.set_dyn_dim('t', seq_len);
-        self.input.set_dyn(
-            input_ids.iter().map(|i| *i as f32).collect::<Vec<_>>(),
-            (1, input_ids.len()),
-        );
-
-        // First token output (from prompt processing)
+    /// Run one step over a batch of sequences, returning the next token distribution and the mean
+    /// hidden state of the new tokens of each.
+    ///
+    /// Cache slots for the new tokens must be available in the block manager.
+    pub fn step(&mut self, batch: &[BatchEntry]) -> (Vec<Vec<f32>>, Vec<Vec<f32>>) {
+        let tokens = self.cache.prepare(batch);
+        let n_tokens = tokens.len();
+        self.input.set_dyn(tokens, (1, n_tokens));
         self.graph.execute();
-
-        // Get the output token
-        let (mut output_id, mut cont) = callback(&self.logits.data());
+        let logits = self.logits.data();
+        let embeddings = self.embeddings.data();
         self.logits.drop();
-        seq_len += 1;
-        self.last_generated_token = Some(output_id);
-
-        // Swap cache
-        transfer_data_same_graph(
-            &self.kv_cache_dest_set,
-            &self.kv_cache_src_set,
-            &mut self.graph,
-        );
-
-        // Decode loop (next token)
-        while output_id != EOS_TOKEN && cont {
-            // Set the data
-            self.graph.set_dyn_dim('p', seq_len - 1);
-            self.graph.set_dyn_dim('t', seq_len);
-            self.input.set_dyn(vec![output_id as f32], (1, 1));
-
-            // Execute the graph
-            self.graph.execute();
-
-            // Get the output token
-            (output_id, cont) = callback(&self.logits.data());
-            seq_len += 1;
-            self.logits.drop();
-            self.last_generated_token = Some(output_id);
-
-            // Swap cache
-            transfer_data_same_graph(
-                &self.kv_cache_dest_set,
-                &self.kv_cache_src_set,
-                &mut self.graph,
-            );
-        }
-    }
-
-    pub fn clear_cache(&mut self) {
-        self.last_generated_token = None;
-        self.graph.set_dyn_dim('p', 0);
+        self.embeddings.drop();
+        self.cache.swap(&mut self.graph);
+        (
+            logits
+                .chunks_exact(VOCAB_SIZE)
+                .map(|l| l.to_vec())
+                .collect(),
+            embeddings
+                .chunks_exact(HIDDEN_DIM)
+                .map(|e| e.to_vec())
+                .collect(),
+        )
     }
 }
 
-fn argmax(dist: &[f32]) -> u32 {
+pub fn argmax(dist: &[f32]) -> u32 {
     dist.iter()
         .position_max_by(|a, b| a.partial_cmp(b).unwrap_or(std::cmp::Ordering::Equal))
         .unwrap() as u32
@@ -1,30 +1,63 @@
+use std::sync::Arc;
+
 use axum::{
-    extract::Extension,
+    extract::{rejection::JsonRejection, Extension},
     http::StatusCode,
+    response::{
+        sse::{KeepAlive, Sse},
+        IntoResponse, Response,
+    },
     routing::{get, post},
     Json, Router,
 };
-use std::sync::Arc;
-use tokio::{net::TcpListener, sync::Mutex};
+use chrono::Utc;
+use serde::Serialize;
+use tokio::net::TcpListener;
 
 mod chat;
+mod completions;
+mod config;
+mod embeddings;
+mod error;
+mod generation;
 mod llama;
+mod scheduler;
 
-use crate::llama::setup::Model;
-use chat::{respond_chat_request, ChatRequest, ChatResponse};
+use crate::{config::Config, error::ApiError, llama::setup::Model, scheduler::Scheduler};
+use chat::{respond_chat_request, stream_chat_request, ChatRequest};
+use completions::{respond_completion_request, stream_completion_request, CompletionRequest};
+use embeddings::{respond_embedding_request, EmbeddingRequest};
+
+/// State shared by all request handlers
+pub struct AppState {
+    pub scheduler: Scheduler,
+    /// Name the model is served under
+    pub model_name: String,
+    /// When the server started serving the model
+    pub created: i64,
+}
 
 #[tokio::main]
 async fn main() {
     tracing_subscriber::fmt::init();
 
-    let model = Arc::new(Mutex::new(Model::setup()));
+    let config = Config::load();
+    let state = Arc::new(AppState {
+        scheduler: Scheduler::new(Model::setup(&config), config.context_length),
+        model_name: config.model_name(),
+        created: Utc::now().timestamp(),
+    });
 
     let app = Router::new()
         .route("/", get(root))
         .route("/chat/completions", post(chat_completions))
-        .layer(Extension(model));
+        .route("/v1/chat/completions", post(chat_completions))
+        .route("/v1/completions", post(completions))
+        .route("/v1/embeddings", post(embeddings))
+        .route("/v1/models", get(models))
+        .layer(Extension(state));
 
-    let listener = TcpListener::bind("127.0.0.1:3000").await.unwrap();
+    let listener = TcpListener::bind(&config.bind).await.unwrap();
     tracing::debug!("listening on {}", listener.local_addr().unwrap());
     axum::serve(listener, app).await.unwrap();
 }
@@ -34,11 +67,66 @@ async fn root() -> &'static str {
 }
 
 async fn chat_completions(
-    Extension(model): Extension<Arc<Mutex<Model>>>,
-    Json(payload): Json<ChatRequest>,
-) -> (StatusCode, Json<ChatResponse>) {
-    let mut model = model.lock().await;
+    Extension(state): Extension<Arc<AppState>>,
+    payload: Result<Json<ChatRequest>, JsonRejection>,
+) -> Result<Response, ApiError> {
+    let Json(payload) = payload?;
+    Ok(if payload.stream {
+        Sse::new(stream_chat_request(state, payload)?)
+            .keep_alive(KeepAlive::default())
+            .into_response()
+    } else {
+        let response = respond_chat_request(&state, payload).await?;
+        (StatusCode::OK, Json(response)).into_response()
+    })
+}
+
+async fn completions(
+    Extension(state): Extension<Arc<AppState>>,
+    payload: Result<Json<CompletionRequest>, JsonRejection>,
+) -> Result<Response, ApiError> {
+    let Json(payload) = payload?;
+    Ok(if payload.stream {
+        Sse::new(stream_completion_request(state, payload)?)
+            .keep_alive(KeepAlive::default())
+            .into_response()
+    } else {
+        let response = respond_completion_request(&state, payload).await?;
+        (StatusCode::OK, Json(response)).into_response()
+    })
+}
+
+async fn embeddings(
+    Extension(state): Extension<Arc<AppState>>,
+    payload: Result<Json<EmbeddingRequest>, JsonRejection>,
+) -> Result<Response, ApiError> {
+    let Json(payload) = payload?;
+    let response = respond_embedding_request(&state, payload).await?;
+    Ok((StatusCode::OK, Json(response)).into_response())
+}
+
+#[derive(Serialize)]
+struct ModelList {
+    object: String,
+    data: Vec<ModelCard>,
+}
+
+#[derive(Serialize)]
+struct ModelCard {
+    id: String,
+    object: String,
+    created: i64,
+    owned_by: String,
+}
 
-    let response = respond_chat_request(&mut model, payload).await;
-    (StatusCode::OK, Json(response))
+async fn models(Extension(state): Extension<Arc<AppState>>) -> Json<ModelList> {
+    Json(ModelList {
+        object: "list".to_string(),
+        data: vec![ModelCard {
+            id: state.model_name.clone(),
+            object: "model".to_string(),
+            created: state.created,
+            owned_by: "luminal".to_string(),
+        }],
+    })
 }
@@ -0,0 +1,217 @@
+use std::{
+    collections::VecDeque,
+    sync::{
+        mpsc::{channel, Receiver, Sender},
+        Arc,
+    },
+    thread,
+};
+
+use tokenizers::Tokenizer;
+use tokio::sync::oneshot;
+
+use crate::llama::{
+    paged::{BatchEntry, SeqId},
+    setup::{argmax, Model},
+};
+
+pub const EOS_TOKEN: u32 = 128009; // From the llama3 vocab
+
+/// Maximum number of sequences run together in one step
+const MAX_BATCH_SEQS: usize = 16;
+/// Maximum number of tokens run together in one step. A single prompt larger than this is still
+/// run, just on its own.
+const MAX_BATCH_TOKENS: usize = 2048;
+
+type ContinueCallback = Box<dyn FnMut(u32) -> bool + Send>;
+
+/// What to do with the output of a sequence
+enum Task {
+    /// Sample tokens, passing each to the callback
+    Generate(ContinueCallback),
+    /// Send back the mean hidden state of the prompt
+    Embed(oneshot::Sender<Vec<f32>>),
+}
+
+struct Request {
+    prompt: Vec<u32>,
+    task: Task,
+}
+
+struct Sequence {
+    id: SeqId,
+    /// Prompt followed by all generated tokens
+    tokens: Vec<u32>,
+    /// Number of tokens already in the KV cache
+    n_cached: usize,
+    task: Task,
+}
+
+/// Handle to the thread running the model. Requests sent from any number of tasks are batched
+/// together into each model step as they come and go.
+#[derive(Clone)]
+pub struct Scheduler {
+    sender: Sender<Request>,
+    pub tokenizer: Arc<Tokenizer>,
+    /// Maximum number of tokens in a sequence, prompt included
+    pub context_length: usize,
+}
+
+impl Scheduler {
+    pub fn new(model: Model, context_length: usize) -> Self {
+        assert!(
+            model.cache.blocks.fits(context_length),
+            "KV cache is too small for the context length"
+        );
+        let tokenizer = Arc::new(model.tokenizer.clone());
+        let (sender, receiver) = channel();
+        thread::spawn(move || run(model, context_length, receiver));
+        Self {
+            sender,
+            tokenizer,
+            context_length,
+        }
+    }
+
+    fn submit(&self, prompt: Vec<u32>, task: Task) {
+        self.sender
+            .send(Request { prompt, task })
+            .expect("Scheduler thread stopped");
+    }
+
+    /// Queue a prompt for generation. `continue_callback` is called with each generated token
+    /// (including the final EOS) and generation stops once it returns false or the context length
+    /// is reached. The callback is dropped when generation finishes.
+    pub fn generate(
+        &self,
+        prompt: Vec<u32>,
+        continue_callback: impl FnMut(u32) -> bool + Send + 'static,
+    ) {
+        self.submit(prompt, Task::Generate(Box::new(continue_callback)));
+    }
+
+    /// Queue a prompt to be embedded, returning a receiver for its mean-pooled final hidden state
+    pub fn embed(&self, prompt: Vec<u32>) -> oneshot::Receiver<Vec<f32>> {
+        let (sender, receiver) = oneshot::channel();
+        self.submit(prompt, Task::Embed(sender));
+        receiver
+    }
+}
+
+fn run(mut model: Model, context_length: usize, receiver: Receiver<Request>) {
+    let mut waiting = VecDeque::new();
+    let mut running: Vec<Sequence> = vec![];
+    let mut next_id = 0;
+    let mut add_request = |waiting: &mut VecDeque<Sequence>, request: Request| {
+        next_id += 1;
+        waiting.push_back(Sequence {
+            id: next_id,
+            tokens: request.prompt,
+            n_cached: 0,
+            task: request.task,
+        });
+    };
+    loop {
+        // Collect new requests, blocking if there's nothing to do
+        if running.is_empty() && waiting.is_empty() {
+            let Ok(request) = receiver.recv() else {
+                return;
+            };
+            add_request(&mut waiting, request);
+        }
+        while let Ok(request) = receiver.try_recv() {
+            add_request(&mut waiting, request);
+        }
+
+        // Make room for running sequences, preempting the most recent ones if the cache is full.
+        // Preempted sequences are recomputed from scratch once they're admitted again.
+        let mut i = 0;
+        let mut reserved = 0;
+        while i < running.len() {
+            let pending = running[i].tokens.len() - running[i].n_cached;
+            let needed = model.cache.blocks.blocks_needed(running[i].id, pending);
+            if reserved + needed <= model.cache.blocks.available() {
+                reserved += needed;
+                i += 1;
+                continue;
+            }
+            let mut preempted = running.pop().unwrap();
+            model.cache.blocks.free(preempted.id);
+            preempted.n_cached = 0;
+            waiting.push_front(preempted);
+        }
+
+        // Admit waiting sequences while there's room
+        let mut batch_tokens = running
+            .iter()
+            .map(|s| s.tokens.len() - s.n_cached)
+            .sum::<usize>();
+        while let Some(seq) = waiting.front_mut() {
+            if seq.tokens.len() > context_length {
+                // Can never be run, so drop it
+                waiting.pop_front();
+                continue;
+            }
+            if running.len() >= MAX_BATCH_SEQS {
+                break;
+            }
+            // Embeddings pool over every prompt token, so they can't skip a cached prefix
+            if matches!(seq.task, Task::Generate(_)) {
+                seq.n_cached = model.cache.blocks.share_prefix(seq.id, &seq.tokens);
+            }
+            let pending = seq.tokens.len() - seq.n_cached;
+            let needed = model.cache.blocks.blocks_needed(seq.id, pending);
+            if (batch_tokens + pending > MAX_BATCH_TOKENS && !running.is_empty())
+                || reserved + needed > model.cache.blocks.available()
+            {
+                model.cache.blocks.free(seq.id);
+                seq.n_cached = 0;
+                break;
+            }
+            batch_tokens += pending;
+            reserved += needed;
+            running.extend(waiting.pop_front());
+        }
+        if running.is_empty() {
+            continue;
+        }
+
+        // Run all running sequences through the model together
+        let batch = running
+            .iter()
+            .map(|s| BatchEntry {
+                seq: s.id,
+                tokens: &s.tokens[s.n_cached..],
+            })
+            .collect::<Vec<_>>();
+        let (logits, embeddings) = model.step(&batch);
+
+        // Sample the next token of each generating sequence, and hand back finished embeddings
+        let mut finished = vec![];
+        for (i, ((seq, dist), embedding)) in
+            running.iter_mut().zip(logits).zip(embeddings).enumerate()
+        {
+            seq.n_cached = seq.tokens.len();
+            model.cache.blocks.commit(seq.id, &seq.tokens);
+            match &mut seq.task {
+                Task::Generate(continue_callback) => {
+                    let token = argmax(&dist);
+                    seq.tokens.push(token);
+                    let cont = continue_callback(token);
+                    if token == EOS_TOKEN || !cont || seq.tokens.len() >= context_length {
+                        finished.push((i, None));
+                    }
+                }
+                Task::Embed(_) => finished.push((i, Some(embedding))),
+            }
+        }
+        for (i, embedding) in finished.into_iter().rev() {
+            let seq = running.remove(i);
+            model.cache.blocks.free(seq.id);
+            if let (Task::Embed(sender), Some(embedding)) = (seq.task, embedding) {
+                // Nothing to do if the request was cancelled
+                let _ = sender.send(embedding);
+            }
+        }
+    }
+}
@@ -1,4 +1,5 @@
 use std::{
+    fs::File,
     io::{self, Write},
     time::Instant,
 };
@@ -6,16 +7,19 @@ use std::{
 use clap::Parser;
 use colored::Colorize;
 use itertools::Itertools;
-use model::{Phi, HEAD_DIM, N_HEADS};
+use luminal_nn::{
+    llm::{Llm, LlmConfig},
+    KVCache, RopeLayout,
+};
 use tokenizers::Tokenizer;
 
 mod gguf;
 mod loader;
-mod model;
 
-use crate::model::KVCache;
 use luminal::prelude::*;
 
+const MODEL_PATH: &str = "setup/phi3.gguf";
+
 // Command args parser
 #[derive(Debug, Parser)]
 #[command(author, version, about, long_about = None)]
@@ -32,6 +36,11 @@ pub struct CLIArgs {
 fn main() {
     let cli_args = CLIArgs::parse();
     let tokenizer = Tokenizer::from_file("setup/tokenizer.json").unwrap();
+    let mut config =
+        LlmConfig::from_gguf(&gguf::Content::read(&mut File::open(MODEL_PATH).unwrap()).unwrap());
+    // The phi3.gguf from setup.sh stores its query and key weights for interleaved rotary
+    // embeddings, unlike the half-split layout of llama.cpp's phi3 conversions
+    config.rope_layout = RopeLayout::Interleaved;
 
     print!("Defining graph");
     io::stdout().flush().unwrap();
@@ -40,29 +49,27 @@ fn main() {
     // Set up graph
     let mut cx = Graph::new();
     let mut input = cx.named_tensor("Input", (1, 's'));
-    let mut cache_src: Vec<KVCache> = (0..model::NUM_LAYERS)
-        .map(|_| {
-            (
-                cx.named_tensor("Key Cache", (1, N_HEADS, 'p', HEAD_DIM)),
-                cx.named_tensor("Value Cache", (1, N_HEADS, 'p', HEAD_DIM)),
-            )
-        })
-        .collect();
-    cache_src.set_dyn(vec![], (1, N_HEADS, 0, HEAD_DIM));
-    let model = Phi::new(&mut cx);
+    let mut cache = KVCache::new(
+        config.n_layers,
+        1,
+        config.n_kv_heads,
+        config.head_dim,
+        'p',
+        &mut cx,
+    );
+    let model = Llm::new(&config, &mut cx);
     let mut model_weights = params(&model);
     cx.keep_tensors(&model_weights);
-    let (logits, mut cache_dest) = model.forward((input, &cache_src));
-    let mut logits = logits
+    let mut logits = model
+        .forward((input, &cache))
         .slice((.., (Expression::from('s') - 1).., ..))
         .retrieve();
-    cache_dest.keep();
 
     // Set up model loading
     #[cfg(any(feature = "metal", feature = "cuda"))]
-    let q_weights = loader::q8_load("setup/phi3.gguf", &model, &mut cx);
+    let q_weights = loader::q8_load(MODEL_PATH, &model, &mut cx);
     #[cfg(all(not(feature = "metal"), not(feature = "cuda")))]
-    loader::q8_load("setup/phi3.gguf", &model, &mut cx);
+    loader::q8_load(MODEL_PATH, &model, &mut cx);
     println!("\t\t - {}ms", now.elapsed().as_millis());
 
     print!("Compiling graph");
@@ -88,15 +95,8 @@ fn main() {
             #[cfg(all(not(feature = "metal"), not(feature = "cuda")))]
             luminal_cpu::CPUCompiler::default(),
         ),
-        (
-            &mut input,
-            &mut logits,
-            &mut cache_src,
-            &mut cache_dest,
-            &mut model_weights,
-        ),
+        (&mut input, &mut logits, &mut cache, &mut model_weights),
     );
-    let cache_src = downstream(&cache_src, &cx);
     println!("\t\t - {}ms", now.elapsed().as_millis());
 
     // Initial forward pass to load weights
@@ -104,15 +104,14 @@ fn main() {
     io::stdout().flush().unwrap();
     let now = Instant::now();
     input.set_dyn(vec![1.], (1, 1));
-    cx.set_dyn_dim('t', 1);
     cx.execute();
     logits.drop();
-    transfer_data_same_graph(&cache_dest, &cache_src, &mut cx);
+    cache.swap();
+    cache.reset();
     println!("\t\t - {}ms", now.elapsed().as_millis());
 
     // Now that weights are loaded, delete the loading nodes so they don't run again
-    delete_inputs(&cache_src, &mut cx);
-    delete_inputs(&downstream(model_weights, &cx), &mut cx);
+    delete_inputs(downstream(model_weights, &cx), &mut cx);
 
     // Run prompt processing pass
     let input_ids = tokenizer
@@ -124,7 +123,6 @@ fn main() {
         input_ids.iter().map(|i| *i as f32).collect::<Vec<_>>(),
         (1, input_ids.len()),
     );
-    cx.set_dyn_dim('t', input_ids.len());
     print!("Processing Prompt");
     io::stdout().flush().unwrap();
     let now = Instant::now();
@@ -145,14 +143,13 @@ fn main() {
     io::stdout().flush().unwrap();
 
     // Swap caches
-    transfer_data_same_graph(&cache_dest, &cache_src, &mut cx);
+    cache.swap();
 
     // Decode loop
     let start_decode = std::time::Instant::now();
     let mut prev_output_len = out.len();
     for _ in 0..cli_args.gen_tokens {
         input.set_dyn(vec![*output_ids.last().unwrap() as f32], (1, 1));
-        cx.set_dyn_dim('p', input_ids.len() + output_ids.len() - 1);
         cx.execute();
 
         // Sample tokens
@@ -171,7 +168,7 @@ fn main() {
         prev_output_len = current_output.len();
 
         // Swap caches
-        transfer_data_same_graph(&cache_dest, &cache_src, &mut cx);
+        cache.swap();
     }
 
     println!();
@@ -1,38 +1,9 @@
 use luminal::prelude::*;
-use luminal_nn::Conv2D;
-
-struct Upsample {
-    scale_factor: usize,
-}
-
-impl Upsample {
-    fn new(scale_factor: usize) -> Self {
-        Upsample { scale_factor }
-    }
-}
-
-impl Module<GraphTensor> for Upsample {
-    type Output = GraphTensor;
-    fn forward(&self, xs: GraphTensor) -> GraphTensor {
-        let (batch, channels, h, w) = xs.dims4();
-        xs.expand(3, self.scale_factor)
-            .expand(5, self.scale_factor)
-            .reshape((
-                batch,
-                channels,
-                self.scale_factor * h,
-                self.scale_factor * w,
-            )) // Double height and width
-    }
-}
+use luminal_nn::{BatchNorm2D, Conv2D, Upsample, UpsampleMode};
 
 struct ConvBlock {
     conv: Conv2D,
-    running_mean: GraphTensor,
-    running_var: GraphTensor,
-    weight: GraphTensor,
-    bias: GraphTensor,
-    original_weight: GraphTensor,
+    bn: BatchNorm2D,
 }
 
 impl ConvBlock {
@@ -44,25 +15,9 @@ impl ConvBlock {
         dilation: (usize, usize),
         cx: &mut Graph,
     ) -> Self {
-        let eps = 1e-3;
-        let mut conv = Conv2D::new(ch_in, ch_out, kernel, stride, dilation, false, cx);
-        let original_weight = conv.weight;
-        let running_mean = cx.named_tensor("mean", ch_in);
-        let running_var = cx.named_tensor("var", ch_in);
-        let o_weight = cx.named_tensor("o_weight", ch_in);
-        let o_bias = cx.named_tensor("o_bias", ch_in);
-        let std_ = o_weight / ((running_var + eps).sqrt());
-        let weight = conv.weight * (std_.reshape((conv.weight.dims2().0, 1, 1, 1)));
-        let bias = o_bias - (std_ * running_mean);
-        conv.weight = weight;
-        conv.bias = Some(bias);
         Self {
-            conv,
-            running_var,
-            running_mean,
-            original_weight,
-            weight: o_weight,
-            bias: o_bias,
+            conv: Conv2D::new(ch_in, ch_out, kernel, stride, dilation, false, cx),
+            bn: BatchNorm2D::new(ch_out, 1e-3, 0.03, cx),
         }
     }
 }
@@ -70,17 +25,14 @@ impl ConvBlock {
 impl Module<GraphTensor> for ConvBlock {
     type Output = GraphTensor;
     fn forward(&self, input: GraphTensor) -> Self::Output {
-        self.conv.forward(input)
+        self.bn.forward(self.conv.forward(input))
     }
 }
 
 impl SerializeModule for ConvBlock {
     fn serialize(&self, s: &mut Serializer) {
-        s.tensor("conv/weight", self.original_weight);
-        s.tensor("bn/weight", self.weight);
-        s.tensor("bn/bias", self.bias);
-        s.tensor("bn/running_mean", self.running_mean);
-        s.tensor("bn/running_var", self.running_var);
+        s.module("conv", &self.conv);
+        s.module("bn", &self.bn);
     }
 }
 
@@ -406,7 +358,7 @@ impl YoloNeck {
     pub fn new(w: f64, r: f64, d: f64, cx: &mut Graph) -> Self {
         let n = (3. * d).round() as usize;
         Self {
-            up: Upsample::new(2),
+            up: Upsample::new(2, UpsampleMode::Nearest),
             n1: C2f::new(
                 (512. * w * (1. + r)) as usize,
                 (512. * w) as usize,
@@ -329,7 +329,7 @@ impl Graph {
     ///     .finish();
     /// let b = GraphTensor::from_id(b_id, a.shape, a.graph());
     /// ```
-    pub fn add_op<O: Operator + 'static>(&mut self, op: O) -> NewOp {
+    pub fn add_op<O: Operator + 'static>(&mut self, op: O) -> NewOp<'_> {
         self.linearized_graph = None;
         NewOp {
             new_op_id: self.graph.add_node(Box::new(op)),
@@ -338,7 +338,7 @@ impl Graph {
         }
     }
     /// Add op on the graph, and get back a NewOp. Just like add_op, except a boxed op is expected.
-    pub fn add_boxed_op(&mut self, op: Box<dyn Operator + 'static>) -> NewOp {
+    pub fn add_boxed_op(&mut self, op: Box<dyn Operator + 'static>) -> NewOp<'_> {
         self.linearized_graph = None;
         NewOp {
             new_op_id: self.graph.add_node(op),
@@ -579,6 +579,27 @@ pub fn remap<T: ToIdsMut>(from: NodeIndex, to: NodeIndex, mut ids: T, graph: &mu
     if let Some(w) = graph.to_retrieve.remove(&from) {
         graph.to_retrieve.insert(to, w);
     }
+    // Transfer shardings
+    if let Some(s) = graph.shardings.remove(&from) {
+        graph.shardings.insert(to, s);
+    }
+}
+
+/// The contiguous buffer an output of a node is stored in, from how it's retrieved or read
+pub fn output_layout(graph: &Graph, node: NodeIndex, output: u8) -> Option<ShapeTracker> {
+    graph
+        .to_retrieve
+        .get(&node)
+        .filter(|(o, _)| *o == output)
+        .map(|(_, shape)| *shape)
+        .or_else(|| {
+            graph
+                .edges_directed(node, petgraph::Direction::Outgoing)
+                .filter_map(|e| e.weight().as_data())
+                .find(|(_, o, _)| *o == output)
+                .map(|(_, _, shape)| shape)
+        })
+        .map(|shape| shape.physical())
 }
 
 pub fn move_outgoing_edge<N, E: Clone>(
@@ -658,6 +679,12 @@ impl GraphSearch {
     pub fn clear_cached_results(&mut self) {
         self.to_return.clear();
     }
+    /// Whether any of the matches found but not yet returned include one of `nodes`
+    pub fn pending_matches_use(&self, nodes: &[NodeIndex]) -> bool {
+        self.to_return
+            .iter()
+            .any(|m| m.values().any(|n| nodes.contains(n)))
+    }
     pub fn reset(&mut self) {
         self.clear_cached_results();
         self.returned_anchors.clear();
@@ -672,6 +699,10 @@ impl GraphSearch {
     pub fn get<T: Borrow<SelectGraph>>(&self, node: T) -> NodeIndex {
         *self.current.get(&node.borrow().id).unwrap()
     }
+    /// Every node in the current match
+    pub fn matched_nodes(&self) -> Vec<NodeIndex> {
+        self.current.values().copied().collect()
+    }
     pub fn try_delete(&self) {
         let graph = unsafe { self.graph.as_mut().unwrap() };
         for node in toposort(&self.selector, None).unwrap().into_iter().rev() {
@@ -720,7 +751,7 @@ fn backtrack_match(
             if let Some(new_mapping) =
                 backtrack_match(pattern_parent, pattern_graph, *parent, main_graph)
             {
-                mapping.extend(new_mapping.into_iter());
+                mapping.extend(new_mapping);
                 continue 'pattern_loop;
             }
         }
@@ -763,7 +794,7 @@ fn test_node(
             if a_sh.len() != b_sh.dims.len() {
                 return false;
             }
-            for (a, b) in a_sh.iter().zip(b_sh.dims().into_iter()) {
+            for (a, b) in a_sh.iter().zip(b_sh.dims()) {
                 match a.to_usize() {
                     Some(n) => {
                         if b.to_usize().map(|i| i != n).unwrap_or(true) {
@@ -0,0 +1,948 @@
+use std::{
+    any::Any,
+    fmt::Debug,
+    sync::atomic::{AtomicU32, Ordering},
+};
+
+use itertools::Itertools;
+use petgraph::{visit::EdgeRef, Direction};
+use rustc_hash::FxHashMap;
+
+use crate::{op::Contiguous, prelude::*};
+
+/// Lay out a CPU tensor contiguously
+fn contiguous(tensor: InputTensor, shape: ShapeTracker) -> Vec<f32> {
+    assert!(
+        tensor.borrowed().is::<Vec<f32>>(),
+        "Control flow ops run on the CPU, so device data has to be copied back first. Compile with \
+        Nested::with_copies to insert copies around them."
+    );
+    let mut out = Contiguous.process(vec![(tensor, shape)]).pop().unwrap();
+    std::mem::take(out.downcast_mut::<Vec<f32>>().unwrap())
+}
+
+/// Get the dynamic dimension an expression is made of, if it's just one
+fn as_dyn_dim(expression: Expression) -> Option<char> {
+    match expression.terms.read().as_slice() {
+        [Term::Var(c)] => Some(*c),
+        _ => None,
+    }
+}
+
+/// A graph nested inside a control flow op, with the nodes its inputs get set on and its results
+/// are read from. Data goes in and out as contiguous `Vec<f32>`s.
+#[derive(Debug)]
+pub struct NestedGraph {
+    // Boxed so constants pointing at its dyn map stay valid when the op is moved
+    pub graph: Box<Graph>,
+    pub inputs: Vec<NodeIndex>,
+    pub results: Vec<NodeIndex>,
+    shapes: Vec<ShapeTracker>,
+}
+
+impl NestedGraph {
+    /// Build a nested graph with a named input of each shape. Inputs from `kept` onwards stay set
+    /// between runs.
+    fn new(
+        inputs: Vec<(&str, Vec<Expression>)>,
+        kept: usize,
+        build: impl FnOnce(&mut Graph, &[GraphTensor]) -> Vec<GraphTensor>,
+    ) -> Self {
+        let mut graph = Box::new(Graph::new());
+        graph.nested = true;
+        let inputs = inputs
+            .into_iter()
+            .map(|(name, dims)| graph.named_tensor(name, dims))
+            .collect_vec();
+        for input in &inputs[kept..] {
+            input.keep();
+        }
+        let results = build(&mut graph, &inputs)
+            .into_iter()
+            .map(|t| t.retrieve())
+            .collect_vec();
+        Self {
+            inputs: inputs.iter().map(|t| t.id).collect(),
+            results: results.iter().map(|t| t.id).collect(),
+            shapes: results.iter().map(|t| t.shape).collect(),
+            graph,
+        }
+    }
+
+    /// The dimensions of each result
+    fn result_dims(&self) -> Vec<Vec<Expression>> {
+        self.shapes.iter().map(|s| s.dims()).collect()
+    }
+
+    /// Set the inputs starting from `first`
+    fn set_inputs(&mut self, first: usize, data: impl IntoIterator<Item = Vec<f32>>) {
+        for (id, data) in self.inputs[first..].iter().zip(data) {
+            self.graph.set_tensor(*id, 0, Tensor::new(data));
+        }
+    }
+
+    /// Execute the graph, taking each result along with its resolved shape
+    fn run(&mut self, dyn_map: &FxHashMap<char, usize>) -> Vec<(Vec<f32>, ShapeTracker)> {
+        self.graph.dyn_map.clone_from(dyn_map);
+        self.graph.execute();
+        let mut dim_stack = vec![];
+        let results = self
+            .results
+            .iter()
+            .zip(&self.shapes)
+            .map(|(id, shape)| {
+                let mut shape = *shape;
+                shape.resolve_global_dyn_dims_stack(&self.graph.dyn_map, &mut dim_stack);
+                let tensor = self.graph.get_tensor_ref(*id, 0).unwrap();
+                (contiguous(InputTensor::Borrowed(tensor), shape), shape)
+            })
+            .collect_vec();
+        for id in &self.results {
+            if !self.inputs.contains(id) {
+                self.graph.tensors.remove(&(*id, 0));
+            }
+        }
+        results
+    }
+
+    /// Remove all data, including kept inputs
+    fn clear(&mut self) {
+        self.graph.tensors.clear();
+    }
+
+    fn compile<C: Compiler>(&mut self, compiler: &C) {
+        compile_nested(&mut self.graph, compiler);
+        compiler.compile(&mut self.graph, (&mut self.inputs, &mut self.results));
+        self.graph.toposort();
+        self.graph.reset();
+    }
+}
+
+/// Compile the graphs nested inside each control flow op in a graph
+fn compile_nested<C: Compiler>(graph: &mut Graph, compiler: &C) {
+    for node in graph.node_indices().collect_vec() {
+        let nested = if let Some(scan) = graph.try_get_op_mut::<Scan>(node) {
+            vec![&mut scan.body]
+        } else if let Some(op) = graph.try_get_op_mut::<If>(node) {
+            vec![&mut op.then, &mut op.otherwise]
+        } else if let Some(op) = graph.try_get_op_mut::<While>(node) {
+            vec![&mut op.cond, &mut op.body]
+        } else {
+            continue;
+        };
+        for nested in nested {
+            nested.compile(compiler);
+        }
+    }
+}
+
+/// Makes a copy op for moving data between the CPU and a device
+pub type CopyOp = Box<dyn Fn() -> Box<dyn Operator>>;
+
+/// Wrap this around a compiler to also compile the graphs nested inside control flow ops.
+///
+/// Control flow ops always run on the CPU, and so do the inputs and results of the graphs nested in
+/// them. A compiler moving the graph onto a device should be given its copy ops with
+/// [`Nested::with_copies`], so data going into each control flow op is copied back to the CPU and
+/// its outputs are copied onto the device.
+pub struct Nested<C: Compiler> {
+    pub compiler: C,
+    copies: Option<(CopyOp, CopyOp)>,
+}
+
+impl<C: Compiler> Nested<C> {
+    pub fn new(compiler: C) -> Self {
+        Self {
+            compiler,
+            copies: None,
+        }
+    }
+
+    /// Copy data around control flow ops with ops made by `to_device` and `from_device`
+    pub fn with_copies(
+        mut self,
+        to_device: impl Fn() -> Box<dyn Operator> + 'static,
+        from_device: impl Fn() -> Box<dyn Operator> + 'static,
+    ) -> Self {
+        self.copies = Some((Box::new(to_device), Box::new(from_device)));
+        self
+    }
+}
+
+impl<C: Compiler + Default> Default for Nested<C> {
+    fn default() -> Self {
+        Self::new(C::default())
+    }
+}
+
+impl<C: Compiler + Debug> Debug for Nested<C> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Nested")
+            .field("compiler", &self.compiler)
+            .field("copies", &self.copies.is_some())
+            .finish()
+    }
+}
+
+impl<C: Compiler> Compiler for Nested<C> {
+    type Output = ();
+    fn compile<T: ToIdsMut>(&self, graph: &mut Graph, remap: T) {
+        compile_nested(graph, &self.compiler);
+        self.compiler.compile(graph, remap);
+        let Some((to_device, from_device)) = &self.copies else {
+            return;
+        };
+        for node in graph.node_indices().collect_vec() {
+            if !graph.check_node_type::<Scan>(node)
+                && !graph.check_node_type::<If>(node)
+                && !graph.check_node_type::<While>(node)
+            {
+                continue;
+            }
+            // Copy inputs back to the CPU
+            for (source, edge, weight) in graph
+                .edges_directed(node, Direction::Incoming)
+                .filter(|e| !e.weight().is_schedule())
+                .map(|e| (e.source(), e.id(), *e.weight()))
+                .collect_vec()
+            {
+                let (input_order, output_order, shape) = weight.as_data().unwrap();
+                let copy = graph
+                    .add_boxed_op(from_device())
+                    .input(source, output_order, shape)
+                    .finish();
+                graph.add_edge(
+                    copy,
+                    node,
+                    Dependency::Data {
+                        input_order,
+                        output_order: 0,
+                        shape,
+                    },
+                );
+                graph.remove_edge(edge);
+            }
+            // Copy each output onto the device
+            let outputs = graph
+                .edges_directed(node, Direction::Outgoing)
+                .filter(|e| !e.weight().is_schedule())
+                .map(|e| (e.target(), e.id(), *e.weight()))
+                .into_group_map_by(|(_, _, w)| w.as_data().unwrap().1);
+            for (output, edges) in outputs {
+                let (_, _, shape) = edges[0].2.as_data().unwrap();
+                let copy = graph
+                    .add_boxed_op(to_device())
+                    .input(node, output, shape)
+                    .finish();
+                for (target, edge, weight) in edges {
+                    let (input_order, _, shape) = weight.as_data().unwrap();
+                    graph.add_edge(
+                        copy,
+                        target,
+                        Dependency::Data {
+                            input_order,
+                            output_order: 0,
+                            shape,
+                        },
+                    );
+                    graph.remove_edge(edge);
+                }
+            }
+        }
+    }
+}
+
+/// Forward one output of an op with multiple outputs, so it can be used as a GraphTensor
+#[derive(Debug, Clone, PartialEq)]
+pub struct SelectOutput(pub u8);
+
+impl Operator for SelectOutput {
+    fn process(&mut self, mut inp: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
+        vec![inp.pop().unwrap().0.cloned()]
+    }
+    crate::op_equality!(0);
+}
+
+/// Runs a nested graph once for each step along the first dimension of its sequence inputs,
+/// passing carried values from one step to the next.
+///
+/// Inputs are the initial carries, then the sequences, then the constants. Outputs are the final
+/// carries, then each step output stacked along a new first dimension. Runs on the CPU, see
+/// [`Nested`].
+pub struct Scan {
+    body: NestedGraph,
+    carries: usize,
+    sequences: usize,
+    reverse: bool,
+    dyn_map: *const FxHashMap<char, usize>,
+}
+
+impl Debug for Scan {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        // Include the body address so scans over the same inputs aren't merged
+        write!(f, "Scan({:p})", &*self.body.graph)
+    }
+}
+
+impl Operator for Scan {
+    fn process(&mut self, inp: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
+        let dyn_map = unsafe { self.dyn_map.as_ref().unwrap() };
+        let mut inp = inp.into_iter().map(|(t, st)| (contiguous(t, st), st));
+        let mut carries = (&mut inp)
+            .take(self.carries)
+            .map(|(data, _)| data)
+            .collect_vec();
+        let sequences = (&mut inp).take(self.sequences).collect_vec();
+        self.body
+            .set_inputs(self.carries + self.sequences, inp.map(|(data, _)| data));
+
+        let length = sequences[0].1.dims()[0].to_usize().unwrap();
+        let order = (0..length).collect_vec();
+        let order = if self.reverse {
+            order.into_iter().rev().collect_vec()
+        } else {
+            order
+        };
+        let mut step_outputs = Vec::with_capacity(length);
+        for step in order {
+            let steps = sequences.iter().map(|(data, _)| {
+                let size = data.len() / length;
+                data[step * size..(step + 1) * size].to_vec()
+            });
+            self.body.set_inputs(0, carries.into_iter().chain(steps));
+            let mut results = self
+                .body
+                .run(dyn_map)
+                .into_iter()
+                .map(|(data, _)| data)
+                .collect_vec();
+            step_outputs.push(results.split_off(self.carries));
+            carries = results;
+        }
+        self.body.clear();
+
+        if self.reverse {
+            step_outputs.reverse();
+        }
+        let n_outputs = self.body.results.len() - self.carries;
+        carries
+            .into_iter()
+            .chain((0..n_outputs).map(|i| {
+                step_outputs
+                    .iter()
+                    .flat_map(|outputs| outputs[i].iter().copied())
+                    .collect()
+            }))
+            .map(Tensor::new)
+            .collect()
+    }
+}
+
+/// Runs one of two nested graphs depending on whether the first element of its first input is
+/// nonzero. The rest of the inputs go to whichever graph runs, and its results are the outputs.
+/// Runs on the CPU, see [`Nested`].
+pub struct If {
+    then: NestedGraph,
+    otherwise: NestedGraph,
+    dyn_map: *const FxHashMap<char, usize>,
+}
+
+impl Debug for If {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "If({:p})", &*self.then.graph)
+    }
+}
+
+impl Operator for If {
+    fn process(&mut self, inp: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
+        let mut inp = inp.into_iter().map(|(t, st)| contiguous(t, st));
+        let branch = if inp.next().unwrap()[0] != 0. {
+            &mut self.then
+        } else {
+            &mut self.otherwise
+        };
+        branch.set_inputs(0, inp);
+        let results = branch.run(unsafe { self.dyn_map.as_ref().unwrap() });
+        branch.clear();
+        results
+            .into_iter()
+            .map(|(data, _)| Tensor::new(data))
+            .collect()
+    }
+}
+
+/// Runs a nested body graph on its state for as long as a nested condition graph gives a nonzero
+/// first element.
+///
+/// Inputs are the initial state, then constants given to both graphs. Outputs are the final state.
+/// State dimensions that are a single dynamic dimension can change size between iterations, so
+/// they're replaced by new dynamic dimensions in the outputs, which the graph binds to the final
+/// sizes after the loop runs. Runs on the CPU, see [`Nested`].
+pub struct While {
+    cond: NestedGraph,
+    body: NestedGraph,
+    state: Vec<Vec<Expression>>,
+    /// Each dynamic dimension of the state, with the dimension of its final size in the outputs
+    outputs: Vec<(char, char)>,
+    /// Final sizes of the output dimensions from the last run
+    sizes: Vec<(char, usize)>,
+    dyn_map: *const FxHashMap<char, usize>,
+}
+
+impl Debug for While {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "While({:p})", &*self.body.graph)
+    }
+}
+
+impl Operator for While {
+    fn process(&mut self, inp: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
+        let mut dyn_map = unsafe { self.dyn_map.as_ref().unwrap() }.clone();
+        let mut inp = inp.into_iter().map(|(t, st)| contiguous(t, st));
+        let mut state = (&mut inp).take(self.state.len()).collect_vec();
+        let constants = inp.collect_vec();
+        self.cond
+            .set_inputs(self.state.len(), constants.iter().cloned());
+        self.body.set_inputs(self.state.len(), constants);
+
+        loop {
+            self.cond.set_inputs(0, state.iter().cloned());
+            if self.cond.run(&dyn_map)[0].0[0] == 0. {
+                break;
+            }
+            self.body.set_inputs(0, state);
+            let results = self.body.run(&dyn_map);
+            // Rebind dynamic dimensions to the new state sizes
+            for (dims, (_, shape)) in self.state.iter().zip(&results) {
+                for (dim, size) in dims.iter().zip(shape.dims()) {
+                    if let Some(c) = as_dyn_dim(*dim) {
+                        dyn_map.insert(c, size.to_usize().unwrap());
+                    }
+                }
+            }
+            state = results.into_iter().map(|(data, _)| data).collect();
+        }
+        self.cond.clear();
+        self.body.clear();
+
+        self.sizes = self
+            .outputs
+            .iter()
+            .map(|(inner, outer)| (*outer, dyn_map[inner]))
+            .collect();
+        state.into_iter().map(Tensor::new).collect()
+    }
+
+    fn custom(&mut self, key: &str, _: Box<dyn Any>) -> Option<Box<dyn Any>> {
+        if key == "dyn_dims" {
+            return Some(Box::new(self.sizes.clone()));
+        }
+        None
+    }
+}
+
+/// Make a dynamic dimension that isn't used anywhere else, from the private use characters
+fn fresh_dyn_dim() -> char {
+    static NEXT: AtomicU32 = AtomicU32::new(0xF0000);
+    char::from_u32(NEXT.fetch_add(1, Ordering::Relaxed)).expect("Ran out of dynamic dimensions")
+}
+
+impl Graph {
+    /// Add a control flow op, with a node selecting each of its outputs
+    fn add_control_flow(
+        &mut self,
+        op: impl Operator + 'static,
+        inputs: impl IntoIterator<Item = GraphTensor>,
+        output_dims: Vec<Vec<Expression>>,
+    ) -> Vec<GraphTensor> {
+        let mut op = self.add_op(op);
+        for t in inputs {
+            op = op.input(t.id, 0, t.shape);
+        }
+        let id = op.finish();
+        output_dims
+            .into_iter()
+            .enumerate()
+            .map(|(i, dims)| {
+                let shape = ShapeTracker::new(dims);
+                let output = self
+                    .add_op(SelectOutput(i as u8))
+                    .input(id, i as u8, shape)
+                    .finish();
+                GraphTensor::from_id(output, shape, self)
+            })
+            .collect()
+    }
+
+    /// Run `body` once for each entry along the first dimension of the `sequences`, carrying values
+    /// from one step to the next. The body is built once into its own graph, so the size of this
+    /// graph doesn't depend on the sequence length, which can be dynamic.
+    ///
+    /// `body` gets the body graph, the current carries, this step's entry of each sequence and the
+    /// `constants`, and returns the new carries and this step's outputs.
+    ///
+    /// Returns the final carries and the outputs of every step, stacked along a new first dimension.
+    /// With `reverse` the steps run from the end of the sequences, with outputs still lined up with
+    /// the steps they came from.
+    /// ```rust
+    /// use luminal::prelude::*;
+    /// let mut cx = Graph::new();
+    /// let xs = cx.tensor(('s', 2)).set_dyn(vec![1., 2., 3., 4., 5., 6.], (3, 2));
+    /// let init = cx.constant(0.).expand(0, 2);
+    /// // Running sum over the sequence
+    /// let (total, sums) = cx.scan(&[init], &[xs], &[], false, |_, carries, steps, _| {
+    ///     let sum = carries[0] + steps[0];
+    ///     (vec![sum], vec![sum])
+    /// });
+    /// let (total, sums) = (total[0].retrieve(), sums[0].retrieve());
+    /// cx.execute();
+    /// assert_eq!(total.data(), vec![9., 12.]);
+    /// assert_eq!(sums.data(), vec![1., 2., 4., 6., 9., 12.]);
+    /// ```
+    #[allow(clippy::type_complexity)]
+    pub fn scan(
+        &mut self,
+        init: &[GraphTensor],
+        sequences: &[GraphTensor],
+        constants: &[GraphTensor],
+        reverse: bool,
+        body: impl FnOnce(
+            &mut Graph,
+            &[GraphTensor],
+            &[GraphTensor],
+            &[GraphTensor],
+        ) -> (Vec<GraphTensor>, Vec<GraphTensor>),
+    ) -> (Vec<GraphTensor>, Vec<GraphTensor>) {
+        assert!(
+            !sequences.is_empty(),
+            "Scan needs at least one sequence to step over"
+        );
+        let length = sequences[0].dims()[0];
+        fn named<'a>(
+            name: &'a str,
+            tensors: &'a [GraphTensor],
+            drop_first: bool,
+        ) -> impl Iterator<Item = (&'a str, Vec<Expression>)> + 'a {
+            tensors.iter().map(move |t| {
+                let mut dims = t.dims();
+                if drop_first {
+                    dims.remove(0);
+                }
+                (name, dims)
+            })
+        }
+        let inputs = named("Carry", init, false)
+            .chain(named("Step", sequences, true))
+            .chain(named("Constant", constants, false))
+            .collect();
+        let (carries, sequences_len) = (init.len(), sequences.len());
+        let body = NestedGraph::new(inputs, carries + sequences_len, |graph, inputs| {
+            let (carries, rest) = inputs.split_at(carries);
+            let (steps, consts) = rest.split_at(sequences_len);
+            let (new_carries, outputs) = body(graph, carries, steps, consts);
+            assert_eq!(
+                new_carries.len(),
+                carries.len(),
+                "Scan body must return a new value for each carry"
+            );
+            new_carries.into_iter().chain(outputs).collect()
+        });
+        let output_dims = init
+            .iter()
+            .map(|t| t.dims())
+            .chain(
+                body.result_dims()
+                    .into_iter()
+                    .skip(carries)
+                    .map(|mut dims| {
+                        dims.insert(0, length);
+                        dims
+                    }),
+            )
+            .collect();
+        let op = Scan {
+            body,
+            carries,
+            sequences: sequences_len,
+            reverse,
+            dyn_map: &self.dyn_map,
+        };
+        let inputs = init.iter().chain(sequences).chain(constants).copied();
+        let mut results = self.add_control_flow(op, inputs, output_dims);
+        let outputs = results.split_off(carries);
+        (results, outputs)
+    }
+
+    /// Run `then` if the first element of `condition` is nonzero, otherwise run `otherwise`. Each
+    /// branch is built into its own graph, gets the `inputs` and must return tensors of the same
+    /// shapes as the other.
+    /// ```rust
+    /// use luminal::prelude::*;
+    /// let mut cx = Graph::new();
+    /// let condition = cx.tensor(1).set(vec![0.]);
+    /// let a = cx.tensor(3).set(vec![1., 2., 3.]);
+    /// let out = cx.if_else(
+    ///     condition,
+    ///     &[a],
+    ///     |_, inputs| vec![inputs[0] * 2.],
+    ///     |_, inputs| vec![inputs[0] + 1.],
+    /// )[0]
+    /// .retrieve();
+    /// cx.execute();
+    /// assert_eq!(out.data(), vec![2., 3., 4.]);
+    /// ```
+    pub fn if_else(
+        &mut self,
+        condition: GraphTensor,
+        inputs: &[GraphTensor],
+        then: impl FnOnce(&mut Graph, &[GraphTensor]) -> Vec<GraphTensor>,
+        otherwise: impl FnOnce(&mut Graph, &[GraphTensor]) -> Vec<GraphTensor>,
+    ) -> Vec<GraphTensor> {
+        let named = || inputs.iter().map(|t| ("Input", t.dims())).collect_vec();
+        let then = NestedGraph::new(named(), inputs.len(), then);
+        let otherwise = NestedGraph::new(named(), inputs.len(), otherwise);
+        assert_eq!(
+            then.results.len(),
+            otherwise.results.len(),
+            "Both branches must return the same number of tensors"
+        );
+        let output_dims = then.result_dims();
+        assert_eq!(
+            output_dims,
+            otherwise.result_dims(),
+            "Both branches must return tensors of the same shapes"
+        );
+        let op = If {
+            then,
+            otherwise,
+            dyn_map: &self.dyn_map,
+        };
+        let inputs = std::iter::once(condition).chain(inputs.iter().copied());
+        self.add_control_flow(op, inputs, output_dims)
+    }
+
+    /// Run `body` on the state for as long as `cond` returns a tensor with a nonzero first element.
+    /// Both are built into their own graphs, and get the current state and the `constants`.
+    ///
+    /// `body` returns the new state. Any state dimension that's a single dynamic dimension can
+    /// change size each iteration, such as a growing sequence of tokens. The returned state gets a
+    /// new dynamic dimension in its place, set to the final size when the loop runs, so the
+    /// initial state keeps its own size.
+    /// ```rust
+    /// use luminal::prelude::*;
+    /// let mut cx = Graph::new();
+    /// let xs = cx.tensor('s').set_dyn(vec![1.], 1);
+    /// // Keep appending double the last value until there are 4 values
+    /// let out = cx.while_loop(
+    ///     &[xs],
+    ///     &[],
+    ///     |cx, state, _| cx.constant_expr('s').less_than(cx.constant(4.)),
+    ///     |_, state, _| {
+    ///         let s = state[0].dims1();
+    ///         let last = state[0].slice_along(s - 1.., 0).reshape(1);
+    ///         vec![state[0].concat_along(last * 2., 0)]
+    ///     },
+    /// )[0]
+    /// .retrieve();
+    /// cx.execute();
+    /// assert_eq!(out.data(), vec![1., 2., 4., 8.]);
+    /// ```
+    pub fn while_loop(
+        &mut self,
+        init: &[GraphTensor],
+        constants: &[GraphTensor],
+        cond: impl FnOnce(&mut Graph, &[GraphTensor], &[GraphTensor]) -> GraphTensor,
+        body: impl FnOnce(&mut Graph, &[GraphTensor], &[GraphTensor]) -> Vec<GraphTensor>,
+    ) -> Vec<GraphTensor> {
+        let named = || {
+            init.iter()
+                .map(|t| ("State", t.dims()))
+                .chain(constants.iter().map(|t| ("Constant", t.dims())))
+                .collect_vec()
+        };
+        let cond = NestedGraph::new(named(), init.len(), |graph, inputs| {
+            let (state, consts) = inputs.split_at(init.len());
+            vec![cond(graph, state, consts)]
+        });
+        let body = NestedGraph::new(named(), init.len(), |graph, inputs| {
+            let (state, consts) = inputs.split_at(init.len());
+            let new_state = body(graph, state, consts);
+            assert_eq!(
+                new_state.len(),
+                state.len(),
+                "While body must return a new value for each state"
+            );
+            new_state
+        });
+        let state = init.iter().map(|t| t.dims()).collect_vec();
+        let outputs = state
+            .iter()
+            .flatten()
+            .filter_map(|d| as_dyn_dim(*d))
+            .unique()
+            .map(|c| (c, fresh_dyn_dim()))
+            .collect_vec();
+        let output_dims = state
+            .iter()
+            .map(|dims| {
+                dims.iter()
+                    .map(|d| match as_dyn_dim(*d) {
+                        Some(c) => outputs.iter().find(|(i, _)| *i == c).unwrap().1.into(),
+                        None => *d,
+                    })
+                    .collect()
+            })
+            .collect();
+        let op = While {
+            cond,
+            body,
+            state,
+            outputs,
+            sizes: vec![],
+            dyn_map: &self.dyn_map,
+        };
+        let inputs = init.iter().chain(constants).copied();
+        self.add_control_flow(op, inputs, output_dims)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        prelude::*,
+        tests::{
+            assert_close,
+            device::{CopyFromDevice, CopyToDevice, DeviceCompiler},
+            random_vec_rng,
+        },
+    };
+    use rand::{rngs::StdRng, SeedableRng};
+
+    /// A tiny RNN step, h = tanh(x + h @ w), also counting steps
+    fn rnn_step(
+        _: &mut Graph,
+        carries: &[GraphTensor],
+        steps: &[GraphTensor],
+        consts: &[GraphTensor],
+    ) -> (Vec<GraphTensor>, Vec<GraphTensor>) {
+        let h = (steps[0] + carries[0].expand(0, 1).matmul(consts[0]).reshape(3)).tanh();
+        (vec![h, carries[1] + 1.], vec![h, carries[1]])
+    }
+
+    fn reference_rnn(xs: &[f32], w: &[f32], order: Vec<usize>) -> (Vec<f32>, Vec<f32>) {
+        let mut h = vec![0.; 3];
+        let mut hs = vec![vec![]; order.len()];
+        for t in order {
+            h = (0..3)
+                .map(|j| {
+                    let acc: f32 = (0..3).map(|i| h[i] * w[i * 3 + j]).sum();
+                    (xs[t * 3 + j] + acc).tanh()
+                })
+                .collect();
+            hs[t] = h.clone();
+        }
+        (h, hs.concat())
+    }
+
+    #[test]
+    fn test_scan() {
+        let mut cx = Graph::new();
+        let mut rng = StdRng::seed_from_u64(0);
+        let xs_data = random_vec_rng(5 * 3, &mut rng);
+        let w_data = random_vec_rng(3 * 3, &mut rng);
+        let xs = cx.tensor(('s', 3)).set_dyn(xs_data.clone(), (5, 3));
+        let w = cx.tensor((3, 3)).set(w_data.clone());
+        let init = cx.constant(0.).expand(0, 3);
+        let step = cx.constant(0.);
+        let nodes = cx.node_count();
+        let (fwd_final, fwd) = cx.scan(&[init, step], &[xs], &[w], false, rnn_step);
+        let (rev_final, rev) = cx.scan(&[init, step], &[xs], &[w], true, rnn_step);
+        // Each scan is a single node, plus one node per output
+        assert_eq!(cx.node_count(), nodes + 2 * 5);
+        let mut outputs = (
+            fwd_final[0].retrieve(),
+            fwd_final[1].retrieve(),
+            fwd[0].retrieve(),
+            fwd[1].retrieve(),
+            rev_final[0].retrieve(),
+            rev[0].retrieve(),
+        );
+        cx.compile(GenericCompiler::default(), &mut outputs);
+        cx.execute();
+
+        let (fwd_h, fwd_count, fwd_hs, fwd_steps, rev_h, rev_hs) = outputs;
+        let (h, hs) = reference_rnn(&xs_data, &w_data, (0..5).collect());
+        assert_close(&fwd_h.data(), &h);
+        assert_close(&fwd_hs.data(), &hs);
+        assert_eq!(fwd_count.data(), vec![5.]);
+        assert_eq!(fwd_steps.data(), vec![0., 1., 2., 3., 4.]);
+        let (h, hs) = reference_rnn(&xs_data, &w_data, (0..5).rev().collect());
+        assert_close(&rev_h.data(), &h);
+        assert_close(&rev_hs.data(), &hs);
+
+        // Run again with a different sequence length
+        xs.set_dyn(xs_data[..6].to_vec(), (2, 3));
+        for t in [fwd_h, fwd_count, fwd_hs, fwd_steps, rev_h, rev_hs] {
+            t.drop();
+        }
+        cx.execute();
+        let (h, hs) = reference_rnn(&xs_data, &w_data, (0..2).collect());
+        assert_close(&fwd_h.data(), &h);
+        assert_close(&fwd_hs.data(), &hs);
+        assert_eq!(fwd_steps.data(), vec![0., 1.]);
+    }
+
+    #[test]
+    fn test_if_else() {
+        let mut cx = Graph::new();
+        let condition = cx.tensor(1).set(vec![1.]);
+        let a = cx.tensor(3).set(vec![1., 2., 3.]);
+        let b = cx.tensor(3).set(vec![4., 5., 6.]);
+        let mut outputs = cx.if_else(
+            condition,
+            &[a, b],
+            |_, inputs| vec![inputs[0] + inputs[1], inputs[0].sum_reduce(0)],
+            |_, inputs| vec![inputs[0] * inputs[1], inputs[1].max_reduce(0)],
+        );
+        outputs.iter_mut().for_each(|t| *t = t.retrieve());
+        cx.compile(Nested::new(GenericCompiler::default()), &mut outputs);
+        cx.execute();
+        assert_eq!(outputs[0].data(), vec![5., 7., 9.]);
+        assert_eq!(outputs[1].data(), vec![6.]);
+
+        condition.set(vec![0.]);
+        outputs.iter().for_each(|t| t.drop());
+        cx.execute();
+        assert_eq!(outputs[0].data(), vec![4., 10., 18.]);
+        assert_eq!(outputs[1].data(), vec![6.]);
+    }
+
+    fn device_if_else(cx: &mut Graph) -> (GraphTensor, Vec<GraphTensor>) {
+        let condition = cx.tensor(1).set(vec![1.]);
+        let a = cx.tensor(3).set(vec![1., 2., 3.]);
+        let b = cx.tensor(3).set(vec![4., 5., 6.]);
+        // Ops on the device feed into and read from the control flow op
+        let outputs = cx.if_else(
+            condition,
+            &[a * 2., b],
+            |_, inputs| vec![inputs[0] + inputs[1]],
+            |_, inputs| vec![inputs[0] * inputs[1]],
+        );
+        (
+            condition,
+            vec![(outputs[0] + 1.).retrieve(), outputs[0].retrieve()],
+        )
+    }
+
+    #[test]
+    #[should_panic(expected = "same shapes")]
+    fn test_if_else_shape_mismatch() {
+        let mut cx = Graph::new();
+        let condition = cx.tensor(1).set(vec![1.]);
+        let a = cx.tensor(3).set(vec![1., 2., 3.]);
+        cx.if_else(
+            condition,
+            &[a],
+            |_, inputs| vec![inputs[0]],
+            |_, inputs| vec![inputs[0].sum_reduce(0)],
+        );
+    }
+
+    #[test]
+    fn test_nested_device_copies() {
+        let mut cx = Graph::new();
+        let (condition, mut outputs) = device_if_else(&mut cx);
+        cx.compile(
+            Nested::new(DeviceCompiler::<0>).with_copies(
+                || Box::new(CopyToDevice::<0>),
+                || Box::new(CopyFromDevice::<0>),
+            ),
+            &mut outputs,
+        );
+        cx.execute();
+        assert_eq!(outputs[0].data(), vec![7., 10., 13.]);
+        assert_eq!(outputs[1].data(), vec![6., 9., 12.]);
+
+        condition.set(vec![0.]);
+        outputs.iter().for_each(|t| t.drop());
+        cx.execute();
+        assert_eq!(outputs[0].data(), vec![9., 21., 37.]);
+        assert_eq!(outputs[1].data(), vec![8., 20., 36.]);
+    }
+
+    #[test]
+    #[should_panic(expected = "Nested::with_copies")]
+    fn test_nested_device_without_copies() {
+        let mut cx = Graph::new();
+        let (_, mut outputs) = device_if_else(&mut cx);
+        cx.compile(Nested::new(DeviceCompiler::<0>), &mut outputs);
+        cx.execute();
+    }
+
+    /// Greedily decode from a table of next token logits until the end token or the max length
+    fn reference_decode(table: &[f32], mut tokens: Vec<usize>, end: usize, max: usize) -> Vec<f32> {
+        while tokens.len() < max && *tokens.last().unwrap() != end {
+            let row = &table[tokens.last().unwrap() * 5..][..5];
+            let next = (0..5).max_by(|a, b| row[*a].total_cmp(&row[*b])).unwrap();
+            tokens.push(next);
+        }
+        tokens.into_iter().map(|t| t as f32).collect()
+    }
+
+    #[test]
+    fn test_while_loop() {
+        let mut cx = Graph::new();
+        let mut rng = StdRng::seed_from_u64(7);
+        let table_data = random_vec_rng(5 * 5, &mut rng);
+        let prompt = cx.tensor('s').set_dyn(vec![1., 3.], 2);
+        let table = cx.tensor((5, 5)).set(table_data.clone());
+        let (end, max) = (4., 8.);
+        let mut tokens = cx.while_loop(
+            &[prompt],
+            &[table],
+            |cx, state, _| {
+                let s = state[0].dims1();
+                let last = state[0].slice_along(s - 1.., 0).reshape(1);
+                let not_end = last.not_equals(cx.constant(end).expand(0, 1));
+                not_end
+                    * cx.constant_expr('s')
+                        .less_than(cx.constant(max))
+                        .expand(0, 1)
+            },
+            |_, state, consts| {
+                let s = state[0].dims1();
+                let last = state[0].slice_along(s - 1.., 0).reshape(1);
+                let next = consts[0].gather(last).argmax();
+                vec![state[0].concat_along(next, 0)]
+            },
+        )[0]
+        .retrieve();
+        // Work on the decoded tokens after the loop
+        let mut count = tokens.sum_reduce(0).retrieve();
+        // The prompt keeps its own size after the loop
+        let mut shifted = (prompt + count.expand(0, 's')).retrieve();
+        cx.compile(
+            Nested::new(GenericCompiler::default()),
+            (&mut tokens, &mut count, &mut shifted),
+        );
+        cx.execute();
+
+        let expected = reference_decode(&table_data, vec![1, 3], end as usize, max as usize);
+        let sum = expected.iter().sum::<f32>();
+        assert_eq!(tokens.data(), expected);
+        assert_eq!(count.data(), vec![sum]);
+        assert_eq!(shifted.data(), vec![1. + sum, 3. + sum]);
+        assert_eq!(cx.dyn_map[&'s'], 2);
+
+        // Decode again from a different prompt
+        prompt.set_dyn(vec![0.], 1);
+        tokens.drop();
+        count.drop();
+        shifted.drop();
+        cx.execute();
+        let expected = reference_decode(&table_data, vec![0], end as usize, max as usize);
+        assert_eq!(tokens.data(), expected);
+        assert_eq!(shifted.data(), vec![expected.iter().sum::<f32>()]);
+        assert_eq!(cx.dyn_map[&'s'], 1);
+    }
+}
@@ -0,0 +1,731 @@
+//! Whole graph optimization by [equality saturation](https://egraphs-good.github.io/).
+//!
+//! The primitive ops of a graph are lifted into an e-graph, algebraic rewrites are applied until
+//! nothing changes (or a limit is hit), and the cheapest equivalent graph is extracted.
+//!
+//! Movement in luminal lives on edges as shape trackers rather than in ops, so every op input is a
+//! `view` of a tensor through a shape tracker. Ops this doesn't know about (functions, backend ops,
+//! control flow) are kept as they are, as opaque nodes.
+
+use std::{fmt::Display, str::FromStr, time::Duration};
+
+use egg::{
+    define_language, rewrite, Analysis, Applier, CostFunction, DidMerge, Extractor, Id, Language,
+    PatternAst, Runner, Subst, Symbol, Var,
+};
+use itertools::Itertools;
+use petgraph::{algo::toposort, visit::EdgeRef, Direction};
+use rustc_hash::{FxHashMap, FxHashSet};
+
+use crate::{op, prelude::*};
+
+/// An f32 that can be hashed and ordered, written like `1.5f`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+struct Float(u32);
+
+impl Float {
+    fn get(self) -> f32 {
+        f32::from_bits(self.0)
+    }
+}
+
+impl Display for Float {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}f", self.get())
+    }
+}
+
+impl FromStr for Float {
+    type Err = ();
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let f = s
+            .strip_suffix('f')
+            .ok_or(())?
+            .parse::<f32>()
+            .map_err(|_| ())?;
+        Ok(Float(f.to_bits()))
+    }
+}
+
+/// An index into the shape table of the analysis, written like `shape3`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+struct ShapeRef(usize);
+
+impl Display for ShapeRef {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "shape{}", self.0)
+    }
+}
+
+impl FromStr for ShapeRef {
+    type Err = ();
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(ShapeRef(
+            s.strip_prefix("shape").ok_or(())?.parse().map_err(|_| ())?,
+        ))
+    }
+}
+
+define_language! {
+    enum GraphLang {
+        "const" = Const(Id),
+        // A tensor read through a shape
+        "view" = View([Id; 2]),
+        "contiguous" = Contiguous(Id),
+        "log2" = Log2(Id),
+        "exp2" = Exp2(Id),
+        "sin" = Sin(Id),
+        "recip" = Recip(Id),
+        "sqrt" = Sqrt(Id),
+        "+" = Add([Id; 2]),
+        "*" = Mul([Id; 2]),
+        "%" = Mod([Id; 2]),
+        "<" = LessThan([Id; 2]),
+        // Reductions take their axis first
+        "sum" = SumReduce([Id; 2]),
+        "max" = MaxReduce([Id; 2]),
+        // An op left as is, with its node index first
+        "opaque" = Opaque(Box<[Id]>),
+        Num(usize),
+        Float(Float),
+        Shape(ShapeRef),
+    }
+}
+
+type EGraph = egg::EGraph<GraphLang, GraphAnalysis>;
+type Rewrite = egg::Rewrite<GraphLang, GraphAnalysis>;
+
+/// Dynamic dimensions of unknown size are assumed to be this big when estimating costs
+const DEFAULT_DIM: usize = 16;
+
+#[derive(Default)]
+struct GraphAnalysis {
+    /// Shapes views read through, along with the output of the tensor they read
+    shapes: Vec<(u8, ShapeTracker)>,
+    /// Known dynamic dimensions, for estimating costs
+    dyn_map: FxHashMap<char, usize>,
+}
+
+impl GraphAnalysis {
+    fn intern(&mut self, output: u8, shape: ShapeTracker) -> ShapeRef {
+        let i = self
+            .shapes
+            .iter()
+            .position(|s| *s == (output, shape))
+            .unwrap_or_else(|| {
+                self.shapes.push((output, shape));
+                self.shapes.len() - 1
+            });
+        ShapeRef(i)
+    }
+
+    /// Estimated number of elements a shape reads
+    fn elements(&self, shape: &ShapeTracker) -> f64 {
+        let n = shape.n_elements();
+        let mut vars = self.dyn_map.clone();
+        for c in n.to_symbols() {
+            vars.entry(c).or_insert(DEFAULT_DIM);
+        }
+        n.exec(&vars).unwrap_or(DEFAULT_DIM) as f64
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+struct ClassData {
+    /// Every element is known to be >= 0
+    non_negative: bool,
+    /// Estimated number of elements, for views
+    elements: f64,
+}
+
+impl Analysis<GraphLang> for GraphAnalysis {
+    type Data = ClassData;
+
+    fn make(egraph: &EGraph, enode: &GraphLang) -> Self::Data {
+        let non_negative = |id: &Id| egraph[*id].data.non_negative;
+        match enode {
+            GraphLang::Float(f) => ClassData {
+                non_negative: f.get() >= 0.,
+                ..Default::default()
+            },
+            GraphLang::Shape(s) => ClassData {
+                non_negative: false,
+                elements: egraph.analysis.elements(&egraph.analysis.shapes[s.0].1),
+            },
+            // Padding is with zeros, so keeps values non-negative
+            GraphLang::View([x, s]) => ClassData {
+                non_negative: non_negative(x),
+                elements: egraph[*s].data.elements,
+            },
+            GraphLang::Exp2(_) | GraphLang::Sqrt(_) | GraphLang::LessThan(_) => ClassData {
+                non_negative: true,
+                ..Default::default()
+            },
+            GraphLang::Const(a)
+            | GraphLang::Contiguous(a)
+            | GraphLang::Recip(a)
+            | GraphLang::SumReduce([_, a])
+            | GraphLang::MaxReduce([_, a]) => ClassData {
+                non_negative: non_negative(a),
+                ..Default::default()
+            },
+            GraphLang::Add([a, b]) | GraphLang::Mul([a, b]) => ClassData {
+                non_negative: (non_negative(a) && non_negative(b))
+                    || matches!(enode, GraphLang::Mul(_)) && a == b,
+                ..Default::default()
+            },
+            _ => ClassData::default(),
+        }
+    }
+
+    fn merge(&mut self, a: &mut Self::Data, b: Self::Data) -> DidMerge {
+        let before = a.non_negative;
+        a.non_negative |= b.non_negative;
+        if a.elements == 0. {
+            a.elements = b.elements;
+        }
+        DidMerge(a.non_negative != before, a.non_negative != b.non_negative)
+    }
+}
+
+fn shape(egraph: &EGraph, id: Id) -> (u8, ShapeTracker) {
+    egraph[id]
+        .nodes
+        .iter()
+        .find_map(|n| match n {
+            GraphLang::Shape(s) => Some(egraph.analysis.shapes[s.0]),
+            _ => None,
+        })
+        .unwrap()
+}
+
+fn num(egraph: &EGraph, id: Id) -> usize {
+    egraph[id]
+        .nodes
+        .iter()
+        .find_map(|n| match n {
+            GraphLang::Num(n) => Some(*n),
+            _ => None,
+        })
+        .unwrap()
+}
+
+/// The shape a view class reads through
+fn view_shape(egraph: &EGraph, id: Id) -> Option<(u8, ShapeTracker)> {
+    egraph[id].nodes.iter().find_map(|n| match n {
+        GraphLang::View([_, s]) => Some(shape(egraph, *s)),
+        _ => None,
+    })
+}
+
+/// A view reading output 0 of a tensor exactly as it's laid out in memory
+fn natural(var: &str) -> impl Fn(&mut EGraph, Id, &Subst) -> bool {
+    let var = var.parse().unwrap();
+    move |egraph, _, subst| {
+        let (output, shape) = shape(egraph, subst[var]);
+        output == 0 && !shape.is_reshaped()
+    }
+}
+
+fn non_negative(var: &str) -> impl Fn(&mut EGraph, Id, &Subst) -> bool {
+    let var = var.parse().unwrap();
+    move |egraph, _, subst| egraph[subst[var]].data.non_negative
+}
+
+/// A natural shape with the same dimensions as a view, so not a reshape of it
+fn same_dims(view: &str, shape_var: &str) -> impl Fn(&mut EGraph, Id, &Subst) -> bool {
+    let (view, shape_var) = (view.parse().unwrap(), shape_var.parse().unwrap());
+    move |egraph, _, subst| {
+        let (output, t) = shape(egraph, subst[shape_var]);
+        let Some((_, s)) = view_shape(egraph, subst[view]) else {
+            return false;
+        };
+        output == 0 && !t.is_reshaped() && t.dims() == s.dims()
+    }
+}
+
+/// `(view (contiguous (view ?x ?s)) ?t)` => `(view ?x s.t)`, cancelling a permute and copy
+struct ComposeViews {
+    x: Var,
+    s: Var,
+    t: Var,
+}
+
+impl Applier<GraphLang, GraphAnalysis> for ComposeViews {
+    fn apply_one(
+        &self,
+        egraph: &mut EGraph,
+        eclass: Id,
+        subst: &Subst,
+        _: Option<&PatternAst<GraphLang>>,
+        _: Symbol,
+    ) -> Vec<Id> {
+        let ((output, s), (t_output, t)) =
+            (shape(egraph, subst[self.s]), shape(egraph, subst[self.t]));
+        let Some(n) = s.compose(&t).filter(|_| t_output == 0) else {
+            return vec![];
+        };
+        let n = egraph.analysis.intern(output, n);
+        let n = egraph.add(GraphLang::Shape(n));
+        let view = egraph.add(GraphLang::View([subst[self.x], n]));
+        if egraph.union(eclass, view) {
+            vec![view]
+        } else {
+            vec![]
+        }
+    }
+}
+
+/// `(sum ?j (view (sum ?i ?v) ?t))` => the same reductions the other way around, so the larger
+/// axis can be reduced first
+struct SwapReductions {
+    max: bool,
+    i: Var,
+    j: Var,
+    v: Var,
+    t: Var,
+}
+
+impl Applier<GraphLang, GraphAnalysis> for SwapReductions {
+    fn apply_one(
+        &self,
+        egraph: &mut EGraph,
+        eclass: Id,
+        subst: &Subst,
+        _: Option<&PatternAst<GraphLang>>,
+        _: Symbol,
+    ) -> Vec<Id> {
+        let (i, j) = (num(egraph, subst[self.i]), num(egraph, subst[self.j]));
+        let (t_output, t) = shape(egraph, subst[self.t]);
+        let Some((_, s)) = view_shape(egraph, subst[self.v]) else {
+            return vec![];
+        };
+        let mut inner_dims = s.dims();
+        inner_dims.remove(i);
+        // The inner reduction's output must be read as is
+        if t_output != 0 || t.is_reshaped() || t.dims() != inner_dims {
+            return vec![];
+        }
+        // Axes in terms of the original input, then the outer axis after the inner is removed
+        let new_inner = if j < i { j } else { j + 1 };
+        let new_outer = if i < new_inner { i } else { i - 1 };
+        let mut dims = s.dims();
+        dims.remove(new_inner);
+        let reduce = |axis, view| {
+            if self.max {
+                GraphLang::MaxReduce([axis, view])
+            } else {
+                GraphLang::SumReduce([axis, view])
+            }
+        };
+        let new_inner = egraph.add(GraphLang::Num(new_inner));
+        let inner = egraph.add(reduce(new_inner, subst[self.v]));
+        let shape = egraph.analysis.intern(0, ShapeTracker::new(dims));
+        let shape = egraph.add(GraphLang::Shape(shape));
+        let view = egraph.add(GraphLang::View([inner, shape]));
+        let new_outer = egraph.add(GraphLang::Num(new_outer));
+        let outer = egraph.add(reduce(new_outer, view));
+        if egraph.union(eclass, outer) {
+            vec![outer]
+        } else {
+            vec![]
+        }
+    }
+}
+
+fn rules() -> Vec<Rewrite> {
+    let swap = |max| SwapReductions {
+        max,
+        i: "?i".parse().unwrap(),
+        j: "?j".parse().unwrap(),
+        v: "?v".parse().unwrap(),
+        t: "?t".parse().unwrap(),
+    };
+    vec![
+        // Identities
+        rewrite!("add-zero"; "(+ (view ?x ?s) (view (const 0f) ?t))" => "?x" if natural("?s")),
+        rewrite!("zero-add"; "(+ (view (const 0f) ?t) (view ?x ?s))" => "?x" if natural("?s")),
+        rewrite!("mul-one"; "(* (view ?x ?s) (view (const 1f) ?t))" => "?x" if natural("?s")),
+        rewrite!("one-mul"; "(* (view (const 1f) ?t) (view ?x ?s))" => "?x" if natural("?s")),
+        // Inverses
+        rewrite!("log2-exp2"; "(log2 (view (exp2 ?v) ?t))" => "(contiguous ?v)" if natural("?t")),
+        rewrite!("exp2-log2"; "(exp2 (view (log2 ?v) ?t))" => "(contiguous ?v)"
+            if natural("?t") if non_negative("?v")),
+        rewrite!("recip-recip"; "(recip (view (recip ?v) ?t))" => "(contiguous ?v)"
+            if natural("?t")),
+        // Movement
+        rewrite!("contiguous-natural"; "(contiguous (view ?x ?s))" => "?x" if natural("?s")),
+        rewrite!("view-contiguous"; "(view (contiguous ?v) ?t)" => "?v" if same_dims("?v", "?t")),
+        rewrite!("view-permuted"; "(view (contiguous (view ?x ?s)) ?t)" => {
+            ComposeViews {
+                x: "?x".parse().unwrap(),
+                s: "?s".parse().unwrap(),
+                t: "?t".parse().unwrap(),
+            }
+        }),
+        // Reductions
+        rewrite!("swap-sums"; "(sum ?j (view (sum ?i ?v) ?t))" => { swap(false) }),
+        rewrite!("swap-maxes"; "(max ?j (view (max ?i ?v) ?t))" => { swap(true) }),
+    ]
+}
+
+/// The number of elements each op processes
+struct Cost<'a> {
+    egraph: &'a EGraph,
+}
+
+impl CostFunction<GraphLang> for Cost<'_> {
+    type Cost = f64;
+
+    fn cost<C>(&mut self, enode: &GraphLang, mut costs: C) -> Self::Cost
+    where
+        C: FnMut(Id) -> Self::Cost,
+    {
+        let children = enode.fold(0., |sum, id| sum + costs(id));
+        let work = match enode {
+            GraphLang::Num(_) | GraphLang::Float(_) | GraphLang::Shape(_) => return 0.,
+            GraphLang::View(_) | GraphLang::Const(_) | GraphLang::Opaque(_) => 0.,
+            _ => enode
+                .children()
+                .iter()
+                .map(|c| self.egraph[*c].data.elements)
+                .fold(0., f64::max),
+        };
+        children + work + 1.
+    }
+}
+
+/// Whole graph algebraic optimization with [egg](https://egraphs-good.github.io/), covering
+/// - Identities: x + 0 => x, x * 1 => x
+/// - Inverses: log2(exp2(x)) => x, exp2(log2(x)) => x for x >= 0, 1 / (1 / x) => x
+/// - Movement: copies that don't move anything, and permutes undone after a copy
+/// - Reductions: reducing the larger axis first when reducing two in a row
+///
+/// The cheapest equivalent graph is picked by counting the elements each op processes, assuming
+/// unknown dynamic dimensions are 16.
+#[derive(Debug, Clone)]
+pub struct EqualitySaturation {
+    pub iter_limit: usize,
+    pub time_limit: Duration,
+}
+
+impl Default for EqualitySaturation {
+    fn default() -> Self {
+        Self {
+            iter_limit: 16,
+            time_limit: Duration::from_secs(5),
+        }
+    }
+}
+
+impl Compiler for EqualitySaturation {
+    type Output = ();
+    fn compile<T: ToIdsMut>(&self, graph: &mut Graph, mut ids: T) {
+        // Lift the graph into an e-graph
+        let mut egraph = EGraph::new(GraphAnalysis {
+            dyn_map: graph.dyn_map.clone(),
+            ..Default::default()
+        });
+        let mut classes = FxHashMap::default();
+        let (mut lifted, mut opaque) = (vec![], vec![]);
+        for node in toposort(&graph.graph, None).unwrap() {
+            let views = graph
+                .get_sources(node)
+                .into_iter()
+                .map(|(src, output, shape)| {
+                    let shape = egraph.analysis.intern(output, shape);
+                    let shape = egraph.add(GraphLang::Shape(shape));
+                    egraph.add(GraphLang::View([classes[&src], shape]))
+                })
+                .collect_vec();
+            let enode = match lift(
+                graph.graph.node_weight(node).unwrap().as_ref(),
+                &views,
+                &mut egraph,
+            ) {
+                Some(enode) => {
+                    lifted.push(node);
+                    enode
+                }
+                None => {
+                    opaque.push(node);
+                    let index = egraph.add(GraphLang::Num(node.index()));
+                    GraphLang::Opaque([index].into_iter().chain(views).collect())
+                }
+            };
+            classes.insert(node, egraph.add(enode));
+        }
+        let n_nodes = egraph.total_number_of_nodes();
+
+        let runner = Runner::default()
+            .with_egraph(egraph)
+            .with_iter_limit(self.iter_limit)
+            .with_time_limit(self.time_limit)
+            .with_node_limit(10_000.max(n_nodes * 4))
+            .run(&rules());
+        let egraph = runner.egraph;
+        let extractor = Extractor::new(&egraph, Cost { egraph: &egraph });
+
+        // Extract everything something outside the e-graph can see
+        let roots = ids
+            .to_ids_mut()
+            .into_iter()
+            .map(|id| *id)
+            .chain(graph.no_delete.iter().copied())
+            .chain(graph.to_retrieve.keys().copied())
+            .chain(opaque.iter().copied())
+            .filter(|n| classes.contains_key(n))
+            .collect::<FxHashSet<_>>();
+        let mut built = FxHashMap::default();
+        for root in roots.iter().sorted() {
+            build(classes[root], &egraph, &extractor, graph, &mut built);
+        }
+
+        // Point everything at the extracted nodes and remove the old ones
+        for (node, class) in &classes {
+            if let Some(new) = built.get(&egraph.find(*class)) {
+                if new != node {
+                    remap(*node, *new, &mut ids, graph);
+                }
+            }
+        }
+        for node in lifted {
+            graph.graph.remove_node(node);
+        }
+    }
+}
+
+/// The e-node for a primitive op, if it is one
+fn lift(op: &dyn Operator, views: &[Id], egraph: &mut EGraph) -> Option<GraphLang> {
+    let op = op.as_any();
+    Some(if op.is::<op::Contiguous>() {
+        GraphLang::Contiguous(views[0])
+    } else if op.is::<op::Log2>() {
+        GraphLang::Log2(views[0])
+    } else if op.is::<op::Exp2>() {
+        GraphLang::Exp2(views[0])
+    } else if op.is::<op::Sin>() {
+        GraphLang::Sin(views[0])
+    } else if op.is::<op::Recip>() {
+        GraphLang::Recip(views[0])
+    } else if op.is::<op::Sqrt>() {
+        GraphLang::Sqrt(views[0])
+    } else if op.is::<op::Add>() {
+        GraphLang::Add([views[0], views[1]])
+    } else if op.is::<op::Mul>() {
+        GraphLang::Mul([views[0], views[1]])
+    } else if op.is::<op::Mod>() {
+        GraphLang::Mod([views[0], views[1]])
+    } else if op.is::<op::LessThan>() {
+        GraphLang::LessThan([views[0], views[1]])
+    } else if let Some(op::SumReduce(axis)) = op.downcast_ref() {
+        GraphLang::SumReduce([egraph.add(GraphLang::Num(*axis)), views[0]])
+    } else if let Some(op::MaxReduce(axis)) = op.downcast_ref() {
+        GraphLang::MaxReduce([egraph.add(GraphLang::Num(*axis)), views[0]])
+    } else if let Some(op::Constant(ConstantValue::Float(f), _)) = op.downcast_ref() {
+        GraphLang::Const(egraph.add(GraphLang::Float(Float(f.to_bits()))))
+    } else {
+        return None;
+    })
+}
+
+/// Build the cheapest node of a class into the graph, along with everything it depends on
+fn build(
+    class: Id,
+    egraph: &EGraph,
+    extractor: &Extractor<Cost, GraphLang, GraphAnalysis>,
+    graph: &mut Graph,
+    built: &mut FxHashMap<Id, NodeIndex>,
+) -> NodeIndex {
+    let class = egraph.find(class);
+    if let Some(node) = built.get(&class) {
+        return *node;
+    }
+    let enode = extractor.find_best_node(class).clone();
+    // Each input is a view of another node
+    let views = match &enode {
+        GraphLang::Const(_) => &[][..],
+        GraphLang::Opaque(c) => &c[1..],
+        GraphLang::SumReduce([_, v]) | GraphLang::MaxReduce([_, v]) => std::slice::from_ref(v),
+        _ => enode.children(),
+    };
+    let mut inputs = vec![];
+    for view in views {
+        let GraphLang::View([x, s]) = extractor.find_best_node(*view).clone() else {
+            unreachable!("Op inputs are always views")
+        };
+        let (output, shape) = shape(egraph, s);
+        inputs.push((build(x, egraph, extractor, graph, built), output, shape));
+    }
+    let node = match enode {
+        GraphLang::Opaque(c) => {
+            // Keep the original node, with its new inputs
+            let node = NodeIndex::new(num(egraph, c[0]));
+            let old = graph
+                .graph
+                .edges_directed(node, Direction::Incoming)
+                .filter(|e| !e.weight().is_schedule())
+                .map(|e| e.id())
+                .collect_vec();
+            for edge in old {
+                graph.graph.remove_edge(edge);
+            }
+            for (i, (src, output, shape)) in inputs.into_iter().enumerate() {
+                graph.graph.add_edge(
+                    src,
+                    node,
+                    Dependency::Data {
+                        input_order: i as u8,
+                        output_order: output,
+                        shape,
+                    },
+                );
+            }
+            node
+        }
+        enode => {
+            let op: Box<dyn Operator> = match enode {
+                GraphLang::Const(f) => {
+                    let f = egraph[f]
+                        .nodes
+                        .iter()
+                        .find_map(|n| match n {
+                            GraphLang::Float(f) => Some(f.get()),
+                            _ => None,
+                        })
+                        .unwrap();
+                    Box::new(op::Constant(ConstantValue::Float(f), &graph.dyn_map))
+                }
+                GraphLang::Contiguous(_) => Box::new(op::Contiguous),
+                GraphLang::Log2(_) => Box::new(op::Log2),
+                GraphLang::Exp2(_) => Box::new(op::Exp2),
+                GraphLang::Sin(_) => Box::new(op::Sin),
+                GraphLang::Recip(_) => Box::new(op::Recip),
+                GraphLang::Sqrt(_) => Box::new(op::Sqrt),
+                GraphLang::Add(_) => Box::new(op::Add),
+                GraphLang::Mul(_) => Box::new(op::Mul),
+                GraphLang::Mod(_) => Box::new(op::Mod),
+                GraphLang::LessThan(_) => Box::new(op::LessThan),
+                GraphLang::SumReduce([a, _]) => Box::new(op::SumReduce(num(egraph, a))),
+                GraphLang::MaxReduce([a, _]) => Box::new(op::MaxReduce(num(egraph, a))),
+                _ => unreachable!("Only ops are built"),
+            };
+            let node = graph.graph.add_node(op);
+            for (i, (src, output, shape)) in inputs.into_iter().enumerate() {
+                graph.graph.add_edge(
+                    src,
+                    node,
+                    Dependency::Data {
+                        input_order: i as u8,
+                        output_order: output,
+                        shape,
+                    },
+                );
+            }
+            node
+        }
+    };
+    built.insert(class, node);
+    node
+}
+
+#[cfg(test)]
+mod tests {
+    use super::EqualitySaturation;
+    use crate::{
+        op::{Contiguous, Exp2, Log2, Recip, SumReduce},
+        prelude::*,
+        tests::{
+            assert_close, assert_exact,
+            fuzz::fuzz_compilers,
+            harness::{test_compilers_close, TestCompiler},
+            random_vec, test_graphs,
+        },
+    };
+
+    fn count<T: Operator + 'static>(cx: &Graph) -> usize {
+        cx.node_indices()
+            .filter(|n| cx.check_node_type::<T>(*n))
+            .count()
+    }
+
+    #[test]
+    fn test_identities_and_inverses() {
+        let mut cx = Graph::new();
+        let data = random_vec(6);
+        let a = cx.tensor((2, 3)).set(data.clone());
+        let zero = cx.constant(0.).expand_to(a.shape);
+        let one = cx.constant(1.).expand_to(a.shape);
+        let b = ((a + zero) * one)
+            .exp2()
+            .log2()
+            .exp2()
+            .log2()
+            .recip()
+            .recip();
+        let mut b = b.retrieve();
+
+        cx.compile(EqualitySaturation::default(), &mut b);
+        assert_eq!(
+            count::<Log2>(&cx) + count::<Exp2>(&cx) + count::<Recip>(&cx),
+            0
+        );
+        cx.execute();
+        assert_close(&b.data(), &data);
+    }
+
+    #[test]
+    fn test_exp2_log2_needs_non_negative() {
+        let mut cx = Graph::new();
+        let a = cx.tensor(4).set(vec![-1., 0., 1., 2.]);
+        let mut b = a.log2().exp2().retrieve();
+        let mut c = a.exp2().log2().exp2().retrieve();
+        cx.compile(EqualitySaturation::default(), (&mut b, &mut c));
+        // exp2(log2(x)) stays for x that could be negative, but not for exp2(y)
+        assert_eq!(count::<Log2>(&cx), 1);
+        cx.execute();
+        assert!(b.data()[0].is_nan());
+        assert_close(&c.data(), &[0.5, 1., 2., 4.]);
+    }
+
+    #[test]
+    fn test_movement_cancellation() {
+        let mut cx = Graph::new();
+        let data = random_vec(6);
+        let a = cx.tensor((2, 3)).set(data.clone());
+        // A permute made contiguous then permuted back needs no copy
+        let mut b = (a.permute((1, 0)).contiguous().permute((1, 0)) + 1.).retrieve();
+        cx.compile(EqualitySaturation::default(), &mut b);
+        assert_eq!(count::<Contiguous>(&cx), 0);
+        cx.execute();
+        assert_close(&b.data(), &data.iter().map(|x| x + 1.).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_reduction_reassociation() {
+        let mut cx = Graph::new();
+        let a = cx.tensor((2, 64)).set(vec![1.; 128]);
+        let mut b = a.sum_reduce(0).sum_reduce(0).retrieve();
+        cx.compile(EqualitySaturation::default(), &mut b);
+        // The larger axis gets reduced first
+        let first = cx
+            .node_indices()
+            .find(|n| cx.check_node_type::<SumReduce>(*n) && cx.get_sources(*n)[0].0 == a.id)
+            .unwrap();
+        assert_eq!(cx.get_op::<SumReduce>(first).0, 1);
+        cx.execute();
+        assert_exact(&b.data(), &[128.]);
+    }
+
+    #[test]
+    fn test_equality_saturation_graphs() {
+        test_compilers_close(
+            test_graphs::ALL,
+            &[
+                TestCompiler::new(EqualitySaturation::default()),
+                TestCompiler::new((GenericCompiler::default(), EqualitySaturation::default())),
+            ],
+        );
+        fuzz_compilers(&[TestCompiler::new(EqualitySaturation::default())], 40, 0);
+    }
+}
@@ -1,4 +1,8 @@
-use std::collections::{HashMap, HashSet};
+use std::{
+    any::Any,
+    collections::{HashMap, HashSet},
+    hash::{Hash, Hasher},
+};
 
 use itertools::Itertools;
 use petgraph::{
@@ -7,9 +11,13 @@ use petgraph::{
     visit::EdgeRef,
     Direction,
 };
+use rustc_hash::FxHasher;
 
 use crate::{
-    op::{Add, Constant, ConstantValue, Function, MaxReduce, Mul, Operator, Recip, SumReduce},
+    op::{
+        Add, Constant, ConstantValue, Exp2, Function, Log2, MaxReduce, Mul, Operator, Recip,
+        SumReduce,
+    },
     prelude::*,
 };
 
@@ -17,7 +25,9 @@ use crate::{
 pub type GenericCompiler = (
     //RemoveSingleReductions,
     RemoveUnusedNodes,
+    ConstantFolding,
     ArithmeticElimination,
+    ContiguousElimination,
     CSE,
 );
 
@@ -28,74 +38,28 @@ pub struct CSE;
 impl Compiler for CSE {
     type Output = ();
     fn compile<T: ToIdsMut>(&self, graph: &mut Graph, mut ids: T) {
-        // Look for nodes that have the exact same srcs
-        // Loop cause I'm lazy
-        let mut eliminated = true;
-        while eliminated {
-            eliminated = false;
-            let mut srcs_set: HashMap<Vec<NodeIndex>, Vec<NodeIndex>> = HashMap::new();
-            for node in graph.graph.node_indices().collect_vec() {
-                if graph
-                    .graph
-                    .node_weight(node)
-                    .unwrap()
-                    .as_any()
-                    .is::<Function>()
-                {
-                    continue;
-                }
-                let srcs = graph
-                    .graph
-                    .edges_directed(node, petgraph::Direction::Incoming)
-                    .filter(|e| !e.weight().is_schedule())
-                    .sorted_by_key(|e| e.weight().as_data().unwrap().0)
-                    .map(|e| e.source())
-                    .collect_vec();
-
-                if let Some(other_nodes) = srcs_set.get(&srcs) {
-                    for other_node in other_nodes {
-                        let a = graph.graph.node_weight(node).unwrap();
-                        let Some(b) = graph.graph.node_weight(*other_node) else {
-                            continue;
-                        };
-                        if format!("{a:?}") != format!("{b:?}") {
-                            // Sloppy way to check if ops are equal, but we only expect primops here so it's ok
-                            continue;
-                        }
-                        let a_src_shapes = graph
-                            .get_sources(node)
-                            .into_iter()
-                            .map(|(_, _, a)| a)
-                            .collect_vec();
-                        let b_src_shapes = graph
-                            .get_sources(*other_node)
-                            .into_iter()
-                            .map(|(_, _, a)| a)
-                            .collect_vec();
-                        if a_src_shapes != b_src_shapes {
-                            continue;
-                        }
-                        // If the op, input shapes, and output shape is the same, we can combine them (UNCLEAR IF THIS IS TRUE, NEED PROPER PartialEq)
-                        // Carry over outgoing edges from node to other_node
-                        move_outgoing_edge(node, *other_node, &mut graph.graph);
-                        // Transfer all references to node over to other node
-                        remap(node, *other_node, &mut ids, graph);
-                        // Remove node
-                        graph.graph.remove_node(node);
-                        eliminated = true;
-                        break;
-                    }
-                    if eliminated {
-                        break;
-                    }
-                }
-                if let Some(nodes) = srcs_set.get_mut(&srcs) {
-                    nodes.push(node);
-                } else {
-                    srcs_set.insert(srcs, vec![node]);
-                }
-            }
-            srcs_set.clear();
+        // Hash-cons nodes in topological order, so a node's sources are already deduplicated by the
+        // time it's visited and one pass is enough
+        let mut seen: HashMap<u64, Vec<NodeIndex>> = HashMap::new();
+        for node in toposort(&graph.graph, None).unwrap() {
+            let srcs = graph.get_sources(node);
+            let op = graph.graph.node_weight(node).unwrap();
+            let mut hasher = FxHasher::default();
+            op.as_any().type_id().hash(&mut hasher);
+            srcs.hash(&mut hasher);
+            op.hash_op(&mut hasher);
+            let bucket = seen.entry(hasher.finish()).or_default();
+            let Some(existing) = bucket.iter().copied().find(|other| {
+                op.equals(graph.graph.node_weight(*other).unwrap().as_ref())
+                    && graph.get_sources(*other) == srcs
+            }) else {
+                bucket.push(node);
+                continue;
+            };
+            // Same op on the same inputs, so existing computes the same outputs as node
+            move_outgoing_edge(node, existing, &mut graph.graph);
+            remap(node, existing, &mut ids, graph);
+            graph.graph.remove_node(node);
         }
     }
 }
@@ -157,6 +121,234 @@ impl Compiler for RemoveSingleReductions {
     }
 }
 
+/// Evaluate subgraphs that only depend on constants at compile time, and replace them with their
+/// result so they aren't recomputed every run. Only primitive ops with static shapes are folded.
+///
+/// Tensors that never change, like weights, can be folded with [`ConstantFolding::with_static`].
+/// They need to be set before compiling, and are removed if folding leaves them unused.
+#[derive(Default, Debug)]
+pub struct ConstantFolding {
+    static_tensors: HashSet<NodeIndex>,
+}
+
+impl ConstantFolding {
+    /// Also fold computation on these tensors
+    pub fn with_static<T: ToIds>(tensors: T) -> Self {
+        Self {
+            static_tensors: tensors.to_ids().into_iter().collect(),
+        }
+    }
+}
+
+impl Compiler for ConstantFolding {
+    type Output = ();
+    fn compile<T: ToIdsMut>(&self, graph: &mut Graph, mut ids: T) {
+        let order = toposort(&graph.graph, None).unwrap();
+        // Find every node whose value is known at compile time
+        let (mut known, mut computed) = (HashSet::new(), vec![]);
+        for &node in &order {
+            let op = graph.graph.node_weight(node).unwrap().as_any();
+            let sources = graph.get_sources(node);
+            if sources.is_empty() {
+                let constant = match op.downcast_ref::<Constant>() {
+                    Some(Constant(ConstantValue::Float(_), _)) => true,
+                    Some(Constant(ConstantValue::Expression(e), _)) => e.to_usize().is_some(),
+                    None => op.is::<Function>() && self.static_tensors.contains(&node),
+                };
+                if constant {
+                    known.insert(node);
+                }
+            } else if is_primitive(op)
+                && sources
+                    .iter()
+                    .all(|(n, _, sh)| known.contains(n) && is_static(sh))
+            {
+                known.insert(node);
+                computed.push(node);
+            }
+        }
+
+        // Fold the computed nodes whose values are needed elsewhere
+        let external = ids
+            .to_ids_mut()
+            .into_iter()
+            .map(|i| *i)
+            .collect::<HashSet<_>>();
+        let folded = computed
+            .into_iter()
+            .filter(|n| {
+                external.contains(n)
+                    || graph.no_delete.contains(n)
+                    || graph.to_retrieve.contains_key(n)
+                    || graph
+                        .graph
+                        .edges_directed(*n, Direction::Outgoing)
+                        .any(|e| !known.contains(&e.target()))
+            })
+            .collect::<HashSet<_>>();
+        if folded.is_empty() {
+            return;
+        }
+        let mut needed = folded.clone();
+        for &node in order.iter().rev() {
+            if needed.contains(&node) {
+                needed.extend(graph.get_sources(node).into_iter().map(|(n, _, _)| n));
+            }
+        }
+
+        // Run the folded subgraphs
+        let mut values = HashMap::new();
+        for &node in order.iter().filter(|n| needed.contains(n)) {
+            let inputs = graph
+                .get_sources(node)
+                .into_iter()
+                .map(|(n, o, sh)| (InputTensor::Borrowed(&values[&(n, o)]), sh))
+                .collect();
+            let outputs = graph.graph.node_weight_mut(node).unwrap().process(inputs);
+            for (i, output) in outputs.into_iter().enumerate() {
+                values.insert((node, i as u8), output);
+            }
+        }
+
+        // Replace them with their results
+        for &node in order.iter().filter(|n| folded.contains(n)) {
+            let value = values.remove(&(node, 0)).unwrap();
+            let new = match value.downcast_ref::<Vec<f32>>() {
+                Some(v) if v.len() == 1 => graph
+                    .add_op(Constant(ConstantValue::Float(v[0]), &graph.dyn_map))
+                    .finish(),
+                _ => graph
+                    .add_op(Function(
+                        "Folded Constant".to_string(),
+                        Box::new(move |_| vec![value.clone()]),
+                    ))
+                    .finish(),
+            };
+            for (edge, target, weight) in graph
+                .graph
+                .edges_directed(node, Direction::Outgoing)
+                .map(|e| (e.id(), e.target(), *e.weight()))
+                .collect_vec()
+            {
+                graph.graph.remove_edge(edge);
+                graph.graph.add_edge(new, target, weight);
+            }
+            remap(node, new, &mut ids, graph);
+        }
+
+        // Remove what folding left unused
+        let external = ids
+            .to_ids_mut()
+            .into_iter()
+            .map(|i| *i)
+            .collect::<HashSet<_>>();
+        for &node in order.iter().rev().filter(|n| needed.contains(n)) {
+            if graph
+                .graph
+                .edges_directed(node, Direction::Outgoing)
+                .all(|e| e.weight().is_schedule())
+                && !graph.no_delete.contains(&node)
+                && !graph.to_retrieve.contains_key(&node)
+                && !external.contains(&node)
+            {
+                graph.graph.remove_node(node);
+            }
+        }
+    }
+}
+
+/// Ops that can be run at compile time
+fn is_primitive(op: &dyn Any) -> bool {
+    op.is::<Contiguous>()
+        || op.is::<Log2>()
+        || op.is::<Exp2>()
+        || op.is::<Sin>()
+        || op.is::<Recip>()
+        || op.is::<Sqrt>()
+        || op.is::<Add>()
+        || op.is::<Mul>()
+        || op.is::<Mod>()
+        || op.is::<LessThan>()
+        || op.is::<SumReduce>()
+        || op.is::<MaxReduce>()
+}
+
+/// A shape with no dynamic dimensions
+fn is_static(shape: &ShapeTracker) -> bool {
+    shape
+        .dims
+        .iter()
+        .chain(shape.mask.iter().flat_map(|(a, b)| [a, b]))
+        .chain(shape.padding.iter().flat_map(|(a, b)| [a, b]))
+        .all(|e| e.to_usize().is_some())
+}
+
+/// Remove copies that don't move anything, by reading their input directly:
+/// - Contiguous ops whose input is already contiguous
+/// - Contiguous ops after a permute, when every consumer can read the permuted input instead.
+///   The two views are merged into one [`ShapeTracker`]
+///
+/// Outputs how many copies were eliminated
+#[derive(Default, Debug)]
+pub struct ContiguousElimination;
+
+impl Compiler for ContiguousElimination {
+    type Output = usize;
+    fn compile<T: ToIdsMut>(&self, graph: &mut Graph, mut ids: T) -> usize {
+        let external = ids
+            .to_ids_mut()
+            .into_iter()
+            .map(|i| *i)
+            .collect::<HashSet<_>>();
+        let mut eliminated = 0;
+        for node in toposort(&graph.graph, None).unwrap() {
+            if !graph.check_node_type::<Contiguous>(node)
+                || graph.no_delete.contains(&node)
+                || graph.to_retrieve.contains_key(&node)
+                || external.contains(&node)
+            {
+                continue;
+            }
+            let (src, src_output, shape) = graph.get_sources(node)[0];
+            // How each consumer reads the input instead of the copy, if it can
+            let Some(consumers) = graph
+                .graph
+                .edges_directed(node, Direction::Outgoing)
+                .map(|e| {
+                    let (input_order, _, view) = e.weight().as_data()?;
+                    let new_view = if !shape.is_reshaped() {
+                        // Copying doesn't move anything
+                        view
+                    } else if is_primitive(graph.graph.node_weight(e.target()).unwrap().as_any()) {
+                        shape.compose(&view)?
+                    } else {
+                        return None;
+                    };
+                    Some((e.id(), e.target(), input_order, new_view))
+                })
+                .collect::<Option<Vec<_>>>()
+            else {
+                continue;
+            };
+            for (edge, target, input_order, shape) in consumers {
+                graph.graph.remove_edge(edge);
+                graph.graph.add_edge(
+                    src,
+                    target,
+                    Dependency::Data {
+                        input_order,
+                        output_order: src_output,
+                        shape,
+                    },
+                );
+            }
+            graph.graph.remove_node(node);
+            eliminated += 1;
+        }
+        eliminated
+    }
+}
+
 /// Remove unused nodes
 #[derive(Default, Debug)]
 pub struct RemoveUnusedNodes;
@@ -235,258 +427,42 @@ impl Compiler for DepthFirst {
 
 /// **Reduces arithmetic expressions**
 ///
-/// - Current: x + 0 => x, x * 1 => x
+/// - Current: x + 0 => x, x * 1 => x, 1 / (1 / x) => x, log2(exp2(x)) => x
 /// - TODO: x / x => 1, x - x => 0, x * 0 => 0, x - 0 => x, x * 0 => 0, 0 / x => 0
-/// - TODO: Find a much cleaner way to do these eliminations
 #[derive(Debug, Default)]
 pub struct ArithmeticElimination;
 
 impl Compiler for ArithmeticElimination {
     type Output = ();
     fn compile<T: ToIdsMut>(&self, graph: &mut Graph, mut ids: T) {
-        // x + 0, 0 + x
-        let zero = constant(0.);
-        let inp = node();
-        let add1 = binary::<Add>(zero.clone(), inp.clone());
-        let add2 = binary::<Add>(inp.clone(), zero.clone());
-        let mut s1 = add1.clone().search(graph);
-        let mut s2 = add2.clone().search(graph);
-        while s1.next_match() || s2.next_match() {
-            let (inp, zero, add) = if s1.matched {
-                (s1.get(&inp), s1.get(&zero), s1.get(&add1))
-            } else {
-                (s2.get(&inp), s2.get(&zero), s2.get(&add2))
-            };
-            if graph.no_delete.contains(&zero) {
-                continue;
-            }
-            // Carry over outgoing edges
-            let input_shape = graph
-                .graph
-                .edges_connecting(inp, add)
-                .find_map(|e| e.weight().as_data())
-                .unwrap()
-                .2;
-            if input_shape.is_reshaped() {
-                // If any output shape is non-contiguous, we need to keep the op for it's contiguous functionality TODO: replace with explicit contiguous op here
-                if graph
-                    .graph
-                    .edges_connecting(inp, add)
-                    .filter_map(|e| e.weight().as_data())
-                    .any(|(_, _, sh)| sh.is_reshaped())
-                {
-                    continue;
-                }
-                for (weight, target) in graph
-                    .graph
-                    .edges_directed(add, petgraph::Direction::Outgoing)
-                    .map(|e| (*e.weight(), e.target()))
-                    .collect::<Vec<_>>()
-                {
-                    if let Some(weight) = weight.as_data() {
-                        graph.graph.add_edge(
-                            inp,
-                            target,
-                            Dependency::Data {
-                                input_order: weight.0,
-                                output_order: weight.1,
-                                shape: input_shape,
-                            },
-                        );
-                    }
-                }
-            } else {
-                move_outgoing_edge(add, inp, &mut graph.graph);
-            }
-            remap(add, inp, &mut ids, graph);
-            if graph
-                .graph
-                .edges_directed(zero, Direction::Outgoing)
-                .count()
-                == 1
-            {
-                graph.graph.remove_node(zero);
-            }
-            graph.graph.remove_node(add);
-        }
-        // x * 1, 1 * x
-        let one = constant(1.);
-        let inp = node();
-        let mul1 = binary::<Mul>(one.clone(), inp.clone());
-        let mul2 = binary::<Mul>(inp.clone(), one.clone());
-        let mut s1 = mul1.clone().search(graph);
-        let mut s2 = mul2.clone().search(graph);
-        while s1.next_match() || s2.next_match() {
-            let (inp, one, mul) = if s1.matched {
-                (s1.get(&inp), s1.get(&one), s1.get(&mul1))
-            } else {
-                (s2.get(&inp), s2.get(&one), s2.get(&mul2))
-            };
-            if graph.no_delete.contains(&one) {
-                continue;
-            }
-            // Carry over outgoing edges
-            let input_shape = graph
-                .graph
-                .edges_connecting(inp, mul)
-                .find_map(|e| e.weight().as_data())
-                .unwrap()
-                .2;
-            if input_shape.is_reshaped() {
-                // If any output shape is non-contiguous, we need to keep the op for it's contiguous functionality TODO: replace with explicit contiguous op here
-                if graph
-                    .graph
-                    .edges_connecting(inp, mul)
-                    .filter_map(|e| e.weight().as_data())
-                    .any(|(_, _, sh)| sh.is_reshaped())
-                {
-                    continue;
-                }
-                for (weight, target) in graph
-                    .graph
-                    .edges_directed(mul, petgraph::Direction::Outgoing)
-                    .map(|e| (*e.weight(), e.target()))
-                    .collect::<Vec<_>>()
-                {
-                    if let Some(weight) = weight.as_data() {
-                        graph.graph.add_edge(
-                            inp,
-                            target,
-                            Dependency::Data {
-                                input_order: weight.0,
-                                output_order: weight.1,
-                                shape: input_shape,
-                            },
-                        );
-                    }
-                }
-            } else {
-                move_outgoing_edge(mul, inp, &mut graph.graph);
-            }
-            remap(mul, inp, &mut ids, graph);
-            graph.safe_remove_node(one, 1);
-            graph.graph.remove_node(mul);
-        }
-        // recip(recip(x))
-        let inp = node();
-        let intermediate = unary::<Recip>(inp.clone());
-        let out = unary::<Recip>(intermediate.clone());
-        let mut s = out.clone().search(graph);
-        while s.next_match() {
-            let (inp, intermediate, out) = (s.get(&inp), s.get(&intermediate), s.get(&out));
-            if graph.no_delete.contains(&intermediate) {
-                continue;
-            }
-            // Carry over outgoing edges
-            let input_shape = graph
-                .graph
-                .edges_connecting(inp, intermediate)
-                .find_map(|e| e.weight().as_data())
-                .unwrap()
-                .2;
-            if input_shape.is_reshaped() {
-                // If any output shape is non-contiguous, we need to keep the op for it's contiguous functionality TODO: replace with explicit contiguous op here
-                if graph
-                    .graph
-                    .edges_connecting(inp, intermediate)
-                    .filter_map(|e| e.weight().as_data())
-                    .any(|(_, _, sh)| sh.is_reshaped())
-                    || graph
-                        .graph
-                        .edges_connecting(intermediate, out)
-                        .filter_map(|e| e.weight().as_data())
-                        .any(|(_, _, sh)| sh.is_reshaped())
-                {
-                    continue;
-                }
-                for (weight, target) in graph
-                    .graph
-                    .edges_directed(intermediate, petgraph::Direction::Outgoing)
-                    .map(|e| (*e.weight(), e.target()))
-                    .collect::<Vec<_>>()
-                {
-                    if let Some(weight) = weight.as_data() {
-                        graph.graph.add_edge(
-                            inp,
-                            target,
-                            Dependency::Data {
-                                input_order: weight.0,
-                                output_order: weight.1,
-                                shape: input_shape,
-                            },
-                        );
-                    }
-                }
-            } else {
-                move_outgoing_edge(out, inp, &mut graph.graph);
-            }
-            remap(intermediate, inp, &mut ids, graph);
-            remap(out, inp, &mut ids, graph);
-            graph.remove_node(out);
-            graph.safe_remove_node(intermediate, 0);
-        }
-
-        // exp2(log2(x))
-        let inp = node();
-        let intermediate = unary::<Exp2>(inp.clone());
-        let out = unary::<Log2>(intermediate.clone());
-        let mut s = out.clone().search(graph);
-        while s.next_match() {
-            let (inp, intermediate, out) = (s.get(&inp), s.get(&intermediate), s.get(&out));
-            if graph.no_delete.contains(&intermediate) {
-                continue;
-            }
-            // Carry over outgoing edges
-            let input_shape = graph
-                .graph
-                .edges_connecting(inp, intermediate)
-                .find_map(|e| e.weight().as_data())
-                .unwrap()
-                .2;
-            if input_shape.is_reshaped() {
-                // If any output shape is non-contiguous, we need to keep the op for it's contiguous functionality TODO: replace with explicit contiguous op here
-                if graph
-                    .graph
-                    .edges_connecting(inp, intermediate)
-                    .filter_map(|e| e.weight().as_data())
-                    .any(|(_, _, sh)| sh.is_reshaped())
-                    || graph
-                        .graph
-                        .edges_connecting(intermediate, out)
-                        .filter_map(|e| e.weight().as_data())
-                        .any(|(_, _, sh)| sh.is_reshaped())
-                {
-                    continue;
-                }
-                for (weight, target) in graph
-                    .graph
-                    .edges_directed(intermediate, petgraph::Direction::Outgoing)
-                    .map(|e| (*e.weight(), e.target()))
-                    .collect::<Vec<_>>()
-                {
-                    if let Some(weight) = weight.as_data() {
-                        graph.graph.add_edge(
-                            inp,
-                            target,
-                            Dependency::Data {
-                                input_order: weight.0,
-                                output_order: weight.1,
-                                shape: input_shape,
-                            },
-                        );
-                    }
-                }
-            } else {
-                move_outgoing_edge(out, inp, &mut graph.graph);
-            }
-            remap(intermediate, inp, &mut ids, graph);
-            remap(out, inp, &mut ids, graph);
-            graph.remove_node(out);
-            graph.safe_remove_node(intermediate, 0);
+        let x = node();
+        let (zero, one) = (constant(0.), constant(1.));
+        let rules = [
+            Rewrite::forward("x + 0", &binary::<Add>(x.clone(), zero.clone()), &x),
+            Rewrite::forward("0 + x", &binary::<Add>(zero, x.clone()), &x),
+            Rewrite::forward("x * 1", &binary::<Mul>(x.clone(), one.clone()), &x),
+            Rewrite::forward("1 * x", &binary::<Mul>(one, x.clone()), &x),
+            inverse::<Recip, Recip>("recip(recip(x))"),
+            inverse::<Exp2, Log2>("log2(exp2(x))"),
+        ];
+        for rule in rules {
+            rule.compile(graph, &mut ids);
         }
     }
 }
 
+/// f(g(x)) => x
+fn inverse<G: Operator + 'static, F: Operator + 'static>(name: &str) -> Rewrite {
+    let x = node();
+    let inner = unary::<G>(x.clone());
+    let mut outer = unary::<F>(inner.clone());
+    // The outer op has to read the inner one as is
+    outer.check(|_, shapes| !shapes[0].is_reshaped());
+    Rewrite::new(name, &outer, move |m| {
+        Some(Replacement::forward(m.edge(&x, &inner)))
+    })
+}
+
 fn constant(num: f32) -> SelectGraph {
     let mut n = op::<Constant>();
     n.check(move |o, _| {
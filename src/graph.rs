@@ -29,11 +29,15 @@ pub struct Graph {
     pub no_delete: FxHashSet<NodeIndex>,
     /// Tensors marked in this set need to be retrieved later (mostly for optimizers to insert copy back calls, the graph itself doesn't treat these differently)
     pub to_retrieve: FxHashMap<NodeIndex, (u8, ShapeTracker)>,
+    /// How tensors should be split across devices, for the [`crate::tensor_parallel`] compiler
+    pub shardings: FxHashMap<NodeIndex, Sharding>,
     /// A cached list of nodes to run, source nodes, and view nodes to delete after execution.
     #[allow(clippy::type_complexity)]
     pub(crate) linearized_graph: Option<Vec<(NodeIndex, Vec<(NodeIndex, u8, ShapeTracker)>)>>,
     /// Cached consumers (for execution only)
     consumers_map: Option<FxHashMap<(NodeIndex, u8), usize>>,
+    /// Nested graphs share expression storage with the graph they live in, so they don't clean it up
+    pub(crate) nested: bool,
 }
 
 /// A dependency between two nodes
@@ -214,6 +218,10 @@ impl Graph {
             for (i, tensor) in tensors.into_iter().enumerate() {
                 self.tensors.insert((*node, i as u8), tensor);
             }
+            bind_dyn_dims(
+                self.graph.node_weight_mut(*node).unwrap(),
+                &mut self.dyn_map,
+            );
 
             // Bookkeep remaining consumers
             for (id, ind, _) in src_ids {
@@ -254,6 +262,10 @@ impl Graph {
             for (i, tensor) in tensors.into_iter().enumerate() {
                 self.tensors.insert((*node, i as u8), tensor);
             }
+            bind_dyn_dims(
+                self.graph.node_weight_mut(*node).unwrap(),
+                &mut self.dyn_map,
+            );
         }
     }
 
@@ -321,6 +333,10 @@ impl Graph {
             let now = std::time::Instant::now();
             let tensors = self.graph.node_weight_mut(*node).unwrap().process(srcs);
             let elapsed = now.elapsed();
+            bind_dyn_dims(
+                self.graph.node_weight_mut(*node).unwrap(),
+                &mut self.dyn_map,
+            );
             println!(
                 "{:.>1$}",
                 format_duration(&elapsed).bold(),
@@ -380,7 +396,9 @@ impl DerefMut for Graph {
 
 impl Drop for Graph {
     fn drop(&mut self) {
-        expression_cleanup();
+        if !self.nested {
+            expression_cleanup();
+        }
     }
 }
 
@@ -408,3 +426,11 @@ fn get_source_tensors<'a>(
     }
     srcs
 }
+
+/// Bind the dynamic dimensions an op sets the sizes of when it runs, such as a
+/// [`While`](crate::control_flow::While) loop's final state sizes
+fn bind_dyn_dims(op: &mut Box<dyn Operator>, dyn_map: &mut FxHashMap<char, usize>) {
+    if let Some(dims) = op.custom("dyn_dims", Box::new(())) {
+        dyn_map.extend(*dims.downcast::<Vec<(char, usize)>>().unwrap());
+    }
+}
@@ -44,6 +44,21 @@ impl GraphTensor {
         self
     }
 
+    /// Mark this tensor to be split across devices. Matmuls with it are split up by
+    /// [`TensorParallel`]
+    ///
+    /// The sharding refers to the dimensions of the tensor as it's stored, so it has to be marked
+    /// before permuting, slicing or expanding it.
+    pub fn shard(self, sharding: Sharding) -> Self {
+        assert!(
+            !self.shape.is_reshaped(),
+            "Only stored tensors can be sharded, not views of them. Shard before permuting, slicing \
+            or expanding."
+        );
+        self.graph().shardings.insert(self.id, sharding);
+        self
+    }
+
     /// Remove this tensor's data from the graph.
     pub fn drop(&self) {
         self.graph().drop_tensors(self.id);
@@ -65,7 +80,7 @@ impl GraphTensor {
     /// ```
     pub fn set_dyn(self, data: impl Data + Clone, shape: impl ToShape) -> Self {
         // Report dyn dim values to graph dyn map
-        for (d, s) in self.shape.dims().iter().zip(shape.to_shape().into_iter()) {
+        for (d, s) in self.shape.dims().iter().zip(shape.to_shape()) {
             if let Some(c) = d.to_symbols().pop() {
                 self.graph().dyn_map.insert(c, s.to_usize().unwrap());
             }
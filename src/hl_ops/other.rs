@@ -1,13 +1,21 @@
-use std::path::PathBuf;
+use std::{cell::Cell, path::PathBuf};
 
 use colored::Colorize;
 use itertools::Itertools;
+use rustc_hash::FxHashMap;
 
 use crate::{
     op::{self, Constant, ConstantValue},
     prelude::*,
 };
 
+/// Scramble the bits of a number, as in splitmix64
+fn mix(mut x: u64) -> u64 {
+    x = (x ^ (x >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+    x = (x ^ (x >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+    x ^ (x >> 31)
+}
+
 impl GraphTensor {
     /// Cumulative sum last dimension
     pub fn cumsum_last_dim(mut self) -> Self {
@@ -152,6 +160,76 @@ impl GraphTensor {
         (one_hot.expand(2, dim) * self.expand(0, batch)).sum_reduce(1)
     }
 
+    /// Uniform random numbers in [0, 1) with the same shape as this tensor.
+    ///
+    /// Each number is a hash of the seed and the index of its element, worked out with integer math
+    /// on the CPU, so a seed always gives the same numbers whatever backend or precision runs the
+    /// rest of the graph. Use a seed with a dynamic dimension, like a step counter, or
+    /// [`GraphTensor::rand_like_per_run`] to get new numbers each run.
+    pub fn rand_like(self, seed: impl Into<Expression>) -> GraphTensor {
+        self.random(seed.into(), false, false)
+    }
+
+    /// Like [`GraphTensor::rand_like`], but with new numbers each time the graph is ran. The seed
+    /// picks which numbers come out on each run.
+    pub fn rand_like_per_run(self, seed: impl Into<Expression>) -> GraphTensor {
+        self.random(seed.into(), true, false)
+    }
+
+    /// Randomly 1 with the probability in each element of this tensor, otherwise 0. Compares
+    /// against [`GraphTensor::rand_like`] on the CPU, so the same elements come out as 1 in any
+    /// precision.
+    pub fn bernoulli(self, seed: impl Into<Expression>) -> GraphTensor {
+        self.random(seed.into(), false, true)
+    }
+
+    /// Like [`GraphTensor::bernoulli`], but with new samples each time the graph is ran
+    pub fn bernoulli_per_run(self, seed: impl Into<Expression>) -> GraphTensor {
+        self.random(seed.into(), true, true)
+    }
+
+    fn random(self, seed: Expression, per_run: bool, bernoulli: bool) -> GraphTensor {
+        let dims = self.dims();
+        let dyn_map: *const FxHashMap<char, usize> = &self.graph().dyn_map;
+        let run = Cell::new(0);
+        let name = if bernoulli { "Bernoulli" } else { "RandLike" };
+        let mut op = self.graph().add_op(op::Function(
+            name.to_string(),
+            Box::new(move |inp| {
+                let dyn_map = unsafe { dyn_map.as_ref().unwrap() };
+                let n = dims
+                    .iter()
+                    .map(|d| d.exec(dyn_map).unwrap())
+                    .product::<usize>();
+                let mut state = mix(seed.exec(dyn_map).unwrap() as u64);
+                if per_run {
+                    state = mix(state ^ run.replace(run.get() + 1));
+                }
+                // Splitmix64 from the seed, taking the top 24 bits of each number so it's exact in f32
+                let uniform = (0..n as u64).map(|i| {
+                    let x = mix(state.wrapping_add((i + 1).wrapping_mul(0x9E37_79B9_7F4A_7C15)));
+                    (x >> 40) as f32 / (1 << 24) as f32
+                });
+                let data = if let Some((probs, shape)) = inp.into_iter().next() {
+                    let probs = op::Contiguous.process(vec![(probs, shape)]).pop().unwrap();
+                    let probs = probs.downcast_ref::<Vec<f32>>().unwrap();
+                    uniform
+                        .zip(probs)
+                        .map(|(u, p)| if u < *p { 1. } else { 0. })
+                        .collect::<Vec<f32>>()
+                } else {
+                    uniform.collect()
+                };
+                vec![Tensor::new(data)]
+            }),
+        ));
+        if bernoulli {
+            op = op.input(self.id, 0, self.shape);
+        }
+        let id = op.finish();
+        GraphTensor::from_id(id, ShapeTracker::new(self.dims()), self.graph())
+    }
+
     /// Print the value of this tensor when the graph is ran
     pub fn print<T: ToString>(&self, message: T) -> Self {
         let message = message.to_string();
@@ -390,6 +468,128 @@ mod tests {
         assert_exact(&arange.data(), &[0., 1., 2., 3., 4., 5.]);
     }
 
+    #[test]
+    fn test_rand_like() {
+        let mut cx = Graph::new();
+        let a = cx
+            .tensor((10, 'a', 30))
+            .set_dyn(vec![0.; 6000], (10, 20, 30));
+        let r = a.rand_like(7).retrieve();
+        let same = a.rand_like(7).retrieve();
+        let other = a.rand_like('s').retrieve();
+        let probs = cx.tensor(2).set(vec![0.2, 0.9]).expand(0, 1000);
+        let samples = probs.bernoulli(3).retrieve();
+        cx.set_dyn_dim('s', 1);
+        cx.execute();
+
+        let data = r.data();
+        assert!(data.iter().all(|x| (0. ..1.).contains(x)));
+        let mean = data.iter().sum::<f32>() / data.len() as f32;
+        assert!((mean - 0.5).abs() < 0.02);
+        // Neighbouring elements aren't correlated
+        let lagged = data
+            .windows(2)
+            .map(|w| (w[0] - 0.5) * (w[1] - 0.5))
+            .sum::<f32>()
+            / data.len() as f32;
+        assert!(lagged.abs() < 0.01);
+        assert_exact(&same.data(), &data);
+        assert_ne!(other.data(), data);
+        let samples = samples.data();
+        assert!(samples.iter().all(|x| *x == 0. || *x == 1.));
+        let rate = |offset| samples.iter().skip(offset).step_by(2).sum::<f32>() / 1000.;
+        assert!((rate(0) - 0.2).abs() < 0.05);
+        assert!((rate(1) - 0.9).abs() < 0.05);
+
+        // A dynamic seed gives new numbers each run
+        let first = other.data();
+        cx.set_dyn_dim('s', 2);
+        other.drop();
+        cx.execute();
+        assert_ne!(other.data(), first);
+    }
+
+    #[test]
+    fn test_rand_like_per_run() {
+        let mut cx = Graph::new();
+        let a = cx.tensor(100).set(vec![0.; 100]);
+        let r = a.rand_like_per_run(7).retrieve();
+        let fixed = a.rand_like(7).retrieve();
+        let samples = cx
+            .constant(0.5)
+            .expand(0, 100)
+            .bernoulli_per_run(7)
+            .retrieve();
+        cx.execute();
+        let (first, first_samples, first_fixed) = (r.data(), samples.data(), fixed.data());
+
+        for t in [r, fixed, samples] {
+            t.drop();
+        }
+        cx.execute();
+        assert_ne!(r.data(), first);
+        assert_ne!(samples.data(), first_samples);
+        assert_exact(&fixed.data(), &first_fixed);
+
+        // The same seed gives the same numbers each run in another graph
+        let mut cx = Graph::new();
+        let again = cx.tensor(100).set(vec![0.; 100]).rand_like_per_run(7);
+        let again = again.retrieve();
+        cx.execute();
+        assert_exact(&again.data(), &first);
+    }
+
+    /// Runs an op as a half precision backend would, storing its outputs in f16
+    #[derive(Debug)]
+    struct Half(Box<dyn Operator>);
+
+    impl Operator for Half {
+        fn process(
+            &mut self,
+            inp: Vec<(InputTensor, ShapeTracker)>,
+        ) -> Vec<crate::prelude::Tensor> {
+            let mut out = self.0.process(inp);
+            for t in &mut out {
+                for a in t.downcast_mut::<Vec<f32>>().unwrap() {
+                    *a = Precision::F16.round(*a);
+                }
+            }
+            out
+        }
+    }
+
+    #[test]
+    fn test_rand_like_across_precisions() {
+        let build = |half: bool| {
+            let mut cx = Graph::new();
+            let a = cx.tensor((50, 's')).set_dyn(vec![0.; 5000], (50, 100));
+            let r = a.rand_like(Expression::from('s') * 3 + 1).retrieve();
+            let samples = (cx.constant(0.25) + 0.125).expand_to(a.shape).bernoulli(5);
+            let samples = samples.retrieve();
+            if half {
+                for node in cx.node_indices().collect::<Vec<_>>() {
+                    let weight = cx.graph.node_weight_mut(node).unwrap();
+                    let op = std::mem::replace(weight, Box::new(crate::op::Contiguous));
+                    *weight = Box::new(Half(op));
+                }
+            }
+            cx.execute();
+            (cx, r.data(), samples.data())
+        };
+        let (_, full, full_samples) = build(false);
+        let (_, half, half_samples) = build(true);
+        // The numbers are only rounded on the way into half precision, and with probabilities exact in
+        // both, the samples don't change
+        let rounded = full
+            .iter()
+            .map(|a| Precision::F16.round(*a))
+            .collect::<Vec<_>>();
+        assert_exact(&half, &rounded);
+        assert_exact(&half_samples, &full_samples);
+        let rate = full_samples.iter().sum::<f32>() / 5000.;
+        assert!((rate - 0.375).abs() < 0.03);
+    }
+
     #[test]
     fn test_tril() {
         let mut cx = Graph::new();
@@ -15,8 +15,9 @@ impl GraphTensor {
                 .add_op(op::SumReduce(dim))
                 .input(new_id, 0, shape)
                 .finish();
-            // Reduce shape
+            // Reduce shape, the output is a new contiguous tensor
             shape.remove_dim(dim);
+            shape = shape.contiguous();
         }
         GraphTensor::from_id(new_id, shape, self.graph_ref)
     }
@@ -32,8 +33,9 @@ impl GraphTensor {
                 .add_op(op::MaxReduce(dim))
                 .input(new_id, 0, shape)
                 .finish();
-            // Reduce shape
+            // Reduce shape, the output is a new contiguous tensor
             shape.remove_dim(dim);
+            shape = shape.contiguous();
         }
         GraphTensor::from_id(new_id, shape, self.graph_ref)
     }
@@ -51,7 +53,10 @@ impl GraphTensor {
                 .finish();
 
             // Divide by size of dimension
-            let div_tensor = self.graph().constant_expr(shape.remove_dim(dim)).id;
+            let size = shape.dims()[dim];
+            shape.remove_dim(dim);
+            shape = shape.contiguous();
+            let div_tensor = self.graph().constant_expr(size).id;
             let mul_tensor = self
                 .graph()
                 .add_op(op::Recip)
@@ -130,4 +135,29 @@ mod tests {
 
         assert_close(&b.data(), &d_b.as_vec());
     }
+
+    #[test]
+    fn test_reduce_reshaped() {
+        let mut cx = Graph::new();
+        let data = (0..24).map(|i| i as f32).collect::<Vec<_>>();
+        let a = cx.tensor((2, 3, 4)).set(data.clone());
+        let sum = a.permute((0, 2, 1)).sum_reduce(0).retrieve();
+        let max = a.permute((2, 0, 1)).max_reduce(1).retrieve();
+        let mean = a.slice_along(1..3, 2).mean_reduce(2).retrieve();
+        cx.execute();
+
+        let at = |i: usize, j: usize, k: usize| data[i * 12 + j * 4 + k];
+        let sum_ref = (0..4)
+            .flat_map(|k| (0..3).map(move |j| at(0, j, k) + at(1, j, k)))
+            .collect::<Vec<_>>();
+        let max_ref = (0..4)
+            .flat_map(|k| (0..3).map(move |j| at(0, j, k).max(at(1, j, k))))
+            .collect::<Vec<_>>();
+        let mean_ref = (0..2)
+            .flat_map(|i| (0..3).map(move |j| (at(i, j, 1) + at(i, j, 2)) / 2.))
+            .collect::<Vec<_>>();
+        assert_exact(&sum.data(), &sum_ref);
+        assert_exact(&max.data(), &max_ref);
+        assert_exact(&mean.data(), &mean_ref);
+    }
 }
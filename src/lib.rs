@@ -1,23 +1,35 @@
 pub mod compiler_utils;
+pub mod control_flow;
+pub mod equality_saturation;
 pub mod generic_compiler;
 pub mod graph;
 pub mod graph_tensor;
 pub mod hl_ops;
+pub mod mixed_precision;
 pub mod module;
 pub mod op;
+pub mod partition;
+pub mod rewrite;
 pub mod shape;
+pub mod tensor_parallel;
 
 pub mod tests;
 
 pub mod prelude {
     pub use crate::compiler_utils::*;
+    pub use crate::control_flow::*;
+    pub use crate::equality_saturation::*;
     pub use crate::generic_compiler::*;
     pub use crate::graph::*;
     pub use crate::graph_tensor::*;
     pub use crate::hl_ops::*;
+    pub use crate::mixed_precision::*;
     pub use crate::module::*;
     pub use crate::op::*;
+    pub use crate::partition::*;
+    pub use crate::rewrite::*;
     pub use crate::shape::*;
+    pub use crate::tensor_parallel::*;
     pub use half::{bf16, f16};
     pub use petgraph;
     pub use petgraph::stable_graph::NodeIndex;
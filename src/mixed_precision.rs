@@ -0,0 +1,346 @@
+//! Automatic mixed precision: running the parts of a graph that tolerate it, like matmuls, in half
+//! precision, while keeping reductions, softmax and norms in full precision.
+//!
+//! Precision is assigned per node, and [`Cast`] ops are inserted wherever data crosses from one
+//! precision to the other. On the CPU, ops in lower precision are wrapped in [`LowPrecision`],
+//! which rounds their results to the nearest value representable in that precision while keeping
+//! them as f32, so running a mixed precision graph on the CPU shows how much numerical drift it
+//! causes.
+//!
+//! Only the CPU runs [`Cast`]s and [`LowPrecision`] ops so far. The CUDA and Metal compilers have
+//! no lowering for them, and pick a single precision for the whole graph with their float type
+//! instead.
+
+use std::{
+    collections::{HashMap, HashSet},
+    hash::{Hash, Hasher},
+};
+
+use half::{bf16, f16};
+use itertools::Itertools;
+use petgraph::{
+    algo::toposort,
+    visit::{EdgeRef, IntoEdgeReferences},
+    Direction,
+};
+
+use crate::{
+    op::{downcast_op, Add, Contiguous, LessThan, Mod, Mul, SumReduce},
+    prelude::*,
+};
+
+/// A floating point precision
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum Precision {
+    #[default]
+    F32,
+    F16,
+    Bf16,
+}
+
+impl Precision {
+    /// Round a value to the nearest one representable in this precision
+    pub fn round(&self, a: f32) -> f32 {
+        match self {
+            Precision::F32 => a,
+            Precision::F16 => f16::from_f32(a).to_f32(),
+            Precision::Bf16 => bf16::from_f32(a).to_f32(),
+        }
+    }
+}
+
+/// Convert a tensor from one precision to another. The output is contiguous.
+///
+/// On the CPU, values are rounded as if they had been stored in the precision they're cast from
+/// and then converted.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Cast {
+    pub from: Precision,
+    pub to: Precision,
+}
+
+impl Operator for Cast {
+    fn process(&mut self, inp: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
+        let mut out = Contiguous.process(inp);
+        for a in out[0].downcast_mut::<Vec<f32>>().unwrap() {
+            *a = self.to.round(self.from.round(*a));
+        }
+        out
+    }
+    crate::op_equality!(from, to);
+}
+
+/// Run an op in lower precision on the CPU. It still computes in f32, but each of its results is
+/// rounded to the precision as if it had been stored in it.
+#[derive(Debug)]
+pub struct LowPrecision {
+    pub precision: Precision,
+    pub op: Box<dyn Operator>,
+}
+
+impl Operator for LowPrecision {
+    fn process(&mut self, inp: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
+        let mut out = self.op.process(inp);
+        for tensor in &mut out {
+            for a in tensor.downcast_mut::<Vec<f32>>().unwrap() {
+                *a = self.precision.round(*a);
+            }
+        }
+        out
+    }
+    fn equals(&self, other: &dyn Operator) -> bool {
+        downcast_op::<Self>(other)
+            .is_some_and(|o| o.precision == self.precision && self.op.equals(o.op.as_ref()))
+    }
+    fn hash_op(&self, state: &mut dyn Hasher) {
+        self.precision.hash(&mut &mut *state);
+        self.op.hash_op(state);
+    }
+}
+
+/// Run matmuls in lower precision, inserting [`Cast`]s where data moves between precisions.
+///
+/// Matmuls (expanded tensors multiplied together and summed) run in lower precision. Elementwise
+/// ops whose inputs are all in lower precision stay in it, and everything else, like reductions,
+/// exponentials and square roots, runs in f32. Outputs are always cast back to f32. Ops in lower
+/// precision are wrapped in [`LowPrecision`].
+#[derive(Debug, Clone, Copy)]
+pub struct AutoMixedPrecision {
+    pub precision: Precision,
+}
+
+impl Default for AutoMixedPrecision {
+    fn default() -> Self {
+        Self {
+            precision: Precision::F16,
+        }
+    }
+}
+
+impl Compiler for AutoMixedPrecision {
+    type Output = ();
+    fn compile<T: ToIdsMut>(&self, graph: &mut Graph, mut ids: T) {
+        if self.precision == Precision::F32 {
+            return;
+        }
+        let external = ids
+            .to_ids_mut()
+            .into_iter()
+            .map(|i| *i)
+            .chain(graph.no_delete.iter().copied())
+            .chain(graph.to_retrieve.keys().copied())
+            .collect::<HashSet<_>>();
+        let layout_known = |graph: &Graph, node| {
+            !external.contains(&node) || output_layout(graph, node, 0).is_some()
+        };
+
+        // Assign precisions
+        let mut low = HashSet::new();
+        for node in toposort(&graph.graph, None).unwrap() {
+            let sources = graph.get_sources(node);
+            if graph.check_node_type::<SumReduce>(node) {
+                let (mul, _, shape) = sources[0];
+                let is_matmul = graph.check_node_type::<Mul>(mul)
+                    && !shape.is_reshaped()
+                    && !external.contains(&mul)
+                    && graph.edges_directed(mul, Direction::Outgoing).count() == 1
+                    && graph
+                        .get_sources(mul)
+                        .iter()
+                        .all(|(_, _, sh)| sh.fake.iter().any(|f| *f));
+                if is_matmul && layout_known(graph, node) {
+                    low.insert(mul);
+                    low.insert(node);
+                }
+            } else if (graph.check_node_type::<Add>(node)
+                || graph.check_node_type::<Mul>(node)
+                || graph.check_node_type::<Mod>(node)
+                || graph.check_node_type::<LessThan>(node)
+                || graph.check_node_type::<Contiguous>(node))
+                && !sources.is_empty()
+                && sources.iter().all(|(s, _, _)| low.contains(s))
+                && layout_known(graph, node)
+            {
+                low.insert(node);
+            }
+        }
+
+        // Cast where data crosses between precisions
+        let mut casts = HashMap::new();
+        for (edge, src, dest, (input_order, output_order, shape)) in graph
+            .graph
+            .edge_references()
+            .filter_map(|e| Some((e.id(), e.source(), e.target(), e.weight().as_data()?)))
+            .collect_vec()
+        {
+            if low.contains(&src) == low.contains(&dest) {
+                continue;
+            }
+            let (from, to) = if low.contains(&dest) {
+                (Precision::F32, self.precision)
+            } else {
+                (self.precision, Precision::F32)
+            };
+            let cast = *casts.entry((src, output_order, to)).or_insert_with(|| {
+                graph
+                    .add_op(Cast { from, to })
+                    .input(src, output_order, shape.physical())
+                    .finish()
+            });
+            graph.graph.remove_edge(edge);
+            graph.graph.add_edge(
+                cast,
+                dest,
+                Dependency::Data {
+                    input_order,
+                    output_order: 0,
+                    shape,
+                },
+            );
+        }
+
+        // Round results of ops in lower precision
+        for node in &low {
+            let weight = graph.graph.node_weight_mut(*node).unwrap();
+            let op = std::mem::replace(weight, Box::new(Contiguous));
+            *weight = Box::new(LowPrecision {
+                precision: self.precision,
+                op,
+            });
+        }
+
+        // Outputs stay in f32
+        for node in low.into_iter().filter(|n| external.contains(n)) {
+            let cast = match casts.get(&(node, 0, Precision::F32)) {
+                Some(cast) => *cast,
+                None => {
+                    let layout = output_layout(graph, node, 0).unwrap();
+                    graph
+                        .add_op(Cast {
+                            from: self.precision,
+                            to: Precision::F32,
+                        })
+                        .input(node, 0, layout)
+                        .finish()
+                }
+            };
+            remap(node, cast, &mut ids, graph);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{AutoMixedPrecision, Cast, Precision};
+    use crate::{
+        prelude::*,
+        tests::{assert_close_precision, assert_exact, random_vec},
+    };
+
+    fn casts(cx: &Graph, precision: Precision) -> usize {
+        cx.node_indices()
+            .filter(|n| {
+                cx.node_weight(*n)
+                    .unwrap()
+                    .as_any()
+                    .downcast_ref::<Cast>()
+                    .is_some_and(|c| c.to == precision)
+            })
+            .count()
+    }
+
+    /// A matmul followed by a softmax, in full precision and then in `precision`
+    fn run(precision: Precision, a: &[f32], b: &[f32]) -> (Graph, Vec<f32>) {
+        let mut cx = Graph::new();
+        let a = cx.tensor((4, 8)).set(a.to_vec());
+        let b = cx.tensor((8, 4)).set(b.to_vec());
+        let mut c = a.matmul(b).softmax(1).retrieve();
+        cx.compile(AutoMixedPrecision { precision }, &mut c);
+        cx.execute();
+        let data = c.data();
+        (cx, data)
+    }
+
+    #[test]
+    fn test_precision_rounding() {
+        assert_exact(
+            &[1.0001, 1.01, 65504., 1e-8].map(|a| Precision::F16.round(a)),
+            &[1., 1.0097656, 65504., 0.],
+        );
+        assert_exact(
+            &[1.0001, 1.01, 65504., 0.5].map(|a| Precision::Bf16.round(a)),
+            &[1., 1.0078125, 65536., 0.5],
+        );
+    }
+
+    #[test]
+    fn test_matmul_in_half_precision() {
+        let (a, b) = (random_vec(32), random_vec(32));
+        let (full_cx, full) = run(Precision::F32, &a, &b);
+        assert_eq!(
+            casts(&full_cx, Precision::F16) + casts(&full_cx, Precision::F32),
+            0
+        );
+        for precision in [Precision::F16, Precision::Bf16] {
+            let (cx, half) = run(precision, &a, &b);
+            // Both matmul inputs are cast down, and the product is cast back before the softmax
+            assert_eq!(casts(&cx, precision), 2);
+            assert_eq!(casts(&cx, Precision::F32), 1);
+            // Rounding shows up in the output, but stays small
+            assert_ne!(half, full);
+            assert_close_precision(&half, &full, 2e-2);
+        }
+    }
+
+    #[test]
+    fn test_matmul_output_cast_back() {
+        let mut cx = Graph::new();
+        let a = cx.tensor((2, 3)).set(vec![1., 2., 3., 4., 5., 6.]);
+        let b = cx.tensor((3, 2)).set(vec![1.0001, 0., 0., 1., 1., 0.]);
+        let mut c = a.matmul(b).retrieve();
+        cx.compile(AutoMixedPrecision::default(), &mut c);
+        assert!(cx.check_node_type::<Cast>(c.id));
+        cx.execute();
+        // 1.0001 isn't representable in f16
+        assert_exact(&c.data(), &[4., 2., 10., 5.]);
+    }
+
+    #[test]
+    fn test_matmul_result_rounded() {
+        let mut cx = Graph::new();
+        // Both inputs are exact in f16, but their dot product isn't
+        let a = cx.tensor((1, 2)).set(vec![1., 1.]);
+        let b = cx.tensor((2, 1)).set(vec![1., 3. / 4096.]);
+        let mut c = a.matmul(b).retrieve();
+        let mut d = (a.matmul(b) * 1.).retrieve();
+        cx.compile(AutoMixedPrecision::default(), (&mut c, &mut d));
+        cx.execute();
+        assert_exact(&c.data(), &[1.0009766]);
+        // Also when the result is read in f32 rather than retrieved
+        assert_exact(&d.data(), &[1.0009766]);
+    }
+
+    #[test]
+    fn test_low_precision_results_rounded() {
+        let mut cx = Graph::new();
+        let a = cx.tensor((1, 1)).set(vec![1.]);
+        // A fraction of the spacing between f16 values around 1
+        let b = cx.tensor((1, 1)).set(vec![3. / 8192.]);
+        let sum = a.matmul(a) + a.matmul(b);
+        let mut c = (sum + a.matmul(b)).retrieve();
+        cx.compile(AutoMixedPrecision::default(), &mut c);
+        cx.execute();
+        // Each sum is rounded back to 1, where summing in f32 would round up at the end
+        assert_exact(&c.data(), &[1.]);
+    }
+
+    #[test]
+    fn test_norms_stay_full_precision() {
+        let mut cx = Graph::new();
+        let a = cx.tensor((3, 5)).set(random_vec(15));
+        let mut b = a.layer_norm(1, 1e-5).softmax(1).retrieve();
+        let nodes = cx.node_count();
+        cx.compile(AutoMixedPrecision::default(), &mut b);
+        assert_eq!(cx.node_count(), nodes);
+    }
+}
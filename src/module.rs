@@ -34,7 +34,7 @@ pub fn transfer_data(
     dests: impl ToIds,
     dest_graph: &mut Graph,
 ) {
-    for (src, dest) in srcs.to_ids().into_iter().zip(dests.to_ids().into_iter()) {
+    for (src, dest) in srcs.to_ids().into_iter().zip(dests.to_ids()) {
         let mut output_num = 0;
         while let Some(tensor) = src_graph.tensors.remove(&(src, output_num)) {
             dest_graph.tensors.insert((dest, output_num), tensor);
@@ -48,7 +48,7 @@ pub fn transfer_data(
 
 /// Transfer data from one set of nodes to another set in the same graph
 pub fn transfer_data_same_graph(srcs: impl ToIds, dests: impl ToIds, graph: &mut Graph) {
-    for (src, dest) in srcs.to_ids().into_iter().zip(dests.to_ids().into_iter()) {
+    for (src, dest) in srcs.to_ids().into_iter().zip(dests.to_ids()) {
         let mut output_num = 0;
         while let Some(tensor) = graph.tensors.remove(&(src, output_num)) {
             graph.tensors.insert((dest, output_num), tensor);
@@ -227,6 +227,9 @@ impl<T: SerializeModule> SerializeModule for &T {
 pub struct Serializer {
     current_path: Vec<String>,
     pub state: FxHashMap<String, NodeIndex>,
+    pub shapes: FxHashMap<String, ShapeTracker>,
+    /// Fan in and fan out of tensors not laid out as (out, in, ..kernel)
+    pub fans: FxHashMap<String, (usize, usize)>,
 }
 
 impl Serializer {
@@ -236,12 +239,26 @@ impl Serializer {
             self.current_path.push(name.to_string());
         }
         // Insert tensor id
-        self.state.insert(self.current_path.join("/"), tensor.id);
+        let path = self.current_path.join("/");
+        self.state.insert(path.clone(), tensor.id);
+        self.shapes.insert(path, tensor.shape);
         if !name.is_empty() {
             // Remove new path component
             self.current_path.pop();
         }
     }
+    /// Add a tensor along with its fan in and fan out, for tensors not laid out as
+    /// (out, in, ..kernel)
+    pub fn tensor_with_fans(&mut self, name: &str, tensor: GraphTensor, fans: (usize, usize)) {
+        self.tensor(name, tensor);
+        let path = self
+            .current_path
+            .iter()
+            .map(|s| s.as_str())
+            .chain((!name.is_empty()).then_some(name))
+            .join("/");
+        self.fans.insert(path, fans);
+    }
     pub fn module(&mut self, name: &str, module: impl SerializeModule) {
         if !name.is_empty() {
             // Add new path component
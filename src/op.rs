@@ -2,6 +2,7 @@ use std::{
     any::Any,
     borrow::BorrowMut,
     fmt::Debug,
+    hash::{Hash, Hasher},
     sync::{Arc, Mutex},
 };
 
@@ -87,6 +88,41 @@ pub trait Operator: Debug + as_any::AsAny {
     fn custom(&mut self, key: &str, input: Box<dyn Any>) -> Option<Box<dyn Any>> {
         None
     }
+    /// Whether this op produces the same outputs as `other` when both are given the same inputs.
+    /// Ops that can't tell (the default) are never equal, so they're never merged
+    #[allow(unused)]
+    fn equals(&self, other: &dyn Operator) -> bool {
+        false
+    }
+    /// Hash the state compared in [`Operator::equals`]. Ops that are equal must hash the same
+    #[allow(unused)]
+    fn hash_op(&self, state: &mut dyn Hasher) {}
+}
+
+/// Downcast an op to a concrete op type
+pub fn downcast_op<T: Operator>(op: &dyn Operator) -> Option<&T> {
+    op.as_any().downcast_ref::<T>()
+}
+
+/// Implement [`Operator::equals`] and [`Operator::hash_op`] for an op that's equal to another op of
+/// the same type when the listed fields are equal. The fields must implement `PartialEq` and `Hash`.
+///
+/// ```ignore
+/// impl Operator for SumReduce {
+///     fn process(..) { .. }
+///     luminal::op_equality!(0);
+/// }
+/// ```
+#[macro_export]
+macro_rules! op_equality {
+    ($($field:tt),*) => {
+        fn equals(&self, other: &dyn $crate::op::Operator) -> bool {
+            $crate::op::downcast_op::<Self>(other).is_some_and(|_other| true $(&& self.$field == _other.$field)*)
+        }
+        fn hash_op(&self, _state: &mut dyn std::hash::Hasher) {
+            $(std::hash::Hash::hash(&self.$field, &mut &mut *_state);)*
+        }
+    };
 }
 
 impl<T: Operator> Operator for Box<T> {
@@ -96,6 +132,15 @@ impl<T: Operator> Operator for Box<T> {
     fn process(&mut self, inp: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
         <T as Operator>::process(self, inp)
     }
+    fn equals(&self, other: &dyn Operator) -> bool {
+        match downcast_op::<Self>(other) {
+            Some(other) => <T as Operator>::equals(self, &**other),
+            None => <T as Operator>::equals(self, other),
+        }
+    }
+    fn hash_op(&self, state: &mut dyn Hasher) {
+        <T as Operator>::hash_op(self, state)
+    }
 }
 impl<T: Operator> Operator for Arc<Mutex<T>> {
     fn custom(&mut self, key: &str, input: Box<dyn Any>) -> Option<Box<dyn Any>> {
@@ -104,6 +149,18 @@ impl<T: Operator> Operator for Arc<Mutex<T>> {
     fn process(&mut self, inp: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
         <T as Operator>::process(self.lock().unwrap().borrow_mut(), inp)
     }
+    fn equals(&self, other: &dyn Operator) -> bool {
+        let op = self.lock().unwrap();
+        match downcast_op::<Self>(other) {
+            // Locking the same op twice would deadlock
+            Some(other) if Arc::ptr_eq(self, other) => <T as Operator>::equals(&op, &*op),
+            Some(other) => <T as Operator>::equals(&op, &*other.lock().unwrap()),
+            None => <T as Operator>::equals(&op, other),
+        }
+    }
+    fn hash_op(&self, state: &mut dyn Hasher) {
+        <T as Operator>::hash_op(&self.lock().unwrap(), state)
+    }
 }
 
 /// An opaque function running on CPU that takes in Vec<f32> tensors and outputs Vec<f32> tensors
@@ -138,6 +195,17 @@ pub enum ConstantValue {
     Float(f32),
 }
 
+impl Hash for ConstantValue {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        std::mem::discriminant(self).hash(state);
+        match self {
+            ConstantValue::Expression(e) => e.hash(state),
+            // 0. and -0. are equal so they need to hash the same
+            ConstantValue::Float(f) => (if *f == 0. { 0 } else { f.to_bits() }).hash(state),
+        }
+    }
+}
+
 /// Produces a single number constant from an expression or a float
 #[derive(Clone, PartialEq)]
 pub struct Constant(pub ConstantValue, pub *const FxHashMap<char, usize>);
@@ -161,6 +229,7 @@ impl Operator for Constant {
             ConstantValue::Float(f) => *f,
         }])]
     }
+    op_equality!(0, 1);
 }
 
 // Unary Op (A -> A)
@@ -180,6 +249,7 @@ impl Operator for Contiguous {
         }
         vec![Tensor::new(out_data)]
     }
+    op_equality!();
 }
 
 #[derive(Debug, Clone, Default, PartialEq)]
@@ -195,6 +265,7 @@ impl Operator for Log2 {
         }
         vec![Tensor::new(out_data)]
     }
+    op_equality!();
 }
 
 #[derive(Debug, Clone, Default, PartialEq)]
@@ -210,6 +281,7 @@ impl Operator for Exp2 {
         }
         vec![Tensor::new(out_data)]
     }
+    op_equality!();
 }
 
 #[derive(Debug, Clone, Default, PartialEq)]
@@ -225,6 +297,7 @@ impl Operator for Sin {
         }
         vec![Tensor::new(out_data)]
     }
+    op_equality!();
 }
 
 #[derive(Debug, Clone, Default, PartialEq)]
@@ -240,6 +313,7 @@ impl Operator for Recip {
         }
         vec![Tensor::new(out_data)]
     }
+    op_equality!();
 }
 
 #[derive(Debug, Clone, Default, PartialEq)]
@@ -255,6 +329,7 @@ impl Operator for Sqrt {
         }
         vec![Tensor::new(out_data)]
     }
+    op_equality!();
 }
 
 // Binary Ops (A x A -> A)
@@ -273,6 +348,7 @@ impl Operator for Add {
         }
         vec![Tensor::new(out_data)]
     }
+    op_equality!();
 }
 
 #[derive(Debug, Clone, Default, PartialEq)]
@@ -289,6 +365,7 @@ impl Operator for Mul {
         }
         vec![Tensor::new(out_data)]
     }
+    op_equality!();
 }
 
 #[derive(Debug, Clone, Default, PartialEq)]
@@ -305,6 +382,7 @@ impl Operator for Mod {
         }
         vec![Tensor::new(out_data)]
     }
+    op_equality!();
 }
 
 #[derive(Debug, Clone, Default, PartialEq)]
@@ -322,6 +400,7 @@ impl Operator for LessThan {
         }
         vec![Tensor::new(out_data)]
     }
+    op_equality!();
 }
 
 // Reduce Ops (A -> B (different shape))
@@ -348,6 +427,7 @@ impl Operator for SumReduce {
         }
         vec![Tensor::new(result)]
     }
+    op_equality!(0);
 }
 
 #[derive(Debug, Clone, PartialEq)]
@@ -375,6 +455,7 @@ impl Operator for MaxReduce {
         }
         vec![Tensor::new(result)]
     }
+    op_equality!(0);
 }
 
 fn get_vec<'a>(tensor: &'a InputTensor<'a>) -> &'a Vec<f32> {
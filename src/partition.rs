@@ -0,0 +1,299 @@
+//! Running parts of a graph on different backends.
+//!
+//! A [`Partitioner`] places each op on the cheapest registered [`Backend`] that can run it, counting
+//! the copies its inputs would need. Ops no backend can run stay on the CPU. Tensors move between
+//! devices through the CPU as `Vec<f32>`, with copy ops inserted wherever an edge crosses devices.
+
+use std::fmt::Debug;
+
+use itertools::Itertools;
+use petgraph::{
+    algo::toposort,
+    visit::{EdgeRef, IntoEdgeReferences},
+};
+use rustc_hash::{FxHashMap, FxHashSet};
+
+use crate::{
+    op::{Contiguous, Function},
+    prelude::*,
+};
+
+/// A device ops can be placed on
+pub trait Backend: Debug {
+    /// The cost of running an op on this backend, or None if it can't run it
+    fn cost(&self, op: &dyn Operator, inputs: &[ShapeTracker]) -> Option<f64>;
+    /// The cost of copying a tensor onto or off of this backend
+    fn copy_cost(&self, shape: &ShapeTracker) -> f64 {
+        shape.n_elements().to_usize().unwrap_or(1) as f64
+    }
+    /// Convert an op to this backend's version of it
+    fn lower(&self, op: Box<dyn Operator>, inputs: &[ShapeTracker]) -> Box<dyn Operator>;
+    /// An op copying a CPU tensor onto this backend
+    fn copy_to_device(&self) -> Box<dyn Operator>;
+    /// An op copying a tensor on this backend back to the CPU
+    fn copy_from_device(&self) -> Box<dyn Operator>;
+}
+
+/// Split a graph across backends. Ties in cost go to the backend registered first.
+///
+/// Outputs the backend each op was placed on, by the order they were registered. Ops left on the
+/// CPU aren't included. Retrieved outputs are copied back to the CPU, and kept outputs nothing
+/// reads stay on it.
+#[derive(Debug, Default)]
+pub struct Partitioner {
+    backends: Vec<Box<dyn Backend>>,
+}
+
+impl Partitioner {
+    /// Add a backend ops can be placed on
+    pub fn register(mut self, backend: impl Backend + 'static) -> Self {
+        self.backends.push(Box::new(backend));
+        self
+    }
+}
+
+impl Compiler for Partitioner {
+    type Output = FxHashMap<NodeIndex, usize>;
+    fn compile<T: ToIdsMut>(&self, graph: &mut Graph, mut ids: T) -> Self::Output {
+        // Place each op on the cheapest backend that can run it
+        let mut placement = FxHashMap::default();
+        for node in toposort(&graph.graph, None).unwrap() {
+            let op = graph.graph.node_weight(node).unwrap().as_ref();
+            if op.as_any().is::<Function>() {
+                continue;
+            }
+            let sources = graph.get_sources(node);
+            let shapes = sources.iter().map(|(_, _, sh)| *sh).collect_vec();
+            let best = self
+                .backends
+                .iter()
+                .enumerate()
+                .filter_map(|(i, backend)| {
+                    let copies = sources
+                        .iter()
+                        .filter(|(src, _, _)| placement.get(src) != Some(&i))
+                        .map(|(_, _, sh)| backend.copy_cost(sh))
+                        .sum::<f64>();
+                    Some((i, backend.cost(op, &shapes)? + copies))
+                })
+                .min_by(|(_, a), (_, b)| a.total_cmp(b));
+            if let Some((i, _)) = best {
+                placement.insert(node, i);
+            }
+        }
+
+        // Outputs nothing reads or retrieves have no known layout to copy back to the CPU with, so
+        // they stay on it
+        let external = ids
+            .to_ids_mut()
+            .into_iter()
+            .map(|i| *i)
+            .chain(graph.no_delete.iter().copied())
+            .chain(graph.to_retrieve.keys().copied())
+            .collect::<FxHashSet<_>>();
+        let output_num = |graph: &Graph, node| graph.to_retrieve.get(&node).map_or(0, |(o, _)| *o);
+        placement.retain(|node, _| {
+            !external.contains(node)
+                || output_layout(graph, *node, output_num(graph, *node)).is_some()
+        });
+
+        // Copy tensors crossing between devices, through the CPU
+        let mut to_cpu = FxHashMap::default();
+        let mut to_device = FxHashMap::default();
+        for (edge, src, dest, (input_order, output_order, shape)) in graph
+            .graph
+            .edge_references()
+            .filter_map(|e| Some((e.id(), e.source(), e.target(), e.weight().as_data()?)))
+            .collect_vec()
+        {
+            let (from, to) = (placement.get(&src).copied(), placement.get(&dest).copied());
+            if from == to {
+                continue;
+            }
+            let (mut new_src, mut new_output) = (src, output_order);
+            if let Some(b) = from {
+                new_src = *to_cpu.entry((src, output_order)).or_insert_with(|| {
+                    graph
+                        .add_boxed_op(self.backends[b].copy_from_device())
+                        .input(src, output_order, shape.physical())
+                        .finish()
+                });
+                new_output = 0;
+            }
+            if let Some(b) = to {
+                new_src = *to_device.entry((src, output_order, b)).or_insert_with(|| {
+                    graph
+                        .add_boxed_op(self.backends[b].copy_to_device())
+                        .input(new_src, new_output, shape.physical())
+                        .finish()
+                });
+                new_output = 0;
+            }
+            graph.graph.remove_edge(edge);
+            graph.graph.add_edge(
+                new_src,
+                dest,
+                Dependency::Data {
+                    input_order,
+                    output_order: new_output,
+                    shape,
+                },
+            );
+        }
+
+        // Copy outputs back to the CPU
+        for (&node, &b) in placement.iter().filter(|(n, _)| external.contains(n)) {
+            let output = output_num(graph, node);
+            let copy = match to_cpu.get(&(node, output)) {
+                Some(copy) => *copy,
+                None => {
+                    let layout = output_layout(graph, node, output).unwrap();
+                    graph
+                        .add_boxed_op(self.backends[b].copy_from_device())
+                        .input(node, output, layout)
+                        .finish()
+                }
+            };
+            remap(node, copy, &mut ids, graph);
+            if let Some((o, _)) = graph.to_retrieve.get_mut(&copy) {
+                *o = 0;
+            }
+        }
+
+        // Swap in each backend's ops
+        for (&node, &b) in &placement {
+            let shapes = graph
+                .get_sources(node)
+                .into_iter()
+                .map(|(_, _, sh)| sh)
+                .collect_vec();
+            let weight = graph.graph.node_weight_mut(node).unwrap();
+            let op = std::mem::replace(weight, Box::new(Contiguous));
+            *weight = self.backends[b].lower(op, &shapes);
+        }
+        placement
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Partitioner;
+    use crate::{
+        op::Sin,
+        prelude::*,
+        tests::{
+            assert_close,
+            device::{CopyFromDevice, CopyToDevice, TestDevice},
+            harness::{test_compilers_close, TestCompiler},
+            test_graphs,
+        },
+    };
+
+    fn count<T: Operator + 'static>(cx: &Graph) -> usize {
+        cx.node_indices()
+            .filter(|n| cx.check_node_type::<T>(*n))
+            .count()
+    }
+
+    #[test]
+    fn test_partition_by_capability() {
+        let mut cx = Graph::new();
+        let a = cx.tensor(4).set(vec![1., 2., 3., 4.]);
+        let b = cx.tensor(4).set(vec![0.5, 1., 1.5, 2.]);
+        let product = a * b;
+        let sin = product.sin();
+        let mut c = (sin + a).retrieve();
+        let add = c.id;
+        let expected = [1., 2., 3., 4.].map(|a: f32| (a * a * 0.5).sin() + a);
+
+        let placement = cx.compile(
+            Partitioner::default()
+                .register(TestDevice::<0> {
+                    cost: 1.,
+                    sin: false,
+                })
+                .register(TestDevice::<1> {
+                    cost: 10.,
+                    sin: true,
+                }),
+            &mut c,
+        );
+        // Only the second device can run sin, and it's cheaper to come back for the add
+        assert_eq!(placement[&product.id], 0);
+        assert_eq!(placement[&sin.id], 1);
+        assert_eq!(placement[&add], 0);
+        // Both inputs go onto device 0, the product goes over to device 1 and back, and the
+        // output comes back to the CPU
+        assert_eq!(count::<CopyToDevice<0>>(&cx), 3);
+        assert_eq!(count::<CopyFromDevice<0>>(&cx), 2);
+        assert_eq!(count::<CopyToDevice<1>>(&cx), 1);
+        assert_eq!(count::<CopyFromDevice<1>>(&cx), 1);
+        cx.execute();
+        assert_close(&c.data(), &expected);
+    }
+
+    #[test]
+    fn test_cpu_fallback() {
+        let mut cx = Graph::new();
+        let a = cx.tensor(4).set(vec![1., 2., 3., 4.]);
+        let sin = (a * 2.).sin();
+        let mut b = (sin * 3.).retrieve();
+
+        let placement = cx.compile(
+            Partitioner::default().register(TestDevice::<0> {
+                cost: 1.,
+                sin: false,
+            }),
+            &mut b,
+        );
+        assert!(!placement.contains_key(&sin.id));
+        assert!(cx.check_node_type::<Sin>(sin.id));
+        cx.execute();
+        assert_close(
+            &b.data(),
+            &[1., 2., 3., 4.].map(|a: f32| (a * 2.).sin() * 3.),
+        );
+    }
+
+    #[test]
+    fn test_unread_kept_output() {
+        let mut cx = Graph::new();
+        let a = cx.tensor(4).set(vec![1., 2., 3., 4.]);
+        let mut b = (a * 2.).keep();
+        let placement = cx.compile(
+            Partitioner::default().register(TestDevice::<0> {
+                cost: 1.,
+                sin: false,
+            }),
+            &mut b,
+        );
+        // Its layout to copy back with isn't known, so it stays on the CPU
+        assert!(!placement.contains_key(&b.id));
+        cx.execute();
+        assert_eq!(b.data(), vec![2., 4., 6., 8.]);
+    }
+
+    #[test]
+    fn test_partition_graphs() {
+        test_compilers_close(
+            test_graphs::ALL,
+            &[
+                TestCompiler::new(Partitioner::default().register(TestDevice::<0> {
+                    cost: 1.,
+                    sin: true,
+                })),
+                TestCompiler::new(
+                    Partitioner::default()
+                        .register(TestDevice::<0> {
+                            cost: 1.,
+                            sin: false,
+                        })
+                        .register(TestDevice::<1> {
+                            cost: 2.,
+                            sin: true,
+                        }),
+                ),
+            ],
+        );
+    }
+}
@@ -0,0 +1,407 @@
+//! Declarative rewrite rules: match a subgraph with a [`SelectGraph`], and replace its output with
+//! an existing node or a new op.
+//!
+//! ```rust
+//! use luminal::{op::{Add, Constant, ConstantValue}, prelude::*};
+//!
+//! // x + 0 => x
+//! let x = node();
+//! let mut zero = op::<Constant>();
+//! zero.check(|o, _| {
+//!     matches!(o.as_any().downcast_ref(), Some(Constant(ConstantValue::Float(f), _)) if *f == 0.)
+//! });
+//! let add = binary::<Add>(x.clone(), zero);
+//! let rule = Rewrite::forward("x + 0", &add, &x);
+//!
+//! let mut cx = Graph::new();
+//! let a = cx.tensor(3).set([1., 2., 3.]);
+//! let mut b = (a + 0.).retrieve();
+//! cx.compile(rule, &mut b);
+//! assert_eq!(b.id, a.id);
+//! ```
+//!
+//! The engine takes care of everything around the replacement:
+//! - Matches whose intermediate nodes are marked no_delete are skipped, since they'd have to stay
+//! - Consumers of the replaced node are rewired, and external ids, no_delete and retrieval are
+//!   transferred
+//! - Matched nodes left without consumers are removed
+
+use std::fmt::Debug;
+
+use itertools::Itertools;
+use petgraph::{visit::EdgeRef, Direction};
+
+use crate::{op::Operator, prelude::*};
+
+/// What the output of a matched pattern gets replaced with
+#[allow(clippy::large_enum_variant)]
+#[derive(Debug)]
+pub enum Replacement {
+    /// Read an existing node's output (through a shape) instead
+    Forward(NodeIndex, u8, ShapeTracker),
+    /// A new op, with its inputs
+    Op(Box<dyn Operator>, Vec<(NodeIndex, u8, ShapeTracker)>),
+}
+
+impl Replacement {
+    /// Read an existing node's output instead, as `(node, output, shape)`
+    pub fn forward((node, output, shape): (NodeIndex, u8, ShapeTracker)) -> Self {
+        Self::Forward(node, output, shape)
+    }
+    /// Replace with a new op. Add inputs with [`Replacement::input`].
+    pub fn op<O: Operator + 'static>(op: O) -> Self {
+        Self::Op(Box::new(op), vec![])
+    }
+    /// Add an input to a new op, as `(node, output, shape)`
+    pub fn input(mut self, input: (NodeIndex, u8, ShapeTracker)) -> Self {
+        if let Self::Op(_, inputs) = &mut self {
+            inputs.push(input);
+        }
+        self
+    }
+}
+
+/// A match of a rule's pattern
+pub struct Match<'a> {
+    graph: &'a Graph,
+    search: &'a GraphSearch,
+}
+
+impl Match<'_> {
+    /// The graph node a pattern node matched
+    pub fn get(&self, node: &SelectGraph) -> NodeIndex {
+        self.search.get(node)
+    }
+    /// The op a pattern node matched
+    pub fn op<T: Operator + 'static>(&self, node: &SelectGraph) -> &T {
+        self.graph.get_op(self.get(node))
+    }
+    /// The inputs of the node a pattern node matched
+    pub fn sources(&self, node: &SelectGraph) -> Vec<(NodeIndex, u8, ShapeTracker)> {
+        self.graph.get_sources(self.get(node))
+    }
+    /// How the node matched by `to` reads the node matched by `from`, as `(from, output, shape)`
+    pub fn edge(&self, from: &SelectGraph, to: &SelectGraph) -> (NodeIndex, u8, ShapeTracker) {
+        let (from, to) = (self.get(from), self.get(to));
+        let (_, output, shape) = self
+            .graph
+            .graph
+            .edges_connecting(from, to)
+            .find_map(|e| e.weight().as_data())
+            .expect("Pattern nodes aren't connected");
+        (from, output, shape)
+    }
+    pub fn graph(&self) -> &Graph {
+        self.graph
+    }
+}
+
+/// A rule replacing the output of a pattern. Running it as a compiler applies it until the pattern
+/// doesn't match anymore, so rules must make the graph simpler or they'll never stop.
+pub struct Rewrite {
+    name: String,
+    pattern: SelectGraph,
+    #[allow(clippy::type_complexity)]
+    replace: Box<dyn Fn(&Match) -> Option<Replacement>>,
+}
+
+impl Debug for Rewrite {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Rewrite({})", self.name)
+    }
+}
+
+impl Rewrite {
+    /// Replace the output of `pattern` with whatever `replace` returns for a match. Returning
+    /// `None` skips the match.
+    pub fn new<F: Fn(&Match) -> Option<Replacement> + 'static>(
+        name: impl ToString,
+        pattern: &SelectGraph,
+        replace: F,
+    ) -> Self {
+        Self {
+            name: name.to_string(),
+            pattern: pattern.clone(),
+            replace: Box::new(replace),
+        }
+    }
+
+    /// Replace the output of `pattern` with one of its inputs, read the way the output reads it
+    pub fn forward(name: impl ToString, pattern: &SelectGraph, input: &SelectGraph) -> Self {
+        let (input, output) = (input.clone(), pattern.clone());
+        Self::new(name, pattern, move |m| {
+            Some(Replacement::forward(m.edge(&input, &output)))
+        })
+    }
+}
+
+impl Compiler for Rewrite {
+    type Output = ();
+    fn compile<T: ToIdsMut>(&self, graph: &mut Graph, mut ids: T) {
+        let mut s = self.pattern.clone().search(graph);
+        // Work through the matches found, only searching again when a rewrite removes nodes from
+        // ones still to come, and once more at the end in case the rewrites made new matches
+        let mut rewritten = false;
+        loop {
+            if !s.next_match() {
+                if !std::mem::take(&mut rewritten) {
+                    break;
+                }
+                s.reset();
+                continue;
+            }
+            let Some(replacement) = (self.replace)(&Match { graph, search: &s }) else {
+                continue;
+            };
+            let root = s.get(&self.pattern);
+            let Some(removed) = replace(root, s.matched_nodes(), replacement, graph, &mut ids)
+            else {
+                continue;
+            };
+            if debug() {
+                println!("Applied {}", self.name);
+            }
+            rewritten = true;
+            if s.pending_matches_use(&removed) {
+                s.reset();
+            }
+        }
+    }
+}
+
+/// Replace `root` and remove what's left unused of the match. Returns the removed nodes, or None if
+/// the replacement can't be done.
+fn replace<T: ToIdsMut>(
+    root: NodeIndex,
+    matched: Vec<NodeIndex>,
+    replacement: Replacement,
+    graph: &mut Graph,
+    ids: &mut T,
+) -> Option<Vec<NodeIndex>> {
+    let used = match &replacement {
+        Replacement::Forward(node, _, _) => vec![*node],
+        Replacement::Op(_, inputs) => inputs.iter().map(|(n, _, _)| *n).collect(),
+    };
+    let mut intermediates = matched
+        .into_iter()
+        .filter(|n| *n != root && !used.contains(n))
+        .collect_vec();
+    // Nothing is gained if intermediates need to stay around
+    if intermediates.iter().any(|n| graph.no_delete.contains(n)) {
+        return None;
+    }
+    let external = graph.no_delete.contains(&root)
+        || graph.to_retrieve.contains_key(&root)
+        || ids.to_ids_mut().iter().any(|id| **id == root);
+    let new = match replacement {
+        Replacement::Forward(node, output, shape) => {
+            if node == root {
+                return None;
+            }
+            let dests = graph
+                .graph
+                .edges_directed(root, Direction::Outgoing)
+                .map(|e| (e.id(), e.target(), *e.weight()))
+                .collect_vec();
+            // A forwarded output that isn't laid out as is needs consumers that read all of it as
+            // is, so they can read through its shape instead
+            let reshaped = shape.is_reshaped();
+            if (external && (reshaped || output != 0))
+                || (reshaped
+                    && dests
+                        .iter()
+                        .filter_map(|(_, _, w)| w.as_data())
+                        .any(|(_, _, s)| s.is_reshaped() || s.dims() != shape.dims()))
+            {
+                return None;
+            }
+            for (edge, target, weight) in dests {
+                graph.graph.remove_edge(edge);
+                let weight = match weight {
+                    Dependency::Data {
+                        input_order,
+                        shape: dest_shape,
+                        ..
+                    } => Dependency::Data {
+                        input_order,
+                        output_order: output,
+                        shape: if reshaped { shape } else { dest_shape },
+                    },
+                    schedule => schedule,
+                };
+                graph.graph.add_edge(node, target, weight);
+            }
+            node
+        }
+        Replacement::Op(op, inputs) => {
+            let mut new = graph.add_boxed_op(op);
+            for (node, output, shape) in inputs {
+                new = new.input(node, output, shape);
+            }
+            let new = new.finish();
+            move_outgoing_edge(root, new, &mut graph.graph);
+            new
+        }
+    };
+    remap(root, new, &mut *ids, graph);
+    graph.graph.remove_node(root);
+    let mut removed = vec![root];
+
+    // Remove intermediates nothing reads anymore, consumers first
+    let ids = ids.to_ids_mut().into_iter().map(|id| *id).collect_vec();
+    loop {
+        let n = intermediates.len();
+        intermediates.retain(|node| {
+            let unused = graph
+                .graph
+                .edges_directed(*node, Direction::Outgoing)
+                .all(|e| e.weight().is_schedule())
+                && !graph.to_retrieve.contains_key(node)
+                && !ids.contains(node);
+            if unused {
+                graph.graph.remove_node(*node);
+                removed.push(*node);
+            }
+            !unused
+        });
+        if intermediates.len() == n {
+            break;
+        }
+    }
+    Some(removed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Replacement, Rewrite};
+    use crate::{
+        op::{Add, Constant, ConstantValue, Exp2, InputTensor, Log2, Operator, Recip, Sin},
+        prelude::*,
+        tests::{assert_close, random_vec},
+    };
+
+    /// 1 / sin(x)
+    #[derive(Debug)]
+    struct Csc;
+
+    impl Operator for Csc {
+        fn process(&mut self, inp: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
+            let shape = inp[0].1.contiguous();
+            let sin = Sin.process(inp);
+            Recip.process(vec![(InputTensor::Borrowed(&sin[0]), shape)])
+        }
+    }
+
+    fn csc_rule() -> Rewrite {
+        let x = node();
+        let sin = unary::<Sin>(x.clone());
+        let mut recip = unary::<Recip>(sin.clone());
+        recip.check(|_, shapes| !shapes[0].is_reshaped());
+        Rewrite::new("csc", &recip, move |m| {
+            Some(Replacement::op(Csc).input(m.edge(&x, &sin)))
+        })
+    }
+
+    fn count<T: Operator + 'static>(cx: &Graph) -> usize {
+        cx.node_indices()
+            .filter(|n| cx.check_node_type::<T>(*n))
+            .count()
+    }
+
+    #[test]
+    fn test_replace_with_op() {
+        let mut cx = Graph::new();
+        let data = random_vec(6);
+        let a = cx.tensor((2, 3)).set(data.clone());
+        let b = a.permute((1, 0)).sin().recip();
+        let mut c = (b.exp2().sin().recip() + b).retrieve();
+        cx.compile(csc_rule(), &mut c);
+        assert_eq!(count::<Csc>(&cx), 2);
+        assert_eq!(count::<Sin>(&cx) + count::<Recip>(&cx), 0);
+
+        let mut cx2 = Graph::new();
+        let a = cx2.tensor((2, 3)).set(data);
+        let b = a.permute((1, 0)).sin().recip();
+        let d = (b.exp2().sin().recip() + b).retrieve();
+        cx.execute();
+        cx2.execute();
+        assert_close(&c.data(), &d.data());
+    }
+
+    #[test]
+    fn test_intermediates_kept() {
+        let mut cx = Graph::new();
+        let a = cx.tensor(3).set([1., 2., 3.]);
+        let b = a.sin().retrieve();
+        let mut c = b.recip().retrieve();
+        cx.compile(csc_rule(), &mut c);
+        // The sin is needed, so fusing it wouldn't help
+        assert_eq!(count::<Csc>(&cx), 0);
+
+        // A permuted read between the ops fails the shape check
+        let mut cx = Graph::new();
+        let a = cx.tensor((2, 2)).set([1., 2., 3., 4.]);
+        let mut b = a.sin().permute((1, 0)).recip().retrieve();
+        cx.compile(csc_rule(), &mut b);
+        assert_eq!(count::<Csc>(&cx), 0);
+    }
+
+    #[test]
+    fn test_chained_matches() {
+        // x + 0 => x, where removing one add changes the matches of the ones after it
+        let x = node();
+        let mut zero = op::<Constant>();
+        zero.check(|o, _| {
+            matches!(o.as_any().downcast_ref(), Some(Constant(ConstantValue::Float(f), _)) if *f == 0.)
+        });
+        let add = binary::<Add>(x.clone(), zero);
+        let rule = Rewrite::forward("x + 0", &add, &x);
+
+        let mut cx = Graph::new();
+        let a = cx.tensor(3).set([1., 2., 3.]);
+        let mut chain = a;
+        for _ in 0..50 {
+            chain = chain + 0.;
+        }
+        let mut b = chain.retrieve();
+        let mut others = (0..50).map(|i| (a + 0.) * i as f32).collect::<Vec<_>>();
+        others.iter_mut().for_each(|t| *t = t.retrieve());
+        cx.compile(rule, (&mut b, &mut others));
+        assert_eq!(count::<Add>(&cx), 0);
+        assert_eq!(b.id, a.id);
+        cx.execute();
+        assert_close(&others[3].data(), &[3., 6., 9.]);
+    }
+
+    #[test]
+    fn test_forward() {
+        // Consumers of the output read through the permute instead
+        let x = node();
+        let exp2 = unary::<Exp2>(x.clone());
+        let log2 = unary::<Log2>(exp2.clone());
+        let rule = || {
+            let (x, exp2) = (x.clone(), exp2.clone());
+            Rewrite::new("log2(exp2(x))", &log2, move |m| {
+                Some(Replacement::forward(m.edge(&x, &exp2)))
+            })
+        };
+        let mut cx = Graph::new();
+        let a = cx.tensor((2, 3)).set(vec![1., 2., 3., 4., 5., 6.]);
+        let b = a.permute((1, 0)).exp2().log2();
+        let mut c = b.sin().retrieve();
+        let mut d = (b + 1.).retrieve();
+        cx.compile(rule(), (&mut c, &mut d));
+        assert_eq!(count::<Exp2>(&cx), 0);
+        cx.execute();
+        assert_close(&d.data(), &[2., 5., 3., 6., 4., 7.]);
+
+        // Unless the output is retrieved, since it'd be read in the wrong layout
+        let mut cx = Graph::new();
+        let a = cx.tensor((2, 3)).set(vec![1., 2., 3., 4., 5., 6.]);
+        let mut b = a.permute((1, 0)).exp2().log2().retrieve();
+        cx.compile(rule(), &mut b);
+        assert_eq!(count::<Log2>(&cx), 1);
+        cx.execute();
+        assert_close(&b.data(), &[1., 4., 2., 5., 3., 6.]);
+    }
+}
@@ -21,9 +21,13 @@ pub fn expression_cleanup() {
     EXPRESSION_OWNER.with(|cell| cell.borrow_mut().take());
 }
 
-/// Get the thread-local owner of expression storage
+/// Get the thread-local owner of expression storage, making a new one if it was cleaned up
 fn expression_owner() -> Owner {
-    EXPRESSION_OWNER.with(|cell| cell.borrow().clone().unwrap())
+    EXPRESSION_OWNER.with(|cell| {
+        cell.borrow_mut()
+            .get_or_insert_with(UnsyncStorage::owner)
+            .clone()
+    })
 }
 
 #[derive(Clone, Copy)]
@@ -979,4 +983,14 @@ mod tests {
         assert_eq!(expr.simplify().terms.read().len(), 7);
         expression_cleanup();
     }
+
+    #[test]
+    fn test_expressions_after_cleanup() {
+        // Dropping a graph cleans up expression storage, which shouldn't stop later graphs on the
+        // same thread from making expressions
+        drop(Graph::new());
+        let n = Expression::from('x') * 2;
+        assert_eq!(n.exec(&[('x', 3)].into_iter().collect()).unwrap(), 6);
+        expression_cleanup();
+    }
 }
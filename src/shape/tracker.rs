@@ -309,6 +309,56 @@ impl ShapeTracker {
         }
     }
 
+    /// The contiguous buffer this shape reads from, without any views
+    pub fn physical(&self) -> ShapeTracker {
+        ShapeTracker::new(
+            (0..self.len())
+                .filter(|i| !self.fake[*i])
+                .map(|i| self.dims[i])
+                .collect::<Vec<_>>(),
+        )
+    }
+
+    /// Read a tensor through `next` after reading it through this shape and making it contiguous,
+    /// in one step. Only works when this shape just permutes, and `next` reads the contiguous
+    /// tensor (isn't a reshape).
+    pub fn compose(&self, next: &ShapeTracker) -> Option<ShapeTracker> {
+        if self.is_sliced() || self.is_padded() || self.fake.iter().any(|f| *f) {
+            return None;
+        }
+        let real = (0..next.len())
+            .filter(|p| !next.fake[*p])
+            .collect::<Vec<_>>();
+        if real.len() != self.len()
+            || real
+                .iter()
+                .zip(self.dims())
+                .any(|(p, d)| next.dims[*p] != d)
+        {
+            return None;
+        }
+        // Where each dimension of next goes in the new shape: real ones to the dimension of the
+        // original tensor they came from, fake ones after those
+        let mut slot = vec![0; next.len()];
+        for (k, p) in real.iter().enumerate() {
+            slot[*p] = self.indexes[k];
+        }
+        for (f, p) in (0..next.len()).filter(|p| next.fake[*p]).enumerate() {
+            slot[p] = self.len() + f;
+        }
+        let mut n = *next;
+        for (p, slot) in slot.iter().enumerate() {
+            n.dims[*slot] = next.dims[p];
+            n.fake[*slot] = next.fake[p];
+            n.mask[*slot] = next.mask[p];
+            n.padding[*slot] = next.padding[p];
+        }
+        for l in 0..next.len() {
+            n.indexes[l] = slot[next.indexes[l]];
+        }
+        Some(n)
+    }
+
     pub fn is_sliced(&self) -> bool {
         self.mask.iter().any(|(b, e)| {
             b.to_usize().map(|i| i != 0).unwrap_or(true)
@@ -0,0 +1,298 @@
+//! Splitting large matmuls across devices.
+//!
+//! Weights are marked with [`GraphTensor::shard`], and [`TensorParallel`] rewrites each matmul with
+//! a sharded input into one matmul per device, reading its slice of the weight, joined back
+//! together by a collective op:
+//! - Splitting a dimension that ends up in the output (the columns of a `(in, out)` weight) gives
+//!   each device a slice of the output, which are concatenated with an [`AllGather`]
+//! - Splitting the dimension that's summed over (the rows of a `(in, out)` weight) gives each
+//!   device a partial sum of the whole output, which are added with an [`AllReduce`]
+//!
+//! Which device runs which shard is up to the backend. The shards feeding a collective are in
+//! device order.
+
+use itertools::Itertools;
+use petgraph::{visit::EdgeRef, Direction};
+
+use crate::{
+    op::{Contiguous, Mul, SumReduce},
+    prelude::*,
+};
+
+/// How a 2D tensor is split across devices
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Sharding {
+    /// Split along the first dimension
+    Rows,
+    /// Split along the last dimension
+    Columns,
+}
+
+/// Concatenate one tensor from each device along an axis
+#[derive(Debug, Clone, PartialEq)]
+pub struct AllGather(pub usize);
+
+impl Operator for AllGather {
+    fn process(&mut self, inp: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
+        let dims = inp.iter().map(|(_, sh)| sh.shape_usize()).collect_vec();
+        let parts = inp
+            .into_iter()
+            .map(|i| Contiguous.process(vec![i]).pop().unwrap())
+            .collect_vec();
+        let mut out = vec![];
+        for o in 0..dims[0][..self.0].iter().product::<usize>() {
+            for (part, dims) in parts.iter().zip(&dims) {
+                let chunk = dims[self.0..].iter().product::<usize>();
+                let part = part.downcast_ref::<Vec<f32>>().unwrap();
+                out.extend_from_slice(&part[o * chunk..(o + 1) * chunk]);
+            }
+        }
+        vec![Tensor::new(out)]
+    }
+    crate::op_equality!(0);
+}
+
+/// Sum one tensor from each device
+#[derive(Debug, Clone, PartialEq)]
+pub struct AllReduce;
+
+impl Operator for AllReduce {
+    fn process(&mut self, inp: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
+        let mut out = vec![0.; inp[0].1.n_elements().to_usize().unwrap()];
+        for i in inp {
+            let part = Contiguous.process(vec![i]).pop().unwrap();
+            for (a, b) in out.iter_mut().zip(part.downcast_ref::<Vec<f32>>().unwrap()) {
+                *a += b;
+            }
+        }
+        vec![Tensor::new(out)]
+    }
+    crate::op_equality!();
+}
+
+/// Split matmuls with a [sharded](GraphTensor::shard) input into one matmul per device.
+///
+/// Matmuls are left as they are if the sharded dimension isn't known at compile time, or doesn't
+/// divide evenly across the devices.
+#[derive(Debug, Clone, Copy)]
+pub struct TensorParallel {
+    pub devices: usize,
+}
+
+impl Compiler for TensorParallel {
+    type Output = ();
+    fn compile<T: ToIdsMut>(&self, graph: &mut Graph, mut ids: T) {
+        for reduce in graph.node_indices().collect_vec() {
+            let Some(axis) = graph
+                .node_weight(reduce)
+                .and_then(|op| op.as_any().downcast_ref::<SumReduce>())
+                .map(|r| r.0)
+            else {
+                continue;
+            };
+            let (mul, _, mul_shape) = graph.get_sources(reduce)[0];
+            if !graph.check_node_type::<Mul>(mul)
+                || mul_shape.is_reshaped()
+                || graph.no_delete.contains(&mul)
+                || graph.to_retrieve.contains_key(&mul)
+                || graph.edges_directed(mul, Direction::Outgoing).count() != 1
+            {
+                continue;
+            }
+            let sources = graph.get_sources(mul);
+            let sharded = sources
+                .iter()
+                .filter_map(|(n, _, sh)| Some((graph.shardings.get(n)?, sh)))
+                .collect_vec();
+            let [(sharding, weight)] = sharded[..] else {
+                continue;
+            };
+            // The dimension of the weight being split, and where it is in the product
+            let real = (0..weight.len()).filter(|i| !weight.fake[*i]).collect_vec();
+            let dim = match sharding {
+                Sharding::Rows => real[0],
+                Sharding::Columns => *real.last().unwrap(),
+            };
+            let split = weight.indexes.iter().position(|i| *i == dim).unwrap();
+            let Some(size) = weight.dims[dim]
+                .to_usize()
+                .filter(|s| s % self.devices == 0)
+            else {
+                continue;
+            };
+            if sources
+                .iter()
+                .any(|(_, _, sh)| sh.is_sliced() || sh.is_padded())
+            {
+                continue;
+            }
+
+            // One matmul per device, on its slice of the split dimension
+            let size = size / self.devices;
+            let shards = (0..self.devices)
+                .map(|device| {
+                    let views = sources
+                        .iter()
+                        .map(|(n, o, sh)| {
+                            let mut sh = *sh;
+                            let d = sh.indexes[split];
+                            if sh.fake[d] {
+                                sh.dims[d] = size.into();
+                            } else {
+                                sh.mask[d] = ((device * size).into(), ((device + 1) * size).into());
+                            }
+                            (*n, *o, sh)
+                        })
+                        .collect_vec();
+                    let dims = views[0].2.dims();
+                    let mut product = graph.add_op(Mul);
+                    for (n, o, sh) in views {
+                        product = product.input(n, o, sh);
+                    }
+                    let product = product.finish();
+                    let sum = graph
+                        .add_op(SumReduce(axis))
+                        .input(product, 0, ShapeTracker::new(dims.clone()))
+                        .finish();
+                    let mut out = dims;
+                    out.remove(axis);
+                    (sum, ShapeTracker::new(out))
+                })
+                .collect_vec();
+            let mut collective = if split == axis {
+                graph.add_op(AllReduce)
+            } else {
+                graph.add_op(AllGather(if split < axis { split } else { split - 1 }))
+            };
+            for (sum, shape) in shards {
+                collective = collective.input(sum, 0, shape);
+            }
+            let collective = collective.finish();
+
+            for (edge, target, weight) in graph
+                .edges_directed(reduce, Direction::Outgoing)
+                .map(|e| (e.id(), e.target(), *e.weight()))
+                .collect_vec()
+            {
+                graph.graph.remove_edge(edge);
+                graph.graph.add_edge(collective, target, weight);
+            }
+            remap(reduce, collective, &mut ids, graph);
+            graph.graph.remove_node(reduce);
+            graph.graph.remove_node(mul);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{AllGather, AllReduce, Sharding, TensorParallel};
+    use crate::{
+        op::Mul,
+        prelude::*,
+        tests::{assert_close, random_vec},
+    };
+
+    fn count<T: Operator + 'static>(cx: &Graph) -> usize {
+        cx.node_indices()
+            .filter(|n| cx.check_node_type::<T>(*n))
+            .count()
+    }
+
+    /// `x @ w`, with w sharded across some devices, and without sharding
+    fn run(
+        x: (&[usize], Vec<f32>),
+        w: (&[usize], Vec<f32>),
+        transpose: bool,
+        sharding: Sharding,
+        devices: usize,
+    ) -> (Graph, Vec<f32>, Vec<f32>) {
+        let mut reference = Graph::new();
+        let mut cx = Graph::new();
+        let mut outputs = [&mut reference, &mut cx].map(|cx| {
+            let a = cx.tensor(x.0).set(x.1.clone());
+            let mut b = cx.tensor(w.0).set(w.1.clone()).shard(sharding);
+            if transpose {
+                b = b.permute((1, 0));
+            }
+            a.matmul(b).retrieve()
+        });
+        cx.compile(TensorParallel { devices }, &mut outputs[1]);
+        reference.execute();
+        cx.execute();
+        let (expected, sharded) = (outputs[0].data(), outputs[1].data());
+        (cx, expected, sharded)
+    }
+
+    #[test]
+    fn test_column_parallel() {
+        let (cx, expected, sharded) = run(
+            (&[3, 4], random_vec(12)),
+            (&[4, 6], random_vec(24)),
+            false,
+            Sharding::Columns,
+            3,
+        );
+        assert_eq!(count::<Mul>(&cx), 3);
+        assert_eq!(count::<AllGather>(&cx), 1);
+        assert_close(&sharded, &expected);
+    }
+
+    #[test]
+    fn test_row_parallel() {
+        let (cx, expected, sharded) = run(
+            (&[3, 4], random_vec(12)),
+            (&[4, 6], random_vec(24)),
+            false,
+            Sharding::Rows,
+            2,
+        );
+        assert_eq!(count::<Mul>(&cx), 2);
+        assert_eq!(count::<AllReduce>(&cx), 1);
+        assert_close(&sharded, &expected);
+    }
+
+    #[test]
+    fn test_batched_transposed_weight() {
+        // A (out, in) weight, like a Linear layer's, split by output features
+        let (cx, expected, sharded) = run(
+            (&[2, 3, 4], random_vec(24)),
+            (&[6, 4], random_vec(24)),
+            true,
+            Sharding::Rows,
+            2,
+        );
+        assert_eq!(count::<AllGather>(&cx), 1);
+        assert_close(&sharded, &expected);
+        // Split along what's summed over
+        let (cx, expected, sharded) = run(
+            (&[2, 3, 4], random_vec(24)),
+            (&[6, 4], random_vec(24)),
+            true,
+            Sharding::Columns,
+            4,
+        );
+        assert_eq!(count::<AllReduce>(&cx), 1);
+        assert_close(&sharded, &expected);
+    }
+
+    #[test]
+    #[should_panic(expected = "Shard before permuting")]
+    fn test_shard_view() {
+        let mut cx = Graph::new();
+        cx.tensor((6, 4)).permute((1, 0)).shard(Sharding::Rows);
+    }
+
+    #[test]
+    fn test_uneven_split() {
+        let (cx, expected, sharded) = run(
+            (&[3, 4], random_vec(12)),
+            (&[4, 6], random_vec(24)),
+            false,
+            Sharding::Columns,
+            4,
+        );
+        assert_eq!(count::<Mul>(&cx), 1);
+        assert_close(&sharded, &expected);
+    }
+}
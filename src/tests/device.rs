@@ -0,0 +1,183 @@
+//! A CPU standing in for a device, with its own data type, for testing code that moves data
+//! between devices.
+
+use std::any::{Any, TypeId};
+
+use itertools::Itertools;
+use petgraph::{visit::EdgeRef, Direction};
+
+use crate::{
+    op::{
+        Add, Constant, Contiguous, Exp2, Function, LessThan, Log2, MaxReduce, Mod, Mul, Recip, Sin,
+        Sqrt, SumReduce,
+    },
+    partition::Backend,
+    prelude::*,
+};
+
+/// Tensor data on a [`TestDevice`]
+#[derive(Debug, Clone)]
+pub struct DeviceBuffer<const D: u8>(pub Vec<f32>);
+
+impl<const D: u8> Data for DeviceBuffer<D> {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+}
+
+/// Runs a CPU op on device buffers
+#[derive(Debug)]
+pub struct DeviceOp<const D: u8>(pub Box<dyn Operator>);
+
+impl<const D: u8> Operator for DeviceOp<D> {
+    fn process(&mut self, inp: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
+        let inp = inp
+            .into_iter()
+            .map(|(t, sh)| {
+                let buffer = t
+                    .borrowed()
+                    .downcast_ref::<DeviceBuffer<D>>()
+                    .expect("Input isn't on this device");
+                (InputTensor::Owned(Tensor::new(buffer.0.clone())), sh)
+            })
+            .collect();
+        self.0
+            .process(inp)
+            .into_iter()
+            .map(|t| {
+                Tensor::new(DeviceBuffer::<D>(
+                    t.downcast_ref::<Vec<f32>>().unwrap().clone(),
+                ))
+            })
+            .collect()
+    }
+}
+
+/// Copies a CPU tensor onto a [`TestDevice`]. Like GPU copies, data already on it is passed along
+#[derive(Debug)]
+pub struct CopyToDevice<const D: u8>;
+
+impl<const D: u8> Operator for CopyToDevice<D> {
+    fn process(&mut self, mut inp: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
+        if inp[0].0.borrowed().is::<DeviceBuffer<D>>() {
+            return vec![inp.pop().unwrap().0.cloned()];
+        }
+        let data = inp[0].0.borrowed().downcast_ref::<Vec<f32>>().unwrap();
+        vec![Tensor::new(DeviceBuffer::<D>(data.clone()))]
+    }
+}
+
+/// Copies a tensor on a [`TestDevice`] back to the CPU. Data already on the CPU is passed along
+#[derive(Debug)]
+pub struct CopyFromDevice<const D: u8>;
+
+impl<const D: u8> Operator for CopyFromDevice<D> {
+    fn process(&mut self, mut inp: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
+        if inp[0].0.borrowed().is::<Vec<f32>>() {
+            return vec![inp.pop().unwrap().0.cloned()];
+        }
+        let data = inp[0]
+            .0
+            .borrowed()
+            .downcast_ref::<DeviceBuffer<D>>()
+            .unwrap();
+        vec![Tensor::new(data.0.clone())]
+    }
+}
+
+/// A CPU standing in for a device in a [`Partitioner`](crate::partition::Partitioner)
+#[derive(Debug)]
+pub struct TestDevice<const D: u8> {
+    pub cost: f64,
+    /// Whether it can run [`Sin`]
+    pub sin: bool,
+}
+
+impl<const D: u8> Backend for TestDevice<D> {
+    fn cost(&self, op: &dyn Operator, _: &[ShapeTracker]) -> Option<f64> {
+        (self.sin || !op.as_any().is::<Sin>()).then_some(self.cost)
+    }
+    fn lower(&self, op: Box<dyn Operator>, _: &[ShapeTracker]) -> Box<dyn Operator> {
+        Box::new(DeviceOp::<D>(op))
+    }
+    fn copy_to_device(&self) -> Box<dyn Operator> {
+        Box::new(CopyToDevice::<D>)
+    }
+    fn copy_from_device(&self) -> Box<dyn Operator> {
+        Box::new(CopyFromDevice::<D>)
+    }
+}
+
+/// Moves a whole graph onto a [`TestDevice`] the way GPU compilers do. Inputs are copied onto the
+/// device and retrieved outputs back off of it, and only primitive ops are lowered, leaving any
+/// others to deal with device data themselves.
+#[derive(Debug, Default)]
+pub struct DeviceCompiler<const D: u8>;
+
+impl<const D: u8> Compiler for DeviceCompiler<D> {
+    type Output = ();
+    fn compile<T: ToIdsMut>(&self, graph: &mut Graph, mut ids: T) {
+        let primitives = [
+            TypeId::of::<Constant>(),
+            TypeId::of::<Contiguous>(),
+            TypeId::of::<Log2>(),
+            TypeId::of::<Exp2>(),
+            TypeId::of::<Sin>(),
+            TypeId::of::<Recip>(),
+            TypeId::of::<Sqrt>(),
+            TypeId::of::<Add>(),
+            TypeId::of::<Mul>(),
+            TypeId::of::<Mod>(),
+            TypeId::of::<LessThan>(),
+            TypeId::of::<SumReduce>(),
+            TypeId::of::<MaxReduce>(),
+        ];
+        let ops = graph.node_indices().collect_vec();
+        let functions = ops
+            .iter()
+            .copied()
+            .filter(|n| graph.check_node_type::<Function>(*n))
+            .collect_vec();
+        for function in functions {
+            let copy = graph
+                .add_op(CopyToDevice::<D>)
+                .input(function, 0, ShapeTracker::new(()))
+                .finish();
+            for (edge, target, weight) in graph
+                .edges_directed(function, Direction::Outgoing)
+                .filter(|e| e.target() != copy)
+                .map(|e| (e.id(), e.target(), *e.weight()))
+                .collect_vec()
+            {
+                graph.remove_edge(edge);
+                graph.add_edge(copy, target, weight);
+            }
+        }
+        for (output, (output_num, shape)) in graph
+            .to_retrieve
+            .iter()
+            .map(|(n, o)| (*n, *o))
+            .filter(|(n, _)| !graph.check_node_type::<Function>(*n))
+            .collect_vec()
+        {
+            let copy = graph
+                .add_op(CopyFromDevice::<D>)
+                .input(output, output_num, shape)
+                .finish();
+            remap(output, copy, &mut ids, graph);
+            if let Some((o, _)) = graph.to_retrieve.get_mut(&copy) {
+                *o = 0;
+            }
+        }
+        for node in ops {
+            let weight = graph.graph.node_weight_mut(node).unwrap();
+            if primitives.contains(&weight.as_any().type_id()) {
+                let op = std::mem::replace(weight, Box::new(Contiguous));
+                *weight = Box::new(DeviceOp::<D>(op));
+            }
+        }
+    }
+}
@@ -0,0 +1,652 @@
+//! Random graph generation for fuzzing compilers against the unoptimized graph.
+//!
+//! A [`Program`] is a list of [`Node`]s, each either a new input or an hl op applied to earlier
+//! nodes. Programs are generated well typed, so every node can be built, and failing programs are
+//! shrunk down to a minimal reproducer which prints as Rust code.
+//!
+//! ```rust
+//! use luminal::{prelude::*, tests::{fuzz::fuzz_compilers, harness::TestCompiler}};
+//!
+//! fuzz_compilers(&[TestCompiler::new(GenericCompiler::default())], 5, 0);
+//! ```
+
+use std::{
+    fmt::{self, Display},
+    panic::{self, AssertUnwindSafe},
+};
+
+use itertools::Itertools;
+use rand::{rngs::StdRng, seq::SliceRandom, Rng, SeedableRng};
+
+use crate::prelude::*;
+
+use super::harness::{check_compilers, GraphIO, TestCompiler};
+
+/// Symbols used by dynamic dimensions
+const SYMBOLS: [char; 2] = ['a', 'b'];
+/// Highest rank of any tensor in a program
+const MAX_RANK: usize = 4;
+/// Nodes in each random program
+const PROGRAM_SIZE: usize = 10;
+
+/// A dimension of `sym + n`, or just `n` without a symbol. Expressions only live as long as their
+/// graph, so programs keep their own dimensions.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Dim {
+    pub sym: Option<char>,
+    pub n: usize,
+}
+
+impl Dim {
+    pub fn fixed(n: usize) -> Self {
+        Self { sym: None, n }
+    }
+
+    pub fn sym(c: char) -> Self {
+        Self { sym: Some(c), n: 0 }
+    }
+
+    fn expr(&self) -> Expression {
+        match self.sym {
+            Some(c) => Expression::from(c) + self.n,
+            None => self.n.into(),
+        }
+    }
+
+    fn add(self, other: Dim) -> Option<Dim> {
+        match (self.sym, other.sym) {
+            (Some(_), Some(_)) => None,
+            (sym, None) | (None, sym) => Some(Dim {
+                sym,
+                n: self.n + other.n,
+            }),
+        }
+    }
+}
+
+impl Display for Dim {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match (self.sym, self.n) {
+            (None, n) => write!(f, "{n}"),
+            (Some(c), 0) => write!(f, "'{c}'"),
+            (Some(c), n) => write!(f, "Expression::from('{c}') + {n}"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Unary {
+    Neg,
+    Abs,
+    Square,
+    Sin,
+    Cos,
+    Exp,
+    Relu,
+    Sigmoid,
+    Tanh,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Binary {
+    Add,
+    Sub,
+    Mul,
+    Max,
+    LessThan,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Reduce {
+    Sum,
+    Max,
+    Mean,
+}
+
+/// A step of a program. Operands are indexes of earlier nodes, and axes come after them.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Node {
+    Input(Vec<Dim>),
+    Unary(Unary, usize),
+    Binary(Binary, usize, usize),
+    Permute(usize, Vec<usize>),
+    /// Broadcast along a new axis
+    Expand(usize, usize, Dim),
+    /// Slice a fixed size axis to `start..end`
+    Slice(usize, usize, usize, usize),
+    /// Pad an axis with zeros before and after
+    Pad(usize, usize, usize, usize),
+    Reduce(Reduce, usize, usize),
+    Concat(usize, usize, usize),
+    Matmul(usize, usize),
+    Softmax(usize, usize),
+    Contiguous(usize),
+}
+
+impl Node {
+    fn operands(&self) -> Vec<usize> {
+        match self {
+            Node::Input(_) => vec![],
+            Node::Binary(_, a, b) | Node::Concat(a, b, _) | Node::Matmul(a, b) => vec![*a, *b],
+            Node::Unary(_, a)
+            | Node::Permute(a, _)
+            | Node::Expand(a, _, _)
+            | Node::Slice(a, _, _, _)
+            | Node::Pad(a, _, _, _)
+            | Node::Reduce(_, a, _)
+            | Node::Softmax(a, _)
+            | Node::Contiguous(a) => vec![*a],
+        }
+    }
+
+    fn map_operands(&mut self, mut f: impl FnMut(usize) -> usize) {
+        match self {
+            Node::Input(_) => {}
+            Node::Binary(_, a, b) | Node::Concat(a, b, _) | Node::Matmul(a, b) => {
+                *a = f(*a);
+                *b = f(*b);
+            }
+            Node::Unary(_, a)
+            | Node::Permute(a, _)
+            | Node::Expand(a, _, _)
+            | Node::Slice(a, _, _, _)
+            | Node::Pad(a, _, _, _)
+            | Node::Reduce(_, a, _)
+            | Node::Softmax(a, _)
+            | Node::Contiguous(a) => *a = f(*a),
+        }
+    }
+
+    /// The shape of this node given the shapes before it, or None if it's ill typed
+    fn shape(&self, shapes: &[Vec<Dim>]) -> Option<Vec<Dim>> {
+        let get = |i: usize| shapes.get(i).cloned();
+        let rank_ok = |s: Vec<Dim>| (1..=MAX_RANK).contains(&s.len()).then_some(s);
+        match *self {
+            Node::Input(ref shape) => rank_ok(shape.clone()),
+            Node::Unary(_, a) => get(a),
+            Node::Binary(_, a, b) => get(a).filter(|s| Some(s) == get(b).as_ref()),
+            Node::Permute(a, ref axes) => {
+                let s = get(a)?;
+                (axes.iter().sorted().copied().eq(0..s.len()))
+                    .then(|| axes.iter().map(|i| s[*i]).collect())
+            }
+            Node::Expand(a, axis, dim) => {
+                let mut s = get(a)?;
+                (axis <= s.len()).then(|| s.insert(axis, dim))?;
+                rank_ok(s)
+            }
+            Node::Slice(a, axis, start, end) => {
+                let mut s = get(a)?;
+                let d = s.get_mut(axis)?;
+                (d.sym.is_none() && start < end && end <= d.n)
+                    .then(|| *d = Dim::fixed(end - start))?;
+                Some(s)
+            }
+            Node::Pad(a, axis, before, after) => {
+                let mut s = get(a)?;
+                let d = s.get_mut(axis)?;
+                d.n += before + after;
+                Some(s)
+            }
+            Node::Reduce(_, a, axis) => {
+                let mut s = get(a)?;
+                (axis < s.len()).then(|| s.remove(axis))?;
+                rank_ok(s)
+            }
+            Node::Concat(a, b, axis) => {
+                let (mut s, t) = (get(a)?, get(b)?);
+                let same_rest =
+                    s.len() == t.len() && (0..s.len()).all(|i| i == axis || s[i] == t[i]);
+                (axis < s.len() && same_rest).then_some(())?;
+                s[axis] = s[axis].add(t[axis])?;
+                Some(s)
+            }
+            Node::Matmul(a, b) => {
+                let (mut s, t) = (get(a)?, get(b)?);
+                ((s.len() == 2 || s.len() == 3) && t.len() == 2 && s.last() == Some(&t[0]))
+                    .then_some(())?;
+                *s.last_mut().unwrap() = t[1];
+                Some(s)
+            }
+            Node::Softmax(a, axis) => get(a).filter(|s| axis < s.len()),
+            Node::Contiguous(a) => get(a),
+        }
+    }
+}
+
+/// A random graph
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Program {
+    pub nodes: Vec<Node>,
+}
+
+impl Program {
+    /// Generate a random well typed program with `size` nodes
+    pub fn random(size: usize, rng: &mut impl Rng) -> Self {
+        let mut program = Program {
+            nodes: vec![Node::Input(random_shape(rng))],
+        };
+        let mut shapes = program.shapes().unwrap();
+        'generate: while program.nodes.len() < size {
+            // Favour recent nodes, so programs are deep rather than wide
+            let recent = shapes.len().saturating_sub(3);
+            let a = if rng.gen_bool(0.7) {
+                rng.gen_range(recent..shapes.len())
+            } else {
+                rng.gen_range(0..shapes.len())
+            };
+            // Nodes are picked loosely and redrawn if they don't type check
+            let Some(nodes) = random_node(a, &shapes, rng) else {
+                continue;
+            };
+            let mut new_shapes = shapes.clone();
+            for node in &nodes {
+                let Some(shape) = node.shape(&new_shapes) else {
+                    continue 'generate;
+                };
+                new_shapes.push(shape);
+            }
+            shapes = new_shapes;
+            program.nodes.extend(nodes);
+        }
+        program
+    }
+
+    /// The shape of every node, or None if the program is ill typed
+    pub fn shapes(&self) -> Option<Vec<Vec<Dim>>> {
+        let mut shapes = vec![];
+        for (i, node) in self.nodes.iter().enumerate() {
+            if node.operands().into_iter().any(|o| o >= i) {
+                return None;
+            }
+            shapes.push(node.shape(&shapes)?);
+        }
+        Some(shapes)
+    }
+
+    /// Nodes no other node uses, which are the outputs of the program
+    fn outputs(&self) -> Vec<usize> {
+        let used = self.nodes.iter().flat_map(|n| n.operands()).collect_vec();
+        (0..self.nodes.len())
+            .filter(|i| !used.contains(i))
+            .collect()
+    }
+
+    /// Build the program into a graph, returning its inputs and outputs
+    pub fn build(&self, cx: &mut Graph) -> GraphIO {
+        let mut inputs = vec![];
+        let mut t: Vec<GraphTensor> = vec![];
+        for node in &self.nodes {
+            let tensor = match *node {
+                Node::Input(ref shape) => {
+                    let input = cx.tensor(shape.iter().map(|d| d.expr()).collect_vec());
+                    inputs.push(input);
+                    input
+                }
+                Node::Unary(op, a) => {
+                    let a = t[a];
+                    match op {
+                        Unary::Neg => -a,
+                        Unary::Abs => a.abs(),
+                        Unary::Square => a.square(),
+                        Unary::Sin => a.sin(),
+                        Unary::Cos => a.cos(),
+                        Unary::Exp => a.exp(),
+                        Unary::Relu => a.relu(),
+                        Unary::Sigmoid => a.sigmoid(),
+                        Unary::Tanh => a.tanh(),
+                    }
+                }
+                Node::Binary(op, a, b) => {
+                    let (a, b) = (t[a], t[b]);
+                    match op {
+                        Binary::Add => a + b,
+                        Binary::Sub => a - b,
+                        Binary::Mul => a * b,
+                        Binary::Max => a.max(b),
+                        Binary::LessThan => a.less_than(b),
+                    }
+                }
+                Node::Permute(a, ref axes) => t[a].permute(axes.clone()),
+                Node::Expand(a, axis, dim) => t[a].expand(axis, dim.expr()),
+                Node::Slice(a, axis, start, end) => t[a].slice_along(start..end, axis),
+                Node::Pad(a, axis, before, after) => t[a].pad_along(before, after, axis),
+                Node::Reduce(op, a, axis) => match op {
+                    Reduce::Sum => t[a].sum_reduce(axis),
+                    Reduce::Max => t[a].max_reduce(axis),
+                    Reduce::Mean => t[a].mean_reduce(axis),
+                },
+                Node::Concat(a, b, axis) => t[a].concat_along(t[b], axis),
+                Node::Matmul(a, b) => t[a].matmul(t[b]),
+                Node::Softmax(a, axis) => t[a].softmax(axis),
+                Node::Contiguous(a) => t[a].contiguous(),
+            };
+            t.push(tensor);
+        }
+        let outputs = self.outputs().into_iter().map(|i| t[i]).collect();
+        (inputs, outputs)
+    }
+
+    /// Remove a node, pointing its users at `replacement` instead
+    fn remove(&self, node: usize, replacement: usize) -> Program {
+        let mut nodes = self.nodes.clone();
+        nodes.remove(node);
+        for n in &mut nodes {
+            n.map_operands(|o| match o {
+                o if o == node => replacement,
+                o if o > node => o - 1,
+                o => o,
+            });
+        }
+        Program { nodes }
+    }
+
+    /// Smaller programs to try when shrinking, simplest first
+    fn shrink_candidates(&self) -> Vec<Program> {
+        let mut candidates = vec![];
+        for i in self.outputs() {
+            candidates.push(self.remove(i, 0));
+        }
+        for (i, node) in self.nodes.iter().enumerate().rev() {
+            for o in node.operands().into_iter().unique() {
+                candidates.push(self.remove(i, o));
+            }
+        }
+        for c in SYMBOLS {
+            let mut p = self.clone();
+            for dim in p.dims_mut() {
+                if dim.sym == Some(c) {
+                    *dim = Dim::fixed(dim.n + 1);
+                }
+            }
+            candidates.push(p);
+        }
+        for i in 0..self.dims().len() {
+            if self.dims()[i].n > 1 {
+                let mut p = self.clone();
+                *p.dims_mut().nth(i).unwrap() = Dim::fixed(self.dims()[i].n - 1);
+                candidates.push(p);
+            }
+        }
+        candidates.retain(|p| p != self && !p.nodes.is_empty() && p.shapes().is_some());
+        candidates
+    }
+
+    fn dims(&self) -> Vec<Dim> {
+        self.clone().dims_mut().map(|d| *d).collect()
+    }
+
+    fn dims_mut(&mut self) -> impl Iterator<Item = &mut Dim> {
+        self.nodes.iter_mut().flat_map(|n| match n {
+            Node::Input(shape) => shape.iter_mut().collect_vec(),
+            Node::Expand(_, _, dim) => vec![dim],
+            _ => vec![],
+        })
+    }
+
+    /// Greedily shrink the program while it still fails
+    pub fn shrink(mut self, fails: impl Fn(&Program) -> bool) -> Program {
+        'shrink: loop {
+            for candidate in self.shrink_candidates() {
+                if fails(&candidate) {
+                    self = candidate;
+                    continue 'shrink;
+                }
+            }
+            return self;
+        }
+    }
+}
+
+impl Display for Program {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let inputs = self
+            .nodes
+            .iter()
+            .positions(|n| matches!(n, Node::Input(_)))
+            .map(|i| format!("t{i}"))
+            .join(", ");
+        let outputs = self
+            .outputs()
+            .into_iter()
+            .map(|i| format!("t{i}"))
+            .join(", ");
+        writeln!(f, "let mut cx = Graph::new();")?;
+        for (i, node) in self.nodes.iter().enumerate() {
+            let expr = match node {
+                Node::Input(shape) if shape.len() == 1 => format!("cx.tensor({})", shape[0]),
+                Node::Input(shape) => format!("cx.tensor(({}))", shape.iter().join(", ")),
+                Node::Unary(Unary::Neg, a) => format!("-t{a}"),
+                Node::Unary(op, a) => format!("t{a}.{}()", format!("{op:?}").to_lowercase()),
+                Node::Binary(op, a, b) => match op {
+                    Binary::Add => format!("t{a} + t{b}"),
+                    Binary::Sub => format!("t{a} - t{b}"),
+                    Binary::Mul => format!("t{a} * t{b}"),
+                    Binary::Max => format!("t{a}.max(t{b})"),
+                    Binary::LessThan => format!("t{a}.less_than(t{b})"),
+                },
+                Node::Permute(a, axes) => format!("t{a}.permute(vec!{axes:?})"),
+                Node::Expand(a, axis, dim) => format!("t{a}.expand({axis}, {dim})"),
+                Node::Slice(a, axis, start, end) => {
+                    format!("t{a}.slice_along({start}..{end}, {axis})")
+                }
+                Node::Pad(a, axis, before, after) => {
+                    format!("t{a}.pad_along({before}, {after}, {axis})")
+                }
+                Node::Reduce(op, a, axis) => {
+                    format!("t{a}.{}_reduce({axis})", format!("{op:?}").to_lowercase())
+                }
+                Node::Concat(a, b, axis) => format!("t{a}.concat_along(t{b}, {axis})"),
+                Node::Matmul(a, b) => format!("t{a}.matmul(t{b})"),
+                Node::Softmax(a, axis) => format!("t{a}.softmax({axis})"),
+                Node::Contiguous(a) => format!("t{a}.contiguous()"),
+            };
+            writeln!(f, "let t{i} = {expr};")?;
+        }
+        write!(f, "(vec![{inputs}], vec![{outputs}])")
+    }
+}
+
+fn random_dim(rng: &mut impl Rng) -> Dim {
+    if rng.gen_bool(0.3) {
+        Dim::sym(*SYMBOLS.choose(rng).unwrap())
+    } else {
+        Dim::fixed(rng.gen_range(1..=4))
+    }
+}
+
+fn random_shape(rng: &mut impl Rng) -> Vec<Dim> {
+    (0..rng.gen_range(1..=3)).map(|_| random_dim(rng)).collect()
+}
+
+/// A random node using node `a`, along with any new inputs it needs. None if the chosen op can't
+/// apply to `a`, and the node may not type check.
+fn random_node(a: usize, shapes: &[Vec<Dim>], rng: &mut impl Rng) -> Option<Vec<Node>> {
+    let shape = &shapes[a];
+    let rank = shape.len();
+    let axis = rng.gen_range(0..rank);
+    let node = match rng.gen_range(0..12) {
+        0 | 1 => Node::Unary(
+            *[
+                Unary::Neg,
+                Unary::Abs,
+                Unary::Square,
+                Unary::Sin,
+                Unary::Cos,
+                Unary::Exp,
+                Unary::Relu,
+                Unary::Sigmoid,
+                Unary::Tanh,
+            ]
+            .choose(rng)
+            .unwrap(),
+            a,
+        ),
+        2 | 3 => {
+            let op = *[
+                Binary::Add,
+                Binary::Sub,
+                Binary::Mul,
+                Binary::Max,
+                Binary::LessThan,
+            ]
+            .choose(rng)
+            .unwrap();
+            let (b, mut nodes) = operand(shape.clone(), shapes, rng);
+            nodes.push(Node::Binary(op, a, b));
+            return Some(nodes);
+        }
+        4 => {
+            let mut axes = (0..rank).collect_vec();
+            axes.shuffle(rng);
+            Node::Permute(a, axes)
+        }
+        5 => Node::Expand(a, rng.gen_range(0..=rank), random_dim(rng)),
+        6 => {
+            let n = shape[axis].n;
+            if shape[axis].sym.is_some() || n < 2 {
+                return None;
+            }
+            let start = rng.gen_range(0..n);
+            Node::Slice(a, axis, start, rng.gen_range(start + 1..=n))
+        }
+        7 => Node::Pad(a, axis, rng.gen_range(0..=2), rng.gen_range(0..=2)),
+        8 => Node::Reduce(
+            *[Reduce::Sum, Reduce::Max, Reduce::Mean]
+                .choose(rng)
+                .unwrap(),
+            a,
+            axis,
+        ),
+        9 => {
+            let mut other = shape.clone();
+            other[axis] = if shape[axis].sym.is_some() {
+                Dim::fixed(rng.gen_range(1..=3))
+            } else {
+                random_dim(rng)
+            };
+            let (b, mut nodes) = operand(other, shapes, rng);
+            nodes.push(Node::Concat(a, b, axis));
+            return Some(nodes);
+        }
+        10 => {
+            if rank == 1 {
+                return None;
+            }
+            let (b, mut nodes) = operand(vec![shape[rank - 1], random_dim(rng)], shapes, rng);
+            nodes.push(Node::Matmul(a, b));
+            return Some(nodes);
+        }
+        _ => {
+            if rng.gen_bool(0.5) {
+                Node::Softmax(a, axis)
+            } else {
+                Node::Contiguous(a)
+            }
+        }
+    };
+    Some(vec![node])
+}
+
+/// An operand of this shape, either an earlier node or a new input (returned to go before the node
+/// using it)
+fn operand(shape: Vec<Dim>, shapes: &[Vec<Dim>], rng: &mut impl Rng) -> (usize, Vec<Node>) {
+    let matching = (0..shapes.len())
+        .filter(|i| shapes[*i] == shape)
+        .collect_vec();
+    match matching.choose(rng) {
+        Some(b) if rng.gen_bool(0.5) => (*b, vec![]),
+        _ => (shapes.len(), vec![Node::Input(shape)]),
+    }
+}
+
+/// Why a program fails under the compilers, if it does
+fn failure(program: &Program, compilers: &[TestCompiler], tolerance: f32) -> Option<String> {
+    let result = panic::catch_unwind(AssertUnwindSafe(|| {
+        check_compilers(&|cx| program.build(cx), compilers, tolerance)
+    }));
+    match result {
+        Ok(Ok(())) => None,
+        Ok(Err(e)) => Some(e),
+        Err(panic) => Some(
+            panic
+                .downcast_ref::<String>()
+                .cloned()
+                .or_else(|| panic.downcast_ref::<&str>().map(|s| s.to_string()))
+                .unwrap_or_default(),
+        ),
+    }
+}
+
+/// Run `cases` random programs under each compiler, checking their outputs match the unoptimized
+/// graph within 1e-3. On a failure, panics with the smallest failing program found.
+pub fn fuzz_compilers(compilers: &[TestCompiler], cases: usize, seed: u64) {
+    let mut rng = StdRng::seed_from_u64(seed);
+    for case in 0..cases {
+        let program = Program::random(PROGRAM_SIZE, &mut rng);
+        if failure(&program, compilers, 1e-3).is_none() {
+            continue;
+        }
+        // Silence the panics of failing candidates while shrinking
+        let hook = panic::take_hook();
+        panic::set_hook(Box::new(|_| {}));
+        let program = program.shrink(|p| failure(p, compilers, 1e-3).is_some());
+        panic::set_hook(hook);
+        panic!(
+            "Case {case} (seed {seed}) failed. Minimal reproducer:\n{program}\n\n{}",
+            failure(&program, compilers, 1e-3).unwrap()
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::{rngs::StdRng, SeedableRng};
+
+    use super::{fuzz_compilers, Dim, Node, Program, Unary};
+    use crate::{prelude::*, tests::harness::TestCompiler};
+
+    #[test]
+    fn test_random_programs_are_well_typed() {
+        let mut rng = StdRng::seed_from_u64(0);
+        for _ in 0..200 {
+            let program = Program::random(10, &mut rng);
+            assert!(program.shapes().is_some(), "{program}");
+        }
+    }
+
+    #[test]
+    fn test_shrink() {
+        // Fails whenever there's a sin, so shrinks to an input and a sin
+        let program = Program {
+            nodes: vec![
+                Node::Input(vec![Dim::sym('a'), Dim::fixed(3)]),
+                Node::Unary(Unary::Exp, 0),
+                Node::Unary(Unary::Sin, 1),
+                Node::Reduce(super::Reduce::Sum, 2, 1),
+            ],
+        };
+        let has_sin = |p: &Program| {
+            p.nodes
+                .iter()
+                .any(|n| matches!(n, Node::Unary(Unary::Sin, _)))
+        };
+        let shrunk = program.shrink(has_sin);
+        assert_eq!(
+            shrunk.nodes,
+            vec![
+                Node::Input(vec![Dim::fixed(1), Dim::fixed(1)]),
+                Node::Unary(Unary::Sin, 0)
+            ]
+        );
+        assert_eq!(
+            shrunk.to_string(),
+            "let mut cx = Graph::new();\nlet t0 = cx.tensor((1, 1));\nlet t1 = t0.sin();\n(vec![t0], vec![t1])"
+        );
+    }
+
+    #[test]
+    fn test_fuzz_generic_compiler() {
+        fuzz_compilers(&[TestCompiler::new(GenericCompiler::default())], 40, 0);
+    }
+}
@@ -1,56 +1,269 @@
-use petgraph::stable_graph::NodeIndex;
+//! Differential testing of compilers: build a graph, run it unoptimized and under each compiler, and
+//! check the outputs match.
+//!
+//! ```rust
+//! use luminal::{prelude::*, tests::harness::*};
+//!
+//! fn add_mul(cx: &mut Graph) -> (Vec<GraphTensor>, Vec<GraphTensor>) {
+//!     let a = cx.tensor(('a', 3));
+//!     let b = cx.tensor(3);
+//!     (vec![a, b], vec![a * b.expand(0, a.dims()[0]) + a])
+//! }
+//!
+//! test_compilers_close(&[add_mul], &[TestCompiler::new(GenericCompiler::default())]);
+//! ```
+
+use std::any::type_name;
+
+use itertools::Itertools;
+use petgraph::{algo::toposort, stable_graph::NodeIndex, Direction};
+use rand::{rngs::StdRng, Rng, SeedableRng};
 
 use crate::prelude::*;
 
-use super::{assert_close, assert_exact};
-
-// #[allow(clippy::type_complexity)]
-// pub fn test_compilers_exact(
-//     graphs: &[fn() -> (Graph, Vec<GraphTensor<()>>)],
-//     compilers: &[Box<dyn Compiler>],
-// ) {
-//     test_compilers(graphs, compilers, assert_exact)
-// }
-
-// #[allow(clippy::type_complexity)]
-// pub fn test_compilers_close(
-//     graphs: &[fn() -> (Graph, Vec<GraphTensor<()>>)],
-//     compilers: &[Box<dyn Compiler>],
-// ) {
-//     test_compilers(graphs, compilers, assert_close)
-// }
-
-// #[allow(clippy::type_complexity)]
-// fn test_compilers<F: Fn(&[f32], &[f32])>(
-//     graphs: &[fn() -> (Graph, Vec<GraphTensor<()>>)],
-//     compilers: &[Box<dyn Compiler>],
-//     condition: F,
-// ) {
-//     for create_graph in graphs {
-//         let (mut cx, result_tensors) = create_graph();
-//         cx.execute();
-//         let unopt_results = result_tensors
-//             .into_iter()
-//             .map(|mut gt| {
-//                 gt.graph_ref = &mut cx;
-//                 gt.data()
-//             })
-//             .collect::<Vec<_>>();
-
-//         for compiler in compilers {
-//             let (mut cx, result_tensors) = create_graph();
-//             compiler.compile(&mut cx);
-//             cx.execute();
-//             let opt_results = result_tensors
-//                 .into_iter()
-//                 .map(|mut gt| {
-//                     gt.graph_ref = &mut cx;
-//                     gt.data()
-//                 })
-//                 .collect::<Vec<_>>();
-//             for (a, b) in unopt_results.iter().zip(opt_results.iter()) {
-//                 condition(a, b);
-//             }
-//         }
-//     }
-// }
+use super::random_vec_rng;
+
+/// Builds a graph to test, returning its inputs and outputs. Inputs get filled with random data, and
+/// dynamic dimensions in the graph get random sizes.
+pub type GraphBuilder = fn(&mut Graph) -> GraphIO;
+
+/// The inputs and outputs of a graph
+pub type GraphIO = (Vec<GraphTensor>, Vec<GraphTensor>);
+
+/// A compiler under test. The compiler's type is erased so different compilers can be tested
+/// together.
+pub struct TestCompiler {
+    name: String,
+    #[allow(clippy::type_complexity)]
+    compile: Box<dyn Fn(&mut Graph, &mut Vec<NodeIndex>)>,
+}
+
+impl TestCompiler {
+    pub fn new<C: Compiler + 'static>(compiler: C) -> Self {
+        Self {
+            name: type_name::<C>().to_string(),
+            compile: Box::new(move |graph, ids| {
+                compiler.compile(graph, ids);
+                graph.toposort();
+                graph.reset();
+            }),
+        }
+    }
+}
+
+/// Number of runs of each graph, each with different inputs and dynamic dimensions
+const RUNS: u64 = 3;
+
+/// Check compilers don't change the outputs of any graph at all
+pub fn test_compilers_exact(graphs: &[GraphBuilder], compilers: &[TestCompiler]) {
+    test_compilers(graphs, compilers, 0.)
+}
+
+/// Check compilers keep the outputs of every graph within 1e-3
+pub fn test_compilers_close(graphs: &[GraphBuilder], compilers: &[TestCompiler]) {
+    test_compilers(graphs, compilers, 1e-3)
+}
+
+/// Check each graph gives the same outputs under each compiler as it does unoptimized, within
+/// `tolerance`. On a mismatch, panics with the first node whose output diverged.
+pub fn test_compilers(graphs: &[GraphBuilder], compilers: &[TestCompiler], tolerance: f32) {
+    for (i, build) in graphs.iter().enumerate() {
+        if let Err(e) = check_compilers(build, compilers, tolerance) {
+            panic!("Graph {i}: {e}");
+        }
+    }
+}
+
+/// Check a single graph gives the same outputs under each compiler as it does unoptimized, returning
+/// a description of the first mismatch
+pub fn check_compilers(
+    build: &dyn Fn(&mut Graph) -> GraphIO,
+    compilers: &[TestCompiler],
+    tolerance: f32,
+) -> Result<(), String> {
+    for seed in 0..RUNS {
+        let (reference, _) = run(build, None, seed, false);
+        for compiler in compilers {
+            let (outputs, _) = run(build, Some(compiler), seed, false);
+            for (i, (a, b)) in reference.iter().zip(&outputs).enumerate() {
+                if let Some(mismatch) = mismatch(a, b, tolerance) {
+                    return Err(format!(
+                        "{} changed output {i} (seed {seed}): {mismatch}\n{}",
+                        compiler.name,
+                        find_divergence(build, compiler, seed, tolerance)
+                    ));
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Describe where two tensors differ by more than the tolerance, if they do. The tolerance is
+/// relative for values larger than 1.
+fn mismatch(a: &[f32], b: &[f32], tolerance: f32) -> Option<String> {
+    if a.len() != b.len() {
+        return Some(format!("{} elements vs {}", a.len(), b.len()));
+    }
+    a.iter()
+        .zip(b)
+        .position(|(a, b)| {
+            // Exact comparisons treat matching NaNs and infinities as equal
+            !(a == b || a.is_nan() && b.is_nan() || (a - b).abs() <= tolerance * a.abs().max(1.))
+        })
+        .map(|i| format!("{} vs {} at index {i}", a[i], b[i]))
+}
+
+/// Build a graph with random inputs, then compile and run it, returning the data of each output.
+/// With `keep_all`, the raw data of every node in the original graph is also returned.
+#[allow(clippy::type_complexity)]
+fn run(
+    build: &dyn Fn(&mut Graph) -> GraphIO,
+    compiler: Option<&TestCompiler>,
+    seed: u64,
+    keep_all: bool,
+) -> (Vec<Vec<f32>>, Vec<(NodeIndex, Option<Vec<f32>>)>) {
+    let mut cx = Graph::new();
+    let (inputs, outputs) = build(&mut cx);
+    let mut rng = StdRng::seed_from_u64(seed);
+    let edge_dims = cx
+        .graph
+        .edge_weights()
+        .filter_map(|e| e.as_data())
+        .flat_map(|(_, _, shape)| shape.dims())
+        .collect_vec();
+    for c in inputs
+        .iter()
+        .chain(&outputs)
+        .flat_map(|t| t.dims())
+        .chain(edge_dims)
+        .flat_map(|d| d.to_symbols())
+        .collect_vec()
+    {
+        cx.dyn_map.entry(c).or_insert_with(|| rng.gen_range(1..=6));
+    }
+    for input in &inputs {
+        let n = input.shape.n_elements().exec(&cx.dyn_map).unwrap();
+        input.set(random_vec_rng(n, &mut rng));
+    }
+    let mut outputs = outputs.into_iter().map(|o| o.retrieve()).collect_vec();
+
+    let nodes = if keep_all {
+        toposort(&cx.graph, None).unwrap()
+    } else {
+        vec![]
+    };
+    for node in &nodes {
+        cx.keep_tensors(*node);
+        let shape = cx
+            .graph
+            .edges_directed(*node, Direction::Outgoing)
+            .find_map(|e| e.weight().as_data())
+            .map(|(_, _, shape)| shape);
+        if let Some(shape) = shape {
+            cx.to_retrieve.entry(*node).or_insert((0, shape));
+        }
+    }
+    if let Some(compiler) = compiler {
+        let mut ids = outputs.iter().map(|o| o.id).chain(nodes.clone()).collect();
+        (compiler.compile)(&mut cx, &mut ids);
+        for (output, id) in outputs.iter_mut().zip(&ids) {
+            output.id = *id;
+        }
+        cx.execute();
+        let kept = nodes
+            .into_iter()
+            .zip(ids.into_iter().skip(outputs.len()))
+            .map(|(node, id)| (node, raw_data(&cx, id)))
+            .collect();
+        (outputs.iter().map(|o| o.data()).collect(), kept)
+    } else {
+        cx.execute();
+        let kept = nodes.into_iter().map(|n| (n, raw_data(&cx, n))).collect();
+        (outputs.iter().map(|o| o.data()).collect(), kept)
+    }
+}
+
+fn raw_data(cx: &Graph, node: NodeIndex) -> Option<Vec<f32>> {
+    cx.get_tensor_ref(node, 0)
+        .and_then(|t| t.downcast_ref::<Vec<f32>>())
+        .cloned()
+}
+
+/// Run the graph again keeping every node, and find the first one whose output diverges
+fn find_divergence(
+    build: &dyn Fn(&mut Graph) -> GraphIO,
+    compiler: &TestCompiler,
+    seed: u64,
+    tolerance: f32,
+) -> String {
+    let (_, reference) = run(build, None, seed, true);
+    let (_, compiled) = run(build, Some(compiler), seed, true);
+    let mut cx = Graph::new();
+    build(&mut cx);
+    for ((node, a), (_, b)) in reference.iter().zip(&compiled) {
+        let (Some(a), Some(b)) = (a, b) else {
+            continue;
+        };
+        if a.len() == b.len() {
+            if let Some(mismatch) = mismatch(a, b, tolerance) {
+                let op = format!("{:?}", cx.graph.node_weight(*node).unwrap());
+                return format!("First diverging node: {} ({op}): {mismatch}", node.index());
+            }
+        }
+    }
+    "No node diverged when every node was kept".to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{test_compilers, test_compilers_close, GraphBuilder, TestCompiler};
+    use crate::{
+        generic_compiler::{ArithmeticElimination, ConstantFolding, RemoveUnusedNodes, CSE},
+        op::Operator,
+        prelude::*,
+        tests::test_graphs,
+    };
+
+    #[test]
+    fn test_generic_compilers() {
+        test_compilers_close(
+            test_graphs::ALL,
+            &[
+                TestCompiler::new(RemoveUnusedNodes),
+                TestCompiler::new(ConstantFolding::default()),
+                TestCompiler::new(ArithmeticElimination),
+                TestCompiler::new(ContiguousElimination),
+                TestCompiler::new(CSE),
+                TestCompiler::new(GenericCompiler::default()),
+            ],
+        );
+    }
+
+    /// Rewrites every Add into a Mul
+    #[derive(Debug, Default)]
+    struct BrokenCompiler;
+
+    impl Compiler for BrokenCompiler {
+        type Output = ();
+        fn compile<T: ToIdsMut>(&self, graph: &mut Graph, _: T) {
+            for node in graph.node_indices().collect::<Vec<_>>() {
+                if graph.check_node_type::<crate::op::Add>(node) {
+                    *graph.graph.node_weight_mut(node).unwrap() =
+                        Box::new(crate::op::Mul) as Box<dyn Operator>;
+                }
+            }
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "First diverging node")]
+    fn test_reports_divergence() {
+        let graph: GraphBuilder = |cx| {
+            let a = cx.tensor(4);
+            let b = cx.tensor(4);
+            (vec![a, b], vec![((a + b) * 2.).exp()])
+        };
+        test_compilers(&[graph], &[TestCompiler::new(BrokenCompiler)], 0.);
+    }
+}
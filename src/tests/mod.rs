@@ -1,8 +1,10 @@
 #![allow(unused)]
 
 #[cfg(test)]
-mod dynamic;
+pub(crate) mod device;
 #[cfg(test)]
+mod dynamic;
+pub mod fuzz;
 pub mod harness;
 pub mod test_graphs;
 #[cfg(test)]
@@ -67,6 +69,127 @@ fn test_shapes() {
     assert_exact(&b.data(), &[1., 3., 2., 4.]);
 }
 
+#[test]
+fn test_constant_folding() {
+    let mut cx = Graph::new();
+    let a = cx.tensor((3, 3)).set(vec![1.; 9]);
+    let w = cx.tensor(3).set(vec![1., 2., 3.]);
+    let mask = cx.tril(3, 0);
+    let mut b = (a * mask).retrieve();
+    let mut c = (a + (w * 2.).exp2().expand(0, 3)).retrieve();
+    let mut d = (cx.constant(2.) * 3.).retrieve();
+
+    cx.compile(ConstantFolding::with_static(w), (&mut b, &mut c, &mut d));
+    // Only the ops reading the input are left
+    let ops = cx
+        .node_indices()
+        .filter(|n| !cx.check_node_type::<Function>(*n) && !cx.check_node_type::<Constant>(*n))
+        .count();
+    assert_eq!(ops, 2);
+    assert!(cx.check_node_type::<Constant>(d.id));
+    cx.execute();
+    assert_exact(&b.data(), &[1., 0., 0., 1., 1., 0., 1., 1., 1.]);
+    assert_close(&c.data(), &[5., 17., 65., 5., 17., 65., 5., 17., 65.]);
+    assert_exact(&d.data(), &[6.]);
+}
+
+#[test]
+fn test_constant_folding_skips_dynamic() {
+    let mut cx = Graph::new();
+    let w = cx.tensor(3).set(vec![1., 2., 3.]);
+    let mut a = cx.arange('a').retrieve();
+    let mut b = w.exp2().retrieve();
+    cx.set_dyn_dim('a', 3);
+    // Tensors aren't static unless marked as such
+    let ops = cx.node_count();
+    cx.compile(ConstantFolding::default(), (&mut a, &mut b));
+    assert_eq!(cx.node_count(), ops);
+    cx.execute();
+    assert_exact(&a.data(), &[0., 1., 2.]);
+    assert_close(&b.data(), &[2., 4., 8.]);
+}
+
+#[test]
+fn test_cse() {
+    let mut cx = Graph::new();
+    let a = cx.tensor((2, 3)).set(vec![1., 2., 3., 4., 5., 6.]);
+    let b = cx.tensor((2, 3)).set(vec![1., 2., 3., 4., 5., 6.]);
+    let mut c = (a.sum_reduce(0) + a.sum_reduce(0)).retrieve();
+    let mut d = (a.sum_reduce(1) * a.max_reduce(1) + b.sum_reduce(1)).retrieve();
+    let mut e = (cx.constant(2.) + cx.constant(3.)).retrieve();
+    let mut f = (cx.constant(2.) + cx.constant(3.)).retrieve();
+
+    cx.compile(CSE, (&mut c, &mut d, &mut e, &mut f));
+    // Equal ops on the same inputs are merged, but loads, different constants and different
+    // reduction dimensions aren't
+    assert_eq!(e.id, f.id);
+    assert_eq!(cx.node_count(), 12);
+    cx.execute();
+    assert_exact(&c.data(), &[10., 14., 18.]);
+    assert_exact(&d.data(), &[24., 105.]);
+    assert_exact(&e.data(), &[5.]);
+}
+
+#[test]
+fn test_wrapped_op_equality() {
+    use crate::op::{MaxReduce, SumReduce};
+    use std::{
+        hash::{DefaultHasher, Hasher},
+        sync::{Arc, Mutex},
+    };
+    fn hash(op: &dyn Operator) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        op.hash_op(&mut hasher);
+        hasher.finish()
+    }
+
+    // Boxed ops compare like the ops inside, whether or not the other op is boxed too
+    let boxed = Box::new(SumReduce(1));
+    assert!(boxed.equals(&Box::new(SumReduce(1))));
+    assert!(boxed.equals(&SumReduce(1)));
+    assert!(!boxed.equals(&Box::new(SumReduce(0))));
+    assert!(!boxed.equals(&MaxReduce(1)));
+    assert_eq!(hash(&boxed), hash(&SumReduce(1)));
+
+    let shared = Arc::new(Mutex::new(SumReduce(1)));
+    assert!(shared.equals(&shared.clone()));
+    assert!(shared.equals(&Arc::new(Mutex::new(SumReduce(1)))));
+    assert!(shared.equals(&SumReduce(1)));
+    assert!(!shared.equals(&Arc::new(Mutex::new(SumReduce(0)))));
+    assert_eq!(hash(&shared), hash(&SumReduce(1)));
+}
+
+#[test]
+fn test_contiguous_elimination() {
+    let mut cx = Graph::new();
+    let a = cx.tensor((2, 3)).set(vec![1., 2., 3., 4., 5., 6.]);
+    // Permuted copy that the multiply can read through
+    let mut b = (a.permute((1, 0)).contiguous().expand(0, 2) * 2.).retrieve();
+    // Permuted copy that's reshaped, so it has to stay
+    let mut c = (a.permute((1, 0)).reshape(6) + 1.).retrieve();
+    // Copy that doesn't move anything
+    let copy = cx
+        .add_op(crate::op::Contiguous)
+        .input(a.id, 0, a.shape)
+        .finish();
+    let mut d = (GraphTensor::from_id(copy, a.shape, &mut cx) * 3.).retrieve();
+
+    let eliminated = cx.compile(ContiguousElimination, (&mut b, &mut c, &mut d));
+    assert_eq!(eliminated, 2);
+    let copies = cx
+        .node_indices()
+        .filter(|n| cx.check_node_type::<crate::op::Contiguous>(*n))
+        .count();
+    assert_eq!(copies, 1);
+    cx.execute();
+    assert_exact(
+        &b.data(),
+        &[2., 8., 4., 10., 6., 12., 2., 8., 4., 10., 6., 12.],
+    );
+    assert_exact(&c.data(), &[2., 5., 3., 6., 4., 7.]);
+    assert_exact(&d.data(), &[3., 6., 9., 12., 15., 18.]);
+}
+
 /// Ensure two arrays are nearly equal
 pub fn assert_close(a_vec: &[f32], b_vec: &[f32]) {
     assert_close_precision(a_vec, b_vec, 1e-3);
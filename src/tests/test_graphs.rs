@@ -1,27 +1,101 @@
-use rand::{rngs::StdRng, SeedableRng};
+//! Graphs for testing compilers with the [`super::harness`]
 
 use crate::prelude::*;
 
-use super::random_vec_rng;
-
-pub fn matmul() -> (Graph, Vec<GraphTensor>) {
-    let mut rng = StdRng::seed_from_u64(0);
-    let mut cx = Graph::new();
-    let a = cx
-        .tensor(('a', 3))
-        .set_dyn(random_vec_rng(2 * 3, &mut rng), (2, 3));
-    let b = cx.tensor((3, 3)).set(random_vec_rng(3 * 3, &mut rng));
-    let c = a.matmul(b).retrieve();
-    (cx, vec![c])
-}
-
-pub fn batch_matmul() -> (Graph, Vec<GraphTensor>) {
-    let mut rng = StdRng::seed_from_u64(0);
-    let mut cx = Graph::new();
-    let a = cx
-        .tensor(('a', 'b', 2))
-        .set_dyn(random_vec_rng(2 * 3 * 2, &mut rng), (2, 3, 2));
-    let b = cx.tensor((2, 4)).set(random_vec_rng(2 * 4, &mut rng));
-    let c = a.matmul(b).retrieve();
-    (cx, vec![c])
+use super::harness::{GraphBuilder, GraphIO};
+
+/// Every test graph
+pub const ALL: &[GraphBuilder] = &[
+    elementwise,
+    identities,
+    duplicates,
+    reductions,
+    movement,
+    matmul,
+    batch_matmul,
+    normalization,
+    constants,
+    copies,
+];
+
+pub fn elementwise(cx: &mut Graph) -> GraphIO {
+    let a = cx.tensor(('a', 4));
+    let b = cx.tensor(('a', 4));
+    let c = (a * b + a.exp2()).sin() / (b.abs() + 1.);
+    let d = a.max(b).sqrt() - a.less_than(b).log2().exp2();
+    (vec![a, b], vec![c, d])
+}
+
+/// Arithmetic identities that can be simplified away
+pub fn identities(cx: &mut Graph) -> GraphIO {
+    let a = cx.tensor((3, 'a'));
+    let zero = cx.constant(0.).expand_to(a.shape);
+    let one = cx.constant(1.).expand_to(a.shape);
+    let b = ((a + zero) * one).exp2().log2() + a * zero;
+    (vec![a], vec![b])
+}
+
+/// Subexpressions computed more than once
+pub fn duplicates(cx: &mut Graph) -> GraphIO {
+    let a = cx.tensor(('a', 'b'));
+    let b = cx.tensor(('a', 'b'));
+    let c = (a + b).sin() * (a + b).sin();
+    let d = (a + b).cos() - c.sum_reduce(1).expand(1, a.dims()[1]);
+    (vec![a, b], vec![c, d])
+}
+
+pub fn reductions(cx: &mut Graph) -> GraphIO {
+    let a = cx.tensor(('a', 3, 'b'));
+    let b = a.sum_reduce(1);
+    let c = a.max_reduce((0, 2));
+    let d = a.mean_reduce(2).exp();
+    (vec![a], vec![b, c, d])
+}
+
+/// Permutes, slices, pads and concatenation
+pub fn movement(cx: &mut Graph) -> GraphIO {
+    let a = cx.tensor(('a', 4));
+    let b = cx.tensor((4, 'a'));
+    let c = a.permute((1, 0)) + b;
+    let d = c.slice((1.., ..)).pad(((0, 2), (1, 0))).exp();
+    let e = a.concat_along(b.permute((1, 0)), 0).sin();
+    (vec![a, b], vec![c, d, e])
+}
+
+pub fn matmul(cx: &mut Graph) -> GraphIO {
+    let a = cx.tensor(('a', 3));
+    let b = cx.tensor((3, 3));
+    (vec![a, b], vec![a.matmul(b)])
+}
+
+pub fn batch_matmul(cx: &mut Graph) -> GraphIO {
+    let a = cx.tensor(('a', 'b', 2));
+    let b = cx.tensor((2, 4));
+    let c = cx.tensor(('a', 4, 'b'));
+    let d = a.matmul(b);
+    (vec![a, b, c], vec![d, d.matmul(c)])
+}
+
+pub fn normalization(cx: &mut Graph) -> GraphIO {
+    let a = cx.tensor(('a', 5));
+    (vec![a], vec![a.softmax(1), a.layer_norm(1, 1e-5)])
+}
+
+/// Subgraphs computable at compile time, next to ones with dynamic dimensions
+pub fn constants(cx: &mut Graph) -> GraphIO {
+    let a = cx.tensor((4, 4));
+    let mask = cx.tril(4, 0);
+    let b = a * mask + cx.arange(4).expand(0, 4).sin() * (cx.constant(2.) * 3.).expand_to(a.shape);
+    let c = cx.tensor('a');
+    let d = c + cx.arange('a').exp2();
+    (vec![a, c], vec![b, d])
+}
+
+/// Copies after permutes, like attention heads being split and merged
+pub fn copies(cx: &mut Graph) -> GraphIO {
+    let a = cx.tensor(('a', 2, 3));
+    let b = cx.tensor((2, 3));
+    let c = a.permute((1, 0, 2)).contiguous().matmul(b.permute((1, 0)));
+    let d = c.permute((1, 0, 2)).reshape(('a', 4)).sin();
+    (vec![a, b], vec![c, d])
 }